@@ -976,7 +976,7 @@ impl<'de> crate::serde::Deserialize<'de> for U256 {
                 where
                     E: serde::de::Error,
                 {
-                    let b = v.try_into().map_err(|_| de::Error::invalid_length(v.len(), &self))?;
+                    let b = core::convert::TryInto::try_into(v).map_err(|_| de::Error::invalid_length(v.len(), &self))?;
                     Ok(U256::from_be_bytes(b))
                 }
             }
@@ -36,6 +36,10 @@ use nakamoto_common::block::{
 };
 use nakamoto_common::nonempty::NonEmpty;
 
+/// Maximum number of orphan headers kept while waiting for their ancestors. Once reached,
+/// the oldest orphans are evicted.
+pub const MAX_ORPHAN_HEADERS: usize = 4096;
+
 /// A block that is being stored by the block cache.
 #[derive(Debug, Clone, Copy)]
 struct CachedBlock {
@@ -79,6 +83,10 @@ pub struct BlockCache<S: Store> {
     chain: NonEmpty<CachedBlock>,
     headers: HashMap<BlockHash, Height>,
     orphans: HashMap<BlockHash, BlockHeader>,
+    /// Orphan hashes, oldest first. May contain hashes that are no longer orphans.
+    orphan_queue: VecDeque<BlockHash>,
+    /// Maximum number of orphans kept.
+    max_orphans: usize,
     checkpoints: BTreeMap<Height, BlockHash>,
     params: Params,
     /// Total cumulative work on the active chain.
@@ -113,6 +121,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             chain,
             headers,
             orphans,
+            orphan_queue: VecDeque::new(),
+            max_orphans: MAX_ORPHAN_HEADERS,
             params,
             checkpoints,
             chainwork,
@@ -272,7 +282,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             }
         }
         // We can now insert the header in the orphan set for further processing.
-        self.orphans.insert(hash, header);
+        self.insert_orphan(hash, header);
 
         // If it doesn't connect to any existing block, there's nothing left to do.
         // We know for a fact we won't discover any new branches.
@@ -293,7 +303,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         }
 
         let mut best_branch = None;
-        let mut best_hash = tip.hash();
+        let mut best_hash = best;
         let mut best_work = Uint256::zero();
 
         for branch in candidates.iter() {
@@ -549,18 +559,40 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     fn rollback(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
         let mut stale = Vec::new();
 
-        for (block, height) in self.chain.tail.drain(height as usize..).zip(height + 1..) {
+        let blocks = self.chain.tail.drain(height as usize..).collect::<Vec<_>>();
+
+        for (block, height) in blocks.into_iter().zip(height + 1..) {
             stale.push((height, block.header));
 
             self.chainwork = self.chainwork - block.work();
             self.headers.remove(&block.hash());
-            self.orphans.insert(block.hash(), block.header);
+            self.insert_orphan(block.hash(), block.header);
         }
         self.store.rollback(height)?;
 
         Ok(stale)
     }
 
+    /// Insert a header in the orphan set, evicting the oldest orphans if the set is full.
+    fn insert_orphan(&mut self, hash: BlockHash, header: BlockHeader) {
+        if self.orphans.insert(hash, header).is_none() {
+            self.orphan_queue.push_back(hash);
+        }
+        while self.orphans.len() > self.max_orphans {
+            let Some(oldest) = self.orphan_queue.pop_front() else {
+                break;
+            };
+            if self.orphans.remove(&oldest).is_some() {
+                log::debug!("Evicted orphan header {}", oldest);
+            }
+        }
+        // Drop hashes of headers that were connected or evicted, so that the queue stays bounded.
+        if self.orphan_queue.len() > self.max_orphans * 2 {
+            let orphans = &self.orphans;
+            self.orphan_queue.retain(|h| orphans.contains_key(h));
+        }
+    }
+
     /// Activate a fork candidate. Returns the list of rolled-back (stale) headers.
     fn switch_to_fork(&mut self, branch: &Candidate) -> Result<Vec<(Height, BlockHeader)>, Error> {
         let stale = self.rollback(branch.fork_height)?;
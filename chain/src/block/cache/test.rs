@@ -1569,3 +1569,41 @@ fn test_cache_locate_headers() {
         "If the stop height is equal to the start height, we don't expect anything"
     );
 }
+
+#[test]
+fn test_cache_orphan_eviction() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    cache.max_orphans = 2;
+
+    let g = &mut fastrand::Rng::new();
+
+    // a0 <- a1 <- a2 <- a3 <- a4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+
+    // Orphans are kept until the pool is full, and the oldest are evicted first.
+    for a in [&a2, &a3, &a4] {
+        cache.import_blocks(iter::once(a.block()), &ctx).unwrap();
+    }
+    assert!(!cache.is_known(&a2.hash));
+    assert!(cache.is_known(&a3.hash));
+    assert!(cache.is_known(&a4.hash));
+
+    // The remaining orphans connect once their ancestors arrive.
+    cache.import_block(a1.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a1.hash);
+
+    let r = cache.import_block(a2.block(), &ctx).unwrap();
+    assert_matches!(r, ImportResult::TipChanged { height: 4, .. });
+    assert_eq!(cache.tip().0, a4.hash);
+    assert!(cache.orphans.is_empty());
+}
@@ -44,7 +44,9 @@ pub use nakamoto_common::network;
 pub use nakamoto_common::network::Network;
 pub use nakamoto_common::p2p::Domain;
 pub use nakamoto_net::event;
-pub use nakamoto_p2p::fsm::{Command, CommandError, Event, Hooks, Limits, Link, Peer};
+pub use nakamoto_p2p::fsm::{
+    ChainImport, Command, CommandError, Event, EventBatcher, Hooks, Limits, Link, Peer,
+};
 
 pub use crate::error::Error;
 pub use crate::event::Loading;
@@ -587,6 +589,22 @@ impl<W: Waker> handle::Handle for Handle<W> {
         };
         self.command(Command::LoadBloomFilter(bloom_filter, addr))
     }
+    fn add_to_bloom_filter(
+        &self,
+        addr: net::SocketAddr,
+        data: Vec<u8>,
+    ) -> Result<(), handle::Error> {
+        self.command(Command::AddToBloomFilter(data, addr))
+    }
+    fn get_bloom_filter(
+        &self,
+        addr: net::SocketAddr,
+    ) -> Result<Option<nakamoto_common::bloom::BloomFilter>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetBloomFilter(addr, transmit))?;
+
+        Ok(receive.recv()?)
+    }
     fn get_tip(&self) -> Result<(Height, BlockHeader, Uint256), handle::Error> {
         let (transmit, receive) = chan::bounded::<(Height, BlockHeader, Uint256)>(1);
         self._command(Command::GetTip(transmit))?;
@@ -750,6 +768,13 @@ impl<W: Waker> handle::Handle for Handle<W> {
         Ok(receive.recv()?)
     }
 
+    fn get_peer_metrics(&self) -> Result<Vec<fsm::PeerMetric>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetPeerMetrics(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
     fn wait<F, T>(&self, f: F) -> Result<T, handle::Error>
     where
         F: FnMut(fsm::Event) -> Option<T>,
@@ -815,7 +840,11 @@ impl<W: Waker> handle::Handle for Handle<W> {
             None => event::wait(
                 &events,
                 |e| match e {
-                    Event::BlockHeadersImported { height, hash, .. } if height == h => Some(hash),
+                    Event::BlockHeadersImported(ChainImport { height, hash, .. })
+                        if height == h =>
+                    {
+                        Some(hash)
+                    }
                     _ => None,
                 },
                 self.timeout,
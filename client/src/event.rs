@@ -183,7 +183,7 @@ mod test {
 
         assert_matches!(
             events.try_recv(),
-            Ok(Event::PeerConnectionFailed { addr, error })
+            Ok(Event::PeerConnectionFailed { addr, error, .. })
             if addr == remote && error.kind() == io::ErrorKind::UnexpectedEof
         );
     }
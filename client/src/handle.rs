@@ -10,7 +10,7 @@ use thiserror::Error;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::Address;
 use nakamoto_common::bitcoin::util::uint::Uint256;
-use nakamoto_common::bitcoin::{Script, Txid};
+use nakamoto_common::bitcoin::{OutPoint, Script, Txid};
 
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::block::filter::BlockFilter;
@@ -135,6 +135,70 @@ pub trait Handle: Sized + Send + Sync + Clone {
 
         Ok(())
     }
+    /// Add a script to the watchlist. If `rescan_from` is set, the chain is rescanned for
+    /// the script starting at that height.
+    ///
+    /// An [`Event::WatchlistUpdated`] event is emitted once the watchlist is updated.
+    fn watch_script(&self, script: Script, rescan_from: Option<Height>) -> Result<(), Error> {
+        self.command(Command::WatchScript {
+            script,
+            rescan_from,
+        })?;
+
+        Ok(())
+    }
+    /// Remove a script from the watchlist.
+    fn unwatch_script(&self, script: Script) -> Result<(), Error> {
+        self.command(Command::UnwatchScript(script))?;
+
+        Ok(())
+    }
+    /// Add an outpoint to the watchlist. Transactions spending the outpoint are reported
+    /// via [`Event::ReceivedMatchedTx`], when found in a downloaded block.
+    fn watch_outpoint(&self, outpoint: OutPoint) -> Result<(), Error> {
+        self.command(Command::WatchOutpoint(outpoint))?;
+
+        Ok(())
+    }
+    /// Resume an interrupted merkle block rescan from the height following the given
+    /// checkpoint, eg. after a restart. The checkpoint and peer are reported by
+    /// [`Event::MerkleBlockRescanProgress`] and [`Event::MerkleBlockRescanStopped`].
+    fn resume_merkle_rescan(
+        &self,
+        checkpoint: Height,
+        to: Option<Height>,
+        peer: Option<net::SocketAddr>,
+    ) -> Result<(), Error> {
+        self.command(Command::ResumeMerkleBlockRescan {
+            checkpoint,
+            to,
+            peer,
+        })?;
+
+        Ok(())
+    }
+    /// Re-run the watchlist against the blocks downloaded so far, from the given height, eg.
+    /// after adding scripts to it. Blocks that weren't downloaded are fetched. Matching
+    /// blocks are reported via [`Event::BlockMatched`].
+    fn rescan_cached(&self, from: Height) -> Result<(), Error> {
+        self.command(Command::RescanCached(from))?;
+
+        Ok(())
+    }
+    /// Watch the mempools of peers with a loaded bloom filter for unconfirmed transactions
+    /// touching the given scripts. Matching transactions are reported via
+    /// [`Event::ReceivedMatchedTx`] and [`Event::TxStatusChanged`].
+    fn watch_mempool(&self, watch: impl Iterator<Item = Script>) -> Result<(), Error> {
+        self.command(Command::WatchMempool(watch.collect()))?;
+
+        Ok(())
+    }
+    /// Stop watching peer mempools.
+    fn unwatch_mempool(&self) -> Result<(), Error> {
+        self.command(Command::UnwatchMempool)?;
+
+        Ok(())
+    }
     /// Broadcast a message to peers matching the predicate.
     /// To only broadcast to outbound peers, use [`Peer::is_outbound`].
     fn broadcast(
@@ -152,6 +216,8 @@ pub trait Handle: Sized + Send + Sync + Clone {
     fn submit_transaction(&self, tx: Transaction) -> Result<NonEmpty<net::SocketAddr>, Error>;
     /// Return a transaction that was propagated by the client.
     fn get_submitted_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error>;
+    /// Get the latency metrics of connected peers with recorded latencies.
+    fn get_peer_metrics(&self) -> Result<Vec<fsm::PeerMetric>, Error>;
     /// Import block headers into the node.
     /// This may cause the node to broadcast header or inventory messages to its peers.
     fn import_headers(
@@ -175,4 +241,12 @@ pub trait Handle: Sized + Send + Sync + Clone {
     fn shutdown(self) -> Result<(), Error>;
     /// load a peer with a bloom filter
     fn load_bloom_filter(&self, addr: net::SocketAddr, filter: BloomFilter) -> Result<(), Error>;
+    /// Add an element to the bloom filter loaded on a peer.
+    fn add_to_bloom_filter(&self, addr: net::SocketAddr, data: Vec<u8>) -> Result<(), Error>;
+    /// Get our copy of the bloom filter loaded on a peer, including the elements added to
+    /// it since it was loaded. Returns `None` if no filter is loaded on the peer.
+    fn get_bloom_filter(
+        &self,
+        addr: net::SocketAddr,
+    ) -> Result<Option<nakamoto_common::bloom::BloomFilter>, Error>;
 }
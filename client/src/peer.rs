@@ -3,12 +3,14 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::{fs, io, net};
 
+pub use nakamoto_common::p2p::onion::OnionAddr;
 pub use nakamoto_common::p2p::peer::*;
 
 /// A file-backed implementation of [`Store`].
 #[derive(Debug)]
 pub struct Cache {
     addrs: HashMap<net::IpAddr, KnownAddress>,
+    onions: HashMap<OnionAddr, KnownOnion>,
     file: fs::File,
 }
 
@@ -32,6 +34,7 @@ impl Cache {
         Ok(Self {
             file,
             addrs: HashMap::new(),
+            onions: HashMap::new(),
         })
     }
 
@@ -43,6 +46,7 @@ impl Cache {
 
         let mut s = String::new();
         let mut addrs = HashMap::new();
+        let mut onions = HashMap::new();
 
         file.read_to_string(&mut s)?;
 
@@ -53,6 +57,14 @@ impl Cache {
             match val {
                 Value::Object(ary) => {
                     for (k, v) in ary.into_iter() {
+                        // Onion addresses are keyed by their name.
+                        if let Ok(onion) = OnionAddr::from_str(k.as_str()) {
+                            let ko = KnownOnion::from_json(v)
+                                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+                            onions.insert(onion, ko);
+                            continue;
+                        }
                         let ka = KnownAddress::from_json(v)
                             .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
                         let ip = net::IpAddr::from_str(k.as_str())
@@ -65,7 +77,11 @@ impl Cache {
             }
         }
 
-        Ok(Self { file, addrs })
+        Ok(Self {
+            file,
+            addrs,
+            onions,
+        })
     }
 }
 
@@ -90,8 +106,25 @@ impl Store for Cache {
         Box::new(self.addrs.iter())
     }
 
+    fn insert_onion(&mut self, onion: OnionAddr, ko: KnownOnion) -> bool {
+        use std::collections::hash_map::Entry;
+
+        match self.onions.entry(onion) {
+            Entry::Vacant(v) => {
+                v.insert(ko);
+            }
+            Entry::Occupied(_) => return false,
+        }
+        true
+    }
+
+    fn onions<'a>(&'a self) -> Box<dyn Iterator<Item = (&OnionAddr, &KnownOnion)> + 'a> {
+        Box::new(self.onions.iter())
+    }
+
     fn clear(&mut self) {
-        self.addrs.clear()
+        self.addrs.clear();
+        self.onions.clear();
     }
 
     fn len(&self) -> usize {
@@ -106,6 +139,11 @@ impl Store for Cache {
             .addrs
             .iter()
             .map(|(ip, ka)| (ip.to_string(), ka.to_json()))
+            .chain(
+                self.onions
+                    .iter()
+                    .map(|(onion, ko)| (onion.to_string(), ko.to_json())),
+            )
             .collect();
         let s = microserde::json::to_string(&Value::Object(peers));
 
@@ -180,4 +218,46 @@ mod test {
             assert_eq!(actual, expected);
         }
     }
+
+    #[test]
+    fn test_save_and_load_onions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("cache");
+        let onion: OnionAddr = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+            .parse()
+            .unwrap();
+        let ip = net::IpAddr::from([88, 88, 88, 88]);
+        let source = Source::Peer(net::SocketAddr::from((ip, 8333)));
+        let ko = KnownOnion::new(
+            8333,
+            ServiceFlags::NETWORK,
+            source,
+            Some(LocalTime::from_secs(42)),
+        );
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+            let ka = KnownAddress::new(
+                Address::new(&net::SocketAddr::from((ip, 8333)), ServiceFlags::NETWORK),
+                Source::Dns,
+                None,
+            );
+
+            assert!(cache.insert(ip, ka));
+            assert!(cache.insert_onion(onion, ko.clone()));
+            assert!(!cache.insert_onion(onion, ko.clone()));
+            cache.flush().unwrap();
+        }
+
+        {
+            let mut cache = Cache::open(&path).unwrap();
+
+            assert_eq!(cache.len(), 1);
+            assert_eq!(cache.onions().collect::<Vec<_>>(), vec![(&onion, &ko)]);
+            assert!(cache.get(&ip).is_some());
+
+            cache.clear();
+            assert_eq!(cache.onions().count(), 0);
+        }
+    }
 }
@@ -95,6 +95,10 @@ where
         self.machine.timer_expired();
     }
 
+    fn shutdown(&mut self) {
+        self.machine.shutdown();
+    }
+
     fn message_received(&mut self, addr: &net::SocketAddr, bytes: Cow<[u8]>) {
         if let Some(inbox) = self.inboxes.get_mut(addr) {
             inbox.input(bytes.borrow());
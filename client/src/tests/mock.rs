@@ -153,6 +153,19 @@ impl Handle for TestHandle {
     ) -> Result<(), handle::Error> {
         unimplemented!()
     }
+    fn add_to_bloom_filter(
+        &self,
+        _addr: net::SocketAddr,
+        _data: Vec<u8>,
+    ) -> Result<(), handle::Error> {
+        unimplemented!()
+    }
+    fn get_bloom_filter(
+        &self,
+        _addr: net::SocketAddr,
+    ) -> Result<Option<nakamoto_common::bloom::BloomFilter>, handle::Error> {
+        unimplemented!()
+    }
     fn get_tip(&self) -> Result<(Height, BlockHeader, Uint256), handle::Error> {
         Ok(self.tip)
     }
@@ -176,6 +189,10 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
+    fn get_peer_metrics(&self) -> Result<Vec<fsm::PeerMetric>, handle::Error> {
+        unimplemented!()
+    }
+
     fn request_block(&self, hash: &BlockHash) -> Result<(), handle::Error> {
         self.command(Command::RequestBlock(*hash))?;
 
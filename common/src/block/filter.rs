@@ -6,14 +6,22 @@ use std::ops::RangeInclusive;
 use bitcoin_hashes::Hash;
 use thiserror::Error;
 
+use bitcoin::consensus::encode::{Decodable, VarInt};
 pub use bitcoin::hash_types::{FilterHash, FilterHeader};
 pub use bitcoin::util::bip158::BlockFilter;
+pub use bitcoin::util::bip158::Error as BuildError;
+use bitcoin::{Block, OutPoint, Script};
 use bitcoincash as bitcoin;
 
 use super::Height;
 use crate::block::store::{self, Genesis};
 use crate::network::Network;
 
+/// Golomb-Rice coding parameter of basic filters, as specified by BIP 158.
+pub const BASIC_FILTER_P: u8 = 19;
+/// Inverse false-positive rate of basic filters, as specified by BIP 158.
+pub const BASIC_FILTER_M: u64 = 784_931;
+
 impl Genesis for FilterHeader {
     /// Filter header for the genesis block.
     ///
@@ -76,3 +84,270 @@ pub trait Filters {
     /// Truncate the filter header chain to zero.
     fn clear(&mut self) -> Result<(), Error>;
 }
+
+/// Build the BIP 158 basic filter of a block, and its filter header, given the header of the
+/// previous block's filter.
+///
+/// The filter is built from the output scripts of the block, and the scripts spent by its
+/// inputs, which are looked up with `script_for_coin`, eg. from the block's undo data.
+/// `OP_RETURN` and empty scripts are not included. Fails if a spent script can't be found.
+///
+/// ```
+/// use nakamoto_common::block::filter::{build_basic_filter, BlockFilter, FilterHeader};
+/// use nakamoto_common::block::store::Genesis as _;
+/// use nakamoto_common::network::Network;
+/// use bitcoin_hashes::Hash as _;
+///
+/// let network = Network::Testnet;
+/// // The genesis block only has a coinbase input, which doesn't spend anything.
+/// let (filter, header) =
+///     build_basic_filter(&network.genesis_block(), &FilterHeader::all_zeros(), |_| None).unwrap();
+///
+/// assert_eq!(filter, BlockFilter::genesis(network));
+/// assert_eq!(header, FilterHeader::genesis(network));
+/// ```
+pub fn build_basic_filter<F>(
+    block: &Block,
+    prev_filter_header: &FilterHeader,
+    script_for_coin: F,
+) -> Result<(BlockFilter, FilterHeader), BuildError>
+where
+    F: Fn(&OutPoint) -> Option<Script>,
+{
+    let filter = BlockFilter::new_script_filter(block, |outpoint| {
+        script_for_coin(outpoint).ok_or(BuildError::UtxoMissing(*outpoint))
+    })?;
+    let header = filter.filter_header(prev_filter_header);
+
+    Ok((filter, header))
+}
+
+/// Verify a filter against the filter header chain, given the expected header of the filter's
+/// block, and the header of the previous block's filter.
+///
+/// Since each filter header commits to the filter hash and the previous filter header, a filter
+/// that doesn't hash to the expected header was tampered with, or doesn't belong to the chain.
+pub fn verify_filter(
+    filter: &BlockFilter,
+    expected_header: &FilterHeader,
+    prev_header: &FilterHeader,
+) -> bool {
+    filter.filter_header(prev_header) == *expected_header
+}
+
+/// Check that a basic filter is well-formed, ie. that it decodes to exactly as many elements
+/// as it claims to contain, with no trailing bytes.
+///
+/// Since a filter header only commits to the filter hash, a filter can match its header and
+/// still fail to decode.
+pub fn is_well_formed(filter: &BlockFilter) -> bool {
+    let mut content = filter.content.as_slice();
+    let count = match VarInt::consensus_decode(&mut content) {
+        Ok(VarInt(count)) => count,
+        // Nb. An empty filter has no elements.
+        Err(_) => return filter.content.is_empty(),
+    };
+    let len = content.len() * 8;
+    let bit = |i: usize| content[i / 8] >> (7 - i % 8) & 1 == 1;
+    let mut i = 0;
+
+    for _ in 0..count {
+        // Unary-coded quotient, terminated by a zero bit, followed by the remainder.
+        loop {
+            if i >= len {
+                return false;
+            }
+            i += 1;
+
+            if !bit(i - 1) {
+                break;
+            }
+        }
+        i += BASIC_FILTER_P as usize;
+
+        if i > len {
+            return false;
+        }
+    }
+    // Only padding bits can follow the last element.
+    len - i < 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use bitcoin::consensus::encode::deserialize;
+    use bitcoin_hashes::hex::FromHex;
+
+    #[test]
+    fn test_build_basic_filter() {
+        // Test vectors from BIP 158, for blocks without spent output scripts.
+        // Format: block, previous filter header, filter, filter header.
+        let vectors = [
+            (
+                "0100000006128e87be8b1b4dea47a7247d5528d2702c96826c7a648497e773b800000000e241352e3bec0a95a6217e10c3abb54adfa05abb12c126695595580fb92e222032e7494dffff001d00d235340101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0e0432e7494d010e062f503253482fffffffff0100f2052a010000002321038a7f6ef1c8ca0c588aa53fa860128077c9e6c11e6830f4d7ee4e763a56b7718fac00000000",
+                "d7bdac13a59d745b1add0d2ce852f1a0442e8945fc1bf3848d3cbffd88c24fe1",
+                "0174a170",
+                "186afd11ef2b5e7e3504f2e8cbf8df28a1fd251fe53d60dff8b1467d1b386cf0",
+            ),
+            (
+                "000000202694f74969fdb542090e95a56bc8aa2d646e27033850e32f1c5f000000000000f7e53676b3f12d5beb524ed617f2d25f5a93b5f4f52c1ba2678260d72712f8dd0a6dfe5740257e1a4b1768960101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff1603e4120ff9c30a1c216900002f424d4920546573742fffffff0001205fa012000000001e76a914c486de584a735ec2f22da7cd9681614681f92173d83d0aa68688ac00000000",
+                "fe4d230dbb0f4fec9bed23a5283e08baf996e3f32b93f52c7de1f641ddfd04ad",
+                "010c0b40",
+                "0965a544743bbfa36f254446e75630c09404b3d164a261892372977538928ed5",
+            ),
+            (
+                "000000204ea88307a7959d8207968f152bedca5a93aefab253f1fb2cfb032a400000000070cebb14ec6dbc27a9dfd066d9849a4d3bac5f674665f73a5fe1de01a022a0c851fda85bf05f4c19a779d1450102000000010000000000000000000000000000000000000000000000000000000000000000ffffffff18034d94154d696e6572476174653030310d000000f238f401ffffffff01c817a804000000000000000000",
+                "5e5e12d90693c8e936f01847859404c67482439681928353ca1296982042864e",
+                "00",
+                "021e8882ef5a0ed932edeebbecfeda1d7ce528ec7b3daa27641acf1189d7b5dc",
+            ),
+        ];
+
+        for (block, prev, content, header) in vectors {
+            let block: Block = deserialize(&Vec::from_hex(block).unwrap()).unwrap();
+            let prev = FilterHeader::from_hex(prev).unwrap();
+            let (filter, actual) = build_basic_filter(&block, &prev, |_| None).unwrap();
+
+            assert_eq!(filter.content, Vec::from_hex(content).unwrap());
+            assert_eq!(actual, FilterHeader::from_hex(header).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_build_basic_filter_spent_scripts() {
+        // Test vector from BIP 158: "Tx spends from empty output script".
+        let block = [
+            "020000006058aa080a655aa991a444bd7d1f2defd9a3bbe68aabb69030cf3b4e00000000d2e826bfd7ef0beaa891a7ee",
+            "dbc92cd6a544a6cb61c7bdaa436762eb2123ef9790f5f552ffff001d0002c90f05010000000100000000000000000000",
+            "00000000000000000000000000000000000000000000ffffffff0e0300c102024608062f503253482fffffffff01c0c6",
+            "072a01000000232102e769e60137a4df6b0df8ebd387cca44c4c57ae74cc0114a8e8317c8f3bfd85e9ac000000000100",
+            "00000381a0802911a01ffb025c4dea0bc77963e8c1bb46313b71164c53f72f37fe5248010000000151ffffffffc904b2",
+            "67833d215e2128bd9575242232ac2bc311550c7fc1f0ef6f264b40d14c010000000151ffffffffdf0915666649dba818",
+            "86519c531649b7b02180b4af67d6885e871299e9d5f775000000000151ffffffff0180817dcb00000000232103bb5213",
+            "8972c48a132fc1f637858c5189607dd0f7fe40c4f20f6ad65f2d389ba4ac0000000001000000018da38b434fba82d660",
+            "52af74fc5e4e94301b114d9bc03f819dc876398404c8b4010000006c493046022100fe738b7580dc5fb5168e51fc61b5",
+            "aed211125eb71068031009a22d9bbad752c5022100be5086baa384d40bcab0fa586e4f728397388d86e18b66cc417dc4",
+            "f7fa4f9878012103f233299455134caa2687bdf15cb0becdfb03bd0ff2ff38e65ec6b7834295c34fffffffff022ebc14",
+            "00000000001976a9147779b7fba1c1e06b717069b80ca170e8b04458a488ac9879c40f000000001976a9142a0307cd92",
+            "5dbb66b534c4db33003dd18c57015788ac0000000001000000026139a62e3422a602de36c873a225c1d3ca5aeee59853",
+            "9ceecb9f0dc8d1ad0f83010000006b483045022100ad9f32b4a0a2ddc19b5a74eba78123e57616f1b3cfd72ce68c03ea",
+            "35a3dda1f002200dbd22aa6da17213df5e70dfc3b2611d40f70c98ed9626aa5e2cde9d97461f0a012103ddb295d2f1e8",
+            "319187738fb4b230fdd9aa29d0e01647f69f6d770b9ab24eea90ffffffff983c82c87cf020040d671956525014d5c2b2",
+            "8c6d948c85e1a522362c0059eeae010000006b4830450221009ca544274c786d30a5d5d25e17759201ea16d3aedddf0b",
+            "9e9721246f7ef6b32e02202cfa5564b6e87dfd9fd98957820e4d4e6238baeb0f65fe305d91506bb13f5f4f012103c991",
+            "13deac0d5d044e3ac0346abc02501542af8c8d3759f1382c72ff84e704f7ffffffff02c0c62d00000000001976a914ae",
+            "19d27efe12f5a886dc79af37ad6805db6f922d88ac70ce2000000000001976a9143b8d051d37a07ea1042067e93efe63",
+            "dbf73920b988ac000000000100000002be566e8cd9933f0c75c4a82c027f7d0c544d5c101d0607ef6ae5d07b98e7f1dc",
+            "000000006b483045022036a8cdfd5ea7ebc06c2bfb6e4f942bbf9a1caeded41680d11a3a9f5d8284abad022100cacb92",
+            "a5be3f39e8bc14db1710910ef7b395fa1e18f45d41c28d914fcdde33be012102bf59abf110b5131fae0a3ce1ec379329",
+            "b4c896a6ae5d443edb68529cc2bc7816ffffffff96cf67645b76ceb23fe922874847456a15feee1655082ff32d25a6bf",
+            "2c0dfc90000000006a47304402203471ca2001784a5ac0abab583581f2613523da47ec5f53df833c117b5abd81500220",
+            "618a2847723d57324f2984678db556dbca1a72230fc7e39df04c2239942ba942012102925c9794fd7bb9f8b29e207d5f",
+            "c491b1150135a21f505041858889fa4edf436fffffffff026c840f00000000001976a914797fb8777d7991d8284d88bf",
+            "d421ce520f0f843188ac00ca9a3b000000001976a9146d10f3f592699265d10b106eda37c3ce793f7a8588ac00000000",
+        ];
+        // Scripts spent by the block's inputs, in order, skipping the coinbase.
+        let spent = [
+            "",
+            "",
+            "",
+            "76a9142903b138c24be9e070b3e73ec495d77a204615e788ac",
+            "76a91433a1941fd9a37b9821d376f5a51bd4b52fa50e2888ac",
+            "76a914e4374e8155d0865742ca12b8d4d14d41b57d682f88ac",
+            "76a914001fa7459a6cfc64bdc178ba7e7a21603bb2568f88ac",
+            "76a914f6039952bc2b307aeec5371bfb96b66078ec17f688ac",
+        ];
+        let block: Block = deserialize(&Vec::from_hex(&block.concat()).unwrap()).unwrap();
+        let prev = FilterHeader::from_hex(
+            "d34ef98386f413769502808d4bac5f20f8dfd5bffc9eedafaa71de0eb1f01489",
+        )
+        .unwrap();
+        let scripts = block
+            .txdata
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.input.iter().map(|i| i.previous_output))
+            .zip(
+                spent
+                    .iter()
+                    .map(|s| Script::from(Vec::from_hex(s).unwrap())),
+            )
+            .collect::<HashMap<_, _>>();
+
+        let (filter, header) =
+            build_basic_filter(&block, &prev, |o| scripts.get(o).cloned()).unwrap();
+
+        assert_eq!(
+            filter.content,
+            Vec::from_hex("0db414c859a07e8205876354a210a75042d0463404913d61a8e068e58a3ae2aa080026")
+                .unwrap()
+        );
+        assert_eq!(
+            header,
+            FilterHeader::from_hex(
+                "c582d51c0ca365e3fcf36c51cb646d7f83a67e867cb4743fd2128e3e022b700c"
+            )
+            .unwrap()
+        );
+
+        // The filter matches the spent scripts.
+        let spent = scripts.values().filter(|s| !s.is_empty());
+        assert!(filter
+            .match_all(&block.block_hash(), &mut spent.map(|s| s.as_bytes()))
+            .unwrap());
+
+        // Without the spent scripts, the filter can't be built.
+        let outpoint = block.txdata[1].input[0].previous_output;
+        assert!(matches!(
+            build_basic_filter(&block, &prev, |_| None),
+            Err(BuildError::UtxoMissing(o)) if o == outpoint
+        ));
+    }
+
+    #[test]
+    fn test_verify_filter() {
+        let network = Network::Testnet;
+        let genesis = FilterHeader::genesis(network);
+        let prev = FilterHeader::all_zeros();
+        let filter = BlockFilter::genesis(network);
+
+        assert!(verify_filter(&filter, &genesis, &prev));
+        // The filter must be verified against the header of its own block.
+        assert!(!verify_filter(&filter, &prev, &prev));
+        assert!(!verify_filter(&filter, &genesis, &genesis));
+
+        // A tampered filter doesn't match its header.
+        let mut content = filter.content.clone();
+        *content.last_mut().unwrap() ^= 0x1;
+        assert!(!verify_filter(&BlockFilter::new(&content), &genesis, &prev));
+    }
+
+    #[test]
+    fn test_is_well_formed() {
+        let network = Network::Testnet;
+
+        assert!(is_well_formed(&BlockFilter::genesis(network)));
+        assert!(is_well_formed(&BlockFilter::new(&[0x00])));
+        assert!(is_well_formed(&BlockFilter::new(&[])));
+        assert!(is_well_formed(&BlockFilter::new(
+            &Vec::from_hex("010c0b40").unwrap()
+        )));
+
+        // Claims more elements than it contains.
+        assert!(!is_well_formed(&BlockFilter::new(
+            &Vec::from_hex("020c0b40").unwrap()
+        )));
+        // Truncated element.
+        assert!(!is_well_formed(&BlockFilter::new(
+            &Vec::from_hex("010c0b").unwrap()
+        )));
+        // Trailing bytes.
+        assert!(!is_well_formed(&BlockFilter::new(
+            &Vec::from_hex("010c0b4000").unwrap()
+        )));
+    }
+}
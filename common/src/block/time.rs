@@ -1,5 +1,5 @@
 //! Block time and other time-related types.
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashSet;
 use std::hash::Hash;
 use std::rc::Rc;
@@ -28,6 +28,38 @@ pub const MAX_TIME_SAMPLES: usize = 200;
 /// A time offset, in seconds.
 pub type TimeOffset = i64;
 
+/// Human-friendly rendering of durations.
+pub trait Humanize {
+    /// Render the duration using the two most significant units, eg. `"2m 13s"` or `"450ms"`.
+    /// Durations under a millisecond render as `"<1ms"`.
+    fn humanize(&self) -> String;
+}
+
+impl Humanize for LocalDuration {
+    fn humanize(&self) -> String {
+        const SEC: u128 = 1000;
+        const MIN: u128 = 60 * SEC;
+        const HOUR: u128 = 60 * MIN;
+        const DAY: u128 = 24 * HOUR;
+
+        let ms = self.as_millis();
+
+        if ms == 0 {
+            "<1ms".to_owned()
+        } else if ms < SEC {
+            format!("{ms}ms")
+        } else if ms < MIN {
+            format!("{}s", ms / SEC)
+        } else if ms < HOUR {
+            format!("{}m {}s", ms / MIN, ms % MIN / SEC)
+        } else if ms < DAY {
+            format!("{}h {}m", ms / HOUR, ms % HOUR / MIN)
+        } else {
+            format!("{}d {}h", ms / DAY, ms % DAY / HOUR)
+        }
+    }
+}
+
 /// Clock that tells the time.
 pub trait Clock: Clone {
     /// Return the local time as seconds since Epoch.
@@ -117,6 +149,71 @@ impl<T: Clock> Clock for RefClock<T> {
     }
 }
 
+/// A clock that only moves when told to, for driving timer-based logic deterministically,
+/// eg. in tests.
+///
+/// Clones share the same time, so that a clone can be handed to a state machine, while the
+/// original is used to move time forward.
+///
+/// ```
+/// use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime, MockClock};
+///
+/// let clock = MockClock::new(LocalTime::from_secs(1_600_000_000));
+/// let shared = clock.clone();
+///
+/// clock.advance(LocalDuration::from_secs(90));
+/// assert_eq!(shared.local_time(), LocalTime::from_secs(1_600_000_090));
+///
+/// clock.set(LocalTime::from_secs(1_700_000_000));
+/// assert_eq!(shared.block_time(), 1_700_000_000);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    time: Rc<Cell<LocalTime>>,
+}
+
+impl MockClock {
+    /// Create a new clock, set to the given time.
+    pub fn new(time: LocalTime) -> Self {
+        Self {
+            time: Rc::new(Cell::new(time)),
+        }
+    }
+
+    /// Set the time.
+    pub fn set(&self, time: LocalTime) {
+        self.time.set(time);
+    }
+
+    /// Move the time forward.
+    pub fn advance(&self, duration: LocalDuration) {
+        self.time.set(self.time.get() + duration);
+    }
+}
+
+impl Clock for MockClock {
+    fn block_time(&self) -> BlockTime {
+        self.time.get().block_time()
+    }
+
+    fn local_time(&self) -> LocalTime {
+        self.time.get()
+    }
+
+    fn from_block_time(t: BlockTime) -> Self {
+        Self::new(LocalTime::from_block_time(t))
+    }
+}
+
+impl<K> AdjustedClock<K> for MockClock {
+    /// Nb. Peer offsets are ignored, so that time only moves when told to.
+    fn record_offset(&mut self, _source: K, _sample: TimeOffset) {}
+
+    fn set(&mut self, local_time: LocalTime) {
+        self.time.set(local_time);
+    }
+}
+
 impl Clock for LocalTime {
     fn block_time(&self) -> BlockTime {
         self.as_secs() as u32
@@ -294,6 +391,43 @@ mod tests {
 
     use std::net::SocketAddr;
 
+    #[test]
+    fn test_mock_clock() {
+        let start = LocalTime::from_secs(1_600_000_000);
+        let clock = MockClock::new(start);
+        let mut shared = clock.clone();
+
+        assert_eq!(shared.local_time(), start);
+        assert_eq!(shared.block_time(), 1_600_000_000);
+
+        clock.advance(LocalDuration::from_millis(1500));
+        assert_eq!(
+            shared.local_time(),
+            start + LocalDuration::from_millis(1500)
+        );
+        assert_eq!(shared.block_time(), 1_600_000_001);
+
+        // Time can be set backwards, eg. to test clock skew.
+        clock.set(start - LocalDuration::from_secs(60));
+        assert_eq!(shared.block_time(), 1_599_999_940);
+
+        // Peer offsets don't move the clock, but setting the time through the
+        // adjusted clock interface does.
+        for i in 0..MIN_TIME_SAMPLES as u16 {
+            AdjustedClock::record_offset(&mut shared, i, 3600);
+        }
+        assert_eq!(clock.block_time(), 1_599_999_940);
+
+        AdjustedClock::<u16>::set(&mut shared, start);
+        assert_eq!(clock.local_time(), start);
+
+        assert_eq!(
+            MockClock::from_block_time(42).local_time(),
+            LocalTime::from_secs(42)
+        );
+        assert_eq!(MockClock::default().local_time(), LocalTime::default());
+    }
+
     #[test]
     fn test_local_duration_display() {
         assert_eq!(LocalDuration::from_mins(90).to_string(), "1.50 hour(s)");
@@ -308,6 +442,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_local_duration_humanize() {
+        let cases = [
+            (LocalDuration::from_millis(0), "<1ms"),
+            (LocalDuration::from_millis(1), "1ms"),
+            (LocalDuration::from_millis(450), "450ms"),
+            (LocalDuration::from_millis(1280), "1s"),
+            (LocalDuration::from_secs(59), "59s"),
+            (LocalDuration::from_secs(60), "1m 0s"),
+            (LocalDuration::from_secs(133), "2m 13s"),
+            (LocalDuration::from_mins(90), "1h 30m"),
+            (LocalDuration::from_mins(24 * 60 - 1), "23h 59m"),
+            (LocalDuration::from_mins(24 * 60), "1d 0h"),
+            (LocalDuration::from_mins(3 * 24 * 60 + 5 * 60 + 7), "3d 5h"),
+            (LocalDuration::from_mins(400 * 24 * 60), "400d 0h"),
+        ];
+        for (duration, expected) in cases {
+            assert_eq!(duration.humanize(), expected, "{duration:?}");
+        }
+    }
+
     #[test]
     fn test_adjusted_time() {
         let mut adjusted_time: AdjustedTime<SocketAddr> = AdjustedTime::default();
@@ -1,4 +1,308 @@
-/// bloom filter type function
-// pub mod cache;
+//! BIP 37 bloom filters.
+//!
+//! Bloom filters are loaded onto peers with the `filterload` message, so that
+//! they only relay transactions and merkle blocks matching our filter.
+//!
+/// Bloom filter storage.
 pub mod store;
-// pub use nakamoto_common::bitcoin::util::bloom::BloomFilter;
+
+use thiserror::Error;
+
+use crate::bitcoin::network::message_bloom::{BloomFlags, FilterLoad};
+
+/// Maximum size of a bloom filter, in bytes.
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+/// Maximum number of hash functions a bloom filter can use.
+pub const MAX_HASH_FUNCS: u32 = 50;
+/// Maximum size of an element added to a loaded filter with `filteradd`, in bytes.
+pub const MAX_FILTER_ADD_SIZE: usize = 520;
+/// Multiplier used to derive the seed of each hash function.
+pub const SEED_MULTIPLIER: u32 = 0xFBA4C795;
+
+const LN2_SQUARED: f64 = std::f64::consts::LN_2 * std::f64::consts::LN_2;
+const LN2: f64 = std::f64::consts::LN_2;
+
+/// An error creating a bloom filter.
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum Error {
+    /// The false-positive rate is not strictly between zero and one.
+    #[error("invalid false-positive rate {0}: must be between 0 and 1 (exclusive)")]
+    InvalidFpRate(f64),
+}
+
+/// A BIP 37 bloom filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    /// Filter bit field.
+    data: Vec<u8>,
+    /// Number of hash functions to use.
+    hash_funcs: u32,
+    /// Random value added to the hash seeds.
+    tweak: u32,
+    /// How matched items are added to the filter by the remote.
+    flags: BloomFlags,
+}
+
+impl BloomFilter {
+    /// Create a new, empty bloom filter, sized to hold the given number of elements with the
+    /// given false-positive rate, as specified by BIP 37.
+    ///
+    /// The filter size and number of hash functions are capped to [`MAX_BLOOM_FILTER_SIZE`]
+    /// and [`MAX_HASH_FUNCS`].
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: BloomFlags) -> Self {
+        let elements = elements.max(1) as f64;
+        let bits = (-1. / LN2_SQUARED * elements * fp_rate.ln()) as usize;
+        let size = usize::min(bits, MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        // Nb. A filter must have at least one byte, otherwise nothing can be inserted.
+        let size = size.max(1);
+        // Nb. A filter must use at least one hash function, otherwise it matches everything.
+        let hash_funcs = ((size as f64 * 8. / elements * LN2) as u32).clamp(1, MAX_HASH_FUNCS);
+
+        Self {
+            data: vec![0; size],
+            hash_funcs,
+            tweak,
+            flags,
+        }
+    }
+
+    /// Create a new, empty bloom filter targeting the given false-positive rate once the
+    /// given number of elements is inserted. The filter has a zero tweak, and isn't updated
+    /// by the remote on matches.
+    ///
+    /// Fails if the rate isn't strictly between zero and one. Other inputs are clamped to
+    /// the protocol limits, in which case the realized rate is higher than the target.
+    pub fn with_fp_rate(elements: usize, fp_rate: f64) -> Result<Self, Error> {
+        if !(fp_rate > 0. && fp_rate < 1.) {
+            return Err(Error::InvalidFpRate(fp_rate));
+        }
+        Ok(Self::new(elements, fp_rate, 0, BloomFlags::None))
+    }
+
+    /// Estimate the false-positive rate of the filter from its current contents, ie. the
+    /// probability that an element that wasn't inserted matches.
+    pub fn fp_rate(&self) -> f64 {
+        let set = self.data.iter().map(|b| b.count_ones()).sum::<u32>();
+        let bits = (self.data.len() * 8) as f64;
+
+        (set as f64 / bits).powi(self.hash_funcs as i32)
+    }
+
+    /// Insert an element into the filter.
+    pub fn insert(&mut self, data: &[u8]) {
+        for n in 0..self.hash_funcs {
+            let index = self.hash(n, data);
+            self.data[index >> 3] |= 1 << (7 & index);
+        }
+    }
+
+    /// Check whether the filter contains an element. Since this is a probabilistic
+    /// data structure, this may return false positives, but never false negatives.
+    pub fn contains(&self, data: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|n| {
+            let index = self.hash(n, data);
+            self.data[index >> 3] & (1 << (7 & index)) != 0
+        })
+    }
+
+    /// Check whether no element was inserted into the filter.
+    pub fn is_empty(&self) -> bool {
+        self.data.iter().all(|b| *b == 0)
+    }
+
+    /// Get the filter bit field.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the number of hash functions used.
+    pub fn hash_funcs(&self) -> u32 {
+        self.hash_funcs
+    }
+
+    /// Get the filter tweak.
+    pub fn tweak(&self) -> u32 {
+        self.tweak
+    }
+
+    /// Get the filter update flags.
+    pub fn flags(&self) -> BloomFlags {
+        self.flags
+    }
+
+    /// Get the bit index of an element, for the given hash function.
+    fn hash(&self, n: u32, data: &[u8]) -> usize {
+        let seed = n.wrapping_mul(SEED_MULTIPLIER).wrapping_add(self.tweak);
+
+        murmur3(seed, data) as usize % (self.data.len() * 8)
+    }
+}
+
+impl From<BloomFilter> for FilterLoad {
+    fn from(filter: BloomFilter) -> Self {
+        Self {
+            filter: filter.data,
+            hash_funcs: filter.hash_funcs,
+            tweak: filter.tweak,
+            flags: filter.flags,
+        }
+    }
+}
+
+impl From<FilterLoad> for BloomFilter {
+    fn from(msg: FilterLoad) -> Self {
+        Self {
+            data: msg.filter,
+            hash_funcs: msg.hash_funcs,
+            tweak: msg.tweak,
+            flags: msg.flags,
+        }
+    }
+}
+
+/// 32-bit MurmurHash3, as used by BIP 37.
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |k, byte| (k << 8) | *byte as u32);
+
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::consensus::encode::serialize;
+    use crate::bitcoin_hashes::hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_murmur3() {
+        let vectors: &[(u32, u32, &str)] = &[
+            (0x00000000, 0x00000000, ""),
+            (0x6a396f08, 0xFBA4C795, ""),
+            (0x81f16f39, 0xffffffff, ""),
+            (0x514e28b7, 0x00000000, "00"),
+            (0xea3f0b17, 0xFBA4C795, "00"),
+            (0xfd6cf10d, 0x00000000, "ff"),
+            (0x16c6b7ab, 0x00000000, "0011"),
+            (0x8eb51c3d, 0x00000000, "001122"),
+            (0xb4471bf8, 0x00000000, "00112233"),
+            (0xe2301fa8, 0x00000000, "0011223344"),
+            (0xfc2e4a15, 0x00000000, "001122334455"),
+            (0xb074502c, 0x00000000, "00112233445566"),
+            (0x8034d2a0, 0x00000000, "0011223344556677"),
+            (0xb4698def, 0x00000000, "001122334455667788"),
+        ];
+        for (expected, seed, data) in vectors {
+            let data = Vec::<u8>::from_hex(data).unwrap();
+            assert_eq!(murmur3(*seed, &data), *expected, "{}", data.to_hex());
+        }
+    }
+
+    fn bip37_filter(tweak: u32) -> BloomFilter {
+        let mut filter = BloomFilter::new(3, 0.01, tweak, BloomFlags::All);
+        let elements = [
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ];
+        assert!(filter.is_empty());
+
+        for e in elements {
+            let e = Vec::<u8>::from_hex(e).unwrap();
+
+            filter.insert(&e);
+            assert!(filter.contains(&e));
+        }
+        assert!(!filter
+            .contains(&Vec::<u8>::from_hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap()));
+        filter
+    }
+
+    #[test]
+    fn test_bloom_insert_serialize() {
+        let filter = bip37_filter(0);
+        let msg = FilterLoad::from(filter.clone());
+
+        assert_eq!(filter.hash_funcs(), 5);
+        assert_eq!(serialize(&msg).to_hex(), "03614e9b050000000000000001");
+        assert_eq!(BloomFilter::from(msg), filter);
+    }
+
+    #[test]
+    fn test_bloom_insert_serialize_with_tweak() {
+        let filter = bip37_filter(2147483649);
+        let msg = FilterLoad::from(filter);
+
+        assert_eq!(serialize(&msg).to_hex(), "03ce4299050000000100008001");
+    }
+
+    #[test]
+    fn test_bloom_size_limits() {
+        let filter = BloomFilter::new(1_000_000, 0.000_001, 0, BloomFlags::None);
+
+        assert_eq!(filter.as_bytes().len(), MAX_BLOOM_FILTER_SIZE);
+        assert!(filter.hash_funcs() <= MAX_HASH_FUNCS);
+
+        let filter = BloomFilter::new(1, 0.9, 0, BloomFlags::None);
+        assert_eq!(filter.as_bytes().len(), 1);
+    }
+
+    #[test]
+    fn test_bloom_with_fp_rate() {
+        // Sizes and hash function counts of the BIP 37 test vectors.
+        for (elements, fp_rate, size, hash_funcs) in [(3, 0.01, 3, 5), (2, 0.001, 3, 8)] {
+            let filter = BloomFilter::with_fp_rate(elements, fp_rate).unwrap();
+
+            assert_eq!(filter.as_bytes().len(), size);
+            assert_eq!(filter.hash_funcs(), hash_funcs);
+            assert_eq!(filter.tweak(), 0);
+            assert_eq!(filter.fp_rate(), 0.);
+        }
+        let mut filter = BloomFilter::with_fp_rate(2, 0.001).unwrap();
+        filter.insert(b"wallet");
+        filter.insert(b"script");
+        assert!(filter.fp_rate() > 0. && filter.fp_rate() < 0.01);
+
+        // Absurd inputs are clamped to the protocol limits.
+        let filter = BloomFilter::with_fp_rate(usize::MAX, f64::MIN_POSITIVE).unwrap();
+        assert_eq!(filter.as_bytes().len(), MAX_BLOOM_FILTER_SIZE);
+        assert_eq!(filter.hash_funcs(), 1);
+
+        let filter = BloomFilter::with_fp_rate(0, 1e-30).unwrap();
+        assert_eq!(filter.hash_funcs(), MAX_HASH_FUNCS);
+
+        for fp_rate in [0., 1., -0.5, 1.5, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                BloomFilter::with_fp_rate(10, fp_rate),
+                Err(Error::InvalidFpRate(_))
+            ));
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! Collections used in `nakamoto`.
 use bitcoin_hashes::siphash24::Hash;
+use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
 
 use crate::nonempty::NonEmpty;
@@ -133,3 +134,467 @@ impl<K, V> DerefMut for AddressBook<K, V> {
         &mut self.inner
     }
 }
+
+/// A map holding up to a fixed number of entries. When full, the least recently used
+/// entry is evicted to make room for new ones.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    /// Entries, along with the time at which they were last used.
+    entries: HashMap<K, (V, u64)>,
+    /// Keys, ordered by the time at which they were last used.
+    order: BTreeMap<u64, K>,
+    /// Logical clock, incremented every time an entry is used.
+    clock: u64,
+    /// Maximum number of entries.
+    capacity: usize,
+}
+
+impl<K, V> Default for LruCache<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::default(),
+            order: BTreeMap::new(),
+            clock: 0,
+            capacity: 0,
+        }
+    }
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Create a new cache holding up to `capacity` entries.
+    /// A cache with a capacity of zero never holds any entry.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::default(),
+            order: BTreeMap::new(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    /// Get an entry, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let (value, used) = self.entries.get_mut(key)?;
+
+        self.order.remove(used);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        *used = self.clock;
+
+        Some(value)
+    }
+
+    /// Insert an entry, marking it as the most recently used. If the cache is full, the
+    /// least recently used entry is evicted and returned.
+    pub fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.clock += 1;
+
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.clock)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.clock, key);
+
+        if self.entries.len() > self.capacity {
+            if let Some((_, key)) = self.order.pop_first() {
+                return self.entries.remove(&key).map(|(value, _)| (key, value));
+            }
+        }
+        None
+    }
+
+    /// Iterate over the entries, from the least to the most recently used.
+    pub fn entries(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.order
+            .values()
+            .filter_map(|k| self.entries.get_key_value(k).map(|(k, (v, _))| (k, v)))
+    }
+
+    /// Remove all entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Maximum number of entries in the cache.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// A map holding entries up to a total size budget, where the size of each entry is given
+/// on insertion. When over budget, the least recently used entries are evicted first.
+#[derive(Debug)]
+pub struct SizedCache<K, V> {
+    /// Entries, along with their size and the time at which they were last used.
+    entries: HashMap<K, (V, usize, u64)>,
+    /// Keys, ordered by the time at which they were last used.
+    order: BTreeMap<u64, K>,
+    /// Logical clock, incremented every time an entry is used.
+    clock: u64,
+    /// Total size of the entries.
+    size: usize,
+    /// Maximum total size of the entries.
+    budget: usize,
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> SizedCache<K, V> {
+    /// Create a new cache holding entries up to a total size of `budget`.
+    /// A cache with a budget of zero never holds any entry.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            entries: HashMap::default(),
+            order: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            budget,
+        }
+    }
+
+    /// Get an entry, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let (value, _, used) = self.entries.get_mut(key)?;
+
+        self.order.remove(used);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        *used = self.clock;
+
+        Some(value)
+    }
+
+    /// Check whether the cache contains an entry, without marking it as used.
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Insert an entry of the given size, marking it as the most recently used. Least
+    /// recently used entries are evicted until the cache is within budget, and returned
+    /// in the order they were evicted.
+    ///
+    /// Entries larger than the budget are never cached.
+    pub fn put(&mut self, key: K, value: V, size: usize) -> Vec<(K, V)> {
+        if size > self.budget {
+            return vec![];
+        }
+        self.remove(&key);
+        self.clock += 1;
+        self.size += size;
+        self.entries.insert(key.clone(), (value, size, self.clock));
+        self.order.insert(self.clock, key);
+
+        let mut evicted = Vec::new();
+        while self.size > self.budget {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((value, size, _)) = self.entries.remove(&key) {
+                self.size -= size;
+                evicted.push((key, value));
+            }
+        }
+        evicted
+    }
+
+    /// Remove an entry.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, size, used) = self.entries.remove(key)?;
+
+        self.order.remove(&used);
+        self.size -= size;
+
+        Some(value)
+    }
+
+    /// Iterate over the entries, from the least to the most recently used.
+    pub fn entries(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.order
+            .values()
+            .filter_map(|k| self.entries.get_key_value(k).map(|(k, (v, _, _))| (k, v)))
+    }
+
+    /// Number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of the entries in the cache.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Maximum total size of the entries in the cache.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+}
+
+/// A map which preserves the insertion order of its entries, with constant-time lookups.
+///
+/// Iteration follows insertion order, which makes it deterministic, unlike with [`HashMap`].
+/// Replacing the value of an existing key keeps its position.
+#[derive(Debug, Clone)]
+pub struct OrderedMap<K, V> {
+    /// Entries, in order.
+    entries: Vec<(K, V)>,
+    /// Position of each key in the entries.
+    index: HashMap<K, usize>,
+}
+
+impl<K, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            index: HashMap::default(),
+        }
+    }
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> OrderedMap<K, V> {
+    /// Create a new, empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert an entry. If the key was already present, its value is replaced and the old
+    /// value is returned, and the entry keeps its position. Otherwise, the entry is added
+    /// last.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(ix) = self.index.get(&key) {
+            return Some(std::mem::replace(&mut self.entries[*ix].1, value));
+        }
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+
+        None
+    }
+
+    /// Get the value of a key.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.index.get(key).map(|ix| &self.entries[*ix].1)
+    }
+
+    /// Get the value of a key, mutably.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.index.get(key).map(|ix| &mut self.entries[*ix].1)
+    }
+
+    /// Check whether the map contains a key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Remove an entry in constant time, by swapping it with the last entry.
+    ///
+    /// Nb. This moves the last entry to the position of the removed one, so the order of
+    /// the remaining entries is changed, though deterministically. Use
+    /// [`OrderedMap::shift_remove`] to preserve it.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let ix = self.index.remove(key)?;
+        let (_, value) = self.entries.swap_remove(ix);
+
+        if let Some((moved, _)) = self.entries.get(ix) {
+            self.index.insert(moved.clone(), ix);
+        }
+        Some(value)
+    }
+
+    /// Remove an entry, shifting all following entries. Preserves the order of the remaining
+    /// entries, but takes linear time.
+    pub fn shift_remove(&mut self, key: &K) -> Option<V> {
+        let ix = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(ix);
+
+        for (i, (k, _)) in self.entries.iter().enumerate().skip(ix) {
+            self.index.insert(k.clone(), i);
+        }
+        Some(value)
+    }
+
+    /// Iterate over the entries, in order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> + '_ {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    /// Iterate over the entries, in order, with mutable values.
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (&K, &mut V)> + '_ {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
+    }
+
+    /// Iterate over the keys, in order.
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &K> + '_ {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    /// Iterate over the values, in order.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> + '_ {
+        self.entries.iter().map(|(_, v)| v)
+    }
+
+    /// Iterate over the values, in order, mutably.
+    pub fn values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut V> + '_ {
+        self.entries.iter_mut().map(|(_, v)| v)
+    }
+
+    /// Remove all entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+
+    /// Number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> std::ops::Index<&K> for OrderedMap<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &Self::Output {
+        self.get(key).expect("OrderedMap::index: key not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(3);
+
+        assert!(cache.is_empty());
+        assert_eq!(cache.put(1, "one"), None);
+        assert_eq!(cache.put(2, "two"), None);
+        assert_eq!(cache.put(3, "three"), None);
+        assert_eq!(cache.len(), 3);
+
+        // Using an entry protects it from eviction.
+        assert_eq!(cache.get(&1), Some(&"one"));
+        assert_eq!(cache.put(4, "four"), Some((2, "two")));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(
+            cache.entries().collect::<Vec<_>>(),
+            vec![(&3, &"three"), (&1, &"one"), (&4, &"four")]
+        );
+
+        // Replacing a value doesn't evict anything.
+        assert_eq!(cache.put(3, "drei"), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.put(5, "five"), Some((1, "one")));
+        assert_eq!(cache.get(&3), Some(&"drei"));
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.entries().count(), 0);
+    }
+
+    #[test]
+    fn test_lru_cache_zero_capacity() {
+        let mut cache = LruCache::new(0);
+
+        assert_eq!(cache.put(1, 1), None);
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_sized_cache() {
+        let mut cache = SizedCache::new(10);
+
+        assert!(cache.put(1, "one", 4).is_empty());
+        assert!(cache.put(2, "two", 4).is_empty());
+        assert_eq!(cache.size(), 8);
+        assert_eq!(cache.get(&1), Some(&"one"));
+        assert_eq!(cache.get(&3), None);
+
+        // The least recently used entries are evicted first, until within budget.
+        assert_eq!(cache.put(3, "three", 4), vec![(2, "two")]);
+        assert_eq!(cache.put(4, "four", 9), vec![(1, "one"), (3, "three")]);
+        assert_eq!(cache.entries().collect::<Vec<_>>(), vec![(&4, &"four")]);
+        assert_eq!(cache.size(), 9);
+
+        // Replacing an entry updates its size.
+        assert!(cache.put(4, "vier", 2).is_empty());
+        assert_eq!(cache.size(), 2);
+
+        // Entries larger than the budget aren't cached.
+        assert!(cache.put(5, "five", 11).is_empty());
+        assert!(!cache.contains(&5));
+
+        assert_eq!(cache.remove(&4), Some("vier"));
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_ordered_map() {
+        let mut map = OrderedMap::new();
+
+        assert!(map.is_empty());
+        for (k, v) in [(3, "three"), (1, "one"), (4, "four"), (2, "two")] {
+            assert_eq!(map.insert(k, v), None);
+        }
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![3, 1, 4, 2]);
+        assert_eq!(map.get(&4), Some(&"four"));
+        assert_eq!(map[&1], "one");
+        assert!(!map.contains_key(&5));
+
+        // Replacing a value keeps the entry's position.
+        assert_eq!(map.insert(1, "eins"), Some("one"));
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(&3, &"three"), (&1, &"eins"), (&4, &"four"), (&2, &"two")]
+        );
+
+        // Swap-removing moves the last entry in place of the removed one.
+        assert_eq!(map.remove(&3), Some("three"));
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![2, 1, 4]);
+        assert_eq!(map.get(&2), Some(&"two"));
+        assert_eq!(map.remove(&3), None);
+
+        // Shift-removing preserves the order of the remaining entries.
+        map.insert(5, "five");
+        assert_eq!(map.shift_remove(&1), Some("eins"));
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![2, 4, 5]);
+        assert_eq!(map.get(&5), Some(&"five"));
+        assert_eq!(map.get(&4), Some(&"four"));
+
+        // Removing the last entry.
+        assert_eq!(map.remove(&5), Some("five"));
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![2, 4]);
+
+        for v in map.values_mut() {
+            *v = "zwei";
+        }
+        assert_eq!(map.values().collect::<Vec<_>>(), vec![&"zwei", &"zwei"]);
+
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(map.get(&2), None);
+    }
+}
@@ -2,7 +2,6 @@
 #![allow(clippy::type_complexity)]
 #![deny(missing_docs, unsafe_code)]
 pub mod block;
-/// does stuff
 pub mod bloom;
 pub mod collections;
 pub mod network;
@@ -25,3 +24,30 @@ macro_rules! source {
         &name[..name.len() - 3]
     }};
 }
+
+/// Return the name of the function at the current source location, without its path.
+///
+/// ```
+/// use nakamoto_common::{source, source_short};
+///
+/// fn peer_negotiated() -> (&'static str, &'static str) {
+///     (source!(), source_short!())
+/// }
+/// let (long, short) = peer_negotiated();
+///
+/// assert!(long.ends_with("::peer_negotiated"));
+/// assert_eq!(short, "peer_negotiated");
+/// ```
+#[macro_export]
+macro_rules! source_short {
+    () => {{
+        fn f() {}
+        fn type_of<T>(_: T) -> &'static str {
+            std::any::type_name::<T>()
+        }
+        let name = type_of(f);
+        let name = &name[..name.len() - 3];
+
+        name.rsplit("::").next().unwrap_or(name)
+    }};
+}
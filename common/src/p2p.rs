@@ -1,5 +1,7 @@
 //! P2P-related types
 use std::net;
+pub mod dsproof;
+pub mod onion;
 pub mod peer;
 
 /// Communication domain of a network socket.
@@ -0,0 +1,596 @@
+//! Double-spend proofs.
+//!
+//! Bitcoin Cash nodes relay proofs that an output was spent by two conflicting transactions,
+//! with the `dsproof-beta` message. A proof carries, for each of the two spends, the
+//! signature and the parts of the signed transaction digest needed to check it, without
+//! having to transmit the transactions themselves.
+//!
+//! See <https://documentation.cash/protocol/network/messages/dsproof-beta>.
+use std::io;
+
+use thiserror::Error;
+
+use crate::bitcoin::blockdata::script::Instruction;
+use crate::bitcoin::consensus::{encode, Decodable, Encodable};
+use crate::bitcoin::hashes::{sha256, sha256d, Hash, HashEngine};
+use crate::bitcoin::secp256k1::{self, ecdsa, Message, Scalar, Secp256k1, SecretKey};
+use crate::bitcoin::util::uint::Uint256;
+use crate::bitcoin::{OutPoint, PublicKey, Script, Sighash, Transaction, Txid};
+
+/// Command of the message carrying a double-spend proof.
+pub const COMMAND: &str = "dsproof-beta";
+/// Inventory type used to announce double-spend proofs.
+pub const MSG_DOUBLESPENDPROOF: u32 = 0x94a0;
+/// Sighash flag committing a signature to the Bitcoin Cash chain.
+pub const SIGHASH_FORKID: u8 = 0x40;
+
+/// An error verifying a double-spend proof.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The transaction doesn't spend the output the proof is about.
+    #[error("transaction doesn't spend {0}")]
+    UnrelatedTransaction(OutPoint),
+    /// Both spends are identical, so they don't conflict.
+    #[error("spends are identical")]
+    IdenticalSpends,
+    /// The spends aren't in canonical order.
+    #[error("spends aren't in canonical order")]
+    UnorderedSpends,
+    /// A spend is missing its signature.
+    #[error("spend is missing its signature")]
+    MissingSignature,
+    /// A signature doesn't commit to the Bitcoin Cash chain.
+    #[error("signature is missing the fork id flag")]
+    MissingForkId,
+    /// A signature couldn't be decoded or doesn't verify.
+    #[error("invalid signature: {0}")]
+    InvalidSignature(#[from] secp256k1::Error),
+}
+
+/// One of the two conflicting spends of a double-spend proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spender {
+    /// Version of the spending transaction.
+    pub version: i32,
+    /// Sequence number of the spending input.
+    pub sequence: u32,
+    /// Lock time of the spending transaction.
+    pub lock_time: u32,
+    /// Hash of all the outputs spent by the transaction.
+    pub hash_prevouts: sha256d::Hash,
+    /// Hash of all the input sequence numbers of the transaction.
+    pub hash_sequence: sha256d::Hash,
+    /// Hash of all the outputs of the transaction.
+    pub hash_outputs: sha256d::Hash,
+    /// Data pushed by the input script. For pay-to-pubkey-hash outputs, this is the signature.
+    pub push_data: Vec<Vec<u8>>,
+}
+
+impl Spender {
+    /// Get the spend of the given transaction input. The public key pushed by the input
+    /// script is left out, as it is identical for both spends.
+    pub fn new(tx: &Transaction, input: usize) -> Option<Self> {
+        let txin = tx.input.get(input)?;
+        let hash = |f: &dyn Fn(&mut <sha256d::Hash as Hash>::Engine)| {
+            let mut engine = sha256d::Hash::engine();
+            f(&mut engine);
+            sha256d::Hash::from_engine(engine)
+        };
+        let signature = match txin.script_sig.instructions().next() {
+            Some(Ok(Instruction::PushBytes(bytes))) => bytes.to_vec(),
+            _ => return None,
+        };
+
+        Some(Self {
+            version: tx.version,
+            sequence: txin.sequence.0,
+            lock_time: tx.lock_time.0,
+            hash_prevouts: hash(&|e| {
+                for i in &tx.input {
+                    i.previous_output.consensus_encode(e).ok();
+                }
+            }),
+            hash_sequence: hash(&|e| {
+                for i in &tx.input {
+                    i.sequence.consensus_encode(e).ok();
+                }
+            }),
+            hash_outputs: hash(&|e| {
+                for o in &tx.output {
+                    o.consensus_encode(e).ok();
+                }
+            }),
+            push_data: vec![signature],
+        })
+    }
+
+    /// Get the spend signature, and its sighash type.
+    pub fn signature(&self) -> Result<(&[u8], u8), Error> {
+        let (sighash_type, signature) = self
+            .push_data
+            .first()
+            .and_then(|sig| sig.split_last())
+            .ok_or(Error::MissingSignature)?;
+
+        if sighash_type & SIGHASH_FORKID == 0 {
+            return Err(Error::MissingForkId);
+        }
+        Ok((signature, *sighash_type))
+    }
+
+    /// Get the key spends are ordered by within a proof.
+    fn sort_key(&self) -> (&sha256d::Hash, &sha256d::Hash) {
+        (&self.hash_prevouts, &self.hash_outputs)
+    }
+
+    /// Compute the digest signed by this spend.
+    pub fn sighash(&self, outpoint: &OutPoint, script_code: &Script, amount: u64) -> Sighash {
+        let mut e = Sighash::engine();
+        let sighash_type = self.signature().map_or(0, |(_, t)| t as u32);
+
+        // Nb. Encoding to a hash engine can't fail.
+        self.version.consensus_encode(&mut e).ok();
+        self.hash_prevouts.consensus_encode(&mut e).ok();
+        self.hash_sequence.consensus_encode(&mut e).ok();
+        outpoint.consensus_encode(&mut e).ok();
+        script_code.consensus_encode(&mut e).ok();
+        amount.consensus_encode(&mut e).ok();
+        self.sequence.consensus_encode(&mut e).ok();
+        self.hash_outputs.consensus_encode(&mut e).ok();
+        self.lock_time.consensus_encode(&mut e).ok();
+        sighash_type.consensus_encode(&mut e).ok();
+
+        Sighash::from_engine(e)
+    }
+}
+
+impl Encodable for Spender {
+    fn consensus_encode<W: io::Write + ?Sized>(&self, e: &mut W) -> Result<usize, io::Error> {
+        let mut len = 0;
+        len += self.version.consensus_encode(e)?;
+        len += self.sequence.consensus_encode(e)?;
+        len += self.lock_time.consensus_encode(e)?;
+        len += self.hash_prevouts.consensus_encode(e)?;
+        len += self.hash_sequence.consensus_encode(e)?;
+        len += self.hash_outputs.consensus_encode(e)?;
+        len += self.push_data.consensus_encode(e)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Spender {
+    fn consensus_decode<D: io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
+        Ok(Self {
+            version: i32::consensus_decode(d)?,
+            sequence: u32::consensus_decode(d)?,
+            lock_time: u32::consensus_decode(d)?,
+            hash_prevouts: sha256d::Hash::consensus_decode(d)?,
+            hash_sequence: sha256d::Hash::consensus_decode(d)?,
+            hash_outputs: sha256d::Hash::consensus_decode(d)?,
+            push_data: Vec::<Vec<u8>>::consensus_decode(d)?,
+        })
+    }
+}
+
+/// A proof that an output was spent by two conflicting transactions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoubleSpendProof {
+    /// The output spent twice.
+    pub outpoint: OutPoint,
+    /// First spend.
+    pub spender1: Spender,
+    /// Second spend.
+    pub spender2: Spender,
+}
+
+impl DoubleSpendProof {
+    /// Create a proof from two conflicting spends of an output, in canonical order.
+    pub fn new(outpoint: OutPoint, a: Spender, b: Spender) -> Self {
+        let (spender1, spender2) = if a.sort_key() <= b.sort_key() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Self {
+            outpoint,
+            spender1,
+            spender2,
+        }
+    }
+
+    /// Get the proof identifier, used to announce it.
+    pub fn id(&self) -> sha256d::Hash {
+        let mut engine = sha256d::Hash::engine();
+        self.consensus_encode(&mut engine).ok();
+
+        sha256d::Hash::from_engine(engine)
+    }
+
+    /// Check the proof against a transaction spending the contested output: both spends
+    /// must be distinct and carry a Bitcoin Cash signature.
+    ///
+    /// Nb. This doesn't check the signatures themselves, as that requires the public key and
+    /// amount of the spent output. See [`DoubleSpendProof::verify`].
+    pub fn validate(&self, tx: &Transaction) -> Result<(), Error> {
+        if !tx.input.iter().any(|i| i.previous_output == self.outpoint) {
+            return Err(Error::UnrelatedTransaction(self.outpoint));
+        }
+        if self.spender1 == self.spender2 {
+            return Err(Error::IdenticalSpends);
+        }
+        for spender in [&self.spender1, &self.spender2] {
+            let (signature, _) = spender.signature()?;
+
+            // Schnorr signatures are always 64 bytes, anything else must be a DER signature.
+            if signature.len() != 64 {
+                ecdsa::Signature::from_der(signature)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify the signatures of both spends, given the public key and amount of the
+    /// pay-to-pubkey-hash output spent, and that the spends are in canonical order. Both
+    /// ECDSA and Schnorr signatures are supported.
+    pub fn verify(&self, pubkey: &PublicKey, amount: u64) -> Result<(), Error> {
+        let secp = Secp256k1::new();
+        let script_code = Script::new_p2pkh(&pubkey.pubkey_hash());
+
+        if self.spender1 == self.spender2 {
+            return Err(Error::IdenticalSpends);
+        }
+        // The first spend must sort before the second, so that a double-spend has a single
+        // proof for both spends.
+        if self.spender1.sort_key() > self.spender2.sort_key() {
+            return Err(Error::UnorderedSpends);
+        }
+        for spender in [&self.spender1, &self.spender2] {
+            let (signature, _) = spender.signature()?;
+            let sighash = spender.sighash(&self.outpoint, &script_code, amount);
+
+            // Schnorr signatures are always 64 bytes, anything else must be a DER signature.
+            if signature.len() == 64 {
+                verify_schnorr(&secp, &sighash.into_inner(), signature, &pubkey.inner)?;
+            } else {
+                let signature = ecdsa::Signature::from_der(signature)?;
+                let msg = Message::from_slice(&sighash[..])?;
+
+                secp.verify_ecdsa(&msg, &signature, &pubkey.inner)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the transaction whose output was spent twice.
+    pub fn txid(&self) -> Txid {
+        self.outpoint.txid
+    }
+}
+
+impl Encodable for DoubleSpendProof {
+    fn consensus_encode<W: io::Write + ?Sized>(&self, e: &mut W) -> Result<usize, io::Error> {
+        let mut len = 0;
+        len += self.outpoint.consensus_encode(e)?;
+        len += self.spender1.consensus_encode(e)?;
+        len += self.spender2.consensus_encode(e)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for DoubleSpendProof {
+    fn consensus_decode<D: io::Read + ?Sized>(d: &mut D) -> Result<Self, encode::Error> {
+        Ok(Self {
+            outpoint: OutPoint::consensus_decode(d)?,
+            spender1: Spender::consensus_decode(d)?,
+            spender2: Spender::consensus_decode(d)?,
+        })
+    }
+}
+
+/// Compute the challenge of a Bitcoin Cash Schnorr signature, ie. `H(r || P || m) mod n`,
+/// where `P` is the compressed public key.
+fn challenge(r: &[u8], pubkey: &secp256k1::PublicKey, msg: &[u8; 32]) -> Option<Scalar> {
+    let mut engine = sha256::Hash::engine();
+    engine.input(r);
+    engine.input(&pubkey.serialize());
+    engine.input(msg);
+
+    let hash = Uint256::from_be_bytes(sha256::Hash::from_engine(engine).into_inner());
+    let order = Uint256::from_be_bytes(secp256k1::constants::CURVE_ORDER);
+
+    // Nb. Since `2^256 < 2n`, a single subtraction is enough.
+    let e = if hash >= order { hash - order } else { hash };
+    Scalar::from_be_bytes(e.to_be_bytes()).ok()
+}
+
+/// Verify a Bitcoin Cash Schnorr signature over a message digest.
+///
+/// Unlike BIP 340, the full public key is committed to, and the nonce point must have a
+/// `y` coordinate that is a quadratic residue.
+///
+/// See <https://documentation.cash/protocol/blockchain/cryptography/signatures>.
+fn verify_schnorr<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &Secp256k1<C>,
+    msg: &[u8; 32],
+    signature: &[u8],
+    pubkey: &secp256k1::PublicKey,
+) -> Result<(), Error> {
+    let invalid = || Error::InvalidSignature(secp256k1::Error::InvalidSignature);
+    let (r, s) = signature.split_at(32);
+    let e = challenge(r, pubkey, msg).ok_or_else(invalid)?;
+    let s = SecretKey::from_slice(s).map_err(|_| invalid())?;
+
+    // R = sG - eP
+    let point = secp256k1::PublicKey::from_secret_key(secp, &s)
+        .combine(&pubkey.mul_tweak(secp, &e)?.negate(secp))
+        .map_err(|_| invalid())?
+        .serialize_uncompressed();
+    let (x, y) = point[1..].split_at(32);
+
+    if x != r || !is_quadratic_residue(y) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Check whether a 256-bit big-endian field element is a quadratic residue modulo the
+/// secp256k1 field prime, by computing its Jacobi symbol.
+fn is_quadratic_residue(bytes: &[u8]) -> bool {
+    let Ok(mut a) = Uint256::from_be_slice(bytes) else {
+        return false;
+    };
+    let mut n = Uint256::from_be_bytes(secp256k1::constants::FIELD_SIZE);
+    let zero = Uint256::default();
+    let mut residue = true;
+
+    a = a % n;
+    while a != zero {
+        while a.low_u64() & 1 == 0 {
+            a = a >> 1;
+            if matches!(n.low_u64() & 7, 3 | 5) {
+                residue = !residue;
+            }
+        }
+        std::mem::swap(&mut a, &mut n);
+        if a.low_u64() & 3 == 3 && n.low_u64() & 3 == 3 {
+            residue = !residue;
+        }
+        a = a % n;
+    }
+    n == Uint256::from_u64(1).unwrap() && residue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::blockdata::script::Builder;
+    use crate::bitcoin::consensus::{deserialize, serialize};
+    use crate::bitcoin::secp256k1::SecretKey;
+    use crate::bitcoin::{PackedLockTime, Sequence, TxIn, TxOut};
+
+    /// Sign a digest with a Bitcoin Cash Schnorr signature, using the given nonce.
+    fn sign_schnorr(msg: &[u8; 32], key: &SecretKey, nonce: SecretKey) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let point = |k: &SecretKey| {
+            secp256k1::PublicKey::from_secret_key(&secp, k).serialize_uncompressed()
+        };
+
+        // The nonce point must have a `y` coordinate that is a quadratic residue.
+        let mut k = nonce;
+        if !is_quadratic_residue(&point(&k)[33..]) {
+            k = k.negate();
+        }
+        let r = point(&k)[1..33].to_vec();
+        let e = challenge(&r, &key.public_key(&secp), msg).unwrap();
+        let s = key
+            .mul_tweak(&e)
+            .unwrap()
+            .add_tweak(&Scalar::from(k))
+            .unwrap();
+
+        [r, s.secret_bytes().to_vec()].concat()
+    }
+
+    /// Create a signed transaction spending the given pay-to-pubkey-hash output.
+    fn spend(outpoint: OutPoint, amount: u64, value: u64, key: &SecretKey) -> Transaction {
+        spend_with(outpoint, amount, value, key, false)
+    }
+
+    /// Create a transaction spending the given pay-to-pubkey-hash output, signed with
+    /// either an ECDSA or a Schnorr signature.
+    fn spend_with(
+        outpoint: OutPoint,
+        amount: u64,
+        value: u64,
+        key: &SecretKey,
+        schnorr: bool,
+    ) -> Transaction {
+        let secp = Secp256k1::new();
+        let pubkey = PublicKey::new(key.public_key(&secp));
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new_p2pkh(&pubkey.pubkey_hash()),
+                token: None,
+            }],
+        };
+        // Sign with a placeholder signature first, to get the digest.
+        tx.input[0].script_sig = Builder::new().push_slice(&[0x41]).into_script();
+
+        let spender = Spender::new(&tx, 0).unwrap();
+        let sighash = spender.sighash(&outpoint, &Script::new_p2pkh(&pubkey.pubkey_hash()), amount);
+        let mut signature = if schnorr {
+            let nonce = SecretKey::from_slice(&sha256::Hash::hash(&value.to_le_bytes())).unwrap();
+            sign_schnorr(&sighash.into_inner(), key, nonce)
+        } else {
+            let signature = secp.sign_ecdsa(&Message::from_slice(&sighash[..]).unwrap(), key);
+            signature.serialize_der().to_vec()
+        };
+        signature.push(0x41); // SIGHASH_ALL | SIGHASH_FORKID
+
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&signature)
+            .push_key(&pubkey)
+            .into_script();
+        tx
+    }
+
+    #[test]
+    fn test_double_spend_proof() {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let pubkey = PublicKey::new(key.public_key(&secp));
+        let outpoint = OutPoint::new(Txid::all_zeros(), 1);
+        let amount = 100_000;
+
+        let tx1 = spend(outpoint, amount, 90_000, &key);
+        let tx2 = spend(outpoint, amount, 80_000, &key);
+        let proof = DoubleSpendProof::new(
+            outpoint,
+            Spender::new(&tx1, 0).unwrap(),
+            Spender::new(&tx2, 0).unwrap(),
+        );
+
+        proof.validate(&tx1).unwrap();
+        proof.validate(&tx2).unwrap();
+        proof.verify(&pubkey, amount).unwrap();
+
+        // The proof survives a round-trip through the wire format.
+        let decoded: DoubleSpendProof = deserialize(&serialize(&proof)).unwrap();
+        assert_eq!(decoded, proof);
+        assert_eq!(decoded.id(), proof.id());
+
+        // Signatures don't verify for another amount.
+        assert!(matches!(
+            proof.verify(&pubkey, amount + 1),
+            Err(Error::InvalidSignature(_))
+        ));
+
+        // Spends have to be in canonical order, whichever order they're given in.
+        let swapped = DoubleSpendProof {
+            spender1: proof.spender2.clone(),
+            spender2: proof.spender1.clone(),
+            ..proof.clone()
+        };
+        assert!(matches!(
+            swapped.verify(&pubkey, amount),
+            Err(Error::UnorderedSpends)
+        ));
+        assert_eq!(
+            DoubleSpendProof::new(outpoint, swapped.spender1.clone(), swapped.spender2),
+            proof
+        );
+
+        // Spends have to conflict.
+        let same = DoubleSpendProof {
+            spender2: proof.spender1.clone(),
+            ..proof.clone()
+        };
+        assert!(matches!(same.validate(&tx1), Err(Error::IdenticalSpends)));
+
+        // Signatures have to commit to the Bitcoin Cash chain.
+        let mut no_forkid = proof.clone();
+        *no_forkid.spender2.push_data[0].last_mut().unwrap() = 0x01;
+        assert!(matches!(
+            no_forkid.validate(&tx1),
+            Err(Error::MissingForkId)
+        ));
+
+        // Proofs are only valid for transactions spending the contested output.
+        let other = spend(OutPoint::new(Txid::all_zeros(), 2), amount, 1, &key);
+        assert!(matches!(
+            proof.validate(&other),
+            Err(Error::UnrelatedTransaction(_))
+        ));
+    }
+
+    #[test]
+    fn test_schnorr_double_spend_proof() {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let pubkey = PublicKey::new(key.public_key(&secp));
+        let outpoint = OutPoint::new(Txid::all_zeros(), 1);
+        let amount = 100_000;
+        let proof = |spends: [(u64, bool); 2]| {
+            let [spender1, spender2] = spends.map(|(value, schnorr)| {
+                Spender::new(&spend_with(outpoint, amount, value, &key, schnorr), 0).unwrap()
+            });
+            DoubleSpendProof::new(outpoint, spender1, spender2)
+        };
+
+        // Signatures of either kind verify, even mixed together.
+        let schnorr = proof([(90_000, true), (80_000, true)]);
+        schnorr.verify(&pubkey, amount).unwrap();
+        proof([(90_000, true), (80_000, false)])
+            .verify(&pubkey, amount)
+            .unwrap();
+        assert_eq!(schnorr.spender1.signature().unwrap().0.len(), 64);
+
+        // Signatures don't verify for another amount or key.
+        let other = PublicKey::new(
+            SecretKey::from_slice(&[0xab; 32])
+                .unwrap()
+                .public_key(&secp),
+        );
+        assert!(matches!(
+            schnorr.verify(&pubkey, amount + 1),
+            Err(Error::InvalidSignature(_))
+        ));
+        assert!(matches!(
+            schnorr.verify(&other, amount),
+            Err(Error::InvalidSignature(_))
+        ));
+
+        // Tampered signatures are rejected, whether `r` or `s` is changed.
+        for i in [0, 32, 63] {
+            let mut bad = schnorr.clone();
+            bad.spender2.push_data[0][i] ^= 1;
+
+            assert!(matches!(
+                bad.verify(&pubkey, amount),
+                Err(Error::InvalidSignature(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_is_quadratic_residue() {
+        let fe = |n: u64| Uint256::from_u64(n).unwrap();
+        let p = Uint256::from_be_bytes(secp256k1::constants::FIELD_SIZE);
+
+        // Squares are residues, and `-1` isn't since `p = 3 (mod 4)`. Nor is `-4 = -1 * 2^2`.
+        assert!(is_quadratic_residue(&fe(4).to_be_bytes()));
+        assert!(is_quadratic_residue(&fe(9).to_be_bytes()));
+        assert!(!is_quadratic_residue(&(p - fe(1)).to_be_bytes()));
+        assert!(!is_quadratic_residue(&(p - fe(4)).to_be_bytes()));
+        assert!(!is_quadratic_residue(&[0; 32]));
+    }
+
+    #[test]
+    fn test_verify_schnorr() {
+        use crate::bitcoin::hashes::hex::FromHex;
+
+        // Test vector of the Schnorr signature specification, signing with key `1`.
+        let secp = Secp256k1::new();
+        let hex = |s: &str| Vec::<u8>::from_hex(s).unwrap();
+        let pubkey = secp256k1::PublicKey::from_slice(&hex(
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        ))
+        .unwrap();
+        let mut signature = hex(
+            "787A848E71043D280C50470E8E1532B2DD5D20EE912A45DBDD2BD1DFBF187EF6\
+             7031A98831859DC34DFFEEDDA86831842CCD0079E1F92AF177F7F22CC1DCED05",
+        );
+        verify_schnorr(&secp, &[0; 32], &signature, &pubkey).unwrap();
+
+        signature[63] ^= 1;
+        assert!(verify_schnorr(&secp, &[0; 32], &signature, &pubkey).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Tor onion addresses.
+//!
+//! Only version 3 onion services are supported, since version 2 services were retired by
+//! the Tor network. A v3 address is the base32 encoding of the service's ed25519 public
+//! key, followed by a checksum and a version byte, eg.
+//! `duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion`.
+//!
+//! Onion addresses are learned from `addrv2` messages, kept in the peer store, and relayed
+//! to peers that accept `addrv2` messages, but they are never dialed: connections are
+//! identified by socket address, and aren't routed through a Tor proxy. Connecting to
+//! onion services is out of scope until the connection layer supports it.
+use std::fmt;
+use std::str::FromStr;
+
+use sha3::{Digest, Sha3_256};
+use thiserror::Error;
+
+/// Domain suffix of onion addresses.
+pub const ONION_SUFFIX: &str = ".onion";
+/// Onion service protocol version supported.
+pub const ONION_VERSION: u8 = 0x03;
+
+/// Length of a decoded onion address: public key, checksum and version.
+const DECODED_LEN: usize = 32 + 2 + 1;
+/// Length of an onion address, without the suffix.
+const ENCODED_LEN: usize = 56;
+/// Base32 alphabet, as specified by RFC 4648.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// An error parsing an onion address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Error {
+    /// The address doesn't end with `.onion`.
+    #[error("missing `.onion` suffix")]
+    MissingSuffix,
+    /// The address has the wrong length.
+    #[error("invalid onion address length {0}")]
+    InvalidLength(usize),
+    /// The address isn't valid base32.
+    #[error("invalid base32 character `{0}`")]
+    InvalidEncoding(char),
+    /// The address is for an unsupported onion service version.
+    #[error("unsupported onion service version {0}")]
+    UnsupportedVersion(u8),
+    /// The address checksum doesn't match its public key.
+    #[error("invalid onion address checksum")]
+    InvalidChecksum,
+}
+
+/// A Tor v3 onion service address, ie. the ed25519 public key of the service.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct OnionAddr([u8; 32]);
+
+impl OnionAddr {
+    /// Create an onion address from a service public key.
+    pub fn new(pubkey: [u8; 32]) -> Self {
+        Self(pubkey)
+    }
+
+    /// Get the public key of the onion service.
+    pub fn pubkey(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Compute the address checksum of a public key.
+    fn checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+        let hash = Sha3_256::new()
+            .chain_update(b".onion checksum")
+            .chain_update(pubkey)
+            .chain_update([ONION_VERSION])
+            .finalize();
+
+        [hash[0], hash[1]]
+    }
+}
+
+impl From<[u8; 32]> for OnionAddr {
+    fn from(pubkey: [u8; 32]) -> Self {
+        Self::new(pubkey)
+    }
+}
+
+impl fmt::Display for OnionAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = [0; DECODED_LEN];
+
+        bytes[..32].copy_from_slice(&self.0);
+        bytes[32..34].copy_from_slice(&Self::checksum(&self.0));
+        bytes[34] = ONION_VERSION;
+
+        // Nb. 35 bytes are exactly 56 base32 characters, so no padding is needed.
+        let mut buffer = 0u16;
+        let mut bits = 0;
+
+        for byte in bytes {
+            buffer = (buffer << 8) | byte as u16;
+            bits += 8;
+
+            while bits >= 5 {
+                bits -= 5;
+                write!(
+                    f,
+                    "{}",
+                    BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char
+                )?;
+            }
+        }
+        write!(f, "{}", ONION_SUFFIX)
+    }
+}
+
+impl FromStr for OnionAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let encoded = s.strip_suffix(ONION_SUFFIX).ok_or(Error::MissingSuffix)?;
+
+        if encoded.len() != ENCODED_LEN {
+            return Err(Error::InvalidLength(encoded.len()));
+        }
+
+        let mut bytes = [0; DECODED_LEN];
+        let mut buffer = 0u16;
+        let mut bits = 0;
+        let mut i = 0;
+
+        for c in encoded.chars() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|a| *a as char == c)
+                .ok_or(Error::InvalidEncoding(c))?;
+
+            buffer = (buffer << 5) | value as u16;
+            bits += 5;
+
+            if bits >= 8 {
+                bits -= 8;
+                bytes[i] = (buffer >> bits) as u8;
+                i += 1;
+            }
+        }
+
+        let mut pubkey = [0; 32];
+        pubkey.copy_from_slice(&bytes[..32]);
+
+        if bytes[34] != ONION_VERSION {
+            return Err(Error::UnsupportedVersion(bytes[34]));
+        }
+        if bytes[32..34] != Self::checksum(&pubkey) {
+            return Err(Error::InvalidChecksum);
+        }
+        Ok(Self(pubkey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin_hashes::hex::FromHex;
+
+    #[test]
+    fn test_onion_addr() {
+        let name = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let pubkey =
+            Vec::<u8>::from_hex("1d04a1d04a338c6e6ae970bfabee49049d6702250984ca950c01673f4ec034ad")
+                .unwrap();
+        let onion: OnionAddr = name.parse().unwrap();
+
+        assert_eq!(onion.pubkey().as_slice(), pubkey.as_slice());
+        assert_eq!(onion.to_string(), name);
+        assert_eq!(name.to_uppercase().parse::<OnionAddr>(), Ok(onion));
+
+        assert_eq!(
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad".parse::<OnionAddr>(),
+            Err(Error::MissingSuffix)
+        );
+        assert_eq!(
+            "duckduckgo.onion".parse::<OnionAddr>(),
+            Err(Error::InvalidLength(10))
+        );
+        assert_eq!(
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzcza1.onion".parse::<OnionAddr>(),
+            Err(Error::InvalidEncoding('1'))
+        );
+        // Tampering with the public key invalidates the checksum.
+        assert_eq!(
+            "xuckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".parse::<OnionAddr>(),
+            Err(Error::InvalidChecksum)
+        );
+        // The last character encodes the version.
+        assert_eq!(
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczac.onion".parse::<OnionAddr>(),
+            Err(Error::UnsupportedVersion(0x02))
+        );
+    }
+}
@@ -1,16 +1,23 @@
 //! Shared peer types.
 
+use std::cmp::Reverse;
+use std::fmt;
+use std::hash::BuildHasher;
 use std::io;
 use std::net;
+use std::str::FromStr;
 
 use microserde as serde;
+use thiserror::Error;
 
-use bitcoincash as bitcoin;
-use bitcoin::network::address::Address;
+use bitcoin::network::address::{AddrV2, AddrV2Message, Address};
 use bitcoin::network::constants::ServiceFlags;
+use bitcoincash as bitcoin;
 
 use crate::block::time::Clock;
-use crate::net::time::LocalTime;
+use crate::net::time::{LocalDuration, LocalTime};
+
+use super::onion::{self, OnionAddr};
 
 /// Peer store.
 ///
@@ -81,6 +88,18 @@ pub trait Store {
         Ok(())
     }
 
+    /// Insert a *new* onion address into the store. Returns `true` if the address was
+    /// inserted, or `false` if it was already known, or if the store doesn't keep onion
+    /// addresses.
+    fn insert_onion(&mut self, _onion: OnionAddr, _ko: KnownOnion) -> bool {
+        false
+    }
+
+    /// Return an iterator over the known onion addresses.
+    fn onions<'a>(&'a self) -> Box<dyn Iterator<Item = (&OnionAddr, &KnownOnion)> + 'a> {
+        Box::new(std::iter::empty())
+    }
+
     /// Clears the store of all addresses.
     fn clear(&mut self);
 
@@ -176,6 +195,7 @@ impl Store for crate::collections::HashMap<net::IpAddr, KnownAddress> {
 
 /// Address source. Specifies where an address originated from.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Source {
     /// An address that was shared by another peer.
     Peer(net::SocketAddr),
@@ -196,6 +216,215 @@ impl std::fmt::Display for Source {
     }
 }
 
+impl Source {
+    /// Convert to a JSON value.
+    fn to_json(self) -> serde::json::Value {
+        use serde::json::Value;
+
+        match self {
+            Self::Dns => Value::String("dns".to_owned()),
+            Self::Imported => Value::String("imported".to_owned()),
+            Self::Peer(addr) => Value::String(addr.to_string()),
+        }
+    }
+
+    /// Convert from a JSON value.
+    fn from_json(v: &serde::json::Value) -> Result<Self, serde::Error> {
+        use serde::json::Value;
+
+        match v {
+            Value::String(s) if s == "dns" => Ok(Self::Dns),
+            Value::String(s) if s == "imported" => Ok(Self::Imported),
+            Value::String(s) => s.parse().map(Self::Peer).map_err(|_| serde::Error),
+            _ => Err(serde::Error),
+        }
+    }
+}
+
+/// A peer address, which may not be representable as a socket address, as
+/// shared with `addrv2` messages.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum PeerAddr {
+    /// An IPv4 or IPv6 address.
+    Ip(net::SocketAddr),
+    /// A Tor v3 onion service address, with its port.
+    Onion(OnionAddr, u16),
+}
+
+impl PeerAddr {
+    /// Get the address of an `addrv2` entry. Returns `None` for unsupported networks.
+    pub fn from_addr_v2(msg: &AddrV2Message) -> Option<Self> {
+        match &msg.addr {
+            AddrV2::TorV3(pubkey) => Some(Self::Onion(OnionAddr::new(*pubkey), msg.port)),
+            _ => msg.socket_addr().ok().map(Self::Ip),
+        }
+    }
+
+    /// Get the socket address, if this is an IP address.
+    pub fn socket_addr(&self) -> Option<net::SocketAddr> {
+        match self {
+            Self::Ip(addr) => Some(*addr),
+            Self::Onion(..) => None,
+        }
+    }
+
+    /// Get the port.
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Onion(_, port) => *port,
+        }
+    }
+
+    /// Check whether this is an onion address.
+    pub fn is_onion(&self) -> bool {
+        matches!(self, Self::Onion(..))
+    }
+}
+
+impl From<net::SocketAddr> for PeerAddr {
+    fn from(addr: net::SocketAddr) -> Self {
+        Self::Ip(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Onion(onion, port) => write!(f, "{}:{}", onion, port),
+        }
+    }
+}
+
+/// An error parsing a peer address.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PeerAddrError {
+    /// The address is missing a valid port.
+    #[error("invalid or missing port")]
+    InvalidPort,
+    /// The address isn't a valid IP address.
+    #[error(transparent)]
+    InvalidIp(#[from] net::AddrParseError),
+    /// The address isn't a valid onion address.
+    #[error(transparent)]
+    InvalidOnion(#[from] onion::Error),
+}
+
+impl FromStr for PeerAddr {
+    type Err = PeerAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((host, port)) if host.to_ascii_lowercase().ends_with(onion::ONION_SUFFIX) => {
+                let port = port.parse().map_err(|_| PeerAddrError::InvalidPort)?;
+
+                Ok(Self::Onion(host.parse()?, port))
+            }
+            _ => Ok(Self::Ip(s.parse()?)),
+        }
+    }
+}
+
+/// A known onion address. These are kept apart from [`KnownAddress`], since they can't
+/// be represented as a network address of the `addr` message.
+///
+/// Nb. Onion addresses are only stored and relayed, never connected to. See
+/// [`super::onion`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownOnion {
+    /// Onion service port.
+    pub port: u16,
+    /// Services offered by the peer.
+    pub services: ServiceFlags,
+    /// Address of the peer who sent us this address.
+    pub source: Source,
+    /// Last time this peer was seen alive.
+    pub last_active: Option<LocalTime>,
+}
+
+impl KnownOnion {
+    /// Create a new known onion address.
+    pub fn new(
+        port: u16,
+        services: ServiceFlags,
+        source: Source,
+        last_active: Option<LocalTime>,
+    ) -> Self {
+        Self {
+            port,
+            services,
+            source,
+            last_active,
+        }
+    }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> serde::json::Value {
+        use serde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        obj.insert(
+            "port".to_owned(),
+            Value::Number(Number::U64(self.port as u64)),
+        );
+        obj.insert(
+            "services".to_owned(),
+            Value::Number(Number::U64(self.services.to_u64())),
+        );
+        obj.insert(
+            "last_active".to_owned(),
+            match self.last_active {
+                Some(t) => Value::Number(Number::U64(t.block_time() as u64)),
+                None => Value::Null,
+            },
+        );
+        obj.insert("source".to_owned(), self.source.to_json());
+
+        Value::Object(obj)
+    }
+
+    /// Convert from a JSON value.
+    pub fn from_json(v: serde::json::Value) -> Result<Self, serde::Error> {
+        use serde::json::{Number, Value};
+
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(serde::Error),
+        };
+
+        let port = match obj.get("port") {
+            Some(Value::Number(Number::U64(port))) => {
+                u16::try_from(*port).map_err(|_| serde::Error)?
+            }
+            _ => return Err(serde::Error),
+        };
+        let services = match obj.get("services") {
+            Some(Value::Number(Number::U64(srv))) => ServiceFlags::from(*srv),
+            _ => return Err(serde::Error),
+        };
+        let last_active = match obj.get("last_active") {
+            Some(Value::Null) => None,
+            Some(Value::Number(Number::U64(n))) => Some(LocalTime::from_block_time(*n as u32)),
+            None => None,
+            _ => return Err(serde::Error),
+        };
+        let source = match obj.get("source") {
+            Some(v) => Source::from_json(v)?,
+            None => return Err(serde::Error),
+        };
+
+        Ok(Self {
+            port,
+            services,
+            source,
+            last_active,
+        })
+    }
+}
+
 /// A known address.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KnownAddress {
@@ -267,14 +496,7 @@ impl KnownAddress {
                 None => Value::Null,
             },
         );
-        obj.insert(
-            "source".to_owned(),
-            match self.source {
-                Source::Dns => Value::String("dns".to_owned()),
-                Source::Imported => Value::String("imported".to_owned()),
-                Source::Peer(addr) => Value::String(addr.to_string()),
-            },
-        );
+        obj.insert("source".to_owned(), self.source.to_json());
 
         Value::Object(obj)
     }
@@ -321,19 +543,8 @@ impl KnownAddress {
             _ => return Err(serde::Error),
         };
         let source = match obj.get("source") {
-            Some(Value::String(s)) => {
-                if s == "dns" {
-                    Source::Dns
-                } else if s == "imported" {
-                    Source::Imported
-                } else {
-                    match s.parse() {
-                        Ok(addr) => Source::Peer(addr),
-                        Err(_) => return Err(serde::Error),
-                    }
-                }
-            }
-            _ => return Err(serde::Error),
+            Some(v) => Source::from_json(v)?,
+            None => return Err(serde::Error),
         };
 
         Ok(Self {
@@ -355,6 +566,132 @@ pub trait AddressSource {
     fn iter(&mut self, services: ServiceFlags) -> Box<dyn Iterator<Item = (Address, Source)> + '_>;
 }
 
+/// Reputation of a peer, accumulated over all sessions with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerReputation {
+    /// Port of the last session with the peer.
+    pub port: u16,
+    /// Services last advertised by the peer.
+    pub services: ServiceFlags,
+    /// Number of sessions recorded.
+    pub sessions: u32,
+    /// Total time connected to the peer.
+    pub uptime: LocalDuration,
+    /// Average latency, over the sessions in which it was measured.
+    pub latency: Option<LocalDuration>,
+    /// Number of sessions in which latency was measured.
+    pub latency_sessions: u32,
+    /// Number of times the peer misbehaved.
+    pub misbehaviors: u32,
+}
+
+impl Default for PeerReputation {
+    fn default() -> Self {
+        Self {
+            port: 0,
+            services: ServiceFlags::NONE,
+            sessions: 0,
+            uptime: LocalDuration::from_secs(0),
+            latency: None,
+            latency_sessions: 0,
+            misbehaviors: 0,
+        }
+    }
+}
+
+/// Peer reputation store.
+///
+/// Used to keep track of peer behavior across sessions, so that the best peers can be
+/// prioritized when connecting. Peers are keyed by IP address, since the port of
+/// inbound connections is ephemeral.
+pub trait Reputation {
+    /// Get the reputation of a peer.
+    fn get(&self, ip: &net::IpAddr) -> Option<&PeerReputation>;
+
+    /// Get the reputation of a peer, for modification. Inserts a blank reputation if the
+    /// peer is unknown.
+    fn entry(&mut self, ip: net::IpAddr) -> &mut PeerReputation;
+
+    /// Iterate over all peer reputations.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &PeerReputation)> + 'a>;
+
+    /// Flush data to permanent storage.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Record a finished session with a peer, with the time we were connected for, and the
+    /// latency measured during the session, if any.
+    fn record_session(
+        &mut self,
+        addr: net::SocketAddr,
+        services: ServiceFlags,
+        uptime: LocalDuration,
+        latency: Option<LocalDuration>,
+    ) {
+        let rep = self.entry(addr.ip());
+
+        rep.port = addr.port();
+        rep.services = services;
+        rep.sessions += 1;
+        rep.uptime = rep.uptime + uptime;
+
+        if let Some(latency) = latency {
+            let n = rep.latency_sessions;
+            let total = rep.latency.map_or(latency, |avg| avg * n as u64 + latency);
+
+            rep.latency_sessions += 1;
+            rep.latency = Some(total / rep.latency_sessions);
+        }
+    }
+
+    /// Record that a peer misbehaved.
+    fn record_misbehavior(&mut self, addr: net::SocketAddr) {
+        self.entry(addr.ip()).misbehaviors += 1;
+    }
+
+    /// Get the addresses of the best `n` peers we've had a session with.
+    ///
+    /// Peers that misbehaved less are ranked first, then peers with lower latency, and
+    /// finally peers we were connected to for longer.
+    fn best_peers(&self, n: usize) -> Vec<Address> {
+        let mut peers = self
+            .iter()
+            .filter(|(_, rep)| rep.sessions > 0)
+            .collect::<Vec<_>>();
+
+        peers.sort_by_key(|(_, rep)| {
+            (
+                rep.misbehaviors,
+                rep.latency.unwrap_or(LocalDuration::MAX),
+                Reverse(rep.uptime),
+            )
+        });
+        peers
+            .into_iter()
+            .take(n)
+            .map(|(ip, rep)| Address::new(&net::SocketAddr::new(*ip, rep.port), rep.services))
+            .collect()
+    }
+}
+
+/// In-memory reputation store.
+impl<S: BuildHasher> Reputation for std::collections::HashMap<net::IpAddr, PeerReputation, S> {
+    fn get(&self, ip: &net::IpAddr) -> Option<&PeerReputation> {
+        self.get(ip)
+    }
+
+    fn entry(&mut self, ip: net::IpAddr) -> &mut PeerReputation {
+        self.entry(ip).or_default()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &PeerReputation)> + 'a> {
+        Box::new(self.iter())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Functions and traits useful for testing.
 pub mod test {
     use super::*;
@@ -395,4 +732,141 @@ mod tests {
 
         assert_eq!(ka, deserialized);
     }
+
+    #[test]
+    fn test_peer_addr() {
+        let name = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let onion: OnionAddr = name.parse().unwrap();
+
+        for (s, expected) in [
+            ("1.2.3.4:8333", PeerAddr::Ip(([1, 2, 3, 4], 8333).into())),
+            (
+                "[2001:db8::1]:8333",
+                PeerAddr::Ip("[2001:db8::1]:8333".parse().unwrap()),
+            ),
+            (&format!("{}:9050", name), PeerAddr::Onion(onion, 9050)),
+        ] {
+            let addr: PeerAddr = s.parse().unwrap();
+
+            assert_eq!(addr, expected);
+            assert_eq!(addr.to_string(), s);
+        }
+        assert_eq!(
+            format!("{}:port", name).parse::<PeerAddr>(),
+            Err(PeerAddrError::InvalidPort)
+        );
+        assert_eq!(
+            "duckduckgo.onion:8333".parse::<PeerAddr>(),
+            Err(PeerAddrError::InvalidOnion(onion::Error::InvalidLength(10)))
+        );
+        assert!(matches!(
+            "1.2.3:8333".parse::<PeerAddr>(),
+            Err(PeerAddrError::InvalidIp(_))
+        ));
+
+        let msg = AddrV2Message {
+            time: 0,
+            services: ServiceFlags::NETWORK,
+            addr: AddrV2::TorV3(*onion.pubkey()),
+            port: 8333,
+        };
+        assert_eq!(
+            PeerAddr::from_addr_v2(&msg),
+            Some(PeerAddr::Onion(onion, 8333))
+        );
+        assert_eq!(PeerAddr::Onion(onion, 8333).socket_addr(), None);
+
+        let msg = AddrV2Message {
+            addr: AddrV2::I2p([0; 32]),
+            ..msg
+        };
+        assert_eq!(PeerAddr::from_addr_v2(&msg), None);
+    }
+
+    #[test]
+    fn test_known_onion() {
+        let ko = KnownOnion::new(
+            8333,
+            ServiceFlags::NETWORK,
+            Source::Peer(net::SocketAddr::from(([4, 5, 6, 7], 8333))),
+            Some(LocalTime::from_secs(42)),
+        );
+        let value = ko.to_json();
+        let deserialized = KnownOnion::from_json(value).unwrap();
+
+        assert_eq!(ko, deserialized);
+    }
+
+    #[test]
+    fn test_reputation() {
+        let mut reputation = std::collections::HashMap::new();
+        let alice = net::SocketAddr::from(([1, 1, 1, 1], 8333));
+        let bob = net::SocketAddr::from(([2, 2, 2, 2], 8333));
+        let eve = net::SocketAddr::from(([6, 6, 6, 6], 8333));
+        let mallory = net::SocketAddr::from(([7, 7, 7, 7], 8333));
+        let services = ServiceFlags::NETWORK;
+        let ms = LocalDuration::from_millis;
+
+        assert!(reputation.best_peers(8).is_empty());
+
+        reputation.record_session(alice, services, LocalDuration::from_mins(10), Some(ms(100)));
+        reputation.record_session(alice, services, LocalDuration::from_mins(5), None);
+        reputation.record_session(alice, services, LocalDuration::from_mins(5), Some(ms(300)));
+        reputation.record_session(bob, services, LocalDuration::from_mins(60), Some(ms(200)));
+        reputation.record_session(eve, services, LocalDuration::from_mins(90), None);
+        reputation.record_session(
+            mallory,
+            services,
+            LocalDuration::from_mins(90),
+            Some(ms(50)),
+        );
+        reputation.record_misbehavior(mallory);
+
+        let rep = Reputation::get(&reputation, &alice.ip()).unwrap();
+        assert_eq!(rep.sessions, 3);
+        assert_eq!(rep.uptime, LocalDuration::from_mins(20));
+        assert_eq!(rep.latency, Some(ms(200)));
+        assert_eq!(rep.latency_sessions, 2);
+        assert_eq!(rep.misbehaviors, 0);
+
+        // Ties in latency are broken by uptime, and misbehaving peers come last.
+        assert_eq!(
+            reputation.best_peers(8),
+            vec![
+                Address::new(&bob, services),
+                Address::new(&alice, services),
+                Address::new(&eve, services),
+                Address::new(&mallory, services),
+            ]
+        );
+        assert_eq!(reputation.best_peers(1), vec![Address::new(&bob, services)]);
+
+        // Peers we never had a session with aren't returned.
+        let unknown = net::SocketAddr::from(([9, 9, 9, 9], 8333));
+        reputation.record_misbehavior(unknown);
+        assert_eq!(reputation.best_peers(8).len(), 4);
+        assert_eq!(
+            Reputation::get(&reputation, &unknown.ip())
+                .unwrap()
+                .misbehaviors,
+            1
+        );
+
+        // Peers are keyed by IP, and the port of the last session is kept.
+        let moved = net::SocketAddr::from(([2, 2, 2, 2], 18333));
+        reputation.record_session(moved, services, LocalDuration::from_mins(1), None);
+        assert_eq!(reputation.len(), 5);
+        assert_eq!(
+            reputation.best_peers(1),
+            vec![Address::new(&moved, services)]
+        );
+
+        // The same store works with any hasher.
+        let mut reputation = crate::collections::HashMap::default();
+        reputation.record_session(alice, services, LocalDuration::from_mins(1), None);
+        assert_eq!(
+            reputation.best_peers(1),
+            vec![Address::new(&alice, services)]
+        );
+    }
 }
@@ -223,6 +223,14 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
                                         }
                                     };
                                     let addr = Id::from(socket_addr);
+
+                                    // Since peers are identified by address, a second socket
+                                    // for the same address can't be told apart from the first.
+                                    // The new socket is dropped, which closes it.
+                                    if self.peers.contains_key(&addr) {
+                                        debug!(target: "net", "{}: Dropping duplicate connection", socket_addr);
+                                        continue;
+                                    }
                                     trace!("{}: Accepting peer connection", socket_addr);
 
                                     conn.set_nonblocking(true)?;
@@ -240,6 +248,9 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
 
                                 // Exit reactor loop if a shutdown was received.
                                 if let Ok(()) = self.shutdown.try_recv() {
+                                    service.shutdown();
+                                    self.flush(&mut service, &mut publisher);
+
                                     return Ok(());
                                 }
                                 popol::Waker::reset(ev.source).ok();
@@ -304,6 +315,13 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
                 }
                 Io::Connect(addr) => {
                     let socket_addr = addr.to_socket_addr();
+
+                    // Nb. Dialing an address that is already registered would replace its
+                    // socket, and leave the existing connection unreachable.
+                    if self.peers.contains_key(&addr) {
+                        debug!(target: "net", "{}: Already connected, not dialing", socket_addr);
+                        continue;
+                    }
                     trace!("Connecting to {}...", socket_addr);
 
                     match self::dial(&socket_addr) {
@@ -351,6 +369,38 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
         }
     }
 
+    /// Flush the outputs of a service that is shutting down. Pending messages are written
+    /// out on a best-effort basis before peers are disconnected. No new connections or
+    /// timers are started.
+    fn flush<S, E>(&mut self, service: &mut S, publisher: &mut E)
+    where
+        S: Service<Id>,
+        E: Publisher<S::Event>,
+    {
+        for out in service.by_ref() {
+            match out {
+                Io::Write(addr, bytes) => {
+                    if let Some(socket) = self.peers.get_mut(&addr) {
+                        socket.push(&bytes);
+                        socket.flush().ok();
+                    }
+                }
+                Io::Disconnect(addr, reason) => {
+                    if let Some(socket) = self.peers.get_mut(&addr) {
+                        trace!("{}: Disconnecting: {}", addr.to_socket_addr(), reason);
+
+                        socket.flush().ok();
+                        socket.disconnect().ok();
+                    }
+                }
+                Io::Event(event) => {
+                    publisher.publish(event);
+                }
+                Io::Connect(_) | Io::SetTimer(_) => {}
+            }
+        }
+    }
+
     fn handle_readable<S>(&mut self, addr: Id, service: &mut S)
     where
         S: Service<Id>,
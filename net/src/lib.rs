@@ -17,6 +17,7 @@ pub use time::{LocalDuration, LocalTime};
 
 /// Link direction of the peer connection.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Link {
     /// Inbound conneciton.
     Inbound,
@@ -163,6 +164,9 @@ pub trait StateMachine<Id: PeerId = net::SocketAddr>:
     fn tick(&mut self, local_time: LocalTime);
     /// A timer set with [`Io::SetTimer`] has expired.
     fn timer_expired(&mut self);
+    /// Called once by the reactor before it exits, eg. after a shutdown was requested.
+    /// Used to persist any state that should survive a restart.
+    fn shutdown(&mut self) {}
 }
 
 /// Used by certain types of reactors to wake the event loop, for example when a
@@ -5,6 +5,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 ///
 /// This clock is monotonic.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Ord, PartialOrd, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LocalTime {
     /// Milliseconds since Epoch.
     millis: u128,
@@ -117,6 +118,7 @@ impl std::ops::Add<LocalDuration> for LocalTime {
 
 /// Time duration as measured locally.
 #[derive(Debug, Copy, Clone, PartialOrd, Ord, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LocalDuration(u128);
 
 impl LocalDuration {
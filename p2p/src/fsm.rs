@@ -29,12 +29,14 @@ use invmgr::InventoryManager;
 use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
 use nakamoto_common::bloom::store::cache::PrivacySegment;
 use nakamoto_common::collections::HashMap;
-use output::Outbox;
+use output::{Bandwidth, Outbox};
 use peermgr::PeerManager;
 use pingmgr::PingManager;
 use syncmgr::SyncManager;
 
-pub use event::Event;
+pub use event::{
+    ChainImport, ErrorKind, Event, EventBatcher, InvType, MessageMask, Severity, TimedEvent,
+};
 pub use nakamoto_net::Link;
 
 use std::borrow::Cow;
@@ -54,15 +56,19 @@ use nakamoto_common::bitcoin::network::message_filter::GetCFilters;
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 use nakamoto_common::bitcoin::network::Address;
 use nakamoto_common::bitcoin::util::uint::Uint256;
-use nakamoto_common::bitcoin::{Script, Txid};
+use nakamoto_common::bitcoin::{OutPoint, Script, Txid};
 use nakamoto_common::block::filter::Filters;
 use nakamoto_common::block::time::AdjustedClock;
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::tree::{self, BlockReader, BlockTree, ImportResult};
 use nakamoto_common::block::{BlockHash, Height};
 use nakamoto_common::block::{BlockTime, Transaction};
+use nakamoto_common::bloom::{
+    BloomFilter, MAX_BLOOM_FILTER_SIZE, MAX_FILTER_ADD_SIZE, MAX_HASH_FUNCS,
+};
 use nakamoto_common::network;
 use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_common::p2p::peer::Reputation as _;
 use nakamoto_common::p2p::{peer, Domain};
 use nakamoto_net as traits;
 
@@ -75,6 +81,76 @@ pub const PROTOCOL_VERSION: u32 = 70016;
 pub const MIN_PROTOCOL_VERSION: u32 = 70012;
 /// User agent included in `version` messages.
 pub const USER_AGENT: &str = "/nakamoto:0.3.0/";
+/// How often bandwidth usage is reported.
+pub const BANDWIDTH_REPORT_INTERVAL: LocalDuration = LocalDuration::from_mins(1);
+
+/// Protocol features that require support from the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Compact block filters (BIP 157), eg. `getcfilters`.
+    CompactFilters,
+    /// Bloom filters (BIP 37), eg. `filterload`.
+    BloomFilters,
+    /// Header announcements (BIP 130), ie. `sendheaders`.
+    SendHeaders,
+    /// Fee filters (BIP 133), ie. `feefilter`.
+    FeeFilter,
+}
+
+impl Feature {
+    /// Minimum protocol version a peer must support for this feature.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Self::CompactFilters => MIN_PROTOCOL_VERSION,
+            Self::BloomFilters => 70011,
+            Self::SendHeaders => 70012,
+            Self::FeeFilter => 70013,
+        }
+    }
+
+    /// Services a peer must signal for this feature.
+    pub fn services(&self) -> ServiceFlags {
+        match self {
+            Self::CompactFilters => ServiceFlags::COMPACT_FILTERS,
+            Self::BloomFilters => ServiceFlags::BLOOM,
+            Self::SendHeaders | Self::FeeFilter => ServiceFlags::NONE,
+        }
+    }
+
+    /// Check whether a peer with the given protocol version and services supports this feature.
+    pub fn is_supported(&self, version: u32, services: ServiceFlags) -> bool {
+        version >= self.min_version() && services.has(self.services())
+    }
+}
+
+/// Kind of block filter a peer may serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FilterKind {
+    /// Compact block filters (BIP 157).
+    Compact,
+    /// Bloom filters (BIP 37).
+    Bloom,
+}
+
+impl FilterKind {
+    /// Services a peer must signal to serve this kind of filter.
+    pub fn services(&self) -> ServiceFlags {
+        match self {
+            Self::Compact => Feature::CompactFilters.services(),
+            Self::Bloom => Feature::BloomFilters.services(),
+        }
+    }
+}
+
+impl fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Compact => write!(f, "compact"),
+            Self::Bloom => write!(f, "bloom"),
+        }
+    }
+}
 
 /// Block locators. Consists of starting hashes and a stop hash.
 type Locators = (Vec<BlockHash>, BlockHash);
@@ -125,6 +201,12 @@ pub enum DisconnectReason {
     DecodeError(Arc<encode::Error>),
     /// Peer was forced to disconnect by external command.
     Command,
+    /// Peer was banned after repeated misbehavior.
+    PeerBanned,
+    /// Peer was evicted to make room for a better peer.
+    Evicted(&'static str),
+    /// Peer was already connected to, through another connection.
+    DuplicateConnection,
     /// Peer was disconnected for another reason.
     Other(&'static str),
 }
@@ -135,9 +217,38 @@ impl DisconnectReason {
     pub fn is_transient(&self) -> bool {
         matches!(
             self,
-            Self::ConnectionLimit | Self::PeerTimeout(_) | Self::PeerHeight(_)
+            Self::ConnectionLimit
+                | Self::PeerTimeout(_)
+                | Self::PeerHeight(_)
+                | Self::Evicted(_)
+                | Self::DuplicateConnection
         )
     }
+
+    /// Ban score penalty incurred by a peer disconnected for this reason. Protocol
+    /// violations are penalized more heavily than transient failures such as timeouts,
+    /// and reasons that aren't the peer's fault carry no penalty.
+    ///
+    /// Nb. Misbehavior is scored when it's reported with [`Event::PeerMisbehaved`], so
+    /// disconnecting a misbehaving peer carries no further penalty.
+    pub fn ban_score(&self) -> u32 {
+        match self {
+            Self::PeerMagic(_) => peermgr::BAN_THRESHOLD,
+            Self::DecodeError(_) => peermgr::BAN_THRESHOLD / 2,
+            Self::PeerTimeout(_) => 5,
+            Self::PeerMisbehaving(_)
+            | Self::PeerProtocolVersion(_)
+            | Self::PeerServices(_)
+            | Self::PeerHeight(_)
+            | Self::SelfConnection
+            | Self::ConnectionLimit
+            | Self::Command
+            | Self::PeerBanned
+            | Self::Evicted(_)
+            | Self::DuplicateConnection
+            | Self::Other(_) => 0,
+        }
+    }
 }
 
 impl From<DisconnectReason> for nakamoto_net::Disconnect<DisconnectReason> {
@@ -159,6 +270,9 @@ impl fmt::Display for DisconnectReason {
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
             Self::DecodeError(err) => write!(f, "message decode error: {}", err),
             Self::Command => write!(f, "received external command"),
+            Self::PeerBanned => write!(f, "peer banned for misbehaving"),
+            Self::Evicted(reason) => write!(f, "peer evicted: {}", reason),
+            Self::DuplicateConnection => write!(f, "duplicate connection"),
             Self::Other(reason) => write!(f, "{}", reason),
         }
     }
@@ -181,6 +295,8 @@ pub struct Peer {
     pub services: ServiceFlags,
     /// Peer user agent string.
     pub user_agent: String,
+    /// Node implementation, as advertised in the user agent.
+    pub implementation: peermgr::PeerImplementation,
     /// Whether this peer relays transactions.
     pub relay: bool,
 }
@@ -202,6 +318,7 @@ impl From<(&peermgr::PeerInfo, &peermgr::Connection)> for Peer {
             height: peer.height,
             services: peer.services,
             user_agent: peer.user_agent.clone(),
+            implementation: peer.implementation.clone(),
             relay: peer.relay,
         }
     }
@@ -216,6 +333,8 @@ pub enum Command {
     GetBlock(BlockHash),
     /// Get connected peers.
     GetPeers(ServiceFlags, chan::Sender<Vec<Peer>>),
+    /// Get the latency metrics of connected peers.
+    GetPeerMetrics(chan::Sender<Vec<PeerMetric>>),
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader, Uint256)>),
     /// Get a block from the active chain.
@@ -243,11 +362,33 @@ pub enum Command {
         // /// Scripts to match on.
         // watch: Vec<Script>,
     },
+    /// Resume an interrupted merkle block rescan.
+    ResumeMerkleBlockRescan {
+        /// Last height that was fully processed. Scanning resumes at the following height.
+        checkpoint: Height,
+        /// Stop scanning at this height. If `None`, don't stop scanning.
+        to: Option<Height>,
+        /// Peer to request merkle blocks from, if it's still connected.
+        peer: Option<PeerId>,
+    },
     /// Update the watchlist with the provided scripts.
     Watch {
         /// Scripts to watch.
         watch: Vec<Script>,
     },
+    /// Add a script to the watchlist.
+    WatchScript {
+        /// Script to watch.
+        script: Script,
+        /// If set, rescan the chain for the script, starting at this height.
+        rescan_from: Option<Height>,
+    },
+    /// Remove a script from the watchlist.
+    UnwatchScript(Script),
+    /// Add an outpoint to the watchlist.
+    WatchOutpoint(OutPoint),
+    /// Re-run the watchlist against downloaded blocks, from the given height.
+    RescanCached(Height),
     /// Broadcast to peers matching the predicate.
     Broadcast(NetworkMessage, fn(Peer) -> bool, chan::Sender<Vec<PeerId>>),
     /// Query the block tree.
@@ -256,6 +397,8 @@ pub enum Command {
     Connect(net::SocketAddr),
     /// Disconnect from a peer.
     Disconnect(net::SocketAddr),
+    /// Force-disconnect a peer for the given reason, cleaning it up right away.
+    DisconnectPeer(PeerId, DisconnectReason),
     /// Import headers directly into the block store.
     ImportHeaders(
         Vec<BlockHeader>,
@@ -272,17 +415,28 @@ pub enum Command {
     GetSubmittedTransaction(Txid, chan::Sender<Option<Transaction>>),
     /// Load Bloom filters to the .
     LoadBloomFilter(FilterLoad, net::SocketAddr),
+    /// Add an element to the bloom filter loaded on a peer.
+    AddToBloomFilter(Vec<u8>, net::SocketAddr),
+    /// Get our copy of the bloom filter loaded on a peer, if any.
+    GetBloomFilter(net::SocketAddr, chan::Sender<Option<BloomFilter>>),
     /// Get mempool
     GetMempool,
+    /// Watch peer mempools for unconfirmed transactions touching the given scripts.
+    WatchMempool(Vec<Script>),
+    /// Stop watching peer mempools.
+    UnwatchMempool,
 }
 
 impl fmt::Debug for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::GetMempool => write!(f, "GetMempool"),
+            Self::WatchMempool(watch) => write!(f, "WatchMempool({:?})", watch),
+            Self::UnwatchMempool => write!(f, "UnwatchMempool"),
             Self::GetBlockByHeight(height, _) => write!(f, "GetBlockByHeight({})", height),
             Self::GetBlock(hash) => write!(f, "GetBlock({})", hash),
             Self::GetPeers(flags, _) => write!(f, "GetPeers({})", flags),
+            Self::GetPeerMetrics(_) => write!(f, "GetPeerMetrics"),
             Self::GetTip(_) => write!(f, "GetTip"),
             Self::RequestBlock(hash) => write!(f, "GetBlock({})", hash),
             Self::RequestFilters(range, _) => write!(f, "GetFilters({:?})", range),
@@ -292,13 +446,36 @@ impl fmt::Debug for Command {
             Self::MerkleBlockRescan { from, to } => {
                 write!(f, "MerkleBlockRescan ({:?}, {:?},)", from, to,)
             }
+            Self::ResumeMerkleBlockRescan {
+                checkpoint,
+                to,
+                peer,
+            } => {
+                write!(
+                    f,
+                    "ResumeMerkleBlockRescan({}, {:?}, {:?})",
+                    checkpoint, to, peer
+                )
+            }
             Self::Watch { watch } => {
                 write!(f, "Watch({:?})", watch)
             }
+            Self::WatchScript {
+                script,
+                rescan_from,
+            } => {
+                write!(f, "WatchScript({:?}, {:?})", script, rescan_from)
+            }
+            Self::UnwatchScript(script) => write!(f, "UnwatchScript({:?})", script),
+            Self::WatchOutpoint(outpoint) => write!(f, "WatchOutpoint({})", outpoint),
+            Self::RescanCached(from) => write!(f, "RescanCached({})", from),
             Self::Broadcast(msg, _, _) => write!(f, "Broadcast({})", msg.cmd()),
             Self::QueryTree(_) => write!(f, "QueryTree"),
             Self::Connect(addr) => write!(f, "Connect({})", addr),
             Self::Disconnect(addr) => write!(f, "Disconnect({})", addr),
+            Self::DisconnectPeer(addr, reason) => {
+                write!(f, "DisconnectPeer({}, {})", addr, reason)
+            }
             Self::ImportHeaders(_headers, _) => write!(f, "ImportHeaders(..)"),
             Self::ImportAddresses(addrs) => write!(f, "ImportAddresses({:?})", addrs),
             Self::SubmitTransaction(tx, _) => write!(f, "SubmitTransaction({:?})", tx),
@@ -306,6 +483,10 @@ impl fmt::Debug for Command {
             Self::LoadBloomFilter(_filter, _addr) => {
                 write!(f, "LoadBloomFilter()" /* filter */,)
             }
+            Self::AddToBloomFilter(data, addr) => {
+                write!(f, "AddToBloomFilter({} byte(s), {})", data.len(), addr)
+            }
+            Self::GetBloomFilter(addr, _) => write!(f, "GetBloomFilter({})", addr),
         }
     }
 }
@@ -316,9 +497,121 @@ pub enum CommandError {
     /// Not connected to any peer with the required services.
     #[error("not connected to any peer with the required services")]
     NotConnected,
+    /// The submitted transaction was rejected.
+    #[error(transparent)]
+    Rejected(#[from] AnnounceError),
+}
+
+/// An error resulting from requesting a scan over an invalid height range.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ScanRangeError {
+    /// The range ends before it starts.
+    #[error("scan range ending at height {stop} is empty, since it starts at height {start}")]
+    Inverted {
+        /// Start height.
+        start: Height,
+        /// Requested stop height.
+        stop: Height,
+    },
+    /// The range is bounded, and starts beyond the tip.
+    #[error("scan range starting at height {start} is beyond the tip at height {tip}")]
+    BeyondTip {
+        /// Start height.
+        start: Height,
+        /// Current tip height.
+        tip: Height,
+    },
+}
+
+/// Resolve scan bounds into an inclusive start height and optional stop height, validated
+/// against the current tip.
+///
+/// An unbounded start means scanning from the block after the tip, and an unbounded stop
+/// means scanning indefinitely, keeping up with the tip. A stop height beyond the tip is
+/// clamped to the tip.
+pub(crate) fn scan_range(
+    start: Bound<Height>,
+    end: Bound<Height>,
+    tip: Height,
+) -> Result<(Height, Option<Height>), ScanRangeError> {
+    let start = match start {
+        Bound::Unbounded => tip + 1,
+        Bound::Included(h) => h,
+        Bound::Excluded(h) => h + 1,
+    };
+    let stop = match end {
+        Bound::Unbounded => return Ok((start, None)),
+        Bound::Included(h) => h,
+        Bound::Excluded(h) => h
+            .checked_sub(1)
+            .ok_or(ScanRangeError::Inverted { start, stop: h })?,
+    };
+
+    if stop < start {
+        return Err(ScanRangeError::Inverted { start, stop });
+    }
+    if start > tip {
+        return Err(ScanRangeError::BeyondTip { start, tip });
+    }
+    Ok((start, Some(Height::min(stop, tip))))
+}
+
+/// Check a message against the protocol size limits of its type. This guards against
+/// peers flooding us with oversized messages, before they're processed.
+///
+/// Returns the misbehavior reason if the message is over the limit.
+pub(crate) fn check_message_size(msg: &NetworkMessage) -> Result<(), &'static str> {
+    match msg {
+        NetworkMessage::Inv(invs) if invs.len() > syncmgr::MAX_MESSAGE_INVS => {
+            Err("oversized `inv` message")
+        }
+        NetworkMessage::GetData(invs) if invs.len() > syncmgr::MAX_MESSAGE_INVS => {
+            Err("oversized `getdata` message")
+        }
+        NetworkMessage::NotFound(invs) if invs.len() > syncmgr::MAX_MESSAGE_INVS => {
+            Err("oversized `notfound` message")
+        }
+        NetworkMessage::Addr(addrs) if addrs.len() > addrmgr::MAX_ADDR_ADDRESSES => {
+            Err("oversized `addr` message")
+        }
+        NetworkMessage::AddrV2(addrs) if addrs.len() > addrmgr::MAX_ADDR_ADDRESSES => {
+            Err("oversized `addrv2` message")
+        }
+        NetworkMessage::Headers(headers) if headers.len() > syncmgr::MAX_MESSAGE_HEADERS => {
+            Err("oversized `headers` message")
+        }
+        NetworkMessage::CFHeaders(msg)
+            if msg.filter_hashes.len() > cbfmgr::MAX_MESSAGE_CFHEADERS =>
+        {
+            Err("oversized `cfheaders` message")
+        }
+        NetworkMessage::FilterLoad(msg)
+            if msg.filter.len() > MAX_BLOOM_FILTER_SIZE || msg.hash_funcs > MAX_HASH_FUNCS =>
+        {
+            Err("oversized `filterload` message")
+        }
+        NetworkMessage::FilterAdd(msg) if msg.data.len() > MAX_FILTER_ADD_SIZE => {
+            Err("oversized `filteradd` message")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Randomly offset a duration by up to the given fraction of it, in either direction.
+pub(crate) fn jitter(
+    duration: LocalDuration,
+    fraction: f64,
+    rng: &mut fastrand::Rng,
+) -> LocalDuration {
+    let millis = duration.as_millis() as f64;
+    let offset = millis * fraction * (rng.f64() * 2. - 1.);
+
+    LocalDuration::from_millis((millis + offset) as u128)
 }
 
 pub use cbfmgr::GetFiltersError;
+pub use invmgr::{AnnounceError, TX_STATUS_BATCH_THRESHOLD};
+pub use pingmgr::PeerMetric;
 
 /// Holds functions that are used to hook into or alter protocol behavior.
 #[derive(Clone)]
@@ -334,6 +627,8 @@ pub struct Hooks {
     pub on_getcfilters: Arc<dyn Fn(PeerId, GetCFilters, &Outbox) + Send + Sync>,
     /// Called when a `getdata` message is received.
     pub on_getdata: Arc<dyn Fn(PeerId, Vec<Inventory>, &Outbox) + Send + Sync>,
+    /// Called with every event emitted, timestamped with the local time of emission.
+    pub on_event: Arc<dyn Fn(TimedEvent) + Send + Sync>,
 }
 
 impl Default for Hooks {
@@ -343,6 +638,7 @@ impl Default for Hooks {
             on_version: Arc::new(|_, _| Ok(())),
             on_getcfilters: Arc::new(|_, _, _| {}),
             on_getdata: Arc::new(|_, _, _| {}),
+            on_event: Arc::new(|_| {}),
         }
     }
 }
@@ -377,15 +673,23 @@ pub struct StateMachine<T, F, P, C> {
     peermgr: PeerManager<C>,
     /// Inventory manager.
     invmgr: InventoryManager<C>,
+    /// Reputation of the outbound peers we've had sessions with, by IP address.
+    reputation: HashMap<net::IpAddr, peer::PeerReputation>,
     /// Network-adjusted clock.
     clock: C,
     /// Last time a "tick" was triggered.
     #[allow(dead_code)]
     last_tick: LocalTime,
+    /// Bytes sent and received since the last bandwidth report.
+    bandwidth: Bandwidth,
+    /// Last time bandwidth usage was reported.
+    last_bandwidth_report: LocalTime,
     /// Outbound I/O. Used to communicate protocol events with a reactor.
     outbox: Outbox,
     /// State machine event hooks.
     hooks: Hooks,
+    /// Received message types published as events.
+    message_mask: MessageMask,
 }
 
 /// Configured limits.
@@ -395,6 +699,9 @@ pub struct Limits {
     pub max_outbound_peers: usize,
     /// Maximum inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Average latency above which inbound peers may be evicted to make room for new ones,
+    /// once the inbound connection limit is reached.
+    pub eviction_latency: LocalDuration,
     /// Size in bytes of the compact filter cache.
     pub filter_cache_size: usize,
 }
@@ -404,6 +711,7 @@ impl Default for Limits {
         Self {
             max_outbound_peers: peermgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: peermgr::MAX_INBOUND_PEERS,
+            eviction_latency: peermgr::EVICTION_LATENCY,
             filter_cache_size: cbfmgr::DEFAULT_FILTER_CACHE_SIZE,
         }
     }
@@ -432,12 +740,38 @@ pub struct Config {
     pub user_agent: &'static str,
     /// Ping timeout, after which remotes are disconnected.
     pub ping_timeout: LocalDuration,
+    /// Handshake timeout, after which remotes that haven't completed the handshake
+    /// are disconnected.
+    pub handshake_timeout: LocalDuration,
+    /// Maximum fraction of the ping interval by which pings are randomly offset.
+    pub ping_jitter: f64,
     /// State machine event hooks.
     pub hooks: Hooks,
     /// Configured limits.
     pub limits: Limits,
     /// Bloom Filter
     pub bloom_segments: HashMap<u32, PrivacySegment>,
+    /// Defer compact filter scanning until the filter header chain is synced.
+    pub defer_scan_during_ibd: bool,
+    /// Received message types published as [`Event::MessageReceived`]. None are
+    /// published by default.
+    pub message_mask: MessageMask,
+    /// Order in which blocks, eg. matched by a rescan, are requested.
+    pub block_order: invmgr::BlockOrder,
+    /// Number of transactions confirmed by the same block, above which their status changes
+    /// are reported as a single [`Event::TxStatusBatch`], eg. [`TX_STATUS_BATCH_THRESHOLD`].
+    /// If `None`, status changes are always reported individually.
+    pub tx_status_batch: Option<usize>,
+    /// Time during which new transaction announcements to a peer are coalesced into a
+    /// single `inv` message.
+    pub announce_window: LocalDuration,
+    /// How long the header or filter header chain can go without advancing while it's
+    /// behind, before sync is considered stalled.
+    pub stall_timeout: LocalDuration,
+    /// Minimum time between two `getaddr` requests to the same peer.
+    pub getaddr_interval: LocalDuration,
+    /// Time interval to wait between pings sent to inbound peers.
+    pub inbound_ping_interval: LocalDuration,
 }
 
 impl Default for Config {
@@ -452,10 +786,20 @@ impl Default for Config {
             whitelist: Whitelist::default(),
             protocol_version: PROTOCOL_VERSION,
             ping_timeout: pingmgr::PING_TIMEOUT,
+            handshake_timeout: peermgr::HANDSHAKE_TIMEOUT,
+            ping_jitter: pingmgr::PING_JITTER,
             user_agent: USER_AGENT,
             hooks: Hooks::default(),
             limits: Limits::default(),
             bloom_segments: HashMap::with_hasher(Rng::new().into()),
+            defer_scan_during_ibd: false,
+            message_mask: MessageMask::NONE,
+            block_order: invmgr::BlockOrder::default(),
+            tx_status_batch: None,
+            announce_window: invmgr::ANNOUNCE_WINDOW,
+            stall_timeout: syncmgr::STALL_TIMEOUT,
+            getaddr_interval: addrmgr::GETADDR_INTERVAL,
+            inbound_ping_interval: pingmgr::INBOUND_PING_INTERVAL,
         }
     }
 }
@@ -512,12 +856,22 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             whitelist,
             protocol_version,
             ping_timeout,
+            handshake_timeout,
+            ping_jitter,
             user_agent,
             required_services,
             params,
             hooks,
             limits,
             bloom_segments,
+            defer_scan_during_ibd,
+            message_mask,
+            block_order,
+            tx_status_batch,
+            announce_window,
+            stall_timeout,
+            getaddr_interval,
+            inbound_ping_interval,
         } = config;
 
         let outbox = Outbox::new(protocol_version);
@@ -525,15 +879,27 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             syncmgr::Config {
                 max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
+                stall_timeout,
                 params,
             },
             rng.clone(),
             clock.clone(),
         );
-        let pingmgr = PingManager::new(ping_timeout, rng.clone(), clock.clone());
+        let pingmgr = PingManager::new(
+            pingmgr::Config {
+                timeout: ping_timeout,
+                inbound_interval: inbound_ping_interval,
+                jitter: ping_jitter,
+                ..pingmgr::Config::default()
+            },
+            rng.clone(),
+            clock.clone(),
+        );
         let cbfmgr = FilterManager::new(
             cbfmgr::Config {
                 filter_cache_size: limits.filter_cache_size,
+                defer_scan_during_ibd,
+                stall_timeout,
                 ..cbfmgr::Config::default()
             },
             rng.clone(),
@@ -550,10 +916,17 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                 max_inbound_peers: limits.max_inbound_peers,
                 retry_max_wait: LocalDuration::from_mins(60),
                 retry_min_wait: LocalDuration::from_secs(1),
+                retry_jitter: peermgr::RETRY_JITTER,
                 required_services,
                 preferred_services: syncmgr::REQUIRED_SERVICES | bfmgr::REQUIRED_SERVICES,
                 services,
                 user_agent,
+                ban_threshold: peermgr::BAN_THRESHOLD,
+                ban_duration: peermgr::BAN_DURATION,
+                ban_score_decay: peermgr::BAN_SCORE_DECAY,
+                handshake_timeout,
+                max_outbound_per_group: peermgr::MAX_OUTBOUND_PER_GROUP,
+                eviction_latency: limits.eviction_latency,
             },
             rng.clone(),
             hooks.clone(),
@@ -563,12 +936,23 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             addrmgr::Config {
                 required_services,
                 domains,
+                getaddr_interval,
+                getaddr_response_interval: addrmgr::GETADDR_RESPONSE_INTERVAL,
             },
             rng.clone(),
             peers,
             clock.clone(),
         );
-        let invmgr = InventoryManager::new(rng.clone(), clock.clone());
+        let invmgr = InventoryManager::new(
+            invmgr::Config {
+                announce_window,
+                block_cache_size: invmgr::BLOCK_CACHE_SIZE,
+                block_order,
+                tx_status_batch,
+            },
+            rng.clone(),
+            clock.clone(),
+        );
 
         let bfmgr = BloomManager::new(rng, clock.clone(), bloom_segments);
 
@@ -583,9 +967,13 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             bfmgr,
             peermgr,
             invmgr,
+            reputation: HashMap::default(),
             last_tick: LocalTime::default(),
+            bandwidth: Bandwidth::default(),
+            last_bandwidth_report: LocalTime::default(),
             outbox,
             hooks,
+            message_mask,
         }
     }
 
@@ -594,6 +982,89 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
         self.peermgr.disconnect(addr, reason);
     }
 
+    /// Force-disconnect a connected peer.
+    ///
+    /// Unlike [`StateMachine::disconnect`], the peer is removed from all sub-managers right
+    /// away, and a [`Event::PeerDisconnected`] is emitted, without waiting for the reactor
+    /// to report the disconnection. Does nothing if the peer isn't connected.
+    pub fn disconnect_peer(&mut self, addr: PeerId, reason: DisconnectReason) {
+        if !self.peermgr.is_connected(&addr) {
+            return;
+        }
+        // Nb. The disconnect is queued before the peer is cleaned up, so that it is
+        // output ahead of the resulting events.
+        self.outbox.disconnect(addr, reason.clone());
+        traits::StateMachine::disconnected(
+            self,
+            &addr,
+            nakamoto_net::Disconnect::StateMachine(reason),
+        );
+    }
+
+    /// Re-run the watchlist against the blocks in the block cache, from the given height.
+    /// Blocks that aren't cached are downloaded. See [`FilterManager::rescan_cached`].
+    pub fn rescan_cached(&mut self, from: Height) {
+        let missing = self
+            .cbfmgr
+            .rescan_cached(from, self.invmgr.cached_blocks(), &self.tree);
+
+        for hash in missing {
+            self.invmgr.get_block(hash);
+        }
+    }
+
+    /// Get the connected peers whose advertised height is at least `height`, ie. peers
+    /// likely to be able to serve data at that height, fastest first. Peers without a
+    /// recorded latency come last.
+    pub fn peers_with_height(&self, height: Height) -> Vec<PeerId> {
+        let mut peers = self
+            .syncmgr
+            .peers_with_height(height)
+            .map(|addr| (*addr, self.pingmgr.latency(addr)))
+            .collect::<Vec<_>>();
+
+        peers.sort_by_key(|(_, latency)| (latency.is_none(), *latency));
+        peers.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Check whether a negotiated peer supports the given feature.
+    pub fn peer_supports(&self, addr: &PeerId, feature: Feature) -> bool {
+        self.peermgr.peer_supports(addr, feature)
+    }
+
+    /// Get the reputation of the outbound peers we've had sessions with. The best of
+    /// these peers, as returned by [`peer::Reputation::best_peers`], are tried first when
+    /// connecting to new peers.
+    pub fn reputation(&self) -> &impl peer::Reputation {
+        &self.reputation
+    }
+
+    /// Check whether we keep track of a peer's reputation, ie. it's an outbound peer, or
+    /// one we've had an outbound session with. Inbound peers aren't tracked, since they
+    /// can connect from any number of addresses.
+    fn is_reputation_tracked(&self, addr: &PeerId) -> bool {
+        self.reputation.contains_key(&addr.ip())
+            || self
+                .peermgr
+                .connected()
+                .any(|c| &c.addr == addr && c.link.is_outbound())
+    }
+
+    /// Called when a peer's reputation changed. Has the address manager try the peers
+    /// with the best reputation first, leaving out the ones that misbehaved.
+    fn reputation_changed(&mut self) {
+        let best = self
+            .reputation
+            .best_peers(self.peermgr.config.target_outbound_peers)
+            .into_iter()
+            .filter_map(|addr| addr.socket_addr().ok())
+            .map(|addr| addr.ip())
+            .filter(|ip| self.reputation[ip].misbehaviors == 0)
+            .collect::<Vec<_>>();
+
+        self.addrmgr.prefer(best);
+    }
+
     /// Create a draining iterator over the protocol outputs.
     pub fn drain(&mut self) -> Box<dyn Iterator<Item = Io> + '_> {
         Box::new(std::iter::from_fn(|| self.next()))
@@ -633,13 +1104,15 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Iterato
             .or_else(|| self.bfmgr.next())
             .or_else(|| self.cbfmgr.next())
             .map(|io| match io {
-                output::Io::Write(addr, payload) => Io::Write(
-                    addr,
-                    RawNetworkMessage {
+                output::Io::Write(addr, payload) => {
+                    let msg = RawNetworkMessage {
                         magic: self.network.magic(),
                         payload,
-                    },
-                ),
+                    };
+                    self.bandwidth.sent(&msg);
+
+                    Io::Write(addr, msg)
+                }
                 output::Io::Connect(addr) => Io::Connect(addr),
                 output::Io::Disconnect(addr, reason) => Io::Disconnect(addr, reason),
                 output::Io::SetTimer(t) => Io::SetTimer(t),
@@ -648,8 +1121,16 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Iterato
 
         match next {
             Some(Io::Event(e)) => {
-                self.event(e.clone());
+                // Nb. Received messages are propagated internally as they arrive, and are
+                // only published here.
+                if !matches!(e, Event::MessageReceived { .. }) {
+                    self.event(e.clone());
+                }
 
+                (self.hooks.on_event)(TimedEvent {
+                    at: self.clock.local_time(),
+                    event: e.clone(),
+                });
                 Some(Io::Event(e))
             }
             other => other,
@@ -660,6 +1141,12 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Iterato
 impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMachine<T, F, P, C> {
     /// Propagate an event internally to the sub-systems.
     pub fn event(&mut self, e: Event) {
+        if let Event::PeerMisbehaved { addr, .. } = &e {
+            if self.is_reputation_tracked(addr) {
+                self.reputation.record_misbehavior(*addr);
+                self.reputation_changed();
+            }
+        }
         self.cbfmgr
             .received_event(e.clone(), &self.tree, &mut self.invmgr);
         self.pingmgr.received_event(e.clone(), &self.tree);
@@ -697,6 +1184,9 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
 
                 reply.send(peers).ok();
             }
+            Command::GetPeerMetrics(reply) => {
+                reply.send(self.pingmgr.metrics()).ok();
+            }
             Command::Connect(addr) => {
                 self.peermgr.whitelist(addr);
                 self.peermgr.connect(&addr);
@@ -704,6 +1194,9 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             Command::Disconnect(addr) => {
                 self.peermgr.disconnect(addr, DisconnectReason::Command);
             }
+            Command::DisconnectPeer(addr, reason) => {
+                self.disconnect_peer(addr, reason);
+            }
             Command::Broadcast(msg, predicate, reply) => {
                 let peers = self.broadcast(msg, |p| predicate(p.clone()));
                 reply.send(peers).ok();
@@ -744,20 +1237,25 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                 self.invmgr.get_block(hash);
             }
             Command::SubmitTransaction(tx, reply) => {
-                // Update local watchlist to track submitted transactions.
-                //
-                // Nb. This is currently non-optimal, as the cfilter matching is based on the
-                // output scripts. This may trigger false-positives, since the same
-                // invoice (address) can be re-used by multiple transactions, ie. outputs
-                // can figure in more than one block.
-                self.cbfmgr.watch_transaction(&tx);
-
-                let peers = self.invmgr.announce(tx);
-
-                if let Some(peers) = NonEmpty::from_vec(peers) {
-                    reply.send(Ok(peers)).ok();
-                } else {
-                    reply.send(Err(CommandError::NotConnected)).ok();
+                match self.invmgr.announce(tx.clone()) {
+                    Ok(peers) => {
+                        // Update local watchlist to track submitted transactions.
+                        //
+                        // Nb. This is currently non-optimal, as the cfilter matching is based
+                        // on the output scripts. This may trigger false-positives, since the
+                        // same invoice (address) can be re-used by multiple transactions, ie.
+                        // outputs can figure in more than one block.
+                        self.cbfmgr.watch_transaction(&tx);
+
+                        if let Some(peers) = NonEmpty::from_vec(peers) {
+                            reply.send(Ok(peers)).ok();
+                        } else {
+                            reply.send(Err(CommandError::NotConnected)).ok();
+                        }
+                    }
+                    Err(err) => {
+                        reply.send(Err(err.into())).ok();
+                    }
                 }
             }
             Command::Rescan { from, to, watch } => {
@@ -769,15 +1267,58 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             Command::MerkleBlockRescan { from, to } => {
                 self.bfmgr.merkle_scan(from, to, &self.tree);
             }
+            Command::ResumeMerkleBlockRescan {
+                checkpoint,
+                to,
+                peer,
+            } => {
+                self.bfmgr
+                    .resume_merkle_scan(checkpoint, to, peer, &self.tree);
+            }
             Command::Watch { watch } => {
                 self.cbfmgr.watch(watch);
             }
+            Command::WatchScript {
+                script,
+                rescan_from,
+            } => {
+                for (_, hash) in self.cbfmgr.watch_script(script, rescan_from, &self.tree) {
+                    self.invmgr.get_block(hash);
+                }
+            }
+            Command::UnwatchScript(script) => {
+                self.cbfmgr.unwatch_script(&script);
+            }
+            Command::WatchOutpoint(outpoint) => {
+                self.cbfmgr.watch_outpoint(outpoint);
+            }
+            Command::RescanCached(from) => {
+                self.rescan_cached(from);
+            }
             Command::GetSubmittedTransaction(ref txid, reply) => {
                 let tx = self.invmgr.get_submitted_tx(txid);
                 reply.send(tx).ok();
             }
             Command::LoadBloomFilter(filter, addr) => self.bfmgr.send_bloom_filter(addr, filter),
+            Command::AddToBloomFilter(data, addr) => {
+                if let Err(err) = self.bfmgr.add_to_bloom_filter(addr, data) {
+                    warn!(target: "p2p", "Couldn't update bloom filter: {err}");
+                    self.outbox.error(ErrorKind::Protocol, err);
+                }
+            }
+            Command::GetBloomFilter(addr, reply) => {
+                let filter = self.bfmgr.loaded_filter(&addr).cloned();
+                reply.send(filter).ok();
+            }
             Command::GetMempool => self.bfmgr.get_mempool(),
+            Command::WatchMempool(watch) => {
+                self.invmgr.watch_mempool();
+                self.bfmgr.watch_mempool(watch);
+            }
+            Command::UnwatchMempool => {
+                self.invmgr.unwatch_mempool();
+                self.bfmgr.unwatch_mempool();
+            }
         }
     }
 }
@@ -797,6 +1338,8 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.peermgr.initialize(&mut self.addrmgr);
         self.cbfmgr.initialize(&self.tree);
         self.bfmgr.initialize(&self.tree);
+        self.last_bandwidth_report = time;
+        self.outbox.set_timer(BANDWIDTH_REPORT_INTERVAL);
         self.outbox.event(Event::Ready {
             tip: self.tree.height(),
             filter_tip: self.cbfmgr.filters.height(),
@@ -809,6 +1352,8 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         let addr = *addr;
         let msg = msg.into_owned();
 
+        self.bandwidth.received(&msg);
+
         if msg.magic != self.network.magic() {
             return self
                 .peermgr
@@ -822,6 +1367,17 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
 
         // debug!(target: "p2p", "Received {:?} from {}", cmd, addr);
 
+        if let Err(reason) = check_message_size(&msg.payload) {
+            debug!(target: "p2p", "Received {:?} over the size limit from {}", cmd, addr);
+
+            self.outbox.event(Event::PeerMisbehaved {
+                addr,
+                reason,
+                severity: Severity::Severe,
+            });
+            return;
+        }
+
         if let Err(err) = (self.hooks.on_message)(addr, &msg.payload, &self.outbox) {
             debug!(
                 target: "p2p",
@@ -831,11 +1387,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
             return;
         }
 
-        // Nb. We only send this message internally, hence we don't
-        // push it to our outbox.
+        let message = Arc::new(msg.payload);
+
+        // Nb. Messages are only published if selected by the message mask, since most
+        // consumers aren't interested in the high volume of gossip.
+        if self.message_mask.contains(&message) {
+            self.outbox.event(Event::MessageReceived {
+                from: addr,
+                message: message.clone(),
+            });
+        }
         self.event(Event::MessageReceived {
             from: addr,
-            message: Arc::new(msg.payload),
+            message,
         });
     }
 
@@ -844,6 +1408,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
     }
 
     fn connected(&mut self, addr: net::SocketAddr, local_addr: &net::SocketAddr, link: Link) {
+        // When at the inbound limit, make room for the new peer by evicting the slowest
+        // peer, provided it's slower than the eviction threshold.
+        if link.is_inbound()
+            && self.peermgr.is_inbound_full()
+            && !self
+                .pingmgr
+                .stale_peers(self.peermgr.config.eviction_latency)
+                .is_empty()
+        {
+            if let Some(evicted) = self.pingmgr.disconnect_slowest() {
+                self.peermgr.peer_disconnecting(evicted);
+            }
+        }
         self.peermgr
             .peer_connected(addr, *local_addr, link, self.tree.height());
     }
@@ -853,6 +1430,20 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         addr: &net::SocketAddr,
         reason: nakamoto_net::Disconnect<DisconnectReason>,
     ) {
+        // Record the session while its latency and duration are still known.
+        let session = self
+            .peermgr
+            .peers()
+            .find(|(_, c)| &c.addr == addr && c.link.is_outbound())
+            .map(|(info, conn)| (info.services, self.clock.local_time() - conn.since));
+
+        if let Some((services, uptime)) = session {
+            let latency = self.pingmgr.latency(addr);
+
+            self.reputation
+                .record_session(*addr, services, uptime, latency);
+            self.reputation_changed();
+        }
         self.peermgr
             .peer_disconnected(addr, &mut self.addrmgr, reason);
     }
@@ -863,6 +1454,29 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.clock.set(local_time);
     }
 
+    fn shutdown(&mut self) {
+        debug!(target: "p2p", "Shutting down..");
+
+        self.outbox.event(Event::ShuttingDown);
+
+        // Queue everything that is still pending, eg. `pong`s and transaction broadcasts,
+        // so that it can be flushed before the peers are disconnected.
+        let pending = self
+            .pingmgr
+            .shutdown()
+            .chain(self.syncmgr.by_ref())
+            .chain(self.invmgr.by_ref())
+            .chain(self.addrmgr.by_ref())
+            .chain(self.bfmgr.by_ref())
+            .chain(self.cbfmgr.by_ref())
+            .collect::<Vec<_>>();
+
+        for io in pending.into_iter().chain(self.peermgr.shutdown()) {
+            self.outbox.push(io);
+        }
+        self.addrmgr.shutdown();
+    }
+
     fn timer_expired(&mut self) {
         trace!("Received wake");
 
@@ -874,6 +1488,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.cbfmgr.timer_expired(&self.tree);
         self.bfmgr.timer_expired(&self.tree);
 
+        let now = self.clock.local_time();
+        if now - self.last_bandwidth_report >= BANDWIDTH_REPORT_INTERVAL {
+            let (sent, received) = self.bandwidth.reset();
+
+            self.outbox.event(Event::BandwidthReport {
+                sent,
+                received,
+                period: now - self.last_bandwidth_report,
+            });
+            self.outbox.set_timer(BANDWIDTH_REPORT_INTERVAL);
+            self.last_bandwidth_report = now;
+        }
+
         #[cfg(not(test))]
         let local_time = self.clock.local_time();
         #[cfg(not(test))]
@@ -4,19 +4,22 @@
 #![warn(missing_docs)]
 use std::net;
 
-use nakamoto_common::bitcoin::network::address::Address;
+use nakamoto_common::bitcoin::network::address::{AddrV2, AddrV2Message, Address};
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::block::time::Clock;
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::BlockTime;
 use nakamoto_common::collections::{HashMap, HashSet};
-use nakamoto_common::p2p::peer::{AddressSource, KnownAddress, Source, Store};
+use nakamoto_common::p2p::onion::OnionAddr;
+use nakamoto_common::p2p::peer::{
+    AddressSource, KnownAddress, KnownOnion, PeerAddr, Source, Store,
+};
 use nakamoto_common::p2p::Domain;
 use nakamoto_net::Disconnect;
 
 use super::output::{Io, Outbox};
-use super::{Event, Link};
+use super::{ErrorKind, Event, Link, Severity};
 
 /// Time to wait until a request times out.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -27,8 +30,17 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 /// Sample timeout. How long before a sampled address can be returned again.
 pub const SAMPLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(3);
 
+/// Minimum time between two `getaddr` requests to the same peer.
+pub const GETADDR_INTERVAL: LocalDuration = LocalDuration::from_mins(10);
+
+/// Minimum time between two responses to `getaddr` requests from the same peer.
+pub const GETADDR_RESPONSE_INTERVAL: LocalDuration = LocalDuration::from_mins(10);
+
+/// Maximum time since an address was last active, for it to be shared with peers.
+pub const MAX_SHARED_ADDR_AGE: LocalDuration = LocalDuration::from_mins(3 * 60);
+
 /// Maximum number of addresses expected in a `addr` message.
-const MAX_ADDR_ADDRESSES: usize = 1000;
+pub const MAX_ADDR_ADDRESSES: usize = 1000;
 /// Maximum number of addresses we store for a given address range.
 const MAX_RANGE_SIZE: usize = 256;
 
@@ -76,6 +88,10 @@ pub struct Config {
     pub required_services: ServiceFlags,
     /// Communication domains we're interested in.
     pub domains: Vec<Domain>,
+    /// Minimum time between two `getaddr` requests to the same peer.
+    pub getaddr_interval: LocalDuration,
+    /// Minimum time between two responses to `getaddr` requests from the same peer.
+    pub getaddr_response_interval: LocalDuration,
 }
 
 impl Default for Config {
@@ -83,6 +99,8 @@ impl Default for Config {
         Self {
             required_services: ServiceFlags::NONE,
             domains: Domain::all(),
+            getaddr_interval: GETADDR_INTERVAL,
+            getaddr_response_interval: GETADDR_RESPONSE_INTERVAL,
         }
     }
 }
@@ -92,15 +110,26 @@ impl Default for Config {
 pub struct AddressManager<P, C> {
     /// Peer address store.
     peers: P,
-    bans: HashSet<net::IpAddr>,
+    /// Banned addresses, and the time until which they are banned, if the ban is temporary.
+    bans: HashMap<net::IpAddr, Option<LocalTime>>,
     address_ranges: HashMap<u8, HashSet<net::IpAddr>>,
     connected: HashSet<net::IpAddr>,
     sources: HashSet<net::SocketAddr>,
     local_addrs: HashSet<net::SocketAddr>,
     /// The last time we asked our peers for new addresses.
     last_request: Option<LocalTime>,
+    /// The last time we asked each peer for new addresses.
+    getaddr_requests: HashMap<net::SocketAddr, LocalTime>,
+    /// The last time we sent addresses to each peer, in response to a `getaddr`.
+    getaddr_responses: HashMap<net::SocketAddr, LocalTime>,
+    /// Peers that prefer receiving addresses in `addrv2` messages, as per BIP 155.
+    addrv2: HashSet<net::SocketAddr>,
     /// The last time we idled.
     last_idle: Option<LocalTime>,
+    /// Whether the address book changed since it was last saved to the store.
+    unsaved: bool,
+    /// Addresses sampled first, best first. See [`Self::prefer`].
+    preferred: Vec<net::IpAddr>,
     cfg: Config,
     outbox: Outbox,
     rng: fastrand::Rng,
@@ -121,6 +150,14 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
         self.idle();
     }
 
+    /// Called before shutting down. Saves addresses to the store, so that they can
+    /// be loaded on the next startup.
+    pub fn shutdown(&mut self) {
+        if let Err(err) = self.save() {
+            log::error!(target: "p2p", "Failed to save peer addresses: {err}");
+        }
+    }
+
     /// Event received.
     pub fn received_event(&mut self, event: Event) {
         match event {
@@ -145,9 +182,17 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
             Event::PeerDisconnected { addr, reason } => {
                 self.peer_disconnected(&addr, reason);
             }
+            Event::PeerBanned { addr, until, .. } => {
+                // Nb. A temporary ban never lifts a permanent one.
+                let ban = self.bans.entry(addr.ip()).or_insert(Some(until));
+                if let Some(t) = ban {
+                    *t = LocalTime::max(*t, until);
+                }
+            }
             Event::MessageReceived { from, message } => {
                 if let Some(ka) = self.peers.get_mut(&from.ip()) {
                     ka.last_active = Some(self.clock.local_time());
+                    self.unsaved = true;
                 }
                 match message.as_ref() {
                     NetworkMessage::Addr(addrs) => {
@@ -155,9 +200,15 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
                         // TODO: Tick the peer manager, because we may have new addresses to connect to.
                         // TODO: Can do this via `Event::AddressesImported`.
                     }
+                    NetworkMessage::AddrV2(addrs) => {
+                        self.received_addr_v2(from, addrs.clone());
+                    }
                     NetworkMessage::GetAddr => {
                         self.received_getaddr(&from);
                     }
+                    NetworkMessage::SendAddrV2 => {
+                        self.addrv2.insert(from);
+                    }
                     _ => {}
                 }
             }
@@ -188,23 +239,96 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
     ////////////////////////////////////////////////////////////////////////////
 
     /// Called when we receive a `getaddr` message.
+    ///
+    /// We respond with one random address per address range, out of the routable addresses
+    /// that were active recently. Each peer is only responded to once in a while.
     fn received_getaddr(&mut self, from: &net::SocketAddr) {
-        // TODO: We should only respond with peers who were last active within
-        // the last 3 hours.
+        let time = self.clock.local_time();
+
+        if let Some(last) = self.getaddr_responses.get(from) {
+            if time - *last < self.cfg.getaddr_response_interval {
+                return;
+            }
+        }
         let mut addrs = Vec::new();
 
-        // Include one random address per address range.
         for range in self.address_ranges.values() {
-            let ix = self.rng.usize(..range.len());
-            let ip = range.iter().nth(ix).expect("index must be present");
-            let ka = self.peers.get(ip).expect("address must exist");
+            let eligible = range
+                .iter()
+                .filter(|ip| **ip != from.ip() && is_routable(ip) && !is_local(ip))
+                .filter_map(|ip| self.peers.get(ip))
+                .filter_map(|ka| {
+                    ka.last_active
+                        .filter(|t| time - *t <= MAX_SHARED_ADDR_AGE)
+                        .map(|t| (t.block_time(), ka.addr.clone()))
+                })
+                .collect::<Vec<_>>();
+
+            if !eligible.is_empty() {
+                addrs.push(eligible[self.rng.usize(..eligible.len())].clone());
+            }
+        }
+        addrs.truncate(MAX_ADDR_ADDRESSES);
 
-            addrs.push((
-                ka.last_active.map(|t| t.block_time()).unwrap_or_default(),
-                ka.addr.clone(),
-            ));
+        // Onion addresses can only be relayed in `addrv2` messages.
+        let onions = if self.addrv2.contains(from) {
+            self.shared_onions(time, MAX_ADDR_ADDRESSES - addrs.len())
+        } else {
+            Vec::new()
+        };
+        if addrs.is_empty() && onions.is_empty() {
+            return;
+        }
+        let count = addrs.len() + onions.len();
+
+        if self.addrv2.contains(from) {
+            let addrs = addrs
+                .into_iter()
+                .filter_map(|(time, addr)| {
+                    let socket_addr = addr.socket_addr().ok()?;
+
+                    Some(AddrV2Message {
+                        time,
+                        services: addr.services,
+                        addr: match socket_addr.ip() {
+                            net::IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+                            net::IpAddr::V6(ip) => AddrV2::Ipv6(ip),
+                        },
+                        port: socket_addr.port(),
+                    })
+                })
+                .chain(onions)
+                .collect();
+            self.outbox.addr_v2(*from, addrs);
+        } else {
+            self.outbox.addr(*from, addrs);
         }
-        self.outbox.addr(*from, addrs);
+        self.getaddr_responses.insert(*from, time);
+        self.outbox.event(Event::AddressesSent { to: *from, count });
+    }
+
+    /// Sample up to `max` onion addresses that were active recently enough to be shared.
+    fn shared_onions(&self, time: LocalTime, max: usize) -> Vec<AddrV2Message> {
+        let mut onions = self
+            .peers
+            .onions()
+            .filter_map(|(onion, ko)| {
+                let last_active = ko
+                    .last_active
+                    .filter(|t| time - *t <= MAX_SHARED_ADDR_AGE)?;
+
+                Some(AddrV2Message {
+                    time: last_active.block_time(),
+                    services: ko.services,
+                    addr: AddrV2::TorV3(*onion.pubkey()),
+                    port: ko.port,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.rng.shuffle(&mut onions);
+        onions.truncate(max);
+        onions
     }
 
     /// Return an iterator over randomly sampled addresses.
@@ -214,17 +338,31 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
 
     /// Get addresses from peers.
     fn get_addresses(&mut self) {
-        for peer in &self.sources {
-            self.outbox.get_addr(*peer);
+        for peer in self.sources.iter().copied().collect::<Vec<_>>() {
+            self.get_addr(peer);
         }
     }
 
+    /// Send a `getaddr` message to a peer, unless we've done so recently.
+    fn get_addr(&mut self, addr: net::SocketAddr) {
+        let time = self.clock.local_time();
+
+        if let Some(last) = self.getaddr_requests.get(&addr) {
+            if time - *last < self.cfg.getaddr_interval {
+                return;
+            }
+        }
+        self.getaddr_requests.insert(addr, time);
+        self.outbox.get_addr(addr);
+    }
+
     /// Called when a peer connection is attempted.
     fn peer_attempted(&mut self, addr: &net::SocketAddr) {
         let time = self.clock.local_time();
         // We're only interested in connection attempts for addresses we keep track of.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
             ka.last_attempt = Some(time);
+            self.unsaved = true;
         }
     }
 
@@ -252,13 +390,25 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
         // or are discovered via a DNS seed.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
             // Only ask for addresses when connecting for the first time.
-            if ka.last_success.is_none() {
-                self.outbox.get_addr(*addr);
+            let first_time = ka.last_success.is_none();
+
+            // If we've negotiated with this peer before, check whether its services changed.
+            if !first_time && ka.addr.services != services {
+                self.outbox.event(Event::PeerServicesChanged {
+                    addr: *addr,
+                    old: ka.addr.services,
+                    new: services,
+                });
             }
             // Keep track of when the last successful handshake was.
             ka.last_success = Some(time);
+            self.unsaved = true;
             ka.last_active = Some(time);
             ka.addr.services = services;
+
+            if first_time {
+                self.get_addr(*addr);
+            }
         }
     }
 
@@ -271,6 +421,9 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
         if self.connected.remove(&addr.ip()) {
             // Disconnected peers cannot be used as a source for new addresses.
             self.sources.remove(addr);
+            self.getaddr_requests.remove(addr);
+            self.getaddr_responses.remove(addr);
+            self.addrv2.remove(addr);
 
             // If the reason for disconnecting the peer suggests that we shouldn't try to
             // connect to this peer again, then remove the peer from the address book.
@@ -287,13 +440,27 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
     }
 
     fn idle(&mut self) {
-        // If it's been a while, save addresses to store.
-        if let Err(err) = self.peers.flush() {
-            self.outbox.error(err);
+        let now = self.clock.local_time();
+
+        // If the address book changed since we last saved it, save it to the store.
+        if let Err(err) = self.save() {
+            self.outbox.error(ErrorKind::Storage, err);
         }
-        self.last_idle = Some(self.clock.local_time());
+        // Lift expired bans, allowing the addresses to be learned again.
+        self.bans
+            .retain(|_, until| until.map_or(true, |until| until > now));
+        self.last_idle = Some(now);
         self.outbox.set_timer(IDLE_TIMEOUT);
     }
+
+    /// Save the address book to the store, if it changed since it was last saved.
+    fn save(&mut self) -> std::io::Result<()> {
+        if self.unsaved {
+            self.peers.flush()?;
+            self.unsaved = false;
+        }
+        Ok(())
+    }
 }
 
 impl<P: Store, C: Clock> AddressManager<P, C> {
@@ -303,13 +470,18 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
         let mut addrmgr = Self {
             cfg,
             peers,
-            bans: HashSet::with_hasher(rng.clone().into()),
+            bans: HashMap::with_hasher(rng.clone().into()),
             address_ranges: HashMap::with_hasher(rng.clone().into()),
             connected: HashSet::with_hasher(rng.clone().into()),
             sources: HashSet::with_hasher(rng.clone().into()),
             local_addrs: HashSet::with_hasher(rng.clone().into()),
             last_request: None,
+            getaddr_requests: HashMap::with_hasher(rng.clone().into()),
+            getaddr_responses: HashMap::with_hasher(rng.clone().into()),
+            addrv2: HashSet::with_hasher(rng.clone().into()),
             last_idle: None,
+            unsaved: false,
+            preferred: Vec::new(),
             outbox: Outbox::default(),
             rng,
             clock,
@@ -340,13 +512,109 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
 
     /// Called when we received an `addr` message from a peer.
     pub fn received_addr(&mut self, peer: net::SocketAddr, addrs: Vec<(BlockTime, Address)>) {
-        if addrs.is_empty() || addrs.len() > MAX_ADDR_ADDRESSES {
-            // Peer misbehaving, got empty message or too many addresses.
+        if addrs.len() > MAX_ADDR_ADDRESSES {
+            // Stop asking this peer for addresses, since it's flooding us.
+            self.sources.remove(&peer);
+            self.outbox.event(Event::PeerMisbehaved {
+                addr: peer,
+                reason: "oversized `addr` message",
+                severity: Severity::Severe,
+            });
+            return;
+        }
+        if addrs.is_empty() {
+            // Harmless, but useless: there's nothing to record.
+            log::debug!(target: "p2p", "Received empty `addr` message from {peer}");
             return;
         }
+        self.outbox.event(Event::AddressesReceived {
+            from: peer,
+            count: addrs.len(),
+        });
         self.insert(addrs.into_iter(), Source::Peer(peer));
     }
 
+    /// Called when we received an `addrv2` message from a peer. IP addresses are handled
+    /// like those of `addr` messages, while onion addresses are stored apart, and only
+    /// relayed to peers that accept `addrv2` messages.
+    ///
+    /// Nb. Onion addresses are never connected to, since peers are identified by socket
+    /// address, and connections aren't routed through a Tor proxy.
+    pub fn received_addr_v2(&mut self, peer: net::SocketAddr, addrs: Vec<AddrV2Message>) {
+        if addrs.len() > MAX_ADDR_ADDRESSES {
+            // Stop asking this peer for addresses, since it's flooding us.
+            self.sources.remove(&peer);
+            self.outbox.event(Event::PeerMisbehaved {
+                addr: peer,
+                reason: "oversized `addrv2` message",
+                severity: Severity::Severe,
+            });
+            return;
+        }
+        if addrs.is_empty() {
+            log::debug!(target: "p2p", "Received empty `addrv2` message from {peer}");
+            return;
+        }
+        self.outbox.event(Event::AddressesReceived {
+            from: peer,
+            count: addrs.len(),
+        });
+
+        let mut ips = Vec::with_capacity(addrs.len());
+        for msg in addrs {
+            match PeerAddr::from_addr_v2(&msg) {
+                Some(PeerAddr::Ip(addr)) => {
+                    ips.push((msg.time, Address::new(&addr, msg.services)));
+                }
+                Some(PeerAddr::Onion(onion, port)) => {
+                    self.insert_onion(onion, port, msg.time, msg.services, Source::Peer(peer));
+                }
+                // Ignore addresses from networks we don't know about.
+                None => {}
+            }
+        }
+        self.insert(ips, Source::Peer(peer));
+    }
+
+    /// Add an onion address to the address manager.
+    ///
+    /// Onion addresses are subject to the same service and time checks as IP addresses.
+    pub fn insert_onion(
+        &mut self,
+        onion: OnionAddr,
+        port: u16,
+        last_active: BlockTime,
+        services: ServiceFlags,
+        source: Source,
+    ) {
+        let time = self
+            .last_idle
+            .expect("AddressManager::insert_onion: manager must be initialized before inserting");
+
+        if !services.has(self.cfg.required_services)
+            || services.has(ServiceFlags::GETUTXO)
+            || services.has(ServiceFlags::BLOOM)
+        {
+            return;
+        }
+        if last_active == 0
+            || LocalTime::from_block_time(last_active) > time + LocalDuration::from_mins(60)
+        {
+            return;
+        }
+        if self.peers.insert_onion(
+            onion,
+            KnownOnion::new(
+                port,
+                services,
+                source,
+                Some(LocalTime::from_block_time(last_active)),
+            ),
+        ) {
+            self.unsaved = true;
+        }
+    }
+
     /// Add addresses to the address manager. The input matches that of the `addr` message
     /// sent by peers on the network.
     pub fn insert(
@@ -388,7 +656,7 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
                 continue;
             }
             // No banned addresses.
-            if self.bans.contains(&ip) {
+            if self.is_banned(&ip) {
                 continue;
             }
 
@@ -417,7 +685,7 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
             {
                 continue;
             }
-
+            self.unsaved = true;
             self.populate_address_ranges(&net_addr.ip());
         }
     }
@@ -467,7 +735,20 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
         let time = self
             .last_idle
             .expect("AddressManager::sample: manager must be initialized before sampling");
-        let domains = &self.cfg.domains;
+
+        // Preferred addresses, eg. of peers with a good reputation, are tried first.
+        for i in 0..self.preferred.len() {
+            let ip = self.preferred[i];
+
+            if self.is_sampleable(&ip, time, &predicate) {
+                let ka = self.peers.get_mut(&ip).expect("address must exist");
+
+                ka.last_sampled = Some(time);
+                self.unsaved = true;
+
+                return Some((ka.addr.clone(), ka.source));
+            }
+        }
 
         let mut ranges: Vec<_> = self.address_ranges.values().collect();
         self.rng.shuffle(&mut ranges);
@@ -481,36 +762,14 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
 
             // Then select a random address in that range.
             for ip in ips.drain(..) {
-                let ka = self.peers.get_mut(ip).expect("address must exist");
-
-                // If the address domain is unsupported, skip it.
-                // Nb. this currently skips Tor addresses too.
-                if !ka
-                    .addr
-                    .socket_addr()
-                    .map_or(false, |a| domains.contains(&Domain::for_address(&a)))
-                {
-                    continue;
-                }
-
-                // If the address was already attempted unsuccessfully, skip it.
-                if ka.last_attempt.is_some() && ka.last_success.is_none() {
-                    continue;
-                }
-                // If we recently sampled this address, don't return it again.
-                if time - ka.last_sampled.unwrap_or_default() < SAMPLE_TIMEOUT {
-                    continue;
-                }
-                // If we're already connected to this address, skip it.
-                if self.connected.contains(ip) {
-                    continue;
-                }
-                // If the provided filter doesn't pass, keep looking.
-                if !predicate(ka) {
+                if !self.is_sampleable(ip, time, &predicate) {
                     continue;
                 }
                 // Ok, we've found a worthy address!
+                let ka = self.peers.get_mut(ip).expect("address must exist");
+
                 ka.last_sampled = Some(time);
+                self.unsaved = true;
 
                 return Some((ka.addr.clone(), ka.source));
             }
@@ -519,8 +778,53 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
         None
     }
 
+    /// Set the addresses to try first when sampling, best first, eg. the addresses of the
+    /// peers with the best reputation. Addresses that aren't known are ignored.
+    pub fn prefer(&mut self, addrs: impl IntoIterator<Item = net::IpAddr>) {
+        self.preferred = addrs.into_iter().collect();
+    }
+
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Check whether a known address can be returned by [`Self::sample_with`], sampling
+    /// at the given time.
+    fn is_sampleable(
+        &self,
+        ip: &net::IpAddr,
+        time: LocalTime,
+        predicate: impl Fn(&KnownAddress) -> bool,
+    ) -> bool {
+        let Some(ka) = self.peers.get(ip) else {
+            return false;
+        };
+
+        // If the address domain is unsupported, skip it.
+        // Nb. this currently skips Tor addresses too.
+        if !ka.addr.socket_addr().map_or(false, |a| {
+            self.cfg.domains.contains(&Domain::for_address(&a))
+        }) {
+            return false;
+        }
+        // If the address was already attempted unsuccessfully, skip it.
+        if ka.last_attempt.is_some() && ka.last_success.is_none() {
+            return false;
+        }
+        // If we recently sampled this address, don't return it again.
+        if time - ka.last_sampled.unwrap_or_default() < SAMPLE_TIMEOUT {
+            return false;
+        }
+        // If we're already connected to this address, skip it.
+        if self.connected.contains(ip) {
+            return false;
+        }
+        // If the address is banned, skip it until the ban expires.
+        if self.is_banned(ip) {
+            return false;
+        }
+        // If the provided filter doesn't pass, keep looking.
+        predicate(ka)
+    }
+
     /// Populate address ranges with an IP. This may remove an existing IP if
     /// its range is full. Returns the range key that was used.
     fn populate_address_ranges(&mut self, ip: &net::IpAddr) -> u8 {
@@ -543,12 +847,22 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
 
             range.remove(&addr);
             self.peers.remove(&addr);
+            self.unsaved = true;
         }
         range.insert(*ip);
 
         key
     }
 
+    /// Check whether an address is banned.
+    fn is_banned(&self, ip: &net::IpAddr) -> bool {
+        let now = self.clock.local_time();
+
+        self.bans
+            .get(ip)
+            .map_or(false, |until| until.map_or(true, |until| until > now))
+    }
+
     /// Remove an address from the address book and prevent it from being sampled again.
     fn ban(&mut self, addr: &net::IpAddr) -> bool {
         debug_assert!(!self.connected.contains(addr));
@@ -560,7 +874,10 @@ impl<P: Store, C: Clock> AddressManager<P, C> {
 
             // TODO: Persist bans.
             self.peers.remove(addr);
-            self.bans.insert(*addr);
+            // Nb. If the address is temporarily banned, eg. because its ban score
+            // crossed the threshold, the ban is left as-is, so that it can expire.
+            self.bans.entry(*addr).or_insert(None);
+            self.unsaved = true;
 
             if range.is_empty() {
                 self.address_ranges.remove(&key);
@@ -656,10 +973,13 @@ fn ipv6_is_routable(_addr: &net::Ipv6Addr) -> bool {
 mod tests {
     use super::*;
     use crate::fsm;
+    use crate::fsm::output;
     use std::collections::HashMap;
     use std::iter;
+    use std::sync::Arc;
 
     use nakamoto_common::block::time::RefClock;
+    use nakamoto_test::assert_matches;
     use quickcheck::TestResult;
     use quickcheck_macros::quickcheck;
 
@@ -825,6 +1145,544 @@ mod tests {
         assert!(addrmgr.sample(services).is_none());
     }
 
+    #[test]
+    fn test_peer_banned() {
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            clock.clone(),
+        );
+        let source = Source::Dns;
+        let services = ServiceFlags::NETWORK;
+        let addr: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [(clock.block_time(), Address::new(&addr, services))],
+            source,
+        );
+        assert!(addrmgr.sample(services).is_some());
+
+        addrmgr.peer_attempted(&addr);
+        addrmgr.peer_connected(&addr);
+        addrmgr.peer_negotiated(&addr, services, Link::Outbound);
+        addrmgr.received_event(Event::PeerBanned {
+            addr,
+            score: 100,
+            until: clock.local_time() + fsm::peermgr::BAN_DURATION,
+        });
+        addrmgr.peer_disconnected(&addr, fsm::DisconnectReason::PeerBanned.into());
+
+        // The address is removed, and can't be learned again while the ban lasts.
+        clock.elapse(LocalDuration::from_mins(60));
+        addrmgr.timer_expired();
+        addrmgr.insert(
+            [(clock.block_time(), Address::new(&addr, services))],
+            source,
+        );
+        assert!(addrmgr.sample(services).is_none());
+
+        clock.elapse(fsm::peermgr::BAN_DURATION);
+        addrmgr.timer_expired();
+        addrmgr.insert(
+            [(clock.block_time(), Address::new(&addr, services))],
+            source,
+        );
+        assert_matches!(
+            addrmgr.sample(services),
+            Some((a, _)) if a.socket_addr().ok() == Some(addr)
+        );
+    }
+
+    #[test]
+    fn test_permanent_ban() {
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            clock.clone(),
+        );
+        let source = Source::Dns;
+        let services = ServiceFlags::NETWORK;
+        let addr: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [(clock.block_time(), Address::new(&addr, services))],
+            source,
+        );
+        addrmgr.peer_attempted(&addr);
+        addrmgr.peer_connected(&addr);
+        addrmgr.peer_negotiated(&addr, services, Link::Outbound);
+        addrmgr.peer_disconnected(&addr, fsm::DisconnectReason::PeerMagic(0).into());
+
+        // A temporary ban on a permanently banned address doesn't lift the permanent ban
+        // when it expires.
+        addrmgr.received_event(Event::PeerBanned {
+            addr,
+            score: 100,
+            until: clock.local_time() + fsm::peermgr::BAN_DURATION,
+        });
+        clock.elapse(fsm::peermgr::BAN_DURATION + LocalDuration::from_mins(60));
+        addrmgr.timer_expired();
+        addrmgr.insert(
+            [(clock.block_time(), Address::new(&addr, services))],
+            source,
+        );
+        assert!(addrmgr.sample(services).is_none());
+        assert_eq!(addrmgr.bans.get(&addr.ip()), Some(&None));
+    }
+
+    #[test]
+    fn test_peer_services_changed() {
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            clock.clone(),
+        );
+        let addr: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let old = ServiceFlags::NETWORK;
+        let new = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let services_changed = |addrmgr: &mut AddressManager<_, _>| {
+            output::test::events(addrmgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::PeerServicesChanged { addr, old, new } => Some((addr, old, new)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [(clock.block_time(), Address::new(&addr, old))],
+            Source::Dns,
+        );
+
+        addrmgr.peer_attempted(&addr);
+        addrmgr.peer_connected(&addr);
+        addrmgr.peer_negotiated(&addr, old, Link::Outbound);
+        addrmgr.peer_disconnected(&addr, fsm::DisconnectReason::PeerTimeout("ping").into());
+        assert!(services_changed(&mut addrmgr).is_empty());
+
+        // Negotiating again with the same services doesn't emit anything.
+        addrmgr.peer_attempted(&addr);
+        addrmgr.peer_connected(&addr);
+        addrmgr.peer_negotiated(&addr, old, Link::Outbound);
+        addrmgr.peer_disconnected(&addr, fsm::DisconnectReason::PeerTimeout("ping").into());
+        assert!(services_changed(&mut addrmgr).is_empty());
+
+        // The peer started serving compact filters.
+        addrmgr.peer_attempted(&addr);
+        addrmgr.peer_connected(&addr);
+        addrmgr.peer_negotiated(&addr, new, Link::Outbound);
+        assert_eq!(services_changed(&mut addrmgr), vec![(addr, old, new)]);
+        assert_eq!(addrmgr.peers.get(&addr.ip()).unwrap().addr.services, new);
+    }
+
+    #[test]
+    fn test_getaddr_throttle() {
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            clock.clone(),
+        );
+        let addr: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let getaddrs = |addrmgr: &mut AddressManager<_, _>| {
+            output::test::messages_from(addrmgr.outbox.drain(), &addr)
+                .filter(|m| matches!(m, NetworkMessage::GetAddr))
+                .count()
+        };
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [(
+                clock.block_time(),
+                Address::new(&addr, ServiceFlags::NETWORK),
+            )],
+            Source::Dns,
+        );
+        addrmgr.peer_attempted(&addr);
+        addrmgr.peer_connected(&addr);
+        addrmgr.peer_negotiated(&addr, ServiceFlags::NETWORK, Link::Outbound);
+        assert_eq!(getaddrs(&mut addrmgr), 1);
+
+        // We asked this peer for addresses too recently.
+        clock.elapse(LocalDuration::from_mins(9));
+        addrmgr.get_addresses();
+        assert_eq!(getaddrs(&mut addrmgr), 0);
+
+        clock.elapse(LocalDuration::from_mins(1));
+        addrmgr.get_addresses();
+        assert_eq!(getaddrs(&mut addrmgr), 1);
+
+        let addrs = (1..=3)
+            .map(|i| {
+                let addr: net::SocketAddr = ([44, 44, 44, i], 8333).into();
+                (
+                    clock.block_time(),
+                    Address::new(&addr, ServiceFlags::NETWORK),
+                )
+            })
+            .collect::<Vec<_>>();
+        addrmgr.received_addr(addr, addrs.clone());
+        assert_matches!(
+            output::test::events(addrmgr.outbox.drain()).next(),
+            Some(Event::AddressesReceived { from, count: 3 }) if from == addr
+        );
+
+        // A peer flooding us with addresses is scored, and no longer asked for addresses.
+        let flood = addrs
+            .into_iter()
+            .cycle()
+            .take(MAX_ADDR_ADDRESSES + 1)
+            .collect();
+        addrmgr.received_addr(addr, flood);
+        assert_matches!(
+            output::test::events(addrmgr.outbox.drain()).next(),
+            Some(Event::PeerMisbehaved { addr: a, .. }) if a == addr
+        );
+
+        clock.elapse(GETADDR_INTERVAL);
+        addrmgr.get_addresses();
+        assert_eq!(getaddrs(&mut addrmgr), 0);
+    }
+
+    #[test]
+    fn test_received_addr_v2() {
+        /// A peer store that keeps onion addresses.
+        #[derive(Default)]
+        struct Book {
+            addrs: HashMap<net::IpAddr, KnownAddress>,
+            onions: HashMap<OnionAddr, KnownOnion>,
+        }
+
+        impl Store for Book {
+            fn get(&self, ip: &net::IpAddr) -> Option<&KnownAddress> {
+                self.addrs.get(ip)
+            }
+
+            fn get_mut(&mut self, ip: &net::IpAddr) -> Option<&mut KnownAddress> {
+                self.addrs.get_mut(ip)
+            }
+
+            fn insert(&mut self, ip: net::IpAddr, ka: KnownAddress) -> bool {
+                Store::insert(&mut self.addrs, ip, ka)
+            }
+
+            fn remove(&mut self, ip: &net::IpAddr) -> Option<KnownAddress> {
+                self.addrs.remove(ip)
+            }
+
+            fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &KnownAddress)> + 'a> {
+                Box::new(self.addrs.iter())
+            }
+
+            fn insert_onion(&mut self, onion: OnionAddr, ko: KnownOnion) -> bool {
+                self.onions.insert(onion, ko).is_none()
+            }
+
+            fn onions<'a>(&'a self) -> Box<dyn Iterator<Item = (&OnionAddr, &KnownOnion)> + 'a> {
+                Box::new(self.onions.iter())
+            }
+
+            fn len(&self) -> usize {
+                self.addrs.len()
+            }
+
+            fn clear(&mut self) {
+                self.addrs.clear();
+                self.onions.clear();
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            Book::default(),
+            clock.clone(),
+        );
+        let remote: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let onion: OnionAddr = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+            .parse()
+            .unwrap();
+        let entry = |addr: AddrV2| AddrV2Message {
+            time: clock.block_time(),
+            services: ServiceFlags::NETWORK,
+            addr,
+            port: 8333,
+        };
+
+        addrmgr.initialize();
+        addrmgr.received_addr_v2(
+            remote,
+            vec![
+                entry(AddrV2::Ipv4([44, 44, 44, 44].into())),
+                entry(AddrV2::Ipv6("2a01:4f8::1".parse().unwrap())),
+                entry(AddrV2::TorV3(*onion.pubkey())),
+                entry(AddrV2::I2p([0; 32])),
+            ],
+        );
+        assert_matches!(
+            output::test::events(addrmgr.outbox.drain()).next(),
+            Some(Event::AddressesReceived { from, count: 4 }) if from == remote
+        );
+        assert_eq!(addrmgr.len(), 2);
+        assert!(addrmgr.peers.get(&[44, 44, 44, 44].into()).is_some());
+
+        let onions = addrmgr.peers.onions().collect::<Vec<_>>();
+        assert_eq!(onions.len(), 1);
+        assert_matches!(
+            onions[0],
+            (o, KnownOnion { port: 8333, source: Source::Peer(s), .. }) if *o == onion && *s == remote
+        );
+
+        // Onion addresses are relayed to peers that accept `addrv2` messages only.
+        let (legacy, modern): (net::SocketAddr, net::SocketAddr) = (
+            ([55, 55, 55, 55], 8333).into(),
+            ([66, 66, 66, 66], 8333).into(),
+        );
+        addrmgr.addrv2.insert(modern);
+
+        for peer in [legacy, modern] {
+            addrmgr.received_getaddr(&peer);
+        }
+        let outputs = addrmgr.outbox.drain().collect::<Vec<_>>();
+
+        assert!(
+            !output::test::messages_from(outputs.clone().into_iter(), &legacy)
+                .any(|m| matches!(m, NetworkMessage::AddrV2(_)))
+        );
+        assert_matches!(
+            output::test::messages_from(outputs.into_iter(), &modern).next(),
+            Some(NetworkMessage::AddrV2(addrs))
+            if addrs.iter().any(|a| a.addr == AddrV2::TorV3(*onion.pubkey()) && a.port == 8333)
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        use std::cell::{Cell, RefCell};
+        use std::rc::Rc;
+
+        /// A peer store that only writes its addresses to "disk" when flushed.
+        struct Disk {
+            addrs: HashMap<net::IpAddr, KnownAddress>,
+            disk: Rc<RefCell<HashMap<net::IpAddr, KnownAddress>>>,
+            flushes: Rc<Cell<usize>>,
+        }
+
+        impl Disk {
+            fn open(
+                disk: &Rc<RefCell<HashMap<net::IpAddr, KnownAddress>>>,
+                flushes: &Rc<Cell<usize>>,
+            ) -> Self {
+                Self {
+                    addrs: disk.borrow().clone(),
+                    disk: disk.clone(),
+                    flushes: flushes.clone(),
+                }
+            }
+        }
+
+        impl Store for Disk {
+            fn get(&self, ip: &net::IpAddr) -> Option<&KnownAddress> {
+                self.addrs.get(ip)
+            }
+
+            fn get_mut(&mut self, ip: &net::IpAddr) -> Option<&mut KnownAddress> {
+                self.addrs.get_mut(ip)
+            }
+
+            fn insert(&mut self, ip: net::IpAddr, ka: KnownAddress) -> bool {
+                Store::insert(&mut self.addrs, ip, ka)
+            }
+
+            fn remove(&mut self, ip: &net::IpAddr) -> Option<KnownAddress> {
+                self.addrs.remove(ip)
+            }
+
+            fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &KnownAddress)> + 'a> {
+                Box::new(self.addrs.iter())
+            }
+
+            fn len(&self) -> usize {
+                self.addrs.len()
+            }
+
+            fn clear(&mut self) {
+                self.addrs.clear();
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                *self.disk.borrow_mut() = self.addrs.clone();
+                self.flushes.set(self.flushes.get() + 1);
+
+                Ok(())
+            }
+        }
+
+        let clock = RefClock::from(LocalTime::now());
+        let disk = Rc::new(RefCell::new(HashMap::new()));
+        let flushes = Rc::new(Cell::new(0));
+        let addrs = [
+            ([183, 8, 55, 2], 8333),
+            ([211, 48, 99, 4], 8333),
+            ([241, 44, 12, 5], 8333),
+        ];
+
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            Disk::open(&disk, &flushes),
+            clock.clone(),
+        );
+        addrmgr.initialize();
+        assert_eq!(
+            flushes.get(),
+            0,
+            "nothing is saved until the address book changes"
+        );
+
+        addrmgr.insert(
+            addrs.iter().map(|a| {
+                (
+                    clock.block_time(),
+                    Address::new(&(*a).into(), ServiceFlags::NONE),
+                )
+            }),
+            Source::Dns,
+        );
+        assert!(
+            disk.borrow().is_empty(),
+            "addresses aren't saved right away"
+        );
+
+        // Changes are saved periodically.
+        clock.elapse(IDLE_TIMEOUT);
+        addrmgr.timer_expired();
+        assert_eq!(flushes.get(), 1);
+        assert_eq!(disk.borrow().len(), addrs.len());
+
+        // If nothing changed, nothing is saved.
+        clock.elapse(IDLE_TIMEOUT);
+        addrmgr.timer_expired();
+        assert_eq!(flushes.get(), 1);
+
+        // Unsaved changes are saved on shutdown.
+        let (addr, _) = addrmgr.sample(ServiceFlags::NONE).unwrap();
+        let addr = addr.socket_addr().unwrap();
+        addrmgr.peer_attempted(&addr);
+        addrmgr.shutdown();
+        assert_eq!(flushes.get(), 2);
+
+        // The saved addresses are loaded on the next startup.
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            Disk::open(&disk, &flushes),
+            clock.clone(),
+        );
+        addrmgr.initialize();
+
+        assert_eq!(addrmgr.len(), addrs.len());
+        assert_eq!(
+            addrmgr.peers.get(&addr.ip()).unwrap().last_attempt,
+            Some(clock.local_time())
+        );
+        assert!(addrmgr.sample(ServiceFlags::NONE).is_some());
+    }
+
+    #[test]
+    fn test_getaddr_response() {
+        let clock = RefClock::from(LocalTime::now());
+        let time = clock.local_time();
+        let stale = time - LocalDuration::from_mins(4 * 60);
+        let remote: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let known = |ip: [u8; 4], last_active: LocalTime| {
+            let addr: net::SocketAddr = (ip, 8333).into();
+            (
+                addr.ip(),
+                KnownAddress::new(
+                    Address::new(&addr, ServiceFlags::NETWORK),
+                    Source::Dns,
+                    Some(last_active),
+                ),
+            )
+        };
+        let mut peers = (1..=5)
+            .map(|i| known([44, i, 1, 1], time))
+            .collect::<HashMap<_, _>>();
+        peers.extend([
+            known([10, 0, 0, 1], time),
+            known([127, 0, 0, 1], time),
+            known([192, 168, 1, 1], time),
+            known([45, 1, 1, 1], stale),
+        ]);
+
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            peers,
+            clock.clone(),
+        );
+        let getaddr = |addrmgr: &mut AddressManager<_, _>, message| {
+            addrmgr.received_event(Event::MessageReceived {
+                from: remote,
+                message: Arc::new(message),
+            });
+            addrmgr.outbox.drain().collect::<Vec<_>>()
+        };
+        addrmgr.initialize();
+        addrmgr.outbox.drain().for_each(drop);
+
+        let outputs = getaddr(&mut addrmgr, NetworkMessage::GetAddr);
+        let addrs = output::test::messages_from(outputs.iter().cloned(), &remote)
+            .find_map(|m| match m {
+                NetworkMessage::Addr(addrs) => Some(addrs),
+                _ => None,
+            })
+            .expect("an `addr` message is sent");
+
+        // Only routable addresses that were recently active are shared.
+        assert_eq!(addrs.len(), 5);
+        for (last_active, addr) in &addrs {
+            let ip = addr.socket_addr().unwrap().ip();
+
+            assert!(is_routable(&ip) && !is_local(&ip), "{ip} is shared");
+            assert_eq!(*last_active, time.block_time());
+        }
+        assert_matches!(
+            output::test::events(outputs.into_iter()).next(),
+            Some(Event::AddressesSent { to, count: 5 }) if to == remote
+        );
+
+        // We responded to this peer too recently.
+        clock.elapse(LocalDuration::from_mins(9));
+        assert!(getaddr(&mut addrmgr, NetworkMessage::GetAddr).is_empty());
+
+        // Peers that signaled support for BIP 155 are sent `addrv2` messages.
+        clock.elapse(LocalDuration::from_mins(1));
+        getaddr(&mut addrmgr, NetworkMessage::SendAddrV2);
+
+        let outputs = getaddr(&mut addrmgr, NetworkMessage::GetAddr);
+        assert_matches!(
+            output::test::messages_from(outputs.into_iter(), &remote).next(),
+            Some(NetworkMessage::AddrV2(addrs)) if addrs.len() == 5
+        );
+    }
+
     #[test]
     fn test_disconnect_rediscover() {
         // Check that if we re-discover an address after permanent disconnection, we still know
@@ -1028,6 +1886,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sample_preferred() {
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            clock.clone(),
+        );
+        let addrs: Vec<net::SocketAddr> = vec![
+            ([183, 8, 55, 2], 8333).into(),
+            ([211, 48, 99, 4], 8333).into(),
+            ([241, 44, 12, 5], 8333).into(),
+            ([99, 129, 2, 15], 8333).into(),
+        ];
+        let (best, good) = (addrs[2], addrs[0]);
+        let unknown: net::IpAddr = [88, 88, 88, 88].into();
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            addrs
+                .iter()
+                .map(|a| (clock.block_time(), Address::new(a, ServiceFlags::NONE))),
+            Source::Dns,
+        );
+        addrmgr.prefer([unknown, best.ip(), good.ip()]);
+
+        // Preferred addresses are sampled first, in order.
+        let sample = |addrmgr: &mut AddressManager<_, _>| {
+            addrmgr
+                .sample(ServiceFlags::NONE)
+                .and_then(|(a, _)| a.socket_addr().ok())
+        };
+        assert_eq!(sample(&mut addrmgr), Some(best));
+        assert_eq!(sample(&mut addrmgr), Some(good));
+
+        // Then, addresses are sampled at random.
+        let rest = [sample(&mut addrmgr), sample(&mut addrmgr)];
+        assert!(rest
+            .iter()
+            .all(|a| a.map_or(false, |a| a != best && a != good)));
+        assert_eq!(sample(&mut addrmgr), None);
+
+        // Preferred addresses are skipped while connected.
+        clock.elapse(SAMPLE_TIMEOUT);
+        addrmgr.timer_expired();
+        addrmgr.peer_connected(&best);
+
+        assert_eq!(sample(&mut addrmgr), Some(good));
+    }
+
     #[test]
     fn test_sample() {
         use std::collections::HashMap;
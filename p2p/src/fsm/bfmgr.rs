@@ -2,7 +2,7 @@
 // //!
 // //! Manages BIP 37 compact block filter sync.
 
-use std::collections::VecDeque;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::ops::{Bound, RangeInclusive};
 
@@ -11,19 +11,21 @@ use thiserror::Error;
 
 mod rescan;
 use super::bloom_cache::FilterCache;
+use super::event::TxStatus;
 use super::output::{Io, Outbox};
-use super::Event;
-use super::{DisconnectReason, Link, Locators, PeerId};
+use super::{scan_range, DisconnectReason, Feature, Link, Locators, PeerId};
+use super::{ErrorKind, Event, Severity};
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
 use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
-use nakamoto_common::bitcoin::{MerkleBlock, Txid};
+use nakamoto_common::bitcoin::{MerkleBlock, Script, Transaction, Txid};
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::{BlockReader, BlockTree};
 use nakamoto_common::block::{BlockHash, Height};
 use nakamoto_common::bloom::store::cache::PrivacySegment;
+use nakamoto_common::bloom::{BloomFilter, MAX_FILTER_ADD_SIZE};
 use nakamoto_common::collections::{AddressBook, HashMap};
 use nakamoto_common::source;
 use rescan::Rescan;
@@ -36,6 +38,10 @@ pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::BLOOM;
 /// Filter cache capacity in bytes.
 pub const DEFAULT_FILTER_CACHE_SIZE: usize = 1024 * 1024 * 4; // 1 MB.
+/// Maximum number of merkle blocks held back during a rescan, waiting for lower blocks.
+pub const MAX_REORDER_BUFFER: usize = 128;
+/// How many merkle blocks to process between [`Event::MerkleBlockRescanProgress`] events.
+pub const RESCAN_PROGRESS_INTERVAL: Height = 1000;
 
 /// State of a bloom filter peer.
 #[derive(Debug, Clone)]
@@ -70,6 +76,101 @@ struct GetBlocks {
     on_timeout: OnTimeout,
 }
 
+/// A merkle block ready to be reported, with its height and matched transactions.
+type ReadyBlock = (Height, MerkleBlock, Vec<Txid>);
+
+/// Holds back merkle blocks received out of order during a rescan, so that they are
+/// reported in ascending height.
+#[derive(Debug)]
+struct ReorderBuffer {
+    /// Height of the next block to report.
+    next: Height,
+    /// Blocks received ahead of the next height.
+    pending: BTreeMap<Height, (MerkleBlock, Vec<Txid>)>,
+    /// Maximum number of blocks held back.
+    capacity: usize,
+}
+
+impl ReorderBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            next: 0,
+            pending: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    /// Start reordering from the given height. Returns the blocks that were still held back.
+    fn reset(&mut self, next: Height) -> Vec<ReadyBlock> {
+        let drained = self.drain();
+        self.next = next;
+
+        drained
+    }
+
+    /// Release all held back blocks in ascending height, regardless of gaps.
+    fn drain(&mut self) -> Vec<ReadyBlock> {
+        let pending = std::mem::take(&mut self.pending);
+
+        if let Some(height) = pending.keys().last() {
+            self.next = self.next.max(height + 1);
+        }
+        pending
+            .into_iter()
+            .map(|(height, (merkle_block, txids))| (height, merkle_block, txids))
+            .collect()
+    }
+
+    /// Insert a received block. Returns the blocks that can be reported, in ascending height.
+    ///
+    /// If the buffer is full, the lowest block is released even though blocks below it
+    /// are still missing.
+    fn insert(
+        &mut self,
+        height: Height,
+        merkle_block: MerkleBlock,
+        txids: Vec<Txid>,
+    ) -> Vec<ReadyBlock> {
+        // Blocks below the next height can't be reordered anymore.
+        if height < self.next {
+            return vec![(height, merkle_block, txids)];
+        }
+        let mut ready = Vec::new();
+
+        self.pending.insert(height, (merkle_block, txids));
+
+        if self.pending.len() > self.capacity {
+            if let Some((lowest, (merkle_block, txids))) = self.pending.pop_first() {
+                log::warn!(
+                    target: "p2p",
+                    "Merkle block reorder buffer is full, releasing block at height {} \
+                     while waiting on height {}",
+                    lowest,
+                    self.next
+                );
+                self.next = lowest + 1;
+                ready.push((lowest, merkle_block, txids));
+            }
+        }
+        while let Some((merkle_block, txids)) = self.pending.remove(&self.next) {
+            ready.push((self.next, merkle_block, txids));
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+/// An error from attempting to add an element to a loaded bloom filter.
+#[derive(Error, Debug)]
+pub enum FilterAddError {
+    /// The element is larger than what the protocol allows.
+    #[error("filter element of {0} bytes exceeds the maximum of {MAX_FILTER_ADD_SIZE} bytes")]
+    ElementTooLarge(usize),
+    /// No bloom filter was loaded on the peer.
+    #[error("no bloom filter loaded on peer {0}")]
+    NotLoaded(PeerId),
+}
+
 /// An error from attempting to get compact filters.
 #[derive(Error, Debug)]
 pub enum GetMerkleBlocksError {
@@ -120,8 +221,17 @@ pub struct BloomManager<C> {
     blocks_inflight: HashMap<PeerId, GetBlocks>,
     /// How long to wait for a response from a peer.
     request_timeout: LocalDuration,
-    /// transactions matched
-    matches: VecDeque<Txid>,
+    /// Transactions matched by merkle blocks, and the height of the matching block.
+    matches: HashMap<Txid, Height>,
+    /// Last time a matched merkle block or transaction was received, while the rescan
+    /// waits on transactions to inspect.
+    last_inspected: Option<LocalTime>,
+    /// Bloom filters loaded on peers, as we expect them to be on the remote side.
+    loaded: HashMap<PeerId, BloomFilter>,
+    /// Whether unconfirmed transactions relayed by peers are matched against the watchlist.
+    mempool_watch: bool,
+    /// Merkle blocks received out of order during a rescan.
+    reorder: ReorderBuffer,
 }
 
 impl<C> Iterator for BloomManager<C> {
@@ -136,8 +246,9 @@ impl<C: Clock> BloomManager<C> {
     pub fn new(rng: fastrand::Rng, clock: C, bloom_segments: HashMap<u32, PrivacySegment>) -> Self {
         let peers = AddressBook::new(rng.clone());
         let rescan = Rescan::new(DEFAULT_FILTER_CACHE_SIZE);
-        let blocks_inflight = HashMap::with_hasher(rng.into());
-        let matches: VecDeque<Txid> = VecDeque::new();
+        let blocks_inflight = HashMap::with_hasher(rng.clone().into());
+        let matches = HashMap::with_hasher(rng.clone().into());
+        let loaded = HashMap::with_hasher(rng.into());
         Self {
             bloom_segments,
             rescan,
@@ -148,6 +259,10 @@ impl<C: Clock> BloomManager<C> {
             blocks_inflight,
             request_timeout: REQUEST_TIMEOUT,
             matches,
+            last_inspected: None,
+            loaded,
+            mempool_watch: false,
+            reorder: ReorderBuffer::new(MAX_REORDER_BUFFER),
         }
     }
     pub fn idle<T: BlockReader>(&mut self, tree: &T) {
@@ -171,8 +286,17 @@ impl<C: Clock> BloomManager<C> {
                 link,
                 services,
                 height,
+                version,
                 ..
             } => {
+                if link.is_outbound() && !Feature::BloomFilters.is_supported(version, services) {
+                    log::debug!(
+                        target: "p2p",
+                        "Peer {} doesn't support bloom filters (version = {}, services = {})",
+                        addr, version, services
+                    );
+                    return;
+                }
                 self.peer_negotiated(addr, height, services, link, tree);
                 // if let Some(ps) = self.bloom_segments.get_mut(&0) {
                 //     let filter = ps.filter.clone();
@@ -212,6 +336,11 @@ impl<C: Clock> BloomManager<C> {
             }
             Event::PeerDisconnected { addr, .. } => {
                 self.unregister(&addr);
+
+                // The matched transactions won't be sent by a peer that is gone.
+                if self.rescan.peer == Some(addr) {
+                    self.abandon_inspection();
+                }
             }
             Event::PeerLoadedBloomFilter { .. } => {
                 // self.send_bloom_filter(filter);
@@ -227,11 +356,7 @@ impl<C: Clock> BloomManager<C> {
             Event::MessageReceived { from, message } => match message.as_ref() {
                 NetworkMessage::MerkleBlock(block) => {
                     if let Some((height, _)) = tree.get_block(&block.header.block_hash()) {
-                        let event = Event::ReceivedMerkleBlock {
-                            height,
-                            merkle_block: block.clone(),
-                        };
-                        self.outbox.event(event);
+                        self.received_merkle_block(from, height, block.clone());
                     }
 
                     // if let Some((height, _)) = tree.get_block(&block.header.block_hash()) {
@@ -254,13 +379,7 @@ impl<C: Clock> BloomManager<C> {
                     // }
                 }
                 NetworkMessage::Tx(tx) => {
-                    let txid = tx.txid();
-                    if self.matches.contains(&txid) {
-                        self.matches.pop_front();
-                    }
-                    self.outbox.event(Event::ReceivedMatchedTx {
-                        transaction: tx.to_owned(),
-                    });
+                    self.received_tx(from, tx);
                 }
 
                 _ => {}
@@ -268,10 +387,138 @@ impl<C: Clock> BloomManager<C> {
             _ => {}
         }
     }
+
+    /// Called when a `tx` is received from a peer.
+    fn received_tx(&mut self, from: PeerId, tx: &Transaction) {
+        // Transactions matched by a merkle block are sent right after it.
+        // Otherwise, the transaction was relayed from the peer's mempool.
+        let height = self.matches.remove(&tx.txid());
+
+        if height.is_none() && self.mempool_watch {
+            // Peers only announce transactions matching our bloom filter, but it may
+            // yield false positives.
+            if !self.rescan.is_relevant(tx) {
+                return;
+            }
+            self.outbox.event(Event::TxStatusChanged {
+                txid: tx.txid(),
+                status: TxStatus::InMempool {
+                    peer: from,
+                    since: self.clock.local_time(),
+                },
+            });
+        }
+        self.outbox.event(Event::ReceivedMatchedTx {
+            transaction: tx.to_owned(),
+            from,
+            height,
+        });
+        if height.is_some() {
+            self.last_inspected = Some(self.clock.local_time());
+        }
+        if let Some(event) = height.and_then(|h| self.rescan.inspect(h, tx)) {
+            self.outbox.event(event);
+        }
+    }
+
+    /// Stop waiting on the transactions of matched merkle blocks. If the rescan is over,
+    /// it is reported as stopped.
+    fn abandon_inspection(&mut self) {
+        if let Some(event) = self.rescan.abandon() {
+            self.outbox.event(event);
+        }
+        self.last_inspected = None;
+    }
+
+    /// Watch the mempools of peers with a loaded bloom filter for unconfirmed transactions
+    /// touching the given scripts. Peers are asked for their mempool, after which they
+    /// announce matching transactions as they arrive. Announced transactions are fetched,
+    /// and reported with a [`TxStatus::InMempool`] status if relevant.
+    pub fn watch_mempool(&mut self, watch: impl IntoIterator<Item = Script>) {
+        self.rescan.watch.extend(watch);
+        self.mempool_watch = true;
+
+        for addr in self.loaded.keys() {
+            self.outbox.get_mempool(addr);
+        }
+    }
+
+    /// Stop watching peer mempools. Relayed transactions are reported as-is.
+    pub fn unwatch_mempool(&mut self) {
+        self.mempool_watch = false;
+    }
+
+    /// Called when a merkle block is received. The partial merkle tree is verified against
+    /// the block header before the block is passed on.
+    fn received_merkle_block(&mut self, from: PeerId, height: Height, merkle_block: MerkleBlock) {
+        let mut matched_txids = Vec::new();
+        let mut indexes = Vec::new();
+
+        if let Err(err) = merkle_block.extract_matches(&mut matched_txids, &mut indexes) {
+            log::debug!(
+                target: "p2p",
+                "Received invalid merkle block {} from {}: {:?}",
+                merkle_block.header.block_hash(),
+                from,
+                err
+            );
+            self.outbox.event(Event::PeerMisbehaved {
+                addr: from,
+                reason: "invalid `merkleblock` message",
+                severity: Severity::Severe,
+            });
+            return;
+        }
+        for txid in &matched_txids {
+            self.matches.insert(*txid, height);
+        }
+        let block_hash = merkle_block.header.block_hash();
+
+        // Blocks requested by a rescan are reported in ascending height. Other blocks are
+        // reported as they arrive.
+        let ready = if self.rescan.active
+            && self
+                .rescan
+                .received(height, merkle_block.clone(), block_hash)
+        {
+            let (_, events, _) = self.rescan.process();
+
+            for event in events {
+                self.outbox.event(event);
+            }
+            if self.rescan.is_inspecting() {
+                self.last_inspected = Some(self.clock.local_time());
+                self.outbox.set_timer(self.request_timeout);
+            }
+            let mut ready = self.reorder.insert(height, merkle_block, matched_txids);
+
+            // Once the rescan is over, there is no block left to wait for.
+            if !self.rescan.active {
+                ready.extend(self.reorder.drain());
+            }
+            ready
+        } else {
+            vec![(height, merkle_block, matched_txids)]
+        };
+        self.merkle_blocks_ready(ready);
+    }
+
+    /// Report merkle blocks that are ready.
+    fn merkle_blocks_ready(&mut self, ready: Vec<ReadyBlock>) {
+        for (height, merkle_block, matched_txids) in ready {
+            self.outbox.event(Event::ReceivedMerkleBlock {
+                height,
+                merkle_block,
+                matched_txids,
+            });
+        }
+    }
+
     /// Unregister a peer.
     fn unregister(&mut self, id: &PeerId) {
         // self.inflight.remove(id);
         self.peers.remove(id);
+        self.loaded.remove(id);
     }
 
     /// Called when a new peer was negotiated.
@@ -289,10 +536,9 @@ impl<C: Clock> BloomManager<C> {
         if link.is_outbound() && !services.has(REQUIRED_SERVICES) {
             return;
         }
-        let seg = self.bloom_segments.get_mut(&0).unwrap().clone();
-        // if let Some(segment) = self.bloom_segments.get_mut(&0) {
-        self.register(addr, Some(seg));
-        // }
+        // Nb. Peers are registered without a segment until we have a bloom filter to load.
+        let segment = self.bloom_segments.get(&0).cloned();
+        self.register(addr, segment);
     }
 
     /// Register a new peer.
@@ -325,11 +571,56 @@ impl<C: Clock> BloomManager<C> {
     pub fn send_bloom_filter(&mut self, addr: SocketAddr, filter: FilterLoad) {
         //TODO filter out segment to peers
         self.outbox.send_bloom_filter_load(&addr, filter.clone());
+        let loaded = BloomFilter::from(filter.clone());
+        let fp_rate = loaded.fp_rate();
+
+        self.loaded.insert(addr, loaded);
+        self.outbox.event(Event::PeerLoadedBloomFilter {
+            filter,
+            peer: addr,
+            fp_rate,
+        });
+        if self.mempool_watch {
+            self.outbox.get_mempool(&addr);
+        }
+    }
+
+    /// Add an element to the bloom filter loaded on a peer, with a `filteradd` message.
+    /// Our copy of the peer's filter is updated to match.
+    pub fn add_to_bloom_filter(
+        &mut self,
+        addr: PeerId,
+        data: Vec<u8>,
+    ) -> Result<(), FilterAddError> {
+        if data.len() > MAX_FILTER_ADD_SIZE {
+            return Err(FilterAddError::ElementTooLarge(data.len()));
+        }
+        let Some(filter) = self.loaded.get_mut(&addr) else {
+            return Err(FilterAddError::NotLoaded(addr));
+        };
+        filter.insert(&data);
+        self.outbox.send_bloom_filter_add(&addr, data);
+
+        Ok(())
+    }
+
+    /// Get the bloom filter loaded on a peer, if any.
+    pub fn loaded_filter(&self, addr: &PeerId) -> Option<&BloomFilter> {
+        self.loaded.get(addr)
     }
     /// A tick was received.
     pub fn timer_expired<T: BlockReader>(&mut self, _tree: &T) {
         let local_time = self.clock.local_time();
         let timeout = self.request_timeout;
+
+        // Give up on matched transactions that weren't sent in time.
+        if let Some(last) = self.last_inspected {
+            if !self.rescan.is_inspecting() {
+                self.last_inspected = None;
+            } else if local_time - last >= timeout {
+                self.abandon_inspection();
+            }
+        }
         let timed_out = self
             .blocks_inflight
             .iter()
@@ -403,6 +694,12 @@ impl<C: Clock> BloomManager<C> {
             })
             .map(|(addr, peer)| vec![(addr, peer)])
             .clone();
+        // Prefer the peer we last requested from, if it's still available, so that a
+        // resumed rescan picks up where it left off.
+        let preferred = self.rescan.peer;
+        let (preferred, others): (Vec<_>, Vec<_>) =
+            peers_with_no_blocks_inflight.partition(|p| Some(*p[0].0) == preferred);
+        let peers_with_no_blocks_inflight = preferred.into_iter().chain(others);
         // log::debug!(
         //     "peers_with_blocks_inflight {:?}",
         //     peers_with_blocks_inflight
@@ -411,12 +708,12 @@ impl<C: Clock> BloomManager<C> {
         //     "peers_with_no_blocks_inflight {:?}",
         //     peers_with_no_blocks_inflight
         // );
-        for (range, peer) in self
-            .rescan
-            .requests(range, tree)
-            .into_iter()
-            .zip(peers_with_no_blocks_inflight.cycle())
-        {
+        for (range, peer) in self.rescan.requests(range, tree).into_iter().zip(
+            peers_with_no_blocks_inflight
+                .collect::<Vec<_>>()
+                .into_iter()
+                .cycle(),
+        ) {
             // let stop_hash = tree
             //     .get_block_by_height(*range.end())
             //     .ok_or(GetMerkleBlocksError::InvalidRange)?
@@ -446,9 +743,9 @@ impl<C: Clock> BloomManager<C> {
                 on_timeout: OnTimeout::Ignore,
             };
             self.outbox.get_data(*peer[0].0, bock_request);
+            self.rescan.peer = Some(*peer[0].0);
             self.outbox.set_timer(timeout);
             self.blocks_inflight.to_owned().insert(*peer[0].0, req);
-            self.rescan.reset();
         }
         Ok(())
     }
@@ -467,7 +764,45 @@ impl<C: Clock> BloomManager<C> {
         );
     }
 
+    /// Resume an interrupted merkle block rescan from the height following the given
+    /// checkpoint, as reported by [`Event::MerkleBlockRescanProgress`] and
+    /// [`Event::MerkleBlockRescanStopped`]. Merkle blocks are requested
+    /// from the given peer if it's still connected, or any other peer otherwise.
+    pub fn resume_merkle_scan<T: BlockReader>(
+        &mut self,
+        checkpoint: Height,
+        end: Option<Height>,
+        peer: Option<PeerId>,
+        tree: &T,
+    ) {
+        self.rescan = Rescan::resume(DEFAULT_FILTER_CACHE_SIZE, checkpoint, end, peer);
+
+        let ready = self.reorder.reset(self.rescan.start);
+        self.merkle_blocks_ready(ready);
+        self.outbox.event(Event::MerkleBlockRescanStarted {
+            start: self.rescan.start,
+            stop: self.rescan.end,
+        });
+
+        let start = self.rescan.start;
+        let stop = self
+            .rescan
+            .end
+            .map(|h| Height::min(h, tree.height()))
+            .unwrap_or_else(|| tree.height());
+
+        match self.get_merkle_blocks(start..=stop, tree) {
+            Ok(()) => {}
+            Err(GetMerkleBlocksError::NotConnected) => {}
+            Err(GetMerkleBlocksError::InvalidRange) => {}
+        }
+    }
+
     /// Rescan merkle blocks.
+    ///
+    /// A bounded end beyond the block tip is clamped to the tip. If the range is inverted,
+    /// or bounded and starting beyond the tip, an [`Event::Error`] is emitted and the current
+    /// rescan is left untouched.
     pub fn merkle_scan<T: BlockReader>(
         &mut self,
         start: Bound<Height>,
@@ -475,19 +810,17 @@ impl<C: Clock> BloomManager<C> {
         // watch: Vec<Script>,
         tree: &T,
     ) -> Vec<(Height, BlockHash)> {
-        self.rescan.restart(
-            match start {
-                Bound::Unbounded => tree.height() + 1,
-                Bound::Included(h) => h,
-                Bound::Excluded(h) => h + 1,
-            },
-            match end {
-                Bound::Unbounded => None,
-                Bound::Included(h) => Some(h),
-                Bound::Excluded(h) => Some(h - 1),
-            },
-            // watch,
-        );
+        let (start, end) = match scan_range(start, end, tree.height()) {
+            Ok(range) => range,
+            Err(err) => {
+                self.outbox.error(ErrorKind::Other, err);
+                return vec![];
+            }
+        };
+        self.rescan.restart(start, end);
+
+        let ready = self.reorder.reset(start);
+        self.merkle_blocks_ready(ready);
 
         self.outbox.event(Event::MerkleBlockRescanStarted {
             start: self.rescan.start,
@@ -550,3 +883,444 @@ impl Iterator for HeightIterator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use nakamoto_common::bitcoin::{Transaction, TxMerkleNode};
+    use nakamoto_common::network::Network;
+    use nakamoto_common::nonempty::NonEmpty;
+    use nakamoto_test::assert_matches;
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    use crate::fsm::{output, ScanRangeError};
+
+    #[test]
+    fn test_filter_add() {
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let rng = fastrand::Rng::new();
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+        let element = vec![0xfe; 20];
+
+        // No filter loaded yet.
+        assert_matches!(
+            bfmgr.add_to_bloom_filter(remote, element.clone()),
+            Err(FilterAddError::NotLoaded(addr)) if addr == remote
+        );
+        assert!(output::test::messages(&mut bfmgr.outbox).next().is_none());
+
+        let mut filter = BloomFilter::with_fp_rate(10, 0.0001).unwrap();
+        filter.insert(b"wallet");
+        bfmgr.send_bloom_filter(remote, filter.into());
+        assert!(!bfmgr.loaded_filter(&remote).unwrap().contains(&element));
+
+        let expected = bfmgr.loaded_filter(&remote).unwrap().fp_rate();
+        assert!(expected > 0. && expected < 0.0001);
+        assert_matches!(
+            output::test::events(bfmgr.outbox.drain()).next(),
+            Some(Event::PeerLoadedBloomFilter { peer, fp_rate, .. })
+            if peer == remote && fp_rate == expected
+        );
+
+        // Elements larger than allowed by the protocol are rejected.
+        assert_matches!(
+            bfmgr.add_to_bloom_filter(remote, vec![0; MAX_FILTER_ADD_SIZE + 1]),
+            Err(FilterAddError::ElementTooLarge(_))
+        );
+
+        bfmgr.add_to_bloom_filter(remote, element.clone()).unwrap();
+        assert!(bfmgr.loaded_filter(&remote).unwrap().contains(&element));
+
+        output::test::messages_from(&mut bfmgr.outbox, &remote)
+            .find(|m| matches!(m, NetworkMessage::FilterAdd(msg) if msg.data == element))
+            .expect("`filteradd` is sent");
+    }
+
+    #[test]
+    fn test_received_merkle_block() {
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let block = gen::block(&Network::Regtest.genesis(), &mut rng);
+        let txid = block.txdata.last().unwrap().txid();
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+
+        let merkle_block = MerkleBlock::from_block_with_predicate(&block, |t| *t == txid);
+        bfmgr.received_merkle_block(remote, 1, merkle_block);
+
+        assert_matches!(
+            output::test::events(&mut bfmgr.outbox).next(),
+            Some(Event::ReceivedMerkleBlock { height: 1, matched_txids, .. })
+            if matched_txids == vec![txid]
+        );
+
+        // A proof that doesn't match the header's merkle root is dropped.
+        let mut merkle_block = MerkleBlock::from_block_with_predicate(&block, |t| *t == txid);
+        merkle_block.header.merkle_root = TxMerkleNode::all_zeros();
+        bfmgr.received_merkle_block(remote, 1, merkle_block);
+
+        let events = output::test::events(&mut bfmgr.outbox).collect::<Vec<_>>();
+        assert_matches!(
+            events.as_slice(),
+            [Event::PeerMisbehaved { addr, .. }] if *addr == remote
+        );
+    }
+
+    #[test]
+    fn test_received_matched_tx() {
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let mut tree = model::Cache::from(NonEmpty::new(network.genesis()));
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let block = gen::block(&network.genesis(), &mut rng);
+        let tx = block.txdata.last().unwrap().clone();
+        let relayed = gen::transaction(&mut rng);
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+        let mut received = |bfmgr: &mut BloomManager<_>, tx: Transaction| {
+            bfmgr.received_event(
+                Event::MessageReceived {
+                    from: remote,
+                    message: Arc::new(NetworkMessage::Tx(tx)),
+                },
+                &mut tree,
+            );
+            output::test::events(&mut bfmgr.outbox)
+                .find_map(|e| match e {
+                    Event::ReceivedMatchedTx {
+                        transaction,
+                        from,
+                        height,
+                    } => Some((transaction.txid(), from, height)),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        // A transaction relayed from the peer's mempool.
+        assert_eq!(
+            received(&mut bfmgr, relayed.clone()),
+            (relayed.txid(), remote, None)
+        );
+
+        // A transaction following a merkle block that matched it.
+        let merkle_block = MerkleBlock::from_block_with_predicate(&block, |t| *t == tx.txid());
+        bfmgr.received_merkle_block(remote, 1, merkle_block);
+        output::test::events(&mut bfmgr.outbox).for_each(drop);
+
+        assert_eq!(
+            received(&mut bfmgr, tx.clone()),
+            (tx.txid(), remote, Some(1))
+        );
+    }
+
+    #[test]
+    fn test_merkle_scan_range() {
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let chain = gen::blockchain(network.genesis_block(), 10, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+
+        // A stop height beyond the tip is clamped.
+        bfmgr.merkle_scan(Bound::Included(2), Bound::Included(99), &tree);
+        assert_matches!(
+            output::test::events(bfmgr.outbox.drain()).next(),
+            Some(Event::MerkleBlockRescanStarted {
+                start: 2,
+                stop: Some(10)
+            })
+        );
+
+        // Inverted ranges are rejected, without a scan being started.
+        bfmgr.merkle_scan(Bound::Included(8), Bound::Excluded(5), &tree);
+        assert_matches!(
+            output::test::events(bfmgr.outbox.drain()).collect::<Vec<_>>().as_slice(),
+            [Event::Error { kind: ErrorKind::Other, error }]
+            if matches!(
+                error.downcast_ref::<ScanRangeError>(),
+                Some(ScanRangeError::Inverted { start: 8, stop: 4 })
+            )
+        );
+        assert_eq!((bfmgr.rescan.start, bfmgr.rescan.end), (2, Some(10)));
+    }
+
+    #[test]
+    fn test_merkle_scan_resume() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let chain = gen::blockchain(network.genesis_block(), 10, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let requested = |bfmgr: &mut BloomManager<_>| {
+            output::test::messages(&mut bfmgr.outbox)
+                .find_map(|(addr, m)| match m {
+                    NetworkMessage::GetData(invs) => Some((addr, invs)),
+                    _ => None,
+                })
+                .map(|(addr, invs)| {
+                    let heights = invs
+                        .iter()
+                        .map(|inv| match inv {
+                            Inventory::FilteredBlock(hash) => tree.get_block(hash).unwrap().0,
+                            _ => panic!("unexpected inventory {:?}", inv),
+                        })
+                        .collect::<Vec<_>>();
+                    (addr, heights)
+                })
+                .unwrap()
+        };
+
+        let mut bfmgr = BloomManager::new(rng.clone(), LocalTime::now(), segments.clone());
+        bfmgr.rescan.progress_interval = 5;
+        bfmgr.register(alice, Some(PrivacySegment::default()));
+        bfmgr.merkle_scan(Bound::Included(1), Bound::Included(10), &tree);
+
+        let (addr, heights) = requested(&mut bfmgr);
+        assert_eq!(addr, alice);
+        assert_eq!(heights, (1..=10).collect::<Vec<_>>());
+
+        // The rescan is interrupted after the first few blocks are processed.
+        for (height, block) in chain.iter().enumerate().skip(1).take(5) {
+            let merkle_block = MerkleBlock::from_block_with_predicate(block, |_| false);
+            bfmgr.received_merkle_block(alice, height as Height, merkle_block);
+        }
+        // The checkpoint to resume from is reported with the rescan progress.
+        let (checkpoint, peer) = output::test::events(&mut bfmgr.outbox)
+            .find_map(|e| match e {
+                Event::MerkleBlockRescanProgress {
+                    checkpoint, peer, ..
+                } => Some((checkpoint, peer)),
+                _ => None,
+            })
+            .expect("rescan progress is reported");
+        assert_eq!(checkpoint, 5);
+        assert_eq!(peer, Some(alice));
+
+        // After a restart, the rescan resumes from the recorded peer.
+        let mut bfmgr = BloomManager::new(rng.clone(), LocalTime::now(), segments.clone());
+        bfmgr.register(bob, Some(PrivacySegment::default()));
+        bfmgr.register(alice, Some(PrivacySegment::default()));
+        bfmgr.resume_merkle_scan(checkpoint, Some(10), peer, &tree);
+
+        assert_matches!(
+            output::test::events(&mut bfmgr.outbox).next(),
+            Some(Event::MerkleBlockRescanStarted {
+                start: 6,
+                stop: Some(10)
+            })
+        );
+        assert_eq!(requested(&mut bfmgr), (alice, (6..=10).collect()));
+
+        // If the recorded peer is gone, any other peer is used.
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+        bfmgr.register(bob, Some(PrivacySegment::default()));
+        bfmgr.resume_merkle_scan(checkpoint, Some(10), peer, &tree);
+
+        assert_eq!(requested(&mut bfmgr), (bob, (6..=10).collect()));
+        assert_eq!(bfmgr.rescan.checkpoint(), checkpoint);
+    }
+
+    #[test]
+    fn test_merkle_scan_reorder() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let chain = gen::blockchain(network.genesis_block(), 10, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+        let received = |bfmgr: &mut BloomManager<_>| {
+            output::test::events(bfmgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::ReceivedMerkleBlock { height, .. } => Some(height),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        bfmgr.register(alice, Some(PrivacySegment::default()));
+        bfmgr.merkle_scan(Bound::Included(1), Bound::Unbounded, &tree);
+        output::test::events(bfmgr.outbox.drain()).for_each(drop);
+
+        // Blocks are held back until the blocks below them arrive.
+        for (height, expected) in [(3, vec![]), (1, vec![1]), (2, vec![2, 3]), (5, vec![])] {
+            let block = &chain[height as usize];
+            let merkle_block = MerkleBlock::from_block_with_predicate(block, |_| false);
+
+            bfmgr.received_merkle_block(alice, height, merkle_block);
+            assert_eq!(received(&mut bfmgr), expected, "height {height}");
+        }
+
+        // Restarting the rescan releases the blocks that were held back.
+        bfmgr.merkle_scan(Bound::Included(1), Bound::Unbounded, &tree);
+        assert_eq!(received(&mut bfmgr), vec![5]);
+    }
+
+    #[test]
+    fn test_reorder_buffer_overflow() {
+        let mut rng = fastrand::Rng::new();
+        let block = gen::block(&Network::Regtest.genesis(), &mut rng);
+        let merkle_block = MerkleBlock::from_block_with_predicate(&block, |_| false);
+        let mut buffer = ReorderBuffer::new(2);
+        let mut insert = |height: Height| {
+            buffer
+                .insert(height, merkle_block.clone(), vec![])
+                .into_iter()
+                .map(|(h, _, _)| h)
+                .collect::<Vec<_>>()
+        };
+        assert!(insert(3).is_empty());
+        assert!(insert(5).is_empty());
+
+        // Once full, the lowest block is released, even though blocks below it are missing.
+        assert_eq!(insert(7), vec![3]);
+        assert_eq!(insert(4), vec![4, 5]);
+        // Missing blocks that arrive late are released right away.
+        assert_eq!(insert(1), vec![1]);
+        assert_eq!(insert(6), vec![6, 7]);
+    }
+
+    #[test]
+    fn test_merkle_scan_false_positives() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let chain = gen::blockchain(network.genesis_block(), 5, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let mut tree = model::Cache::from(headers);
+        let segments = HashMap::with_hasher(rng.clone().into());
+        let mut bfmgr = BloomManager::new(rng, LocalTime::now(), segments);
+
+        // Only the transaction at height 1 pays to a watched script.
+        let txs = chain
+            .iter()
+            .map(|b| b.txdata.last().unwrap().clone())
+            .collect::<Vec<_>>();
+        bfmgr
+            .rescan
+            .watch
+            .insert(txs[1].output[0].script_pubkey.clone());
+
+        bfmgr.register(alice, Some(PrivacySegment::default()));
+        bfmgr.merkle_scan(Bound::Included(1), Bound::Included(5), &tree);
+        output::test::events(bfmgr.outbox.drain()).for_each(drop);
+
+        // Every block but the one at height 3 matches our filter.
+        for (height, block) in chain.iter().enumerate().skip(1).take(4) {
+            let txid = txs[height].txid();
+            let merkle_block =
+                MerkleBlock::from_block_with_predicate(block, |t| height != 3 && *t == txid);
+            bfmgr.received_merkle_block(alice, height as Height, merkle_block);
+        }
+        assert!(!bfmgr.rescan.active);
+
+        // The rescan only stops once the matched transactions are inspected.
+        for height in [1, 2, 4] {
+            assert!(!output::test::events(bfmgr.outbox.drain())
+                .any(|e| matches!(e, Event::MerkleBlockRescanStopped { .. })));
+
+            bfmgr.received_event(
+                Event::MessageReceived {
+                    from: alice,
+                    message: Arc::new(NetworkMessage::Tx(txs[height].clone())),
+                },
+                &mut tree,
+            );
+        }
+        assert_matches!(
+            output::test::events(bfmgr.outbox.drain()).last(),
+            Some(Event::MerkleBlockRescanStopped {
+                height: 5,
+                checkpoint: 4,
+                matched: 3,
+                false_positives: 2,
+            })
+        );
+        assert_eq!(bfmgr.rescan.false_positive_rate(), Some(2. / 3.));
+    }
+
+    #[test]
+    fn test_merkle_scan_abandon_inspection() {
+        use nakamoto_common::block::time::RefClock;
+
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let chain = gen::blockchain(network.genesis_block(), 5, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let mut tree = model::Cache::from(headers);
+        let txs = chain
+            .iter()
+            .map(|b| b.txdata.last().unwrap().clone())
+            .collect::<Vec<_>>();
+
+        for disconnect in [true, false] {
+            let clock = RefClock::from(LocalTime::now());
+            let segments = HashMap::with_hasher(rng.clone().into());
+            let mut bfmgr = BloomManager::new(rng.clone(), clock.clone(), segments);
+
+            bfmgr
+                .rescan
+                .watch
+                .insert(txs[1].output[0].script_pubkey.clone());
+            bfmgr.register(alice, Some(PrivacySegment::default()));
+            bfmgr.merkle_scan(Bound::Included(1), Bound::Included(5), &tree);
+
+            for (height, block) in chain.iter().enumerate().skip(1).take(4) {
+                let txid = txs[height].txid();
+                let merkle_block = MerkleBlock::from_block_with_predicate(block, |t| *t == txid);
+                bfmgr.received_merkle_block(alice, height as Height, merkle_block);
+            }
+            // Only the first matched transaction is sent.
+            bfmgr.received_event(
+                Event::MessageReceived {
+                    from: alice,
+                    message: Arc::new(NetworkMessage::Tx(txs[1].clone())),
+                },
+                &mut tree,
+            );
+            assert!(!output::test::events(bfmgr.outbox.drain())
+                .any(|e| matches!(e, Event::MerkleBlockRescanStopped { .. })));
+
+            // The rescan stops once the peer disconnects, or the transactions time out.
+            if disconnect {
+                bfmgr.received_event(
+                    Event::PeerDisconnected {
+                        addr: alice,
+                        reason: DisconnectReason::Command.into(),
+                    },
+                    &mut tree,
+                );
+            } else {
+                clock.elapse(REQUEST_TIMEOUT / 2);
+                bfmgr.timer_expired(&tree);
+                assert_eq!(output::test::events(bfmgr.outbox.drain()).count(), 0);
+
+                clock.elapse(REQUEST_TIMEOUT / 2);
+                bfmgr.timer_expired(&tree);
+            }
+            assert_matches!(
+                output::test::events(bfmgr.outbox.drain()).last(),
+                Some(Event::MerkleBlockRescanStopped {
+                    height: 5,
+                    checkpoint: 4,
+                    matched: 4,
+                    false_positives: 0,
+                })
+            );
+            assert!(!bfmgr.rescan.is_inspecting());
+        }
+    }
+}
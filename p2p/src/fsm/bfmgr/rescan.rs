@@ -6,12 +6,13 @@ use std::rc::Rc;
 
 // use nakamoto_common::bitcoin::util::bloom::{self, BloomFilter};
 use crate::fsm::syncmgr::MAX_MESSAGE_INVS;
-use nakamoto_common::bitcoin::{Script, Txid};
+use nakamoto_common::bitcoin::{Script, Transaction, Txid};
 use nakamoto_common::block::tree::BlockReader;
 use nakamoto_common::block::{BlockHash, Height, MerkleBlock};
 use nakamoto_common::collections::{HashMap, HashSet};
 
-use super::{Event, FilterCache, HeightIterator /* MAX_MESSAGE_CFILTERS */};
+use super::RESCAN_PROGRESS_INTERVAL;
+use super::{Event, FilterCache, HeightIterator, PeerId /* MAX_MESSAGE_CFILTERS */};
 
 /// Bloom Filter (re)scan state.
 #[derive(Debug, Default)]
@@ -31,11 +32,22 @@ pub struct Rescan {
     pub watch: HashSet<Script>,
     /// Transactions to watch for.
     pub transactions: HashMap<Txid, HashSet<Script>>,
+    /// Peer merkle blocks were last requested from.
+    pub peer: Option<PeerId>,
+    /// Number of merkle blocks that matched during this rescan.
+    pub matched: usize,
+    /// Number of matched merkle blocks that didn't contain any watched transaction.
+    pub false_positives: usize,
+    /// How many merkle blocks to process between progress events. Zero disables them.
+    pub progress_interval: Height,
 
     /// Filters requested and remaining to download.
     requested: BTreeSet<Height>,
     /// Received filters waiting to be matched.
     received: HashMap<Height, (Rc<MerkleBlock>, BlockHash, bool)>,
+    /// Matched merkle blocks waiting for their transactions to be inspected, along with
+    /// whether a watched transaction was found so far.
+    inspecting: HashMap<Height, (HashSet<Txid>, bool)>,
 }
 
 impl Rescan {
@@ -45,9 +57,37 @@ impl Rescan {
 
         Self {
             cache,
+            progress_interval: RESCAN_PROGRESS_INTERVAL,
             ..Self::default()
         }
     }
+    /// Create a rescan state resuming an interrupted rescan, right after the given
+    /// checkpoint. The checkpoint is the last height that was fully processed.
+    pub fn resume(
+        cache: usize,
+        checkpoint: Height,
+        end: Option<Height>,
+        peer: Option<PeerId>,
+    ) -> Self {
+        let mut rescan = Self::new(cache);
+
+        rescan.restart(checkpoint + 1, end);
+        rescan.peer = peer;
+        rescan
+    }
+    /// The last fully processed height. Processing should resume from the height
+    /// after this one.
+    ///
+    /// Nb. Matched merkle blocks whose transactions are yet to be inspected aren't fully
+    /// processed.
+    pub fn checkpoint(&self) -> Height {
+        self.inspecting
+            .keys()
+            .min()
+            .copied()
+            .unwrap_or(self.current)
+            .saturating_sub(1)
+    }
     /// Start or restart a rescan. Resets the request state.
     pub fn restart(
         &mut self,
@@ -61,6 +101,9 @@ impl Rescan {
         self.end = end;
         // self.watch = watch.into_iter().collect();
         self.requested.clear();
+        self.inspecting.clear();
+        self.matched = 0;
+        self.false_positives = 0;
     }
     /// Return info string on rescan state.
     pub fn info(&self) -> String {
@@ -73,6 +116,69 @@ impl Rescan {
             self.requested.len()
         )
     }
+    /// The realized false-positive rate of this rescan, ie. the ratio of matched merkle
+    /// blocks that didn't contain any watched transaction. Returns `None` if nothing
+    /// matched yet.
+    pub fn false_positive_rate(&self) -> Option<f64> {
+        if self.matched == 0 {
+            return None;
+        }
+        Some(self.false_positives as f64 / self.matched as f64)
+    }
+    /// Check whether a transaction is one we watch, pays to a watched script, or spends
+    /// one of the watched transactions.
+    pub fn is_relevant(&self, tx: &Transaction) -> bool {
+        self.transactions.contains_key(&tx.txid())
+            || tx
+                .output
+                .iter()
+                .any(|o| self.watch.contains(&o.script_pubkey))
+            || tx
+                .input
+                .iter()
+                .any(|i| self.transactions.contains_key(&i.previous_output.txid))
+    }
+    /// Inspect a transaction matched by the merkle block at the given height, checking it
+    /// against the watch list. Returns the rescan stop event if this was the last
+    /// transaction the rescan was waiting on.
+    pub fn inspect(&mut self, height: Height, tx: &Transaction) -> Option<Event> {
+        let txid = tx.txid();
+        let is_relevant = self.is_relevant(tx);
+        let (remaining, relevant) = self.inspecting.get_mut(&height)?;
+
+        if !remaining.remove(&txid) {
+            return None;
+        }
+        *relevant |= is_relevant;
+
+        if remaining.is_empty() {
+            if let Some((_, false)) = self.inspecting.remove(&height) {
+                self.false_positives += 1;
+            }
+            if !self.active {
+                return self.stopped();
+            }
+        }
+        None
+    }
+    /// Check whether the transactions of some matched merkle blocks are yet to be inspected.
+    pub fn is_inspecting(&self) -> bool {
+        !self.inspecting.is_empty()
+    }
+    /// Stop waiting on the transactions of matched merkle blocks, eg. because the peer
+    /// that was to send them disconnected or timed out. These blocks aren't counted as
+    /// false positives. Returns the rescan stop event, if the rescan is over.
+    pub fn abandon(&mut self) -> Option<Event> {
+        if self.inspecting.is_empty() {
+            return None;
+        }
+        self.inspecting.clear();
+
+        if self.active {
+            return None;
+        }
+        self.stopped()
+    }
     /// Reset requested heights. This allows for requests to be re-issued.
     pub fn reset(&mut self) {
         self.requested.clear();
@@ -124,6 +230,10 @@ impl Rescan {
 
             if matched {
                 block_matches.push((current, block_hash));
+
+                self.matched += 1;
+                self.inspecting
+                    .insert(current, (matches.iter().cloned().collect(), false));
             }
             let merkle_block = merkle_block.as_ref().clone();
             events.push(Event::MerkleBlockProcessed {
@@ -134,19 +244,54 @@ impl Rescan {
                 merkle_block,
             });
             current += 1;
+
+            if self.progress_interval > 0 && (current - self.start) % self.progress_interval == 0 {
+                // Nb. The checkpoint is computed once `current` is up to date, so that it
+                // accounts for this merkle block.
+                self.current = current;
+                events.push(Event::MerkleBlockRescanProgress {
+                    checkpoint: self.checkpoint(),
+                    start: self.start,
+                    stop: self.end,
+                    matched: self.matched,
+                    peer: self.peer,
+                });
+            }
         }
         self.current = current;
 
         if let Some(stop) = self.end {
             if self.current == stop {
                 self.active = false;
-                events.push(Event::MerkleBlockRescanStopped { height: stop });
+                events.extend(self.stopped());
             }
         }
 
         (block_matches, events, current - old)
     }
 
+    /// Return the rescan stop event, once all matched merkle blocks were inspected.
+    fn stopped(&self) -> Option<Event> {
+        match self.end {
+            Some(height) if self.inspecting.is_empty() => {
+                log::debug!(
+                    target: "p2p",
+                    "Merkle block rescan stopped at height {} (false-positive rate = {})",
+                    height,
+                    self.false_positive_rate()
+                        .map_or(String::from("n/a"), |rate| format!("{:.4}", rate))
+                );
+                Some(Event::MerkleBlockRescanStopped {
+                    height,
+                    checkpoint: self.checkpoint(),
+                    matched: self.matched,
+                    false_positives: self.false_positives,
+                })
+            }
+            _ => None,
+        }
+    }
+
     /// Given a range of heights, return the ranges that are missing.
     /// This is useful to figure out which ranges to fetch while ensuring we don't request
     /// the same heights more than once.
@@ -4,7 +4,7 @@
 //!
 mod rescan;
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::{Bound, RangeInclusive};
 
 use thiserror::Error;
@@ -12,18 +12,21 @@ use thiserror::Error;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders};
-use nakamoto_common::bitcoin::{Script, Transaction, Txid};
+use nakamoto_common::bitcoin::{OutPoint, Script, Transaction, Txid};
 use nakamoto_common::block::filter::{self, BlockFilter, Filters};
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{Block, BlockHash, Height};
 use nakamoto_common::collections::{AddressBook, HashMap};
 use nakamoto_common::source;
 
-use super::event::TxStatus;
+use super::event::{ChainImport, TxStatus};
 use super::filter_cache::FilterCache;
 use super::output::{Io, Outbox};
-use super::{BlockSource, DisconnectReason, Event, Link, PeerId};
+use super::{
+    scan_range, BlockSource, DisconnectReason, ErrorKind, Event, Feature, FilterKind, Link, PeerId,
+    Severity,
+};
 
 use rescan::Rescan;
 
@@ -45,6 +48,16 @@ pub const DEFAULT_FILTER_CACHE_SIZE: usize = 1024 * 1024; // 1 MB.
 /// How long to wait to receive a reply from a peer.
 pub const DEFAULT_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 
+/// How many filters ahead of the current scan height to request.
+pub const DEFAULT_FILTER_PREFETCH_WINDOW: Height = MAX_MESSAGE_CFILTERS as Height * 2;
+
+/// How many filters to process between rescan progress events.
+pub const DEFAULT_RESCAN_PROGRESS_INTERVAL: Height = 1000;
+
+/// How long the filter header chain can go without advancing while it's behind the block
+/// header chain, before filter sync is considered stalled.
+pub const DEFAULT_STALL_TIMEOUT: LocalDuration = LocalDuration::from_mins(5);
+
 /// An error originating in the CBF manager.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -90,6 +103,19 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Filter cache size, in bytes.
     pub filter_cache_size: usize,
+    /// Defer filter scanning until the filter header chain is synced with the block header
+    /// chain. Once synced, the full rescan range is fetched and matched in one go.
+    pub defer_scan_during_ibd: bool,
+    /// How many filters ahead of the current scan height to request. Filters that are
+    /// received out of order are queued in memory until they can be processed, so
+    /// this should be kept in proportion to the filter cache size.
+    pub filter_prefetch_window: Height,
+    /// How many filters to process between [`Event::FilterRescanProgress`] events.
+    /// A value of zero disables these events.
+    pub rescan_progress_interval: Height,
+    /// How long the filter header chain can go without advancing while it's behind the
+    /// block header chain, before filter sync is considered stalled.
+    pub stall_timeout: LocalDuration,
 }
 
 impl Default for Config {
@@ -97,6 +123,10 @@ impl Default for Config {
         Self {
             request_timeout: DEFAULT_REQUEST_TIMEOUT,
             filter_cache_size: DEFAULT_FILTER_CACHE_SIZE,
+            defer_scan_during_ibd: false,
+            filter_prefetch_window: DEFAULT_FILTER_PREFETCH_WINDOW,
+            rescan_progress_interval: DEFAULT_RESCAN_PROGRESS_INTERVAL,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
         }
     }
 }
@@ -106,9 +136,37 @@ impl Default for Config {
 struct Peer {
     #[allow(dead_code)]
     height: Height,
+    /// Services announced by the peer.
+    services: ServiceFlags,
     #[allow(dead_code)]
     last_active: LocalTime,
     persistent: bool,
+    /// Ranges of filters requested from this peer, merged when they overlap or touch.
+    requested: Vec<RangeInclusive<Height>>,
+}
+
+impl Peer {
+    /// Record a range of filters requested from this peer.
+    fn requested(&mut self, range: RangeInclusive<Height>) {
+        let (mut start, mut end) = range.into_inner();
+
+        // Since filters are mostly requested in sequence, this keeps the list short.
+        self.requested.retain(|r| {
+            if *r.start() > end.saturating_add(1) || start > r.end().saturating_add(1) {
+                return true;
+            }
+            start = start.min(*r.start());
+            end = end.max(*r.end());
+
+            false
+        });
+        self.requested.push(start..=end);
+    }
+
+    /// Check whether the filter at the given height was ever requested from this peer.
+    fn was_requested(&self, height: Height) -> bool {
+        self.requested.iter().any(|r| r.contains(&height))
+    }
 }
 
 /// A compact block filter manager.
@@ -130,8 +188,18 @@ pub struct FilterManager<F, C> {
     last_processed: Option<LocalTime>,
     /// Pending block requests.
     pending_blocks: BTreeSet<Height>,
+    /// Blocks to match against the watchlist once downloaded. See [`Self::rescan_cached`].
+    pending_checks: BTreeSet<Height>,
     /// Inflight requests.
     inflight: HashMap<BlockHash, (Height, PeerId, LocalTime)>,
+    /// Peers who served the cached filters, by height.
+    sources: BTreeMap<Height, PeerId>,
+    /// Highest height reported with [`Event::Scanned`]. Only rewound by a re-org or rescan.
+    scanned: Option<Height>,
+    /// Last filter header height observed, and since when.
+    last_progress: Option<(Height, LocalTime)>,
+    /// Whether filter sync was reported stalled since the filter header chain last advanced.
+    stalled: bool,
 }
 
 impl<F, C> Iterator for FilterManager<F, C> {
@@ -146,7 +214,8 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
     /// Create a new filter manager.
     pub fn new(config: Config, rng: fastrand::Rng, filters: F, clock: C) -> Self {
         let peers = AddressBook::new(rng.clone());
-        let rescan = Rescan::new(config.filter_cache_size);
+        let mut rescan = Rescan::new(config.filter_cache_size);
+        rescan.progress_interval = config.rescan_progress_interval;
 
         Self {
             config,
@@ -156,9 +225,14 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             clock,
             filters,
             pending_blocks: BTreeSet::new(),
+            pending_checks: BTreeSet::new(),
             inflight: HashMap::with_hasher(rng.into()),
+            sources: BTreeMap::new(),
             last_idle: None,
             last_processed: None,
+            scanned: None,
+            last_progress: None,
+            stalled: false,
         }
     }
 
@@ -181,14 +255,25 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                 services,
                 height,
                 persistent,
+                version,
                 ..
             } => {
+                if !Feature::CompactFilters.is_supported(version, services) {
+                    log::debug!(
+                        target: "p2p",
+                        "Peer {} doesn't support compact filters (version = {}, services = {})",
+                        addr, version, services
+                    );
+                    return;
+                }
                 self.peer_negotiated(addr, height, services, link, persistent, tree);
             }
             Event::PeerDisconnected { addr, .. } => {
                 self.peers.remove(&addr);
             }
             Event::BlockProcessed { block, height, .. } => {
+                let check = self.pending_checks.remove(&height);
+
                 if self.pending_blocks.remove(&height) {
                     self.outbox.event(Event::BlockMatched { block, height });
 
@@ -201,14 +286,25 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                     } else {
                         height
                     };
-                    self.outbox.event(Event::Scanned { height });
+                    self.scanned(height);
+                } else {
+                    if check && self.rescan.match_block(&block) {
+                        self.outbox.event(Event::BlockMatched {
+                            block: block.clone(),
+                            height,
+                        });
+                    }
+                    // This block wasn't matched by its filter, check that it really
+                    // doesn't contain any of our scripts.
+                    self.check_filter(&block, height);
                 }
             }
             Event::BlockDisconnected { height, .. } => {
                 // In case of a re-org, make sure we don't accept old blocks that were requested.
                 self.pending_blocks.remove(&height);
+                self.pending_checks.remove(&height);
             }
-            Event::BlockHeadersImported { reverted, .. } => {
+            Event::BlockHeadersImported(ChainImport { reverted, .. }) => {
                 // Nb. the reverted blocks are ordered from the tip down to
                 // the oldest ancestor.
                 if let Some((height, _)) = reverted.last() {
@@ -217,7 +313,7 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                     let fork_height = height - 1;
 
                     if let Err(e) = self.rollback(fork_height) {
-                        self.outbox.error(e);
+                        self.outbox.error(ErrorKind::Storage, e);
                     }
                 }
                 // Trigger a filter sync, since we're going to have to catch up on the
@@ -236,7 +332,18 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                 }
                 _ => {}
             },
+            Event::TxStatusBatch {
+                status: TxStatus::Confirmed { .. },
+                txids,
+            } => {
+                for txid in &txids {
+                    self.unwatch_transaction(txid);
+                }
+            }
             Event::MessageReceived { from, message } => match message.as_ref() {
+                NetworkMessage::Block(block) => {
+                    self.received_block(from, block, tree);
+                }
                 NetworkMessage::CFHeaders(msg) => {
                     log::debug!(
                         target: "p2p",
@@ -251,10 +358,11 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                             self.outbox.event(Event::PeerMisbehaved {
                                 addr: from,
                                 reason: "invalid `cfheaders` message",
+                                severity: Severity::Severe,
                             });
                         }
                         Err(e @ Error::Filters { .. }) => {
-                            self.outbox.error(e);
+                            self.outbox.error(ErrorKind::Storage, e);
                         }
                         Err(e @ Error::Ignored { .. }) => {
                             log::warn!(target: "p2p", "Dropped `cfheaders` message: {e}");
@@ -268,10 +376,11 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                             self.outbox.event(Event::PeerMisbehaved {
                                 addr: from,
                                 reason: "invalid `getcfheaders` message",
+                                severity: Severity::Severe,
                             });
                         }
                         Err(e @ Error::Filters { .. }) => {
-                            self.outbox.error(e);
+                            self.outbox.error(ErrorKind::Storage, e);
                         }
                         Err(e @ Error::Ignored { .. }) => {
                             log::warn!(target: "p2p", "Dropped `getcfheaders` message: {e}");
@@ -289,19 +398,18 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                             // Filters being processed only updates our progress if there are no
                             // pending blocks. Otherwise we have to wait for the block to arrive.
                             if self.pending_blocks.is_empty() {
-                                self.outbox.event(Event::Scanned {
-                                    height: self.rescan.current,
-                                });
+                                self.scanned(self.rescan.current);
                             }
                         }
                         Err(Error::InvalidMessage { from, .. }) => {
                             self.outbox.event(Event::PeerMisbehaved {
                                 addr: from,
                                 reason: "invalid `cfilter` message",
+                                severity: Severity::Severe,
                             });
                         }
                         Err(e @ Error::Filters { .. }) => {
-                            self.outbox.error(e);
+                            self.outbox.error(ErrorKind::Storage, e);
                         }
                         Err(e @ Error::Ignored { .. }) => {
                             log::warn!(target: "p2p", "Dropped `cfilter` message: {e}");
@@ -317,9 +425,11 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
     /// A tick was received.
     pub fn timer_expired<T: BlockReader>(&mut self, tree: &T) {
         self.idle(tree);
+        self.check_stalled(tree);
 
         let timeout = self.config.request_timeout;
         let now = self.clock.local_time();
+        let services = FilterKind::Compact.services();
 
         // Check if any header request expired. If so, retry with a different peer and disconnect
         // the unresponsive peer.
@@ -329,7 +439,7 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
 
                 // Nb. Purposefully allow re-sampling the same peer, for cases where we are only
                 // connected to one peer.
-                if let Some((a, peer)) = self.peers.sample() {
+                if let Some((a, peer)) = self.peers.sample_with(|_, p| p.services.has(services)) {
                     let a = *a;
                     // Disconnect only if we found a different peer, and this isn't
                     // a persistent peer.
@@ -350,28 +460,203 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         // If we've waited too long since the last processed filter, re-issue requests
         // for missing filters.
         if now - self.last_processed.unwrap_or_default() >= DEFAULT_REQUEST_TIMEOUT {
-            if self.rescan.active {
+            if self.rescan.active && !self.is_scan_deferred(tree) {
                 self.rescan.reset(); // Clear pending request queue.
-                self.get_cfilters(self.rescan.current..=self.filters.height(), tree)
-                    .ok();
+                self.prefetch(tree).ok();
+            }
+        }
+    }
+
+    /// Check whether filter header sync has stalled, ie. the filter header chain hasn't
+    /// advanced in a while, even though it's behind the block header chain.
+    fn check_stalled<T: BlockReader>(&mut self, tree: &T) {
+        let now = self.clock.local_time();
+        let height = self.filters.height();
+
+        match self.last_progress {
+            Some((h, since)) if h == height => {
+                // Filter header sync doesn't start before the last checkpoint is reached.
+                let waiting = tree
+                    .checkpoints()
+                    .keys()
+                    .next_back()
+                    .map_or(false, |c| tree.height() < *c);
+
+                if waiting || height >= tree.height() {
+                    // We're not behind, so there's nothing to stall on.
+                    self.last_progress = Some((height, now));
+                    self.stalled = false;
+                    return;
+                }
+                if self.stalled {
+                    return;
+                }
+                let stalled_for = now - since;
+
+                if stalled_for >= self.config.stall_timeout {
+                    self.stalled = true;
+                    self.outbox.event(Event::FilterSyncStalled {
+                        stalled_for,
+                        height,
+                    });
+                } else {
+                    self.outbox
+                        .set_timer(since + self.config.stall_timeout - now);
+                }
+            }
+            _ => {
+                // The filter header chain moved, or we're checking for the first time.
+                self.last_progress = Some((height, now));
+                self.stalled = false;
+                self.outbox.set_timer(self.config.stall_timeout);
             }
         }
     }
 
     /// Add scripts to the list of scripts to watch.
     pub fn watch(&mut self, scripts: Vec<Script>) {
-        self.rescan.watch.extend(scripts);
+        self.rescan.watch_scripts(scripts);
+    }
+
+    /// Add a script to the watchlist. If `rescan_from` is set, rescans the chain for
+    /// all watched scripts, starting at that height.
+    ///
+    /// Adding a script that is already watched has no effect.
+    pub fn watch_script<T: BlockReader>(
+        &mut self,
+        script: Script,
+        rescan_from: Option<Height>,
+        tree: &T,
+    ) -> Vec<(Height, BlockHash)> {
+        let added = self.rescan.watch_script(script);
+        self.watchlist_updated();
+
+        match rescan_from {
+            Some(height) if added => {
+                let watch = self.rescan.watch().iter().cloned().collect();
+                let end = self.rescan.end.map_or(Bound::Unbounded, Bound::Included);
+
+                self.rescan(Bound::Included(height), end, watch, tree)
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Remove a script from the watchlist.
+    pub fn unwatch_script(&mut self, script: &Script) {
+        self.rescan.unwatch_script(script);
+        self.watchlist_updated();
+    }
+
+    /// Add an outpoint to the watchlist. Transactions spending it are reported when
+    /// found in a downloaded block.
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint) {
+        self.rescan.watch_outpoint(outpoint);
+        self.watchlist_updated();
+    }
+
+    /// Called when a block is received. Reports transactions spending watched outpoints.
+    ///
+    /// Since an outpoint can only be spent once, it is removed from the watchlist once
+    /// reported. This also prevents reporting it again if the block is received twice.
+    fn received_block<T: BlockReader>(&mut self, from: PeerId, block: &Block, tree: &T) {
+        if self.rescan.outpoints().is_empty() {
+            return;
+        }
+        let height = tree.get_block(&block.block_hash()).map(|(h, _)| h);
+        let mut spent = false;
+
+        for tx in &block.txdata {
+            let mut matched = false;
+
+            for input in &tx.input {
+                matched |= self.rescan.unwatch_outpoint(&input.previous_output);
+            }
+            if matched {
+                spent = true;
+
+                self.outbox.event(Event::ReceivedMatchedTx {
+                    transaction: tx.clone(),
+                    from,
+                    height,
+                });
+            }
+        }
+        if spent {
+            self.watchlist_updated();
+        }
+    }
+
+    /// Re-run the watchlist against already downloaded blocks, eg. after adding scripts to
+    /// it, so that these blocks don't have to be downloaded again.
+    ///
+    /// Emits an [`Event::BlockMatched`] for every given block of the active chain, from the
+    /// given height, that matches the watchlist, in order of height. Returns the blocks from
+    /// that height up to the tip that weren't given, and must be downloaded to be checked.
+    /// These are matched and reported as they are processed.
+    pub fn rescan_cached<'a, T: BlockReader>(
+        &mut self,
+        from: Height,
+        cached: impl IntoIterator<Item = &'a Block>,
+        tree: &T,
+    ) -> Vec<BlockHash> {
+        let mut found = BTreeSet::new();
+        let mut matched = Vec::new();
+
+        for block in cached {
+            let hash = block.block_hash();
+
+            match tree.get_block(&hash) {
+                Some((height, _)) if height >= from => {
+                    if self.rescan.match_block(block) {
+                        matched.push((height, block.clone()));
+                    }
+                    found.insert(hash);
+                }
+                _ => {}
+            }
+        }
+        matched.sort_unstable_by_key(|(height, _)| *height);
+
+        for (height, block) in matched {
+            self.outbox.event(Event::BlockMatched { height, block });
+        }
+        let mut missing = Vec::new();
+
+        for height in from..=tree.height() {
+            let Some(header) = tree.get_block_by_height(height) else {
+                continue;
+            };
+            let hash = header.block_hash();
+
+            if !found.contains(&hash) {
+                self.pending_checks.insert(height);
+                missing.push(hash);
+            }
+        }
+        missing
+    }
+
+    /// Emit an event with the size of the watchlist.
+    fn watchlist_updated(&mut self) {
+        self.outbox.event(Event::WatchlistUpdated {
+            size: self.rescan.watch().len() + self.rescan.outpoints().len(),
+        });
     }
 
     /// Add transaction outputs to list of transactions to watch.
     pub fn watch_transaction(&mut self, tx: &Transaction) {
-        self.rescan.transactions.insert(
+        self.rescan.watch_transaction(
             tx.txid(),
             tx.output.iter().map(|o| o.script_pubkey.clone()).collect(),
         );
     }
 
     /// Rescan compact block filters.
+    ///
+    /// A bounded end beyond the block tip is clamped to the tip. If the range is inverted,
+    /// or bounded and starting beyond the tip, an [`Event::Error`] is emitted and the current
+    /// rescan is left untouched.
     pub fn rescan<T: BlockReader>(
         &mut self,
         start: Bound<Height>,
@@ -379,26 +664,26 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         watch: Vec<Script>,
         tree: &T,
     ) -> Vec<(Height, BlockHash)> {
-        self.rescan.restart(
-            match start {
-                Bound::Unbounded => tree.height() + 1,
-                Bound::Included(h) => h,
-                Bound::Excluded(h) => h + 1,
-            },
-            match end {
-                Bound::Unbounded => None,
-                Bound::Included(h) => Some(h),
-                Bound::Excluded(h) => Some(h - 1),
-            },
-            watch,
-        );
+        let (start, end) = match scan_range(start, end, tree.height()) {
+            Ok(range) => range,
+            Err(err) => {
+                self.outbox.error(ErrorKind::Other, err);
+                return vec![];
+            }
+        };
+        self.rescan.restart(start, end, watch);
+        self.rewind(start.saturating_sub(1));
 
         self.outbox.event(Event::FilterRescanStarted {
             start: self.rescan.start,
             stop: self.rescan.end,
         });
 
-        if self.rescan.watch.is_empty() {
+        if self.rescan.watch().is_empty() {
+            return vec![];
+        }
+        // Filters will be fetched once the filter header chain is synced.
+        if self.is_scan_deferred(tree) {
             return vec![];
         }
 
@@ -410,7 +695,7 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             // Don't request further than the filter chain height.
             .map(|h| Height::min(h, height))
             .unwrap_or(height);
-        let range = start..=stop;
+        let range = start..=self.prefetch_stop(stop);
 
         if range.is_empty() {
             return vec![];
@@ -431,6 +716,18 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         matches
     }
 
+    /// Get the connected peers that serve the given kind of filter, according to the
+    /// services they announced, in random order.
+    pub fn filter_capable_peers(&self, kind: FilterKind) -> Vec<PeerId> {
+        let services = kind.services();
+
+        self.peers
+            .shuffled()
+            .filter(|(_, peer)| peer.services.has(services))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
     /// Send one or more `getcfilters` messages to random peers.
     ///
     /// If the range is greater than [`MAX_MESSAGE_CFILTERS`], request filters from multiple
@@ -440,7 +737,12 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         range: RangeInclusive<Height>,
         tree: &T,
     ) -> Result<(), GetFiltersError> {
-        if self.peers.is_empty() {
+        let peers = self.filter_capable_peers(FilterKind::Compact);
+
+        if peers.is_empty() {
+            self.outbox.event(Event::NoFilterPeers {
+                kind: FilterKind::Compact,
+            });
             return Err(GetFiltersError::NotConnected);
         }
         if range.is_empty() {
@@ -454,7 +756,7 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             .rescan
             .requests(range, tree)
             .into_iter()
-            .zip(self.peers.cycle())
+            .zip(peers.iter().cycle())
         {
             let stop_hash = tree
                 .get_block_by_height(*range.end())
@@ -473,6 +775,10 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
 
             self.outbox
                 .get_cfilters(*peer, *range.start(), stop_hash, timeout);
+
+            if let Some(peer) = self.peers.get_mut(peer) {
+                peer.requested(range);
+            }
         }
 
         Ok(())
@@ -490,6 +796,8 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
 
         // Purge stale block filters.
         self.rescan.rollback(height);
+        self.rewind(height);
+        self.sources.split_off(&(height + 1));
         // Rollback filter header chain.
         self.filters.rollback(height)?;
 
@@ -534,6 +842,23 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         Ok(())
     }
 
+    /// Report scanning progress. Progress is monotonic: heights lower than or equal to the
+    /// last one reported are ignored, unless the scan was rewound.
+    fn scanned(&mut self, height: Height) {
+        if self.scanned.map_or(true, |h| height > h) {
+            self.scanned = Some(height);
+            self.outbox.event(Event::Scanned { height });
+        }
+    }
+
+    /// Rewind scanning progress to the given height, if it was reported beyond it.
+    fn rewind(&mut self, to: Height) {
+        if self.scanned.map_or(false, |h| h > to) {
+            self.scanned = Some(to);
+            self.outbox.event(Event::ScanRewound { to });
+        }
+    }
+
     /// Called when a new peer was negotiated.
     fn peer_negotiated<T: BlockReader>(
         &mut self,
@@ -557,7 +882,9 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             Peer {
                 last_active: time,
                 height,
+                services,
                 persistent,
+                requested: Vec::new(),
             },
         );
         self.sync(tree);
@@ -592,16 +919,15 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             self.send_getcfheaders(start_height..=stop_height, tree);
         }
 
-        if self.rescan.active {
+        if self.rescan.active && !self.is_scan_deferred(tree) {
             // TODO: Don't do this too often.
-            self.get_cfilters(self.rescan.current..=self.filters.height(), tree)
-                .ok();
+            self.prefetch(tree).ok();
         }
     }
 
     /// Remove transaction from list of transactions being watch.
     fn unwatch_transaction(&mut self, txid: &Txid) -> bool {
-        self.rescan.transactions.remove(txid).is_some()
+        self.rescan.unwatch_transaction(txid)
     }
 
     /// Handle a `cfheaders` message from a peer.
@@ -709,7 +1035,14 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                 assert!(height <= tree.height());
 
                 if height == tree.height() {
-                    self.outbox.event(Event::FilterHeadersSynced { height });
+                    let (hash, _) = self.filters.tip();
+                    let (block, _) = tree.tip();
+
+                    self.outbox.event(Event::FilterHeadersSynced {
+                        height,
+                        hash: *hash,
+                        block,
+                    });
                 } else {
                     self.sync(tree);
                 }
@@ -802,6 +1135,25 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             });
         };
 
+        // Filters we're not waiting for are either late responses to earlier requests, eg.
+        // if a request timed out and was re-issued to another peer, or unsolicited. Only the
+        // latter are penalized: a late filter may no longer be cached, or its request may
+        // have been reset.
+        if !self.rescan.is_requested(height) {
+            if !self
+                .peers
+                .get(&from)
+                .map_or(false, |p| p.was_requested(height))
+            {
+                self.outbox.event(Event::PeerMisbehaved {
+                    addr: from,
+                    reason: "unsolicited `cfilter` message",
+                    severity: Severity::Minor,
+                });
+            }
+            return Ok(Vec::default());
+        }
+
         // The expected hash for this block filter.
         let header = if let Some((_, header)) = self.filters.get_header(height) {
             header
@@ -822,12 +1174,18 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         let filter = BlockFilter::new(&msg.filter);
         let block_hash = msg.block_hash;
 
-        if filter.filter_header(&prev_header) != header {
+        if !filter::verify_filter(&filter, &header, &prev_header) {
             return Err(Error::InvalidMessage {
                 from,
                 reason: "cfilter: filter hash doesn't match header",
             });
         }
+        if !filter::is_well_formed(&filter) {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "cfilter: malformed filter",
+            });
+        }
         self.outbox.event(Event::FilterReceived {
             from,
             block: block_hash,
@@ -836,18 +1194,23 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         });
 
         if self.rescan.received(height, filter, block_hash) {
+            self.sources.insert(height, from);
+            // Only keep track of the sources of filters we still have.
+            match self.rescan.cache.start() {
+                Some(start) => self.sources = self.sources.split_off(&start),
+                None => self.sources.clear(),
+            }
             let (matches, events, processed) = self.rescan.process();
             for event in events {
                 self.outbox.event(event);
             }
             // If we processed some filters, update the time to further delay requesting new
-            // filters.
+            // filters, and keep the prefetch window full.
             if processed > 0 {
                 self.last_processed = Some(self.clock.local_time());
+                self.prefetch(tree).ok();
             }
             return Ok(matches);
-        } else {
-            // Unsolicited filter.
         }
         Ok(Vec::default())
     }
@@ -908,7 +1271,9 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
             return None;
         }
         // TODO: We should select peers that are caught up to the requested height.
-        if let Some((peer, _)) = self.peers.sample() {
+        let services = FilterKind::Compact.services();
+
+        if let Some((peer, _)) = self.peers.sample_with(|_, p| p.services.has(services)) {
             let time = self.clock.local_time();
             let timeout = self.config.request_timeout;
 
@@ -918,10 +1283,12 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
                 .insert(stop_hash, (start_height, *peer, time + timeout));
 
             return Some((*peer, start_height, stop_hash));
-        } else {
-            // TODO: Emit 'NotConnected' event, and make sure we retry later, or when a
-            // peer connects.
         }
+        // Nb. The request is retried when a peer connects, since we sync on negotiation.
+        self.outbox.event(Event::NoFilterPeers {
+            kind: FilterKind::Compact,
+        });
+
         None
     }
 
@@ -938,13 +1305,20 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         stop: Height,
         tree: &T,
     ) -> Result<(), GetFiltersError> {
-        if !self.rescan.active {
+        if !self.rescan.active || self.is_scan_deferred(tree) {
             return Ok(());
         }
 
-        let start = Height::max(start, self.rescan.current);
+        // If scanning was deferred, nothing was fetched up to now, so we have to start
+        // from the current rescan height. Heights already requested are skipped.
+        let start = if self.config.defer_scan_during_ibd {
+            self.rescan.current
+        } else {
+            Height::max(start, self.rescan.current)
+        };
         let stop = Height::min(stop, self.rescan.end.unwrap_or(stop));
-        let range = start..=stop; // If the range is empty, it means we are not caught up yet.
+        // If the range is empty, it means we are not caught up yet.
+        let range = start..=self.prefetch_stop(stop);
 
         if !range.is_empty() {
             self.get_cfilters(range, tree)?;
@@ -952,6 +1326,74 @@ impl<F: Filters, C: Clock> FilterManager<F, C> {
         Ok(())
     }
 
+    /// Check a block against its cached filter. If the block contains a watched script that
+    /// the filter didn't match, the peer who served the filter is flagged.
+    fn check_filter(&mut self, block: &Block, height: Height) {
+        let (Some(filter), Some(peer)) =
+            (self.rescan.cache.get(&height), self.sources.get(&height))
+        else {
+            return;
+        };
+        let block_hash = block.block_hash();
+        let faulty = block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .filter(|out| self.rescan.watch().contains(&out.script_pubkey))
+            .any(|out| {
+                !matches!(
+                    filter.match_any(
+                        &block_hash,
+                        &mut std::iter::once(out.script_pubkey.as_bytes())
+                    ),
+                    Ok(true)
+                )
+            });
+
+        if faulty {
+            let peer = *peer;
+
+            log::warn!(target: "p2p", "Peer {peer} served a faulty filter for block #{height}");
+
+            self.outbox
+                .event(Event::FaultyFilterDetected { height, peer });
+            self.outbox.event(Event::PeerMisbehaved {
+                addr: peer,
+                reason: "faulty `cfilter`",
+                severity: Severity::Severe,
+            });
+        }
+    }
+
+    /// Request filters from the current scan height up to the end of the prefetch window.
+    /// Filters that were already requested or are cached aren't requested again.
+    fn prefetch<T: BlockReader>(&mut self, tree: &T) -> Result<(), GetFiltersError> {
+        let height = self.filters.height();
+        let stop = self.rescan.end.map_or(height, |h| Height::min(h, height));
+        let range = self.rescan.current..=self.prefetch_stop(stop);
+
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.get_cfilters(range, tree)
+    }
+
+    /// Cap the given stop height to the end of the prefetch window.
+    fn prefetch_stop(&self, stop: Height) -> Height {
+        let window = self
+            .rescan
+            .current
+            .saturating_add(self.config.filter_prefetch_window);
+
+        Height::min(stop, window)
+    }
+
+    /// Check whether filter scanning should be deferred, ie. if the option is set and the
+    /// filter header chain is still catching up with the block header chain.
+    fn is_scan_deferred<T: BlockReader>(&self, tree: &T) -> bool {
+        self.config.defer_scan_during_ibd && self.filters.height() < tree.height()
+    }
+
     fn schedule_wake(&mut self) {
         self.last_idle = None; // Disable rate-limiting for the next tick.
         self.outbox.set_timer(LocalDuration::from_secs(1));
@@ -984,8 +1426,10 @@ impl Iterator for HeightIterator {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::iter;
     use std::ops::RangeBounds;
+    use std::sync::Arc;
 
     use nakamoto_common::bitcoin;
     use nakamoto_common::bitcoin_hashes;
@@ -1013,7 +1457,7 @@ mod tests {
     use nakamoto_test::block::gen;
     use nakamoto_test::BITCOIN_HEADERS;
 
-    use crate::fsm::output;
+    use crate::fsm::{output, ScanRangeError};
 
     use super::*;
 
@@ -1257,6 +1701,59 @@ mod tests {
             .unwrap();
     }
 
+    /// Test that filters are only requested from peers that serve them.
+    #[test]
+    fn test_filter_capable_peers() {
+        let best = 144;
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(LocalTime::now()));
+        let alice: PeerId = ([1, 1, 1, 1], 8333).into();
+        let bob: PeerId = ([2, 2, 2, 2], 8333).into();
+        let carol: PeerId = ([3, 3, 3, 3], 8333).into();
+        let dave: PeerId = ([4, 4, 4, 4], 8333).into();
+
+        // Without any capable peer, the lack of peers is reported.
+        cbfmgr.rescan(
+            Bound::Included(0),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        assert!(
+            output::test::events(cbfmgr.outbox.drain()).any(|e| matches!(
+                e,
+                Event::NoFilterPeers {
+                    kind: FilterKind::Compact
+                }
+            ))
+        );
+
+        for (addr, services) in [
+            (alice, ServiceFlags::COMPACT_FILTERS | ServiceFlags::BLOOM),
+            (bob, ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK),
+            (carol, ServiceFlags::BLOOM | ServiceFlags::NETWORK),
+            (dave, ServiceFlags::NETWORK),
+        ] {
+            cbfmgr.peer_negotiated(addr, best, services, Link::Outbound, false, &tree);
+        }
+        let capable = |kind| {
+            cbfmgr
+                .filter_capable_peers(kind)
+                .into_iter()
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(capable(FilterKind::Compact), HashSet::from([alice, bob]));
+        assert_eq!(capable(FilterKind::Bloom), HashSet::from([alice]));
+
+        let requested = output::test::messages(&mut cbfmgr.outbox)
+            .filter(|(_, m)| matches!(m, NetworkMessage::GetCFilters(_)))
+            .map(|(addr, _)| addr)
+            .collect::<HashSet<_>>();
+        assert!(!requested.is_empty());
+        assert!(requested.is_subset(&HashSet::from([alice, bob])));
+    }
+
     /// Test that we don't make redundant `getcfilters` requests.
     #[test]
     #[ignore]
@@ -1349,21 +1846,20 @@ mod tests {
             .expect("Rescanning should trigger filters to be fetched");
     }
 
-    /// Test that `getcfilters` request is retried.
+    /// Test that no filters are fetched or matched until the filter header chain is synced,
+    /// when scanning is deferred.
     #[test]
-    fn test_rescan_getcfilters_retry() {
-        let birth = 11;
+    fn test_defer_scan_during_ibd() {
+        let birth = 4;
         let best = 42;
         let mut rng = fastrand::Rng::new();
         let time = LocalTime::now();
         let network = Network::Regtest;
-        let filter_type = 0x0;
         let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
-        let previous_filter_header = FilterHeader::genesis(network);
-        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
-        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+        let (watch, heights, _) = gen::watchlist_rng(birth, chain.iter(), &mut rng);
 
+        cbfmgr.config.defer_scan_during_ibd = true;
         cbfmgr.filters.clear().unwrap();
         cbfmgr.initialize(&tree);
         cbfmgr.peer_negotiated(
@@ -1374,76 +1870,630 @@ mod tests {
             false,
             &tree,
         );
+        let matched = cbfmgr.rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree);
+        assert!(matched.is_empty());
+        assert!(!output::test::messages_from(&mut cbfmgr.outbox, &remote)
+            .any(|m| matches!(m, NetworkMessage::GetCFilters(_))));
+
+        // Import part of the filter header chain. Scanning is still deferred.
+        let half = best as usize / 2;
+        let cfheaders = util::cfheaders(FilterHeader::genesis(network), &chain.tail[..half]);
+        cbfmgr
+            .inflight
+            .insert(cfheaders.stop_hash, (1, remote, time));
         cbfmgr
             .received_cfheaders(&remote, cfheaders, &tree)
             .unwrap();
-        assert_eq!(cbfmgr.filters.height(), best);
-
-        // Start rescan.
-        cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Unbounded,
-            vec![gen::script(&mut rng)],
-            &tree,
-        );
-        assert!(cbfmgr.last_processed.is_none());
-        assert_eq!(cbfmgr.rescan.current, birth);
+        assert_eq!(cbfmgr.filters.height(), half as Height);
+        assert!(!output::test::messages_from(&mut cbfmgr.outbox, &remote)
+            .any(|m| matches!(m, NetworkMessage::GetCFilters(_))));
 
-        output::test::messages_from(&mut cbfmgr.outbox, &remote)
-            .find(|m| matches!(m, NetworkMessage::GetCFilters(_)))
-            .expect("`getcfilters` sent");
-
-        // Receive a filter, but not one that we can process immediately.
+        // Import the rest of the chain. The whole rescan range is requested.
+        let (_, prev) = cbfmgr.filters.tip();
+        let cfheaders = util::cfheaders(*prev, &chain.tail[half..]);
         cbfmgr
-            .received_cfilter(&remote, cfilters[birth as usize + 1].clone(), &tree)
-            .unwrap();
-
-        assert!(cbfmgr.last_processed.is_none());
-        assert_eq!(cbfmgr.rescan.current, birth);
-
-        // Receive a filter, that we can process immediately.
+            .inflight
+            .insert(cfheaders.stop_hash, (half as Height + 1, remote, time));
         cbfmgr
-            .received_cfilter(&remote, cfilters[birth as usize].clone(), &tree)
+            .received_cfheaders(&remote, cfheaders, &tree)
             .unwrap();
+        assert_eq!(cbfmgr.filters.height(), best);
 
-        // We should be futher along now.
-        let current = birth + 2;
-
-        assert!(cbfmgr.last_processed.is_some());
-        assert_eq!(cbfmgr.rescan.current, current);
-
-        // We still have filters we are waiting for, but let's let some time pass.
-        cbfmgr.clock.elapse(DEFAULT_REQUEST_TIMEOUT);
-        cbfmgr.timer_expired(&tree);
-
-        // We expect a new request to be sent from the new starting height.
-        let (stop_hash, _) = tree.tip();
-        let expected = GetCFilters {
-            filter_type,
-            start_height: current as u32,
-            stop_hash,
-        };
+        let tip = chain.last().block_hash();
         output::test::messages_from(&mut cbfmgr.outbox, &remote)
-            .find(|m| matches!(m, NetworkMessage::GetCFilters(msg) if msg == &expected))
-            .expect("`getcfilters` sent");
+            .find(|m| {
+                matches!(
+                    m,
+                    NetworkMessage::GetCFilters(GetCFilters {
+                        start_height,
+                        stop_hash,
+                        ..
+                    }) if *start_height as Height == birth && stop_hash == &tip
+                )
+            })
+            .expect("`getcfilters` sent once filter headers are synced");
 
-        cbfmgr
-            .received_cfilter(&remote, cfilters[current as usize].clone(), &tree)
-            .unwrap();
-        assert_eq!(cbfmgr.rescan.current, current + 1);
+        let mut matches = Vec::new();
+        for msg in util::cfilters(chain.iter().skip(birth as usize)) {
+            let hashes = cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+            matches.extend(hashes.into_iter().map(|(h, _)| h));
+        }
+        assert_eq!(matches, heights);
+        assert_eq!(cbfmgr.rescan.current, best + 1);
     }
 
-    /// Test that if we start with our cfheader chain behind our header
-    /// chain, we immediately try to catch up.
+    /// Test that filters are requested ahead of the scan height, up to the prefetch window.
     #[test]
-    fn test_cfheaders_behind() {
-        let cfheader_height = 10;
-        let header_height = 15;
-
+    fn test_filter_prefetch_window() {
+        let birth = 11;
+        let best = 42;
+        let window = 5;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
         let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
-        let mut rng = fastrand::Rng::with_seed(772092983);
-        let time = LocalTime::now();
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+        let mut requested = BTreeSet::new();
+        let mut record = |cbfmgr: &mut FilterManager<_, _>| {
+            for msg in output::test::messages_from(&mut cbfmgr.outbox, &remote) {
+                if let NetworkMessage::GetCFilters(GetCFilters {
+                    start_height,
+                    stop_hash,
+                    ..
+                }) = msg
+                {
+                    let (stop, _) = tree.get_block(&stop_hash).unwrap();
+                    requested.extend(start_height as Height..=stop);
+                }
+            }
+            requested.clone()
+        };
+
+        cbfmgr.config.filter_prefetch_window = window;
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(birth),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        assert_eq!(
+            record(&mut cbfmgr),
+            (birth..=birth + window).collect::<BTreeSet<_>>(),
+            "Only filters within the window are requested"
+        );
+
+        for height in birth..=best {
+            let current = cbfmgr.rescan.current;
+            let requested = record(&mut cbfmgr);
+
+            assert_eq!(current, height);
+            assert!(
+                (current..=Height::min(current + window, best)).all(|h| requested.contains(&h)),
+                "Filters up to the prefetch window are requested"
+            );
+            assert!(requested.iter().all(|h| *h <= current + window));
+
+            cbfmgr
+                .received_cfilter(&remote, cfilters[height as usize].clone(), &tree)
+                .unwrap();
+        }
+        assert_eq!(cbfmgr.rescan.current, best + 1);
+    }
+
+    /// Test that a peer serving a filter which omits a watched script is detected.
+    #[test]
+    fn test_faulty_filter_detected() {
+        let birth = 1;
+        let best = 12;
+        let faulty = 7;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) = util::setup(
+            network,
+            best,
+            DEFAULT_FILTER_CACHE_SIZE,
+            RefClock::from(time),
+        );
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+
+        // Watch an output of the faulty block, and create a version of the block without it.
+        let block = chain[faulty as usize].clone();
+        let script = block.txdata[0].output[0].script_pubkey.clone();
+        let mut stripped = block.clone();
+        stripped.txdata[0].output[0].script_pubkey = gen::script(&mut rng);
+
+        // The filter header chain commits to the faulty filter.
+        let blocks = chain
+            .iter()
+            .map(|b| {
+                if b.block_hash() == block.block_hash() {
+                    &stripped
+                } else {
+                    b
+                }
+            })
+            .collect::<Vec<_>>();
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr
+            .filters
+            .import_headers(gen::cfheaders_from_blocks(
+                FilterHeader::genesis(network),
+                blocks.iter().skip(1).cloned(),
+            ))
+            .unwrap();
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(birth),
+            Bound::Unbounded,
+            vec![script],
+            &tree,
+        );
+
+        for msg in util::cfilters(blocks.iter().skip(birth as usize).cloned()) {
+            let matches = cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+            assert!(matches.iter().all(|(h, _)| *h != faulty));
+        }
+        cbfmgr.outbox.drain().for_each(drop);
+
+        // The block is fetched, and turns out to contain the watched script.
+        cbfmgr.received_event(
+            Event::BlockProcessed {
+                block,
+                height: faulty,
+                fees: None,
+            },
+            &tree,
+            &mut (),
+        );
+        let mut events = output::test::events(cbfmgr.outbox.drain());
+
+        assert!(events.any(|e| matches!(
+            e,
+            Event::FaultyFilterDetected { height, peer } if height == faulty && peer == remote
+        )));
+    }
+
+    #[test]
+    fn test_tampered_filter() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 6;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        // Filters are only requested if there's something to watch.
+        cbfmgr.rescan(
+            Bound::Included(1),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        let mut msg = util::cfilters(chain.tail.iter()).next().unwrap();
+        *msg.filter.last_mut().unwrap() ^= 0x1;
+
+        cbfmgr.received_event(
+            Event::MessageReceived {
+                from: remote,
+                message: Arc::new(NetworkMessage::CFilter(msg)),
+            },
+            &tree,
+            &mut (),
+        );
+        let events = output::test::events(cbfmgr.outbox.drain()).collect::<Vec<_>>();
+
+        assert!(events.iter().any(|e| matches!(
+            e,
+            Event::PeerMisbehaved { addr, reason: "invalid `cfilter` message", .. } if *addr == remote
+        )));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::FilterReceived { .. })));
+        assert!(cbfmgr.rescan.cache.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_filter() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let other: PeerId = ([99, 99, 99, 99], 8333).into();
+        let best = 6;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(1),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        // The other peer connects after the filters were requested from the first one.
+        cbfmgr.peer_negotiated(other, best, REQUIRED_SERVICES, Link::Outbound, false, &tree);
+        cbfmgr.outbox.drain().for_each(drop);
+
+        let msg = util::cfilters(chain.tail.iter()).next().unwrap();
+        let height = tree.get_block(&msg.block_hash).unwrap().0;
+
+        cbfmgr
+            .received_cfilter(&remote, msg.clone(), &tree)
+            .unwrap();
+        assert!(cbfmgr.rescan.cache.get(&height).is_some());
+        assert!(output::test::events(cbfmgr.outbox.drain())
+            .any(|e| matches!(e, Event::FilterReceived { height: h, .. } if h == height)));
+
+        // The same filter arrives again from the peer we requested it from. It's dropped
+        // without any event.
+        cbfmgr
+            .received_cfilter(&remote, msg.clone(), &tree)
+            .unwrap();
+        assert_eq!(output::test::events(cbfmgr.outbox.drain()).count(), 0);
+
+        // A peer we never requested it from sends it too.
+        cbfmgr.received_cfilter(&other, msg, &tree).unwrap();
+        assert!(
+            output::test::events(cbfmgr.outbox.drain()).any(|e| matches!(
+                e,
+                Event::PeerMisbehaved { addr, .. } if addr == other
+            ))
+        );
+    }
+
+    #[test]
+    fn test_late_filter() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 6;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(1),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        // The requests are reset before the peer responds, eg. because the rescan stalled.
+        cbfmgr.rescan.reset();
+
+        // The peer's response arrives late, and isn't cached. Since we did request it from
+        // this peer, it isn't penalized for it.
+        for msg in util::cfilters(chain.tail.iter()) {
+            cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+        }
+        assert!(cbfmgr.rescan.cache.is_empty());
+        assert!(!output::test::events(cbfmgr.outbox.drain())
+            .any(|e| matches!(e, Event::PeerMisbehaved { .. })));
+    }
+
+    #[test]
+    fn test_unsolicited_filter() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 6;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        // No rescan is active, so no filters were requested.
+        let msg = util::cfilters(chain.tail.iter()).next().unwrap();
+        cbfmgr.received_event(
+            Event::MessageReceived {
+                from: remote,
+                message: Arc::new(NetworkMessage::CFilter(msg)),
+            },
+            &tree,
+            &mut (),
+        );
+        let events = output::test::events(cbfmgr.outbox.drain()).collect::<Vec<_>>();
+
+        assert!(events.iter().any(|e| matches!(
+            e,
+            Event::PeerMisbehaved { addr, reason: "unsolicited `cfilter` message", .. }
+            if *addr == remote
+        )));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::FilterReceived { .. })));
+        assert!(cbfmgr.rescan.cache.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_filter() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 6;
+        let malformed = 3;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+
+        // The filter header chain commits to a filter that claims more elements than it
+        // contains.
+        let mut filters = gen::cfilters(chain.tail.iter()).collect::<Vec<_>>();
+        filters[malformed as usize - 1] = BlockFilter::new(&[0x02, 0x0c, 0x0b, 0x40]);
+
+        let mut parent = FilterHeader::genesis(network);
+        let headers = filters
+            .iter()
+            .map(|filter| {
+                let (hash, header) = gen::cfheader(&parent, filter);
+                parent = header;
+                (hash, header)
+            })
+            .collect();
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.filters.import_headers(headers).unwrap();
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(1),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        for (block, filter) in chain.tail.iter().zip(filters) {
+            let msg = CFilter {
+                filter_type: 0x0,
+                block_hash: block.block_hash(),
+                filter: filter.content,
+            };
+            cbfmgr.received_event(
+                Event::MessageReceived {
+                    from: remote,
+                    message: Arc::new(NetworkMessage::CFilter(msg)),
+                },
+                &tree,
+                &mut (),
+            );
+        }
+        let events = output::test::events(cbfmgr.outbox.drain()).collect::<Vec<_>>();
+
+        assert!(events.iter().any(|e| matches!(
+            e,
+            Event::PeerMisbehaved { addr, reason: "invalid `cfilter` message", .. } if *addr == remote
+        )));
+        assert!(!events.iter().any(|e| matches!(
+            e,
+            Event::FilterReceived { height, .. } | Event::FilterProcessed { height, .. }
+            if *height == malformed
+        )));
+        // Filters up to the malformed one are processed.
+        assert!(events.iter().any(
+            |e| matches!(e, Event::FilterProcessed { height, .. } if *height == malformed - 1)
+        ));
+        assert_eq!(cbfmgr.rescan.current, malformed);
+    }
+
+    #[test]
+    fn test_rescan_cached() {
+        let network = Network::Regtest;
+        let best = 6;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+        let script = chain[3].txdata[0].output[0].script_pubkey.clone();
+        let cached = [&chain[1], &chain[2], &chain[3], &chain[5]];
+
+        // Nothing matches before the script is watched.
+        cbfmgr.outbox.drain().for_each(drop);
+        cbfmgr.rescan_cached(2, cached, &tree);
+        assert!(!output::test::events(cbfmgr.outbox.drain())
+            .any(|e| matches!(e, Event::BlockMatched { .. })));
+
+        cbfmgr.watch_script(script, None, &tree);
+        cbfmgr.outbox.drain().for_each(drop);
+
+        let missing = cbfmgr.rescan_cached(2, cached, &tree);
+        let matched = output::test::events(cbfmgr.outbox.drain())
+            .filter_map(|e| match e {
+                Event::BlockMatched { height, block } => Some((height, block.block_hash())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(matched, vec![(3, chain[3].block_hash())]);
+        assert_eq!(
+            missing,
+            vec![chain[4].block_hash(), chain[6].block_hash()],
+            "Blocks that aren't cached must be downloaded"
+        );
+
+        // Once downloaded, the missing blocks are matched too.
+        cbfmgr.watch_script(
+            chain[4].txdata[0].output[0].script_pubkey.clone(),
+            None,
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        for height in [4, 6] {
+            cbfmgr.received_event(
+                Event::BlockProcessed {
+                    block: chain[height].clone(),
+                    height: height as Height,
+                    fees: None,
+                },
+                &tree,
+                &mut (),
+            );
+        }
+        let matched = output::test::events(cbfmgr.outbox.drain())
+            .filter_map(|e| match e {
+                Event::BlockMatched { height, .. } => Some(height),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(matched, vec![4]);
+    }
+
+    /// Test that `getcfilters` request is retried.
+    #[test]
+    fn test_rescan_getcfilters_retry() {
+        let birth = 11;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let filter_type = 0x0;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let previous_filter_header = FilterHeader::genesis(network);
+        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), best);
+
+        // Start rescan.
+        cbfmgr.rescan(
+            Bound::Included(birth),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        assert!(cbfmgr.last_processed.is_none());
+        assert_eq!(cbfmgr.rescan.current, birth);
+
+        output::test::messages_from(&mut cbfmgr.outbox, &remote)
+            .find(|m| matches!(m, NetworkMessage::GetCFilters(_)))
+            .expect("`getcfilters` sent");
+
+        // Receive a filter, but not one that we can process immediately.
+        cbfmgr
+            .received_cfilter(&remote, cfilters[birth as usize + 1].clone(), &tree)
+            .unwrap();
+
+        assert!(cbfmgr.last_processed.is_none());
+        assert_eq!(cbfmgr.rescan.current, birth);
+
+        // Receive a filter, that we can process immediately.
+        cbfmgr
+            .received_cfilter(&remote, cfilters[birth as usize].clone(), &tree)
+            .unwrap();
+
+        // We should be futher along now.
+        let current = birth + 2;
+
+        assert!(cbfmgr.last_processed.is_some());
+        assert_eq!(cbfmgr.rescan.current, current);
+
+        // We still have filters we are waiting for, but let's let some time pass.
+        cbfmgr.clock.elapse(DEFAULT_REQUEST_TIMEOUT);
+        cbfmgr.timer_expired(&tree);
+
+        // We expect a new request to be sent from the new starting height.
+        let (stop_hash, _) = tree.tip();
+        let expected = GetCFilters {
+            filter_type,
+            start_height: current as u32,
+            stop_hash,
+        };
+        output::test::messages_from(&mut cbfmgr.outbox, &remote)
+            .find(|m| matches!(m, NetworkMessage::GetCFilters(msg) if msg == &expected))
+            .expect("`getcfilters` sent");
+
+        cbfmgr
+            .received_cfilter(&remote, cfilters[current as usize].clone(), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.current, current + 1);
+    }
+
+    /// Test that if we start with our cfheader chain behind our header
+    /// chain, we immediately try to catch up.
+    #[test]
+    fn test_cfheaders_behind() {
+        let cfheader_height = 10;
+        let header_height = 15;
+
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(772092983);
+        let time = LocalTime::now();
 
         let mut cbfmgr = {
             let cache = FilterCache::load(store::memory::Memory::genesis(network)).unwrap();
@@ -1488,6 +2538,87 @@ mod tests {
         }).unwrap();
     }
 
+    #[test]
+    fn test_filter_sync_stalled() {
+        let cfheader_height = 10;
+        let header_height = 15;
+
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(772092983);
+        let clock = RefClock::from(LocalTime::now());
+
+        let mut cbfmgr = {
+            let cache = FilterCache::load(store::memory::Memory::genesis(network)).unwrap();
+            FilterManager::new(
+                Config::default(),
+                fastrand::Rng::new(),
+                cache,
+                clock.clone(),
+            )
+        };
+        let chain = gen::blockchain(network.genesis_block(), header_height, &mut rng);
+        let cfheaders =
+            gen::cfheaders_from_blocks(FilterHeader::genesis(network), chain.tail.iter());
+        let tree = {
+            let params = network.params();
+            let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+            BlockCache::from(store::Memory::new(headers), params, &[]).unwrap()
+        };
+        let stalled = |cbfmgr: &mut FilterManager<_, _>| {
+            output::test::events(cbfmgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::FilterSyncStalled {
+                        stalled_for,
+                        height,
+                    } => Some((stalled_for, height)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The filter header chain is behind, and the remote never sends us filter headers.
+        cbfmgr
+            .filters
+            .import_headers(cfheaders[..cfheader_height].to_vec())
+            .unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            header_height,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.timer_expired(&tree);
+        assert!(stalled(&mut cbfmgr).is_empty());
+
+        clock.elapse(DEFAULT_STALL_TIMEOUT);
+        cbfmgr.timer_expired(&tree);
+        assert_eq!(
+            stalled(&mut cbfmgr),
+            vec![(DEFAULT_STALL_TIMEOUT, cfheader_height as Height)]
+        );
+
+        // The stall is only reported once.
+        clock.elapse(DEFAULT_STALL_TIMEOUT);
+        cbfmgr.timer_expired(&tree);
+        assert!(stalled(&mut cbfmgr).is_empty());
+
+        // Once the filter header chain has caught up, no stall is reported.
+        cbfmgr
+            .filters
+            .import_headers(cfheaders[cfheader_height..].to_vec())
+            .unwrap();
+
+        for _ in 0..3 {
+            clock.elapse(DEFAULT_STALL_TIMEOUT);
+            cbfmgr.timer_expired(&tree);
+            assert!(stalled(&mut cbfmgr).is_empty());
+        }
+    }
+
     #[test]
     fn test_partial_cache_hit_overlap_max() {
         // Head              8
@@ -1926,7 +3057,62 @@ mod tests {
         assert_eq!(matched.len(), matches.len());
         assert_eq!(matched.iter().map(|(h, _)| *h).collect::<Vec<_>>(), matches);
         assert_eq!(cbfmgr.rescan.current, best + 1);
-        assert_eq!(cbfmgr.rescan.watch, watch.into_iter().collect());
+        assert_eq!(cbfmgr.rescan.watch(), &watch.into_iter().collect());
+    }
+
+    #[test]
+    fn test_watchlist() {
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 17;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+        let (watch, _, _) = gen::watchlist_rng(1, chain.iter(), &mut rng);
+        let script = watch.first().unwrap().clone();
+        let outpoint = OutPoint::new(chain[3].txdata[0].txid(), 0);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        let sizes = |cbfmgr: &mut FilterManager<_, _>| {
+            output::test::events(cbfmgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::WatchlistUpdated { size } => Some(size),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Adding the same script twice only counts it once, and only rescans once.
+        assert!(cbfmgr.watch_script(script.clone(), None, &tree).is_empty());
+        assert_eq!(sizes(&mut cbfmgr), vec![1]);
+        assert!(cbfmgr
+            .watch_script(script.clone(), Some(1), &tree)
+            .is_empty());
+        assert_eq!(sizes(&mut cbfmgr), vec![1]);
+        assert!(!cbfmgr.rescan.active);
+
+        cbfmgr.watch_outpoint(outpoint);
+        assert_eq!(sizes(&mut cbfmgr), vec![2]);
+
+        cbfmgr.unwatch_script(&script);
+        assert_eq!(sizes(&mut cbfmgr), vec![1]);
+        assert!(cbfmgr.rescan.watch().is_empty());
+
+        // Adding a new script with a rescan height triggers a rescan.
+        cbfmgr.watch_script(script, Some(1), &tree);
+        assert!(cbfmgr.rescan.active);
+        assert_eq!(cbfmgr.rescan.start, 1);
+        assert_eq!(sizes(&mut cbfmgr), vec![2]);
     }
 
     /// Test that we re-request all filters after blocks are reverted and eventually
@@ -2085,6 +3271,161 @@ mod tests {
         }
     }
 
+    /// Test that rescan ranges beyond the tip are clamped, and that invalid ranges are
+    /// rejected.
+    #[test]
+    fn test_rescan_range() {
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let best = 17;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+        let (watch, _, _) = gen::watchlist_rng(1, chain.iter(), &mut rng);
+        let error = |cbfmgr: &mut FilterManager<_, _>| {
+            output::test::events(cbfmgr.outbox.drain()).find_map(|e| match e {
+                Event::Error {
+                    kind: ErrorKind::Other,
+                    error,
+                } => error.downcast_ref::<ScanRangeError>().cloned(),
+                _ => None,
+            })
+        };
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.outbox.drain().for_each(drop);
+
+        // A stop height beyond the tip is clamped.
+        cbfmgr.rescan(
+            Bound::Included(4),
+            Bound::Included(99),
+            watch.clone(),
+            &tree,
+        );
+        assert_matches!(
+            output::test::events(cbfmgr.outbox.drain()).next(),
+            Some(Event::FilterRescanStarted { start: 4, stop: Some(h) }) if h == best
+        );
+        assert_eq!(cbfmgr.rescan.end, Some(best));
+
+        // Inverted ranges are rejected, and the current rescan is left untouched.
+        cbfmgr.rescan(Bound::Included(8), Bound::Included(4), watch.clone(), &tree);
+        assert_eq!(
+            error(&mut cbfmgr),
+            Some(ScanRangeError::Inverted { start: 8, stop: 4 })
+        );
+        cbfmgr.rescan(Bound::Included(0), Bound::Excluded(0), watch.clone(), &tree);
+        assert_eq!(
+            error(&mut cbfmgr),
+            Some(ScanRangeError::Inverted { start: 0, stop: 0 })
+        );
+        assert_eq!((cbfmgr.rescan.start, cbfmgr.rescan.end), (4, Some(best)));
+
+        // Bounded ranges starting beyond the tip are rejected.
+        cbfmgr.rescan(Bound::Unbounded, Bound::Included(99), watch.clone(), &tree);
+        assert_eq!(
+            error(&mut cbfmgr),
+            Some(ScanRangeError::BeyondTip {
+                start: best + 1,
+                tip: best
+            })
+        );
+
+        // Unbounded ranges keep up with the tip.
+        cbfmgr.rescan(Bound::Unbounded, Bound::Unbounded, watch, &tree);
+        assert_eq!(error(&mut cbfmgr), None);
+        assert_eq!((cbfmgr.rescan.start, cbfmgr.rescan.end), (best + 1, None));
+    }
+
+    #[test]
+    fn test_scanned_monotonic() {
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 6;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, time);
+        let received = |cbfmgr: &mut FilterManager<_, _>, filter: CFilter| {
+            cbfmgr.received_event(
+                Event::MessageReceived {
+                    from: remote,
+                    message: Arc::new(NetworkMessage::CFilter(filter)),
+                },
+                &tree,
+                &mut (),
+            );
+        };
+        let scanned = |cbfmgr: &mut FilterManager<_, _>| {
+            output::test::events(cbfmgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::Scanned { height } => Some(Ok(height)),
+                    Event::ScanRewound { to } => Some(Err(to)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            remote,
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(1),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        cbfmgr.outbox.drain().for_each(drop);
+
+        for filter in util::cfilters(chain.iter().skip(1)) {
+            received(&mut cbfmgr, filter);
+        }
+        let heights = scanned(&mut cbfmgr);
+        assert_eq!(heights.last(), Some(&Ok(best + 1)));
+        assert!(heights.windows(2).all(|w| w[0] < w[1]), "{:?}", heights);
+
+        // Receiving the same filters again doesn't report lower heights.
+        for filter in util::cfilters(chain.iter().skip(3)) {
+            received(&mut cbfmgr, filter);
+        }
+        assert_eq!(scanned(&mut cbfmgr), vec![]);
+
+        // After a re-org, scanning progress is rewound before lower heights are reported.
+        cbfmgr.rollback(3).unwrap();
+        assert_eq!(scanned(&mut cbfmgr), vec![Err(3)]);
+
+        cbfmgr.sync(&tree);
+        cbfmgr.outbox.drain().for_each(drop);
+
+        let (_, parent) = cbfmgr.filters.tip();
+        let cfheaders = util::cfheaders(*parent, &chain.tail[3..]);
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        cbfmgr.outbox.drain().for_each(drop);
+
+        for filter in util::cfilters(chain.iter().skip(4)) {
+            received(&mut cbfmgr, filter);
+        }
+        let heights = scanned(&mut cbfmgr);
+        assert_eq!(heights.first(), Some(&Ok(5)));
+        assert_eq!(heights.last(), Some(&Ok(best + 1)));
+        assert!(heights.windows(2).all(|w| w[0] < w[1]), "{:?}", heights);
+
+        // A rescan from a lower height also rewinds progress.
+        cbfmgr.rescan(
+            Bound::Included(2),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        assert_eq!(scanned(&mut cbfmgr).first(), Some(&Err(1)));
+    }
+
     #[quickcheck]
     fn prop_rescan(birth: Height, best: Height, cache: usize) -> quickcheck::TestResult {
         // We don't gain anything by testing longer chains.
@@ -2152,6 +3493,7 @@ mod tests {
         }
 
         let cfheaders = util::cfheaders(FilterHeader::genesis(network), &chain.tail);
+        let filter_hash = *cfheaders.filter_hashes.last().unwrap();
         let height = cbfmgr
             .received_cfheaders(&remote, cfheaders, &tree)
             .unwrap();
@@ -2175,7 +3517,13 @@ mod tests {
             .unwrap();
 
         output::test::events(cbfmgr.outbox.drain())
-            .find(|e| matches!(e, Event::FilterHeadersSynced { height } if height == &best))
+            .find(|e| {
+                matches!(
+                    e,
+                    Event::FilterHeadersSynced { height, hash, block }
+                    if height == &best && hash == &filter_hash && block == &tip
+                )
+            })
             .unwrap();
 
         // Create and shuffle filters so that they arrive out-of-order.
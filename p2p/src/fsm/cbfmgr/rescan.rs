@@ -3,15 +3,19 @@ use std::collections::BTreeSet;
 use std::ops::RangeInclusive;
 use std::rc::Rc;
 
+use nakamoto_common::bitcoin::hashes::Hash as _;
 use nakamoto_common::bitcoin::util::bip158;
-use nakamoto_common::bitcoin::{Script, Txid};
-use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::bitcoin::{OutPoint, Script, Txid};
+use nakamoto_common::block::filter::{BlockFilter, FilterHash};
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::block::{BlockHash, Height};
-use nakamoto_common::collections::{HashMap, HashSet};
+use nakamoto_common::block::{Block, BlockHash, Height};
+use nakamoto_common::collections::{HashMap, HashSet, LruCache};
 
 use super::{Event, FilterCache, HeightIterator, MAX_MESSAGE_CFILTERS};
 
+/// Number of filter match results memoized.
+pub const MATCH_CACHE_SIZE: usize = 1024;
+
 /// Filter (re)scan state.
 #[derive(Debug, Default)]
 pub struct Rescan {
@@ -26,15 +30,26 @@ pub struct Rescan {
     pub end: Option<Height>,
     /// Filter cache.
     pub cache: FilterCache<Rc<BlockFilter>>,
-    /// Addresses and outpoints to watch for.
-    pub watch: HashSet<Script>,
-    /// Transactions to watch for.
-    pub transactions: HashMap<Txid, HashSet<Script>>,
+    /// Emit a progress event every time this many filters are processed.
+    /// A value of zero disables progress events.
+    pub progress_interval: Height,
+    /// Number of filters matched since the rescan started.
+    pub matched: usize,
 
     /// Filters requested and remaining to download.
     requested: BTreeSet<Height>,
     /// Received filters waiting to be matched.
     received: HashMap<Height, (Rc<BlockFilter>, BlockHash, bool)>,
+    /// Scripts to watch for.
+    watch: HashSet<Script>,
+    /// Transactions to watch for, with their output scripts.
+    transactions: HashMap<Txid, HashSet<Script>>,
+    /// Outpoints to watch for. Since filters don't commit to outpoints, these are matched
+    /// against the blocks we download.
+    outpoints: HashSet<OutPoint>,
+    /// Recent filter match results, by block and filter. Only valid for the current
+    /// watchlist.
+    matches: LruCache<(BlockHash, FilterHash), Vec<Script>>,
 }
 
 impl Rescan {
@@ -44,6 +59,7 @@ impl Rescan {
 
         Self {
             cache,
+            matches: LruCache::new(MATCH_CACHE_SIZE),
             ..Self::default()
         }
     }
@@ -60,6 +76,8 @@ impl Rescan {
         self.current = start;
         self.end = end;
         self.watch = watch.into_iter().collect();
+        self.matched = 0;
+        self.watchlist_changed();
         self.requested.clear();
     }
 
@@ -86,6 +104,11 @@ impl Rescan {
         self.cache.rollback(to)
     }
 
+    /// Check whether the filter at the given height was requested and not yet received.
+    pub fn is_requested(&self, height: Height) -> bool {
+        self.requested.contains(&height)
+    }
+
     /// A filter was received.
     pub fn received(&mut self, height: Height, filter: BlockFilter, block_hash: BlockHash) -> bool {
         let requested = self.requested.remove(&height);
@@ -111,22 +134,46 @@ impl Rescan {
         let old = current;
 
         while let Some((filter, block_hash, cached)) = self.received.remove(&current) {
-            let (matched, valid) = if let Ok(matched) = self.match_filter(&filter, &block_hash) {
-                (matched, true)
-            } else {
-                (false, false)
+            // Nb. Filters are checked to be well-formed when they are received, so we
+            // shouldn't fail to match them. If we do, the filter is treated as a match, so
+            // that the block is fetched and matched directly, instead of being missed.
+            let (matched, scripts) = match self.match_filter(&filter, &block_hash) {
+                Ok(scripts) => (!scripts.is_empty(), scripts),
+                Err(err) => {
+                    log::error!(target: "p2p", "Failed to match filter for {block_hash}: {err}");
+
+                    (true, Vec::new())
+                }
             };
 
             if matched {
                 matches.push((current, block_hash));
+                self.matched += 1;
             }
             events.push(Event::FilterProcessed {
                 block: block_hash,
                 height: current,
-                valid,
                 matched,
                 cached,
             });
+            if !scripts.is_empty() {
+                events.push(Event::CompactFilterMatched {
+                    block: block_hash,
+                    height: current,
+                    scripts,
+                });
+            }
+
+            if self.progress_interval > 0
+                && (current + 1 - self.start) % self.progress_interval == 0
+            {
+                events.push(Event::FilterRescanProgress {
+                    current,
+                    start: self.start,
+                    stop: self.end,
+                    matched: self.matched,
+                });
+            }
             current += 1;
         }
         self.current = current;
@@ -141,24 +188,160 @@ impl Rescan {
         (matches, events, current - old)
     }
 
-    /// Check whether a filter matches one of our scripts.
+    /// Get the watched scripts.
+    pub fn watch(&self) -> &HashSet<Script> {
+        &self.watch
+    }
+
+    /// Get the watched transactions, with their output scripts.
+    #[cfg(test)]
+    pub fn transactions(&self) -> &HashMap<Txid, HashSet<Script>> {
+        &self.transactions
+    }
+
+    /// Get the watched outpoints.
+    pub fn outpoints(&self) -> &HashSet<OutPoint> {
+        &self.outpoints
+    }
+
+    /// Add scripts to the watchlist.
+    pub fn watch_scripts(&mut self, scripts: impl IntoIterator<Item = Script>) {
+        let len = self.watch.len();
+
+        self.watch.extend(scripts);
+
+        if self.watch.len() != len {
+            self.watchlist_changed();
+        }
+    }
+
+    /// Add a script to the watchlist. Returns `true` if it wasn't already watched.
+    pub fn watch_script(&mut self, script: Script) -> bool {
+        let added = self.watch.insert(script);
+
+        if added {
+            self.watchlist_changed();
+        }
+        added
+    }
+
+    /// Remove a script from the watchlist. Returns `true` if it was watched.
+    pub fn unwatch_script(&mut self, script: &Script) -> bool {
+        let removed = self.watch.remove(script);
+
+        if removed {
+            self.watchlist_changed();
+        }
+        removed
+    }
+
+    /// Add a transaction to the watchlist, with its output scripts.
+    pub fn watch_transaction(&mut self, txid: Txid, scripts: HashSet<Script>) {
+        self.transactions.insert(txid, scripts);
+        self.watchlist_changed();
+    }
+
+    /// Remove a transaction from the watchlist. Returns `true` if it was watched.
+    pub fn unwatch_transaction(&mut self, txid: &Txid) -> bool {
+        let removed = self.transactions.remove(txid).is_some();
+
+        if removed {
+            self.watchlist_changed();
+        }
+        removed
+    }
+
+    /// Add an outpoint to the watchlist. Returns `true` if it wasn't already watched.
+    ///
+    /// Nb. Outpoints aren't matched against filters, so filter match results are kept.
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint) -> bool {
+        self.outpoints.insert(outpoint)
+    }
+
+    /// Remove an outpoint from the watchlist. Returns `true` if it was watched.
+    pub fn unwatch_outpoint(&mut self, outpoint: &OutPoint) -> bool {
+        self.outpoints.remove(outpoint)
+    }
+
+    /// Discard match results obtained with the previous watchlist. Called whenever the
+    /// scripts or transactions watched change.
+    fn watchlist_changed(&mut self) {
+        self.matches.clear();
+    }
+
+    /// Check whether a filter matches any of our scripts, and return the matching scripts.
+    /// Returns an empty list if the filter didn't match.
+    ///
+    /// Results are memoized by block and filter, since rescans repeatedly match the same
+    /// filters. A different filter for the same block, eg. from another peer, is matched
+    /// again.
     pub fn match_filter(
+        &mut self,
+        filter: &BlockFilter,
+        block_hash: &BlockHash,
+    ) -> Result<Vec<Script>, bip158::Error> {
+        let key = (*block_hash, FilterHash::hash(&filter.content));
+
+        if let Some(matched) = self.matches.get(&key) {
+            return Ok(matched.clone());
+        }
+        let matched = self.match_watchlist(filter, block_hash)?;
+        self.matches.put(key, matched.clone());
+
+        Ok(matched)
+    }
+
+    /// Check whether a block pays to any of the watched scripts, contains a watched
+    /// transaction, or spends a watched outpoint or an output of a watched transaction.
+    ///
+    /// Nb. Since a block doesn't include the scripts spent by its inputs, a block spending
+    /// from a watched script is only matched if the spent outpoint is watched as well.
+    pub fn match_block(&self, block: &Block) -> bool {
+        block.txdata.iter().any(|tx| {
+            self.transactions.contains_key(&tx.txid())
+                || tx
+                    .output
+                    .iter()
+                    .any(|out| self.watch.contains(&out.script_pubkey))
+                || tx.input.iter().any(|input| {
+                    self.outpoints.contains(&input.previous_output)
+                        || self.transactions.contains_key(&input.previous_output.txid)
+                })
+        })
+    }
+
+    /// Match a filter against the watchlist.
+    fn match_watchlist(
         &self,
         filter: &BlockFilter,
         block_hash: &BlockHash,
-    ) -> Result<bool, bip158::Error> {
-        let mut matched = false;
+    ) -> Result<Vec<Script>, bip158::Error> {
+        let mut matched = Vec::new();
 
         // Match scripts first, then match transactions. All outputs of a transaction must
         // match to consider the transaction matched.
-        if !self.watch.is_empty() {
-            matched = filter.match_any(block_hash, &mut self.watch.iter().map(|k| k.as_bytes()))?;
+        //
+        // Nb. Scripts are only matched one by one once we know the filter matches, since
+        // each membership check decodes the whole filter.
+        if !self.watch.is_empty()
+            && filter.match_any(block_hash, &mut self.watch.iter().map(|k| k.as_bytes()))?
+        {
+            for script in &self.watch {
+                if filter.match_any(block_hash, &mut std::iter::once(script.as_bytes()))? {
+                    matched.push(script.clone());
+                }
+            }
         }
-        if !matched && !self.transactions.is_empty() {
-            matched = self.transactions.values().any(|outs| {
-                let mut outs = outs.iter().map(|k| k.as_bytes());
-                filter.match_all(block_hash, &mut outs).unwrap_or(false)
-            })
+        for outs in self.transactions.values() {
+            let mut iter = outs.iter().map(|k| k.as_bytes());
+
+            if filter.match_all(block_hash, &mut iter).unwrap_or(false) {
+                for script in outs {
+                    if !matched.contains(script) {
+                        matched.push(script.clone());
+                    }
+                }
+            }
         }
         Ok(matched)
     }
@@ -234,6 +417,7 @@ mod tests {
     use super::*;
     use nakamoto_common::network::Network;
     use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
 
     #[test]
     fn test_rescan_requests() {
@@ -259,4 +443,198 @@ mod tests {
             vec![0..=3, 7..=8, 10..=11, 15..=16]
         );
     }
+
+    #[test]
+    fn test_rescan_progress() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 10, &mut rng);
+        let watch = [3, 7]
+            .iter()
+            .map(|h| chain[*h].txdata[0].output[0].script_pubkey.clone())
+            .collect::<Vec<_>>();
+        let mut rescan = Rescan {
+            progress_interval: 4,
+            ..Rescan::default()
+        };
+        rescan.restart(1, Some(10), watch);
+        rescan.requested.extend(1..=10);
+
+        for (height, block) in chain.iter().enumerate().skip(1) {
+            assert!(rescan.received(height as Height, gen::cfilter(block), block.block_hash()));
+        }
+        let (matches, events, processed) = rescan.process();
+        let progress = events
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::FilterRescanProgress {
+                    current,
+                    start,
+                    stop,
+                    matched,
+                } => Some((current, start, stop, matched)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            matches.iter().map(|(h, _)| *h).collect::<Vec<_>>(),
+            vec![3, 7]
+        );
+        assert_eq!(processed, 10);
+        assert_eq!(progress, vec![(4, 1, Some(10), 1), (8, 1, Some(10), 2)]);
+        assert_eq!(rescan.matched, 2);
+    }
+
+    #[test]
+    fn test_unmatchable_filter() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 3, &mut rng);
+        let script = chain[3].txdata[0].output[0].script_pubkey.clone();
+        let mut rescan = Rescan::default();
+
+        rescan.restart(1, Some(3), vec![script]);
+        rescan.requested.extend(1..=3);
+
+        // The filter at height 2 is truncated, and can't be decoded.
+        for (height, block) in chain.iter().enumerate().skip(1) {
+            let filter = if height == 2 {
+                BlockFilter::new(&[0x05])
+            } else {
+                gen::cfilter(block)
+            };
+            assert!(rescan.received(height as Height, filter, block.block_hash()));
+        }
+        let (matches, events, processed) = rescan.process();
+
+        // The block is fetched, as if the filter matched, rather than skipped.
+        assert_eq!(processed, 3);
+        assert_eq!(
+            matches,
+            vec![(2, chain[2].block_hash()), (3, chain[3].block_hash())]
+        );
+        assert!(events.iter().any(|e| matches!(
+            e,
+            Event::FilterProcessed {
+                height: 2,
+                matched: true,
+                ..
+            }
+        )));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::CompactFilterMatched { height: 2, .. })));
+    }
+
+    #[test]
+    fn test_compact_filter_matched() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 10, &mut rng);
+        let script = chain[3].txdata[0].output[0].script_pubkey.clone();
+        let unmatched = Script::from(vec![0x51]);
+        let mut rescan = Rescan::default();
+
+        rescan.restart(1, Some(10), vec![script.clone(), unmatched]);
+        rescan.requested.extend(1..=10);
+
+        for (height, block) in chain.iter().enumerate().skip(1) {
+            assert!(rescan.received(height as Height, gen::cfilter(block), block.block_hash()));
+        }
+        let (_, events, _) = rescan.process();
+        let matched = events
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::CompactFilterMatched {
+                    block,
+                    height,
+                    scripts,
+                } => Some((block, height, scripts)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(matched, vec![(chain[3].block_hash(), 3, vec![script])]);
+    }
+
+    #[test]
+    fn test_match_filter_memoized() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 2, &mut rng);
+        let block = &chain[1];
+        let filter = gen::cfilter(block);
+        let hash = block.block_hash();
+        let script = block.txdata[0].output[0].script_pubkey.clone();
+        let mut rescan = Rescan::new(1);
+
+        assert!(rescan.match_filter(&filter, &hash).unwrap().is_empty());
+        assert_eq!(rescan.matches.len(), 1);
+
+        // Memoized results are discarded once the watchlist changes.
+        assert!(rescan.watch_script(script.clone()));
+        assert!(rescan.matches.is_empty());
+
+        assert_eq!(
+            rescan.match_filter(&filter, &hash).unwrap(),
+            vec![script.clone()]
+        );
+        let key = (hash, FilterHash::hash(&filter.content));
+        assert_eq!(rescan.matches.get(&key), Some(&vec![script.clone()]));
+
+        // Re-watching the same script keeps the memoized results.
+        assert!(!rescan.watch_script(script.clone()));
+        assert_eq!(rescan.matches.len(), 1);
+
+        // A different filter for the same block isn't answered from memory.
+        let other = gen::cfilter(&chain[2]);
+        assert!(rescan.match_filter(&other, &hash).unwrap().is_empty());
+        assert_eq!(rescan.matches.len(), 2);
+        assert_eq!(
+            rescan.match_filter(&filter, &hash).unwrap(),
+            vec![script.clone()]
+        );
+
+        // Un-watching discards them.
+        assert!(rescan.unwatch_script(&script));
+        assert!(rescan.matches.is_empty());
+        assert!(rescan.match_filter(&filter, &hash).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_match_block() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = gen::genesis(&mut rng);
+        let chain = gen::blockchain(genesis, 2, &mut rng);
+        let coinbase = chain[1].txdata[0].clone();
+        let outpoint = OutPoint::new(coinbase.txid(), 0);
+        // A block spending the output of the previous block's coinbase.
+        let mut block = chain[2].clone();
+        block.txdata.push(gen::transaction_with(
+            outpoint,
+            coinbase.output[0].value,
+            &mut rng,
+        ));
+
+        let mut rescan = Rescan::default();
+        assert!(!rescan.match_block(&chain[1]));
+        assert!(!rescan.match_block(&block));
+
+        // A watched script paid to by the block.
+        rescan.watch_script(coinbase.output[0].script_pubkey.clone());
+        assert!(rescan.match_block(&chain[1]));
+        // Spending from a watched script isn't visible in the block.
+        assert!(!rescan.match_block(&block));
+
+        // A watched outpoint spent by the block.
+        rescan.watch_outpoint(outpoint);
+        assert!(rescan.match_block(&block));
+
+        // A watched transaction, or one of its outputs, spent by the block.
+        let mut rescan = Rescan::default();
+        rescan.watch_transaction(coinbase.txid(), HashSet::default());
+        assert!(rescan.match_block(&chain[1]));
+        assert!(rescan.match_block(&block));
+    }
 }
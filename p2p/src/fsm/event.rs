@@ -6,8 +6,9 @@ use nakamoto_common::bitcoin::network::address::Address;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
-use nakamoto_common::bitcoin::{MerkleBlock, Transaction, Txid};
-use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::bitcoin::{MerkleBlock, Script, Transaction, Txid};
+use nakamoto_common::block::filter::{BlockFilter, FilterHash};
+use nakamoto_common::block::time::Humanize;
 use nakamoto_common::block::{Block, BlockHash, BlockHeader, Height};
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::Source;
@@ -15,10 +16,42 @@ use nakamoto_net::Disconnect;
 
 use crate::fsm;
 use crate::fsm::fees::FeeEstimate;
-use crate::fsm::{Link, LocalTime, PeerId};
+use crate::fsm::{FilterKind, Link, LocalDuration, LocalTime, PeerId};
+
+/// Recording and replaying of event streams.
+#[cfg(feature = "serde")]
+pub mod replay;
+#[cfg(feature = "serde")]
+pub use replay::{EventLog, EventReplay};
+
+/// Block headers imported into the active chain.
+///
+/// Shared by [`Event::BlockHeadersImported`] and [`Event::BlockFilterImported`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChainImport {
+    /// New tip hash.
+    pub hash: BlockHash,
+    /// New tip height.
+    pub height: Height,
+    /// Block headers connected to the active chain.
+    pub connected: NonEmpty<(Height, BlockHeader)>,
+    /// Block headers reverted from the active chain.
+    pub reverted: Vec<(Height, BlockHeader)>,
+    /// Set if this import triggered a chain reorganization.
+    pub reorg: bool,
+    /// Number of blocks reverted from the active chain, ie. the depth of the re-org.
+    pub reorg_depth: usize,
+}
 
 /// Event emitted by the client, after the "loading" phase is over.
+///
+/// With the `serde` feature enabled, events are serialized in the externally-tagged
+/// representation, ie. with the variant name as the key. Variants carrying errors or
+/// network messages are serialized using their string representation, and can't be
+/// deserialized.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Event {
     /// The node is initializing its state machine and about to start network activity.
     Initializing,
@@ -35,15 +68,19 @@ pub enum Event {
     /// A BloomFilter was loaded to a peer
     PeerLoadedBloomFilter {
         /// the filter loaded to peer
+        #[cfg_attr(feature = "serde", serde(with = "encoding::consensus"))]
         filter: FilterLoad,
         /// Peer address.
         peer: PeerId,
+        /// Estimated false-positive rate of the loaded filter.
+        fp_rate: f64,
     },
     /// A BloomFilter was received from client
     LoadBloomFilter {
         /// the peer address
         addr: PeerId,
         /// the bloom filter sent from client
+        #[cfg_attr(feature = "serde", serde(with = "encoding::consensus"))]
         filter: FilterLoad,
     },
 
@@ -57,21 +94,44 @@ pub enum Event {
         local_addr: net::SocketAddr,
         /// Connection link.
         link: Link,
+        /// Connection attempt id. For outbound connections, this matches the id of the
+        /// [`Event::PeerConnecting`] event.
+        attempt_id: u64,
+        /// Time it took to establish the connection, since the [`Event::PeerConnecting`]
+        /// event. Always zero for inbound connections.
+        connect_latency: LocalDuration,
     },
     /// Outbound peer connection initiated.
+    ///
+    /// Nb. Only IP addresses are connected to. Onion addresses are stored and relayed, but
+    /// never dialed.
     PeerConnecting {
         /// Peer address.
         addr: PeerId,
         /// Address source.
         source: Source,
         /// Peer services.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::services"))]
         services: ServiceFlags,
+        /// Connection attempt id, unique to this connection attempt.
+        attempt_id: u64,
+    },
+    /// A candidate peer address was skipped instead of connected to.
+    #[cfg_attr(feature = "serde", serde(skip_deserializing))]
+    PeerSkipped {
+        /// Peer address.
+        addr: PeerId,
+        /// Reason the address was skipped.
+        #[cfg_attr(feature = "serde", serde(skip_deserializing))]
+        reason: &'static str,
     },
     /// Peer disconnected after successful connection.
+    #[cfg_attr(feature = "serde", serde(skip_deserializing))]
     PeerDisconnected {
         /// Peer address.
         addr: PeerId,
         /// Reason for disconnection.
+        #[cfg_attr(feature = "serde", serde(serialize_with = "encoding::display"))]
         reason: Disconnect<fsm::DisconnectReason>,
     },
     /// Peer timed out when waiting for response.
@@ -80,12 +140,32 @@ pub enum Event {
         /// Peer address.
         addr: PeerId,
     },
+    /// A peer's round-trip latency was measured, after receiving a `pong`.
+    PeerLatencyUpdated {
+        /// Peer address.
+        addr: PeerId,
+        /// Round-trip time of the last `ping`.
+        rtt: LocalDuration,
+        /// Average round-trip time over the recorded samples.
+        average: LocalDuration,
+    },
+    /// A peer's latency has been growing steadily, which usually means its connection
+    /// is congested. Reported once, until the peer's latency goes back down.
+    PeerCongested {
+        /// Peer address.
+        addr: PeerId,
+        /// Ratio of the peer's recent average latency to its older average latency.
+        trend: f64,
+    },
     /// Connection was never established and timed out or failed.
     PeerConnectionFailed {
         /// Peer address.
         addr: PeerId,
         /// Connection error.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::io_error"))]
         error: Arc<io::Error>,
+        /// Connection attempt id.
+        attempt_id: u64,
     },
     /// Peer handshake completed. The peer connection is fully functional from this point.
     PeerNegotiated {
@@ -94,12 +174,14 @@ pub enum Event {
         /// Connection link.
         link: Link,
         /// Peer services.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::services"))]
         services: ServiceFlags,
         /// Whether this is a persistent peer.
         persistent: bool,
         /// Peer height.
         height: Height,
         /// Address of our node, as seen by remote.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::consensus"))]
         receiver: Address,
         /// Peer user agent.
         user_agent: String,
@@ -107,6 +189,8 @@ pub enum Event {
         version: u32,
         /// Transaction relay.
         relay: bool,
+        /// Id of the connection attempt that established the connection.
+        attempt_id: u64,
     },
     /// The best known height amongst connected peers has been updated.
     /// Note that there is no guarantee that this height really exists;
@@ -116,12 +200,45 @@ pub enum Event {
         /// Best block height known.
         height: Height,
     },
+    /// A peer's services changed since we last negotiated with it.
+    PeerServicesChanged {
+        /// Peer address.
+        addr: PeerId,
+        /// Services advertised in the previous negotiation.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::services"))]
+        old: ServiceFlags,
+        /// Services advertised in the latest negotiation.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::services"))]
+        new: ServiceFlags,
+    },
     /// A peer misbehaved.
+    #[cfg_attr(feature = "serde", serde(skip_deserializing))]
     PeerMisbehaved {
         /// Peer address.
         addr: PeerId,
         /// Reason of misbehavior.
+        #[cfg_attr(feature = "serde", serde(skip_deserializing))]
         reason: &'static str,
+        /// Severity of the misbehavior.
+        severity: Severity,
+    },
+    /// A peer was banned, after its misbehavior ban score crossed the threshold.
+    /// The peer is disconnected and won't be reconnected to until the ban expires.
+    PeerBanned {
+        /// Peer address.
+        addr: PeerId,
+        /// Ban score of the peer.
+        score: u32,
+        /// Time until which the peer is banned.
+        until: LocalTime,
+    },
+    /// A reconnection to a persistent peer was scheduled, after the peer disconnected or
+    /// couldn't be connected to. Successive attempts are backed off exponentially.
+    PeerReconnectScheduled {
+        /// Peer address.
+        addr: PeerId,
+        /// Time at which the reconnection is attempted.
+        at: LocalTime,
     },
     /// A block was added to the main chain.
     BlockConnected {
@@ -138,6 +255,8 @@ pub enum Event {
         header: BlockHeader,
         /// Height of the block when it was part of the main chain.
         height: Height,
+        /// Height of the common ancestor of the reverted and the new active chain.
+        fork_height: Height,
     },
     /// Block downloaded and processed by inventory manager.
     BlockProcessed {
@@ -159,6 +278,7 @@ pub enum Event {
     /// We received a merkle block and extracted transactions matches.
     MerkleBlockProcessed {
         /// A merkle block was proccesed.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::consensus"))]
         merkle_block: MerkleBlock,
         /// The height at which the block was processed.
         height: Height,
@@ -169,12 +289,16 @@ pub enum Event {
         /// Filter was cached.
         cached: bool,
     },
-    /// We received a merkle block from the network.
+    /// We received a merkle block from the network, and verified its partial merkle tree
+    /// against the block header.
     ReceivedMerkleBlock {
         /// Block height.
         height: Height,
         /// Matching block.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::consensus"))]
         merkle_block: MerkleBlock,
+        /// Transactions of the block that matched our bloom filter.
+        matched_txids: Vec<Txid>,
     },
     /// Block header chain is in sync with network.
     BlockHeadersSynced {
@@ -183,33 +307,18 @@ pub enum Event {
         /// Chain tip.
         hash: BlockHash,
     },
+    /// Block headers were sent to a peer, in response to its `getheaders` request.
+    HeadersServed {
+        /// Peer the headers were sent to.
+        to: PeerId,
+        /// Number of headers sent.
+        count: usize,
+    },
     /// Block headers imported. Emitted when headers are fetched from peers,
     /// or imported by the user.
-    BlockHeadersImported {
-        /// New tip hash.
-        hash: BlockHash,
-        /// New tip height.
-        height: Height,
-        /// Block headers connected to the active chain.
-        connected: NonEmpty<(Height, BlockHeader)>,
-        /// Block headers reverted from the active chain.
-        reverted: Vec<(Height, BlockHeader)>,
-        /// Set if this import triggered a chain reorganization.
-        reorg: bool,
-    },
+    BlockHeadersImported(ChainImport),
     /// BlockFilter Imported
-    BlockFilterImported {
-        /// New tip hash.
-        hash: BlockHash,
-        /// New tip height.
-        height: Height,
-        /// Block headers connected to the active chain.
-        connected: NonEmpty<(Height, BlockHeader)>,
-        /// Block headers reverted from the active chain.
-        reverted: Vec<(Height, BlockHeader)>,
-        /// Set if this import triggered a chain reorganization.
-        reorg: bool,
-    },
+    BlockFilterImported(ChainImport),
     /// Transaction fee rate estimated for a block.
     FeeEstimated {
         /// Block hash of the estimate.
@@ -229,23 +338,76 @@ pub enum Event {
         height: Height,
         /// Whether or not this filter matched any of the watched scripts.
         matched: bool,
-        /// Whether or not this filter is valid.
-        // TODO: Do not emit event for invalid filter.
-        valid: bool,
         /// Filter was cached.
         cached: bool,
     },
+    /// A compact filter matched some of the watched scripts. Emitted after the corresponding
+    /// [`Event::FilterProcessed`] event.
+    CompactFilterMatched {
+        /// Corresponding block hash.
+        block: BlockHash,
+        /// Filter height (same as block).
+        height: Height,
+        /// Watched scripts that matched the filter.
+        scripts: Vec<Script>,
+    },
+    /// A peer answers our pings, but hasn't sent us any useful data in a while.
+    PeerIdle {
+        /// Peer address.
+        addr: PeerId,
+        /// Time since the peer last sent us useful data.
+        idle_for: LocalDuration,
+    },
+    /// Header sync has stalled: our tip hasn't advanced in a while, even though our
+    /// peers reported longer chains. Sync resumes with [`Event::BlockHeadersImported`] events.
+    SyncStalled {
+        /// How long since the tip last advanced.
+        stalled_for: LocalDuration,
+        /// Our current tip height.
+        tip: Height,
+    },
+    /// Filter header sync has stalled: the filter header chain hasn't advanced in a while,
+    /// even though it's behind the block header chain. Reported once, until the filter
+    /// header chain advances again.
+    FilterSyncStalled {
+        /// How long since the filter header chain last advanced.
+        stalled_for: LocalDuration,
+        /// Our current filter header chain height.
+        height: Height,
+    },
+    /// A valid double-spend proof was received for one of our transactions. This event
+    /// is followed by a [`TxStatus::DoubleSpent`] status change.
+    DoubleSpendProofReceived {
+        /// Double-spent transaction.
+        txid: Txid,
+        /// Peer that sent the proof.
+        from: PeerId,
+    },
+    /// The watchlist was updated.
+    WatchlistUpdated {
+        /// Number of scripts and outpoints watched.
+        size: usize,
+    },
     /// A filter was received.
     FilterReceived {
         /// Peer we received from.
         from: PeerId,
         /// The received filter.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::filter"))]
         filter: BlockFilter,
         /// Filter height.
         height: Height,
         /// Hash of corresponding block.
         block: BlockHash,
     },
+    /// A block contained a watched script that its compact filter didn't match.
+    /// This means the peer that served the filter is faulty or malicious.
+    FaultyFilterDetected {
+        /// Block height.
+        height: Height,
+        /// Peer who served the filter.
+        peer: PeerId,
+    },
     /// A filter rescan has started.
     FilterRescanStarted {
         /// Start height.
@@ -258,10 +420,28 @@ pub enum Event {
         /// Stop height.
         height: Height,
     },
+    /// A filter rescan has made progress. Emitted periodically while scanning.
+    FilterRescanProgress {
+        /// Height of the last processed filter.
+        current: Height,
+        /// Start height of the rescan.
+        start: Height,
+        /// Stop height of the rescan, if any.
+        stop: Option<Height>,
+        /// Number of filters matched so far.
+        matched: usize,
+    },
     /// A merkle block rescan has stopped.
     MerkleBlockRescanStopped {
         /// Stop height.
         height: Height,
+        /// Last fully processed height.
+        checkpoint: Height,
+        /// Number of merkle blocks that matched our bloom filter.
+        matched: usize,
+        /// Number of matched merkle blocks that turned out not to contain any
+        /// watched transaction, once their transactions were inspected.
+        false_positives: usize,
     },
     /// A merkle block rescan has started.
     MerkleBlockRescanStarted {
@@ -270,10 +450,29 @@ pub enum Event {
         /// End height.
         stop: Option<Height>,
     },
+    /// A merkle block rescan has made progress. Emitted periodically while scanning, so
+    /// that an interrupted rescan can be resumed from the checkpoint with
+    /// [`crate::fsm::Command::ResumeMerkleBlockRescan`].
+    MerkleBlockRescanProgress {
+        /// Last fully processed height.
+        checkpoint: Height,
+        /// Start height of the rescan.
+        start: Height,
+        /// Stop height of the rescan, if any.
+        stop: Option<Height>,
+        /// Number of merkle blocks matched so far.
+        matched: usize,
+        /// Peer merkle blocks were last requested from.
+        peer: Option<PeerId>,
+    },
     /// Filter headers synced up to block header height.
     FilterHeadersSynced {
         /// Block height.
         height: Height,
+        /// Filter hash of the filter header chain tip.
+        hash: FilterHash,
+        /// Hash of the block at the tip.
+        block: BlockHash,
     },
     /// The status of a transaction has changed.
     TxStatusChanged {
@@ -282,10 +481,24 @@ pub enum Event {
         /// The new transaction status.
         status: TxStatus,
     },
+    /// The status of several transactions changed to the same status at once, eg. when
+    /// a block confirms many of our transactions. Emitted instead of individual
+    /// [`Event::TxStatusChanged`] events.
+    TxStatusBatch {
+        /// The new status of the transactions.
+        status: TxStatus,
+        /// The Transaction IDs.
+        txids: Vec<Txid>,
+    },
     /// A matched transaction was receiced.
     ReceivedMatchedTx {
         /// The Transaction.
         transaction: Transaction,
+        /// Peer we received the transaction from.
+        from: PeerId,
+        /// Height of the block containing the transaction, if it was matched in a block.
+        /// `None` if it was relayed from the peer's mempool.
+        height: Option<Height>,
     },
     /// Scanned the chain up to a certain height.
     Scanned {
@@ -293,25 +506,556 @@ pub enum Event {
         height: Height,
     },
     /// A gossip message was received from a peer.
+    #[cfg_attr(feature = "serde", serde(skip_deserializing))]
     MessageReceived {
         /// Peer that sent the message.
         from: PeerId,
         /// Message payload.
+        #[cfg_attr(feature = "serde", serde(serialize_with = "encoding::command"))]
         message: Arc<NetworkMessage>,
     },
     /// Address book exhausted.
     AddressBookExhausted,
+    /// Addresses were received from a peer, in an `addr` message.
+    AddressesReceived {
+        /// Peer that sent the addresses.
+        from: PeerId,
+        /// Number of addresses received.
+        count: usize,
+    },
+    /// Addresses were sent to a peer, in response to a `getaddr` message.
+    AddressesSent {
+        /// Peer the addresses were sent to.
+        to: PeerId,
+        /// Number of addresses sent.
+        count: usize,
+    },
+    /// The node is shutting down. Pending messages are flushed and peers are disconnected.
+    ShuttingDown,
+    /// A transaction couldn't be announced, since none of our peers accept transaction
+    /// relay. It will be announced once such a peer is connected.
+    NoRelayPeers {
+        /// Transaction ID.
+        txid: Txid,
+    },
+    /// Scanning progress was rewound, due to a re-org or a new rescan. The next
+    /// [`Event::Scanned`] event may report a lower height than the previous one.
+    ScanRewound {
+        /// Height scanning progress was rewound to.
+        to: Height,
+    },
+    /// A block header was received whose parent we don't have. It is kept in a bounded pool,
+    /// and connected once its ancestors arrive.
+    OrphanHeaderReceived {
+        /// Orphan block hash.
+        hash: BlockHash,
+        /// Hash of the missing parent.
+        prev: BlockHash,
+    },
+    /// A peer announced the minimum fee rate of transactions it relays, with BIP 133
+    /// `feefilter`. Transactions paying less aren't announced to it.
+    PeerFeeFilter {
+        /// Peer address.
+        addr: PeerId,
+        /// Minimum fee rate, in satoshis per kilobyte.
+        feerate: u64,
+    },
+    /// Filters couldn't be requested, since none of our peers serve this kind of filter.
+    /// They are requested once such a peer is connected.
+    NoFilterPeers {
+        /// Kind of filter requested.
+        kind: FilterKind,
+    },
+    /// A downloaded block was dropped from the block cache, to stay within its size budget.
+    /// The block has to be downloaded again if it's needed.
+    BlockEvicted {
+        /// Block hash.
+        hash: BlockHash,
+        /// Block height.
+        height: Height,
+    },
+    /// A peer responded to one of our data requests with `notfound`. The data is requested
+    /// from another peer right away, if possible.
+    DataNotFound {
+        /// Peer that didn't have the data.
+        from: PeerId,
+        /// Type of the data that wasn't found.
+        kind: InvType,
+    },
     /// An error occured.
     Error {
+        /// Error category, eg. to route the error to the appropriate handler.
+        kind: ErrorKind,
         /// Error source.
+        #[cfg_attr(feature = "serde", serde(with = "encoding::error"))]
         error: Arc<dyn error::Error + 'static + Sync + Send>,
     },
     /// Connected to four atleast peers
     FourOrMorePeersConnected,
+    /// The bytes queued for a peer crossed the backpressure threshold.
+    /// This usually means the peer is slow to receive data.
+    PeerBackpressure {
+        /// Peer address.
+        addr: PeerId,
+        /// Bytes queued for the peer.
+        queued_bytes: usize,
+    },
+    /// The bytes queued for a peer fell back below the low-water mark, after
+    /// [`Event::PeerBackpressure`] was emitted.
+    PeerBackpressureRelieved {
+        /// Peer address.
+        addr: PeerId,
+        /// Bytes queued for the peer.
+        queued_bytes: usize,
+    },
+    /// Bandwidth used over the last reporting period.
+    BandwidthReport {
+        /// Bytes sent to peers during the period.
+        sent: u64,
+        /// Bytes received from peers during the period.
+        received: u64,
+        /// Length of the reporting period.
+        period: LocalDuration,
+    },
+}
+
+impl Event {
+    /// Get a stable, machine-readable identifier for this event, eg. `"peer-connected"`.
+    ///
+    /// Unlike the [`fmt::Display`] output, which is meant for humans and may change,
+    /// these codes are kebab-case versions of the variant names and are stable.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Initializing => "initializing",
+            Self::Ready { .. } => "ready",
+            Self::PeerLoadedBloomFilter { .. } => "peer-loaded-bloom-filter",
+            Self::LoadBloomFilter { .. } => "load-bloom-filter",
+            Self::PeerConnected { .. } => "peer-connected",
+            Self::PeerConnecting { .. } => "peer-connecting",
+            Self::PeerDisconnected { .. } => "peer-disconnected",
+            Self::PeerTimedOut { .. } => "peer-timed-out",
+            Self::PeerLatencyUpdated { .. } => "peer-latency-updated",
+            Self::PeerCongested { .. } => "peer-congested",
+            Self::PeerConnectionFailed { .. } => "peer-connection-failed",
+            Self::PeerNegotiated { .. } => "peer-negotiated",
+            Self::PeerHeightUpdated { .. } => "peer-height-updated",
+            Self::PeerServicesChanged { .. } => "peer-services-changed",
+            Self::PeerMisbehaved { .. } => "peer-misbehaved",
+            Self::PeerBanned { .. } => "peer-banned",
+            Self::PeerReconnectScheduled { .. } => "peer-reconnect-scheduled",
+            Self::BlockConnected { .. } => "block-connected",
+            Self::BlockDisconnected { .. } => "block-disconnected",
+            Self::BlockProcessed { .. } => "block-processed",
+            Self::BlockMatched { .. } => "block-matched",
+            Self::MerkleBlockProcessed { .. } => "merkle-block-processed",
+            Self::ReceivedMerkleBlock { .. } => "received-merkle-block",
+            Self::BlockHeadersSynced { .. } => "block-headers-synced",
+            Self::HeadersServed { .. } => "headers-served",
+            Self::BlockHeadersImported(_) => "block-headers-imported",
+            Self::BlockFilterImported(_) => "block-filter-imported",
+            Self::FeeEstimated { .. } => "fee-estimated",
+            Self::FilterProcessed { .. } => "filter-processed",
+            Self::CompactFilterMatched { .. } => "compact-filter-matched",
+            Self::WatchlistUpdated { .. } => "watchlist-updated",
+            Self::PeerIdle { .. } => "peer-idle",
+            Self::SyncStalled { .. } => "sync-stalled",
+            Self::FilterSyncStalled { .. } => "filter-sync-stalled",
+            Self::DoubleSpendProofReceived { .. } => "double-spend-proof-received",
+            Self::FilterReceived { .. } => "filter-received",
+            Self::FaultyFilterDetected { .. } => "faulty-filter-detected",
+            Self::FilterRescanStarted { .. } => "filter-rescan-started",
+            Self::FilterRescanStopped { .. } => "filter-rescan-stopped",
+            Self::FilterRescanProgress { .. } => "filter-rescan-progress",
+            Self::MerkleBlockRescanStopped { .. } => "merkle-block-rescan-stopped",
+            Self::MerkleBlockRescanStarted { .. } => "merkle-block-rescan-started",
+            Self::MerkleBlockRescanProgress { .. } => "merkle-block-rescan-progress",
+            Self::FilterHeadersSynced { .. } => "filter-headers-synced",
+            Self::TxStatusChanged { .. } => "tx-status-changed",
+            Self::TxStatusBatch { .. } => "tx-status-batch",
+            Self::ReceivedMatchedTx { .. } => "received-matched-tx",
+            Self::Scanned { .. } => "scanned",
+            Self::MessageReceived { .. } => "message-received",
+            Self::AddressBookExhausted => "address-book-exhausted",
+            Self::AddressesReceived { .. } => "addresses-received",
+            Self::AddressesSent { .. } => "addresses-sent",
+            Self::ShuttingDown => "shutting-down",
+            Self::NoRelayPeers { .. } => "no-relay-peers",
+            Self::ScanRewound { .. } => "scan-rewound",
+            Self::OrphanHeaderReceived { .. } => "orphan-header-received",
+            Self::PeerFeeFilter { .. } => "peer-fee-filter",
+            Self::NoFilterPeers { .. } => "no-filter-peers",
+            Self::BlockEvicted { .. } => "block-evicted",
+            Self::DataNotFound { .. } => "data-not-found",
+            Self::PeerSkipped { .. } => "peer-skipped",
+            Self::Error { .. } => "error",
+            Self::FourOrMorePeersConnected => "four-or-more-peers-connected",
+            Self::BandwidthReport { .. } => "bandwidth-report",
+            Self::PeerBackpressure { .. } => "peer-backpressure",
+            Self::PeerBackpressureRelieved { .. } => "peer-backpressure-relieved",
+        }
+    }
+
+    /// Get the last block header connected by a [`Event::BlockHeadersImported`] or
+    /// [`Event::BlockFilterImported`] event, ie. the new tip. Returns [`None`] for
+    /// other events.
+    pub fn imported_tip(&self) -> Option<(Height, BlockHeader)> {
+        self.imported().map(|connected| *connected.last())
+    }
+
+    /// Get the range of heights connected by a [`Event::BlockHeadersImported`] or
+    /// [`Event::BlockFilterImported`] event, as an inclusive `(start, end)` pair.
+    /// Returns [`None`] for other events.
+    pub fn imported_range(&self) -> Option<(Height, Height)> {
+        self.imported()
+            .map(|connected| (connected.first().0, connected.last().0))
+    }
+
+    /// Get the block headers connected by an import event.
+    fn imported(&self) -> Option<&NonEmpty<(Height, BlockHeader)>> {
+        match self {
+            Self::BlockHeadersImported(import) | Self::BlockFilterImported(import) => {
+                Some(&import.connected)
+            }
+            _ => None,
+        }
+    }
+
+    /// Write the event as space-separated `key=value` pairs, starting with the event code.
+    /// Values that may contain spaces are quoted.
+    fn fmt_fields(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "event={}", self.code())?;
+
+        match self {
+            Self::Initializing
+            | Self::AddressBookExhausted
+            | Self::ShuttingDown
+            | Self::FourOrMorePeersConnected => Ok(()),
+            Self::Ready {
+                tip,
+                filter_tip,
+                time,
+            } => write!(fmt, " tip={tip} filter_tip={filter_tip} time={time}"),
+            Self::PeerLoadedBloomFilter {
+                filter,
+                peer,
+                fp_rate,
+            } => write!(
+                fmt,
+                " peer={peer} size={} hash_funcs={} fp_rate={fp_rate}",
+                filter.filter.len(),
+                filter.hash_funcs
+            ),
+            Self::LoadBloomFilter { addr, filter } => write!(
+                fmt,
+                " addr={addr} size={} hash_funcs={}",
+                filter.filter.len(),
+                filter.hash_funcs
+            ),
+            Self::PeerConnected {
+                addr,
+                local_addr,
+                link,
+                attempt_id,
+                connect_latency,
+            } => write!(
+                fmt,
+                " addr={addr} local_addr={local_addr} link={link:?} attempt_id={attempt_id} \
+                 connect_latency={}ms",
+                connect_latency.as_millis()
+            ),
+            Self::PeerConnecting {
+                addr,
+                source,
+                services,
+                attempt_id,
+            } => write!(
+                fmt,
+                " addr={addr} source={source} services={services} attempt_id={attempt_id}"
+            ),
+            Self::PeerDisconnected { addr, reason } => {
+                write!(fmt, " addr={addr} reason={:?}", reason.to_string())
+            }
+            Self::PeerTimedOut { addr } => write!(fmt, " addr={addr}"),
+            Self::PeerLatencyUpdated { addr, rtt, average } => write!(
+                fmt,
+                " addr={addr} rtt={}ms average={}ms",
+                rtt.as_millis(),
+                average.as_millis()
+            ),
+            Self::PeerCongested { addr, trend } => write!(fmt, " addr={addr} trend={trend:.2}"),
+            Self::PeerConnectionFailed {
+                addr,
+                error,
+                attempt_id,
+            } => write!(
+                fmt,
+                " addr={addr} error={:?} attempt_id={attempt_id}",
+                error.to_string()
+            ),
+            Self::PeerNegotiated {
+                addr,
+                link,
+                services,
+                persistent,
+                height,
+                user_agent,
+                version,
+                relay,
+                attempt_id,
+                ..
+            } => write!(
+                fmt,
+                " addr={addr} link={link:?} services={services} persistent={persistent} \
+                  height={height} user_agent={user_agent:?} version={version} relay={relay} \
+                  attempt_id={attempt_id}"
+            ),
+            Self::PeerHeightUpdated { height } => write!(fmt, " height={height}"),
+            Self::PeerServicesChanged { addr, old, new } => {
+                write!(fmt, " addr={addr} old={old} new={new}")
+            }
+            Self::PeerMisbehaved {
+                addr,
+                reason,
+                severity,
+            } => {
+                write!(fmt, " addr={addr} reason={reason:?} severity={severity}")
+            }
+            Self::PeerBanned { addr, score, until } => {
+                write!(fmt, " addr={addr} score={score} until={until}")
+            }
+            Self::PeerReconnectScheduled { addr, at } => write!(fmt, " addr={addr} at={at}"),
+            Self::BlockConnected { header, height } => {
+                write!(fmt, " hash={} height={height}", header.block_hash())
+            }
+            Self::BlockDisconnected {
+                header,
+                height,
+                fork_height,
+            } => write!(
+                fmt,
+                " hash={} height={height} fork_height={fork_height}",
+                header.block_hash()
+            ),
+            Self::BlockProcessed {
+                block,
+                height,
+                fees,
+            } => {
+                write!(fmt, " hash={} height={height}", block.block_hash())?;
+                if let Some(fees) = fees {
+                    write!(
+                        fmt,
+                        " fee_low={} fee_median={} fee_high={}",
+                        fees.low, fees.median, fees.high
+                    )?;
+                }
+                Ok(())
+            }
+            Self::BlockMatched { height, block } => {
+                write!(fmt, " hash={} height={height}", block.block_hash())
+            }
+            Self::MerkleBlockProcessed {
+                merkle_block,
+                height,
+                matches,
+                matched,
+                cached,
+            } => write!(
+                fmt,
+                " hash={} height={height} matches={} matched={matched} cached={cached}",
+                merkle_block.header.block_hash(),
+                matches.len()
+            ),
+            Self::ReceivedMerkleBlock {
+                height,
+                merkle_block,
+                matched_txids,
+            } => write!(
+                fmt,
+                " hash={} height={height} matches={}",
+                merkle_block.header.block_hash(),
+                matched_txids.len()
+            ),
+            Self::BlockHeadersSynced { height, hash } => {
+                write!(fmt, " hash={hash} height={height}")
+            }
+            Self::HeadersServed { to, count } => write!(fmt, " to={to} count={count}"),
+            Self::BlockHeadersImported(import) | Self::BlockFilterImported(import) => write!(
+                fmt,
+                " hash={} height={} connected={} reverted={} reorg={}",
+                import.hash,
+                import.height,
+                import.connected.len(),
+                import.reverted.len(),
+                import.reorg
+            ),
+            Self::FeeEstimated {
+                block,
+                height,
+                fees,
+            } => write!(
+                fmt,
+                " hash={block} height={height} fee_low={} fee_median={} fee_high={} \
+                sample_size={} low_confidence={}",
+                fees.low, fees.median, fees.high, fees.sample_size, fees.low_confidence
+            ),
+            Self::FilterProcessed {
+                block,
+                height,
+                matched,
+                cached,
+            } => write!(
+                fmt,
+                " hash={block} height={height} matched={matched} cached={cached}"
+            ),
+            Self::CompactFilterMatched {
+                block,
+                height,
+                scripts,
+            } => write!(
+                fmt,
+                " hash={block} height={height} scripts={}",
+                scripts.len()
+            ),
+            Self::PeerIdle { addr, idle_for } => {
+                write!(fmt, " addr={addr} idle_for={}ms", idle_for.as_millis())
+            }
+            Self::SyncStalled { stalled_for, tip } => {
+                write!(fmt, " stalled_for={}ms tip={tip}", stalled_for.as_millis())
+            }
+            Self::FilterSyncStalled {
+                stalled_for,
+                height,
+            } => write!(
+                fmt,
+                " stalled_for={}ms height={height}",
+                stalled_for.as_millis()
+            ),
+            Self::DoubleSpendProofReceived { txid, from } => {
+                write!(fmt, " txid={txid} from={from}")
+            }
+            Self::WatchlistUpdated { size } => write!(fmt, " size={size}"),
+            Self::FilterReceived {
+                from,
+                height,
+                block,
+                ..
+            } => write!(fmt, " from={from} hash={block} height={height}"),
+            Self::FaultyFilterDetected { height, peer } => {
+                write!(fmt, " height={height} peer={peer}")
+            }
+            Self::FilterRescanStarted { start, stop }
+            | Self::MerkleBlockRescanStarted { start, stop } => {
+                write!(fmt, " start={start}")?;
+                if let Some(stop) = stop {
+                    write!(fmt, " stop={stop}")?;
+                }
+                Ok(())
+            }
+            Self::FilterRescanStopped { height } => write!(fmt, " height={height}"),
+            Self::FilterRescanProgress {
+                current,
+                start,
+                stop,
+                matched,
+            } => {
+                write!(fmt, " current={current} start={start}")?;
+                if let Some(stop) = stop {
+                    write!(fmt, " stop={stop}")?;
+                }
+                write!(fmt, " matched={matched}")
+            }
+            Self::MerkleBlockRescanStopped {
+                height,
+                checkpoint,
+                matched,
+                false_positives,
+            } => write!(
+                fmt,
+                " height={height} checkpoint={checkpoint} matched={matched} \
+                 false_positives={false_positives}"
+            ),
+            Self::MerkleBlockRescanProgress {
+                checkpoint,
+                start,
+                stop,
+                matched,
+                peer,
+            } => {
+                write!(fmt, " checkpoint={checkpoint} start={start}")?;
+                if let Some(stop) = stop {
+                    write!(fmt, " stop={stop}")?;
+                }
+                write!(fmt, " matched={matched}")?;
+                if let Some(peer) = peer {
+                    write!(fmt, " peer={peer}")?;
+                }
+                Ok(())
+            }
+            Self::FilterHeadersSynced {
+                height,
+                hash,
+                block,
+            } => write!(fmt, " height={height} filter_hash={hash} hash={block}"),
+            Self::TxStatusChanged { txid, status } => {
+                write!(fmt, " txid={txid} status={}", status.code())
+            }
+            Self::TxStatusBatch { status, txids } => {
+                write!(fmt, " status={} count={}", status.code(), txids.len())
+            }
+            Self::ReceivedMatchedTx {
+                transaction,
+                from,
+                height,
+            } => {
+                write!(fmt, " txid={} from={from}", transaction.txid())?;
+                if let Some(height) = height {
+                    write!(fmt, " height={height}")?;
+                }
+                Ok(())
+            }
+            Self::Scanned { height } => write!(fmt, " height={height}"),
+            Self::MessageReceived { from, message } => {
+                write!(fmt, " from={from} command={}", message.cmd())
+            }
+            Self::AddressesReceived { from, count } => write!(fmt, " from={from} count={count}"),
+            Self::AddressesSent { to, count } => write!(fmt, " to={to} count={count}"),
+            Self::NoRelayPeers { txid } => write!(fmt, " txid={txid}"),
+            Self::ScanRewound { to } => write!(fmt, " to={to}"),
+            Self::OrphanHeaderReceived { hash, prev } => write!(fmt, " hash={hash} prev={prev}"),
+            Self::PeerFeeFilter { addr, feerate } => write!(fmt, " addr={addr} feerate={feerate}"),
+            Self::NoFilterPeers { kind } => write!(fmt, " kind={kind}"),
+            Self::BlockEvicted { hash, height } => write!(fmt, " hash={hash} height={height}"),
+            Self::DataNotFound { from, kind } => write!(fmt, " from={from} kind={kind}"),
+            Self::PeerSkipped { addr, reason } => write!(fmt, " addr={addr} reason={reason:?}"),
+            Self::Error { kind, error } => {
+                write!(fmt, " kind={kind} error={:?}", error.to_string())
+            }
+            Self::PeerBackpressure { addr, queued_bytes }
+            | Self::PeerBackpressureRelieved { addr, queued_bytes } => {
+                write!(fmt, " addr={addr} queued_bytes={queued_bytes}")
+            }
+            Self::BandwidthReport {
+                sent,
+                received,
+                period,
+            } => write!(
+                fmt,
+                " sent={sent} received={received} period={}ms",
+                period.as_millis()
+            ),
+        }
+    }
 }
 
 impl fmt::Display for Event {
+    /// Formats the event for humans. With the alternate flag, ie. `{:#}`, the event is
+    /// instead formatted as `key=value` pairs for log tooling, eg.
+    /// `event=peer-connected addr=1.2.3.4:8333 link=Outbound`.
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if fmt.alternate() {
+            return self.fmt_fields(fmt);
+        }
         match self {
             Self::Initializing => {
                 write!(fmt, "Initializing peer-to-peer system..")
@@ -319,16 +1063,49 @@ impl fmt::Display for Event {
             Self::FourOrMorePeersConnected => {
                 write!(fmt, "Connected to atleast 4 peers")
             }
+            Self::PeerBackpressure { addr, queued_bytes } => {
+                write!(
+                    fmt,
+                    "Peer {addr} is backed up with {queued_bytes} byte(s) queued"
+                )
+            }
+            Self::PeerBackpressureRelieved { addr, queued_bytes } => {
+                write!(
+                    fmt,
+                    "Peer {addr} is no longer backed up ({queued_bytes} byte(s) queued)"
+                )
+            }
+            Self::BandwidthReport {
+                sent,
+                received,
+                period,
+            } => {
+                write!(
+                    fmt,
+                    "Sent {sent} byte(s) and received {received} byte(s) in the last {period}"
+                )
+            }
             // TODO update filter to to segment
-            Self::PeerLoadedBloomFilter { filter, peer } => {
-                _ = filter;
-                write!(fmt, "Bloom filter loaded to peer {}", peer)
+            Self::PeerLoadedBloomFilter { peer, fp_rate, .. } => {
+                write!(
+                    fmt,
+                    "Bloom filter loaded to peer {} (false-positive rate = {:.6})",
+                    peer, fp_rate
+                )
             }
             Self::MerkleBlockRescanStarted { start, .. } => {
                 write!(fmt, "A merkle block rescan started at height {start}")
             }
-            Self::MerkleBlockRescanStopped { height } => {
-                write!(fmt, "A merkle block resan stopped {height}")
+            Self::MerkleBlockRescanStopped {
+                height,
+                matched,
+                false_positives,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "A merkle block resan stopped {height} ({false_positives}/{matched} false positive(s))"
+                )
             }
             Self::LoadBloomFilter { addr, filter } => {
                 _ = filter;
@@ -338,6 +1115,9 @@ impl fmt::Display for Event {
             Self::Ready { .. } => {
                 write!(fmt, "Ready to process events and commands")
             }
+            Self::HeadersServed { to, count } => {
+                write!(fmt, "Sent {count} block header(s) to peer {to}")
+            }
             Self::BlockHeadersSynced { height, hash } => {
                 write!(
                     fmt,
@@ -347,31 +1127,36 @@ impl fmt::Display for Event {
             Self::ReceivedMerkleBlock {
                 height,
                 merkle_block,
+                matched_txids,
             } => {
                 let hash = merkle_block.header.block_hash();
-                _ = merkle_block;
-                write!(fmt, "MerkleBlock {hash} received at height {height}")
+                write!(
+                    fmt,
+                    "MerkleBlock {hash} received at height {height} ({} match(es))",
+                    matched_txids.len()
+                )
             }
-            Self::BlockFilterImported {
+            Self::BlockFilterImported(ChainImport {
                 hash,
                 height,
                 reorg,
                 ..
-            } => {
+            }) => {
                 write!(
                     fmt,
                     "Block Filters imported to {hash} at height {height} (reorg={reorg})"
                 )
             }
-            Self::BlockHeadersImported {
+            Self::BlockHeadersImported(ChainImport {
                 hash,
                 height,
                 reorg,
+                reorg_depth,
                 ..
-            } => {
+            }) => {
                 write!(
                     fmt,
-                    "Chain tip updated to {hash} at height {height} (reorg={reorg})"
+                    "Chain tip updated to {hash} at height {height} (reorg={reorg}, depth={reorg_depth})"
                 )
             }
             Self::BlockConnected { header, height, .. } => {
@@ -382,12 +1167,17 @@ impl fmt::Display for Event {
                     height
                 )
             }
-            Self::BlockDisconnected { header, height, .. } => {
+            Self::BlockDisconnected {
+                header,
+                height,
+                fork_height,
+            } => {
                 write!(
                     fmt,
-                    "Block {} disconnected at height {}",
+                    "Block {} disconnected at height {} (fork at height {})",
                     header.block_hash(),
-                    height
+                    height,
+                    fork_height
                 )
             }
             Self::BlockProcessed { block, height, .. } => {
@@ -401,15 +1191,44 @@ impl fmt::Display for Event {
             Self::MerkleBlockProcessed { .. } => {
                 write!(fmt, "Merkle Block processed")
             }
-            Self::BlockMatched { height, .. } => {
-                write!(fmt, "Block matched at height {}", height)
-            }
+            Self::MerkleBlockRescanProgress {
+                checkpoint,
+                stop: Some(stop),
+                matched,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Merkle block rescan reached height {checkpoint}/{stop} ({matched} block(s) matched)"
+                )
+            }
+            Self::MerkleBlockRescanProgress {
+                checkpoint,
+                matched,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Merkle block rescan reached height {checkpoint} ({matched} block(s) matched)"
+                )
+            }
+            Self::BlockMatched { height, .. } => {
+                write!(fmt, "Block matched at height {}", height)
+            }
             Self::FeeEstimated { fees, height, .. } => {
                 write!(
                     fmt,
                     "Transaction median fee rate for block #{} is {} sat/vB",
                     height, fees.median,
-                )
+                )?;
+                if fees.low_confidence {
+                    write!(
+                        fmt,
+                        " (low confidence: {} transaction(s) sampled)",
+                        fees.sample_size
+                    )?;
+                }
+                Ok(())
             }
             Self::FilterRescanStarted {
                 start,
@@ -423,12 +1242,41 @@ impl fmt::Display for Event {
             Self::FilterRescanStopped { height } => {
                 write!(fmt, "Rescan completed at height {height}")
             }
-            Self::FilterHeadersSynced { height } => {
-                write!(fmt, "Filter headers synced up to height {height}")
+            Self::FilterRescanProgress {
+                current,
+                stop: Some(stop),
+                matched,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Rescan reached height {current}/{stop} ({matched} filter(s) matched)"
+                )
+            }
+            Self::FilterRescanProgress {
+                current, matched, ..
+            } => {
+                write!(
+                    fmt,
+                    "Rescan reached height {current} ({matched} filter(s) matched)"
+                )
+            }
+            Self::FilterHeadersSynced {
+                height,
+                hash,
+                block,
+            } => {
+                write!(
+                    fmt,
+                    "Filter headers synced up to height {height} (filter {hash}, block {block})"
+                )
             }
             Self::FilterReceived { from, block, .. } => {
                 write!(fmt, "Filter for block {block} received from {from}")
             }
+            Self::FaultyFilterDetected { height, peer } => {
+                write!(fmt, "Faulty filter for block #{height} served by {peer}")
+            }
             Self::FilterProcessed {
                 height, matched, ..
             } => {
@@ -438,14 +1286,53 @@ impl fmt::Display for Event {
                     height, matched
                 )
             }
+            Self::PeerIdle { addr, idle_for } => {
+                write!(fmt, "Peer {addr} has been idle for {}", idle_for.humanize())
+            }
+            Self::SyncStalled { stalled_for, tip } => {
+                write!(
+                    fmt,
+                    "Sync stalled at height {tip} for {}",
+                    stalled_for.humanize()
+                )
+            }
+            Self::FilterSyncStalled {
+                stalled_for,
+                height,
+            } => {
+                write!(
+                    fmt,
+                    "Filter sync stalled at height {height} for {}",
+                    stalled_for.humanize()
+                )
+            }
+            Self::DoubleSpendProofReceived { txid, from } => {
+                write!(fmt, "Received double-spend proof for {txid} from {from}")
+            }
+            Self::WatchlistUpdated { size } => {
+                write!(fmt, "Watchlist updated ({size} item(s) watched)")
+            }
+            Self::CompactFilterMatched {
+                height, scripts, ..
+            } => {
+                write!(
+                    fmt,
+                    "Filter at height {height} matched {} script(s)",
+                    scripts.len()
+                )
+            }
             Self::TxStatusChanged { txid, status } => {
                 write!(fmt, "Transaction {} status changed: {}", txid, status)
             }
+            Self::TxStatusBatch { status, txids } => {
+                let count = txids.len();
+                write!(fmt, "{count} transaction(s) status changed: {status}")
+            }
             Self::Scanned { height, .. } => write!(fmt, "Chain scanned up to height {height}"),
             Self::PeerConnected { addr, link, .. } => {
                 write!(fmt, "Peer {} connected ({:?})", &addr, link)
             }
-            Self::PeerConnectionFailed { addr, error } => {
+            Self::PeerConnectionFailed { addr, error, .. } => {
                 write!(
                     fmt,
                     "Peer connection attempt to {} failed with {}",
@@ -455,18 +1342,55 @@ impl fmt::Display for Event {
             Self::PeerHeightUpdated { height } => {
                 write!(fmt, "Peer height updated to {}", height)
             }
-            Self::ReceivedMatchedTx { transaction } => {
-                write!(fmt, "Received transaction match {}", transaction.txid())
+            Self::ReceivedMatchedTx {
+                transaction,
+                from,
+                height: Some(height),
+            } => {
+                write!(
+                    fmt,
+                    "Received transaction match {} from {from} in block #{height}",
+                    transaction.txid()
+                )
+            }
+            Self::ReceivedMatchedTx {
+                transaction, from, ..
+            } => {
+                write!(
+                    fmt,
+                    "Received transaction match {} from {from}",
+                    transaction.txid()
+                )
             }
-            Self::PeerMisbehaved { addr, reason } => {
+            Self::PeerServicesChanged { addr, old, new } => {
+                write!(fmt, "Peer {addr} services changed from {old} to {new}")
+            }
+            Self::PeerMisbehaved { addr, reason, .. } => {
                 write!(fmt, "Peer {addr} misbehaved: {reason}")
             }
+            Self::PeerBanned { addr, score, until } => {
+                write!(fmt, "Peer {addr} banned until {until} (score = {score})")
+            }
+            Self::PeerReconnectScheduled { addr, at } => {
+                write!(fmt, "Reconnecting to peer {addr} at {at}")
+            }
             Self::PeerDisconnected { addr, reason } => {
                 write!(fmt, "Disconnected from {} ({})", &addr, reason)
             }
             Self::PeerTimedOut { addr } => {
                 write!(fmt, "Peer {addr} timed out")
             }
+            Self::PeerLatencyUpdated { addr, rtt, average } => {
+                write!(
+                    fmt,
+                    "Peer {addr} latency is {} (average {})",
+                    rtt.humanize(),
+                    average.humanize()
+                )
+            }
+            Self::PeerCongested { addr, trend } => {
+                write!(fmt, "Peer {addr} is congested (latency up {trend:.1}x)")
+            }
             Self::PeerConnecting { addr, .. } => {
                 write!(fmt, "Connecting to peer {addr}")
             }
@@ -489,8 +1413,50 @@ impl fmt::Display for Event {
                     "Address book exhausted.. fetching new addresses from peers"
                 )
             }
-            Self::Error { error } => {
-                write!(fmt, "Error: {error}")
+            Self::AddressesReceived { from, count } => {
+                write!(fmt, "Received {count} address(es) from {from}")
+            }
+            Self::AddressesSent { to, count } => {
+                write!(fmt, "Sent {count} address(es) to {to}")
+            }
+            Self::ShuttingDown => {
+                write!(fmt, "Shutting down..")
+            }
+            Self::NoRelayPeers { txid } => {
+                write!(
+                    fmt,
+                    "Transaction {txid} not announced: no peer accepts transaction relay"
+                )
+            }
+            Self::ScanRewound { to } => {
+                write!(fmt, "Scan rewound to height {to}")
+            }
+            Self::OrphanHeaderReceived { hash, prev } => {
+                write!(
+                    fmt,
+                    "Received orphan header {hash} with missing parent {prev}"
+                )
+            }
+            Self::PeerFeeFilter { addr, feerate } => {
+                write!(
+                    fmt,
+                    "Peer {addr} only relays transactions paying at least {feerate} sat/kB"
+                )
+            }
+            Self::NoFilterPeers { kind } => {
+                write!(fmt, "No peers serving {kind} filters")
+            }
+            Self::BlockEvicted { hash, height } => {
+                write!(fmt, "Block {hash} at height {height} evicted from cache")
+            }
+            Self::DataNotFound { from, kind } => {
+                write!(fmt, "Peer {from} didn't find the requested {kind}")
+            }
+            Self::PeerSkipped { addr, reason } => {
+                write!(fmt, "Skipped connecting to peer {addr}: {reason}")
+            }
+            Self::Error { kind, error } => {
+                write!(fmt, "Error ({kind}): {error}")
             }
         }
     }
@@ -498,6 +1464,7 @@ impl fmt::Display for Event {
 
 /// Transaction status of a given transaction.
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TxStatus {
     /// This is the initial state of a transaction after it has been announced by the
     /// client.
@@ -510,6 +1477,16 @@ pub enum TxStatus {
         /// Peer acknowledging the transaction.
         peer: net::SocketAddr,
     },
+    /// Transaction was announced to us by a peer, via an `inv` message.
+    ///
+    /// This is the case when a peer has the transaction in its mempool, which means it
+    /// was likely accepted by the peer's node as valid.
+    InMempool {
+        /// Peer announcing the transaction.
+        peer: net::SocketAddr,
+        /// Time at which the transaction was announced.
+        since: LocalTime,
+    },
     /// Transaction was included in a block. This event is fired after
     /// a block from the main chain is scanned.
     Confirmed {
@@ -535,6 +1512,53 @@ pub enum TxStatus {
         /// Block of the included transaction.
         block: BlockHash,
     },
+    /// Transaction was double-spent by a conflicting transaction spending some of the same
+    /// outputs. Bitcoin Cash has no replace-by-fee: nodes keep the first transaction they
+    /// see and reject conflicting ones, so a conflicting transaction is a double-spend
+    /// rather than a replacement. Once the conflicting transaction is included in a block,
+    /// the given transaction is invalid and can never be confirmed.
+    DoubleSpent {
+        /// Transaction spending some of the same outputs, if known. Transactions reported
+        /// by a double-spend proof are not identified.
+        conflicting: Option<Txid>,
+        /// Block in which the conflicting transaction was included, if any.
+        block: Option<BlockHash>,
+    },
+}
+
+impl TxStatus {
+    /// Get a stable, machine-readable identifier for this status, eg. `"confirmed"`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unconfirmed => "unconfirmed",
+            Self::Acknowledged { .. } => "acknowledged",
+            Self::InMempool { .. } => "in-mempool",
+            Self::Confirmed { .. } => "confirmed",
+            Self::Reverted { .. } => "reverted",
+            Self::Stale { .. } => "stale",
+            Self::DoubleSpent { .. } => "double-spent",
+        }
+    }
+
+    /// Check whether a transaction can go from this status to the given one. Illegal
+    /// transitions indicate a bug, eg. a confirmed transaction becoming unconfirmed without
+    /// first being reverted.
+    pub fn can_transition_to(&self, next: &TxStatus) -> bool {
+        match (self, next) {
+            // A confirmed transaction can only be reverted, and only confirmed transactions
+            // can be reverted.
+            (Self::Confirmed { .. }, next) => matches!(next, Self::Reverted { .. }),
+            (_, Self::Reverted { .. }) => false,
+            // Once the conflicting transaction is in a block, a double-spent transaction
+            // can't be confirmed anymore.
+            (Self::DoubleSpent { block: Some(_), .. }, _) => false,
+            // A stale transaction is only marked stale again, eg. when the transaction
+            // replacing it is confirmed again after a re-org.
+            (Self::Stale { .. }, next) => matches!(next, Self::Stale { .. }),
+            // Otherwise, the transaction is pending and can change to any status.
+            (_, _) => true,
+        }
+    }
 }
 
 impl fmt::Display for TxStatus {
@@ -544,6 +1568,11 @@ impl fmt::Display for TxStatus {
             Self::Acknowledged { peer } => {
                 write!(fmt, "transaction was acknowledged by peer {}", peer)
             }
+            Self::InMempool { peer, since } => write!(
+                fmt,
+                "transaction was seen in the mempool of peer {} since {}",
+                peer, since
+            ),
             Self::Confirmed { height, block } => write!(
                 fmt,
                 "transaction was included in block {} at height {}",
@@ -557,6 +1586,432 @@ impl fmt::Display for TxStatus {
                 "transaction was replaced by {} in block {}",
                 replaced_by, block
             ),
+            Self::DoubleSpent {
+                conflicting: Some(conflicting),
+                block: Some(block),
+            } => write!(
+                fmt,
+                "transaction was double-spent by {} in block {}, and is now invalid",
+                conflicting, block
+            ),
+            Self::DoubleSpent {
+                conflicting: Some(conflicting),
+                block: None,
+            } => write!(
+                fmt,
+                "transaction was double-spent by unconfirmed transaction {}",
+                conflicting
+            ),
+            Self::DoubleSpent {
+                conflicting: None, ..
+            } => write!(
+                fmt,
+                "transaction was double-spent by an unknown transaction"
+            ),
+        }
+    }
+}
+
+/// Category of an error carried by an [`Event::Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ErrorKind {
+    /// A network error, eg. a connection failure.
+    Network,
+    /// A consensus error, eg. an invalid block or filter.
+    Consensus,
+    /// An error with one of the underlying stores, eg. the block header or filter store.
+    Storage,
+    /// A protocol error, eg. a request exceeding protocol limits.
+    Protocol,
+    /// Any other error, eg. an invalid user request.
+    Other,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network => write!(fmt, "network"),
+            Self::Consensus => write!(fmt, "consensus"),
+            Self::Storage => write!(fmt, "storage"),
+            Self::Protocol => write!(fmt, "protocol"),
+            Self::Other => write!(fmt, "other"),
+        }
+    }
+}
+
+/// Severity of a peer misbehavior, carried by an [`Event::PeerMisbehaved`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Severity {
+    /// A protocol violation that may be accidental, eg. an unsolicited message.
+    /// Adds to the peer's ban score, but the peer stays connected.
+    Minor,
+    /// The peer served invalid data, or otherwise can't be trusted to follow the protocol.
+    /// The peer is disconnected, and usually banned.
+    Severe,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Minor => write!(fmt, "minor"),
+            Self::Severe => write!(fmt, "severe"),
+        }
+    }
+}
+
+/// Type of the data requested from a peer with `getdata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InvType {
+    /// A transaction.
+    Transaction,
+    /// A block.
+    Block,
+}
+
+impl fmt::Display for InvType {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transaction => write!(fmt, "transaction"),
+            Self::Block => write!(fmt, "block"),
+        }
+    }
+}
+
+/// Collects events into batches, coalescing redundant progress events.
+///
+/// Only consecutive events of the following kinds are coalesced, keeping the one with the
+/// highest height:
+///
+/// * [`Event::Scanned`]
+/// * [`Event::FilterProcessed`], if the filter didn't match
+///
+/// All other events, eg. [`Event::BlockMatched`] or [`Event::TxStatusChanged`], are kept as-is.
+///
+/// ```
+/// use nakamoto_p2p::fsm::{Event, EventBatcher};
+///
+/// let mut batcher = EventBatcher::new();
+///
+/// batcher.extend((1..=3).map(|height| Event::Scanned { height }));
+/// assert_eq!(batcher.len(), 1);
+///
+/// let batch = batcher.drain();
+/// assert!(matches!(batch.as_slice(), [Event::Scanned { height: 3 }]));
+/// assert!(batcher.is_empty());
+/// ```
+#[derive(Debug, Default)]
+pub struct EventBatcher {
+    events: Vec<Event>,
+}
+
+impl EventBatcher {
+    /// Create a new, empty batcher.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an event to the current batch.
+    pub fn push(&mut self, event: Event) {
+        if let Some(last) = self.events.last_mut() {
+            match (Self::coalescable(last), Self::coalescable(&event)) {
+                (Some((a, kind)), Some((b, kind_))) if kind == kind_ => {
+                    if b >= a {
+                        *last = event;
+                    }
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.events.push(event);
+    }
+
+    /// Number of events in the current batch.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Check whether the current batch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Take the current batch, leaving the batcher empty.
+    pub fn drain(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Get the height and kind of an event, if it can be coalesced.
+    fn coalescable(event: &Event) -> Option<(Height, &'static str)> {
+        match event {
+            Event::Scanned { height } => Some((*height, event.code())),
+            Event::FilterProcessed {
+                height,
+                matched: false,
+                ..
+            } => Some((*height, event.code())),
+            _ => None,
+        }
+    }
+}
+
+impl Extend<Event> for EventBatcher {
+    fn extend<I: IntoIterator<Item = Event>>(&mut self, iter: I) {
+        for event in iter {
+            self.push(event);
+        }
+    }
+}
+
+/// An event, along with the local time at which it was emitted.
+///
+/// Timed events are passed to the [`fsm::Hooks::on_event`] hook.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimedEvent {
+    /// Local time at which the event was emitted.
+    pub at: LocalTime,
+    /// The event.
+    pub event: Event,
+}
+
+impl fmt::Display for TimedEvent {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if fmt.alternate() {
+            write!(fmt, "at={} {:#}", self.at, self.event)
+        } else {
+            write!(fmt, "[{}] {}", self.at, self.event)
+        }
+    }
+}
+
+impl From<TimedEvent> for Event {
+    fn from(timed: TimedEvent) -> Self {
+        timed.event
+    }
+}
+
+/// A set of message types, selecting which received messages are published as
+/// [`Event::MessageReceived`].
+///
+/// Received messages are always handled internally; the mask only gates the public event.
+/// Message types are identified by their command, eg. `"headers"`.
+///
+/// ```
+/// use nakamoto_common::bitcoin::network::message::NetworkMessage;
+/// use nakamoto_p2p::fsm::MessageMask;
+///
+/// let mask = MessageMask::from_commands(["headers", "tx"]).unwrap();
+///
+/// assert!(mask.contains(&NetworkMessage::Headers(vec![])));
+/// assert!(!mask.contains(&NetworkMessage::Inv(vec![])));
+/// assert!(MessageMask::from_commands(["pizza"]).is_none());
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MessageMask(u64);
+
+impl MessageMask {
+    /// No message type.
+    pub const NONE: Self = Self(0);
+    /// All message types.
+    pub const ALL: Self = Self(u64::MAX);
+
+    /// Commands of the message types that can be selected, in bit order.
+    const COMMANDS: [&'static str; 36] = [
+        "version",
+        "verack",
+        "addr",
+        "inv",
+        "getdata",
+        "notfound",
+        "getblocks",
+        "getheaders",
+        "mempool",
+        "tx",
+        "block",
+        "headers",
+        "sendheaders",
+        "getaddr",
+        "ping",
+        "pong",
+        "merkleblock",
+        "filterload",
+        "filteradd",
+        "filterclear",
+        "getcfilters",
+        "cfilter",
+        "getcfheaders",
+        "cfheaders",
+        "getcfcheckpt",
+        "cfcheckpt",
+        "sendcmpct",
+        "cmpctblock",
+        "getblocktxn",
+        "blocktxn",
+        "alert",
+        "reject",
+        "feefilter",
+        "addrv2",
+        "sendaddrv2",
+        "unknown",
+    ];
+
+    /// Create a mask selecting the given message commands. Returns `None` if a command
+    /// isn't known.
+    pub fn from_commands<'a>(commands: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        let mut mask = Self::NONE;
+
+        for cmd in commands {
+            mask.insert(cmd).then_some(())?;
+        }
+        Some(mask)
+    }
+
+    /// Add a message command to the mask. Returns `false` if the command isn't known.
+    pub fn insert(&mut self, cmd: &str) -> bool {
+        match Self::bit(cmd) {
+            Some(bit) => {
+                self.0 |= bit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a message command from the mask. Returns `false` if the command isn't known.
+    pub fn remove(&mut self, cmd: &str) -> bool {
+        match Self::bit(cmd) {
+            Some(bit) => {
+                self.0 &= !bit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check whether the mask selects the type of the given message.
+    pub fn contains(&self, msg: &NetworkMessage) -> bool {
+        Self::bit(msg.cmd()).map_or(false, |bit| self.0 & bit != 0)
+    }
+
+    /// Check whether the mask selects no message type.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+
+    /// Get the bit of a message command.
+    fn bit(cmd: &str) -> Option<u64> {
+        Self::COMMANDS
+            .iter()
+            .position(|c| *c == cmd)
+            .map(|i| 1 << i)
+    }
+}
+
+/// Serde encodings for event fields that don't implement `Serialize` and `Deserialize`.
+#[cfg(feature = "serde")]
+mod encoding {
+    use std::fmt;
+    use std::sync::Arc;
+
+    use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// Serialize a value as its `Display` string.
+    pub fn display<T: fmt::Display, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(value)
+    }
+
+    /// Serialize a network message as its command string, eg. `"ping"`.
+    pub fn command<S: Serializer>(msg: &Arc<NetworkMessage>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(msg.cmd())
+    }
+
+    /// Encode a value as a hex string of its consensus encoding.
+    pub mod consensus {
+        use super::*;
+
+        use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable};
+        use nakamoto_common::bitcoin_hashes::hex::{FromHex, ToHex};
+
+        pub fn serialize<T: Encodable, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_str(&encode::serialize(value).to_hex())
+        }
+
+        pub fn deserialize<'de, T: Decodable, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+            let hex = String::deserialize(d)?;
+            let bytes = Vec::<u8>::from_hex(&hex).map_err(serde::de::Error::custom)?;
+
+            encode::deserialize(&bytes).map_err(serde::de::Error::custom)
+        }
+    }
+
+    /// Encode an I/O error as its message. Decodes into an error of kind
+    /// [`std::io::ErrorKind::Other`].
+    pub mod io_error {
+        use super::*;
+
+        use std::io;
+
+        pub use super::display as serialize;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Arc<io::Error>, D::Error> {
+            let msg = String::deserialize(d)?;
+
+            Ok(Arc::new(io::Error::new(io::ErrorKind::Other, msg)))
+        }
+    }
+
+    /// Encode an error as its message. Decodes into an I/O error, since the original
+    /// error type is lost.
+    pub mod error {
+        use super::*;
+
+        use std::error::Error;
+
+        pub use super::display as serialize;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Arc<dyn Error + 'static + Sync + Send>, D::Error> {
+            Ok(super::io_error::deserialize(d)?)
+        }
+    }
+
+    /// Encode service flags as an integer.
+    pub mod services {
+        use super::*;
+
+        use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+
+        pub fn serialize<S: Serializer>(services: &ServiceFlags, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_u64(services.to_u64())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ServiceFlags, D::Error> {
+            u64::deserialize(d).map(ServiceFlags::from)
+        }
+    }
+
+    /// Encode a block filter as a hex string of its content.
+    pub mod filter {
+        use super::*;
+
+        use nakamoto_common::bitcoin_hashes::hex::{FromHex, ToHex};
+        use nakamoto_common::block::filter::BlockFilter;
+
+        pub fn serialize<S: Serializer>(filter: &BlockFilter, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_str(&filter.content.to_hex())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<BlockFilter, D::Error> {
+            let hex = String::deserialize(d)?;
+            let content = Vec::<u8>::from_hex(&hex).map_err(serde::de::Error::custom)?;
+
+            Ok(BlockFilter::new(&content))
         }
     }
 }
@@ -565,8 +2020,122 @@ impl fmt::Display for TxStatus {
 mod test {
     use super::*;
     use nakamoto_common::bitcoin_hashes::Hash;
+    use nakamoto_test::assert_matches;
     use nakamoto_test::block::gen;
 
+    #[test]
+    fn test_event_batcher() {
+        let mut rng = fastrand::Rng::new();
+        let tx = gen::transaction(&mut rng);
+        let block = BlockHash::all_zeros();
+        let processed = |height: Height, matched: bool| Event::FilterProcessed {
+            block,
+            height,
+            matched,
+            cached: false,
+        };
+        let mut batcher = EventBatcher::new();
+
+        batcher.extend([
+            processed(1, false),
+            processed(2, false),
+            processed(3, true),
+            processed(4, false),
+            processed(5, false),
+            Event::Scanned { height: 1 },
+            Event::Scanned { height: 3 },
+            Event::BlockMatched {
+                height: 3,
+                block: gen::genesis(&mut rng),
+            },
+            Event::TxStatusChanged {
+                txid: tx.txid(),
+                status: TxStatus::Unconfirmed,
+            },
+            Event::TxStatusChanged {
+                txid: tx.txid(),
+                status: TxStatus::Unconfirmed,
+            },
+            Event::Scanned { height: 4 },
+            Event::Scanned { height: 5 },
+        ]);
+        let batch = batcher.drain();
+
+        assert!(batcher.is_empty());
+        assert_matches!(
+            batch.as_slice(),
+            [
+                Event::FilterProcessed {
+                    height: 2,
+                    matched: false,
+                    ..
+                },
+                Event::FilterProcessed {
+                    height: 3,
+                    matched: true,
+                    ..
+                },
+                Event::FilterProcessed {
+                    height: 5,
+                    matched: false,
+                    ..
+                },
+                Event::Scanned { height: 3 },
+                Event::BlockMatched { height: 3, .. },
+                Event::TxStatusChanged { .. },
+                Event::TxStatusChanged { .. },
+                Event::Scanned { height: 5 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_imported_tip_and_range() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = nakamoto_common::network::Network::Regtest.genesis();
+        let headers = gen::headers(genesis, 5, &mut rng);
+        let connected = NonEmpty::from_vec(
+            headers
+                .iter()
+                .cloned()
+                .enumerate()
+                .skip(3)
+                .map(|(h, header)| (h as Height, header))
+                .collect(),
+        )
+        .unwrap();
+        let tip = *connected.last();
+
+        let event = Event::BlockHeadersImported(ChainImport {
+            hash: tip.1.block_hash(),
+            height: tip.0,
+            connected,
+            reverted: vec![],
+            reorg: false,
+            reorg_depth: 0,
+        });
+        assert_eq!(event.imported_tip(), Some(tip));
+        assert_eq!(event.imported_range(), Some((3, 5)));
+
+        let event = Event::BlockFilterImported(ChainImport {
+            hash: tip.1.block_hash(),
+            height: tip.0,
+            connected: NonEmpty::new(tip),
+            reverted: vec![],
+            reorg: false,
+            reorg_depth: 0,
+        });
+        assert_eq!(event.imported_tip(), Some(tip));
+        assert_eq!(event.imported_range(), Some((5, 5)));
+
+        let event = Event::BlockConnected {
+            header: tip.1,
+            height: tip.0,
+        };
+        assert_eq!(event.imported_tip(), None);
+        assert_eq!(event.imported_range(), None);
+    }
+
     #[test]
     fn test_tx_status_ordering() {
         assert!(
@@ -578,6 +2147,15 @@ mod test {
         assert!(
             TxStatus::Acknowledged {
                 peer: ([0, 0, 0, 0], 0).into()
+            } < TxStatus::InMempool {
+                peer: ([0, 0, 0, 0], 0).into(),
+                since: LocalTime::default(),
+            }
+        );
+        assert!(
+            TxStatus::InMempool {
+                peer: ([0, 0, 0, 0], 0).into(),
+                since: LocalTime::default(),
             } < TxStatus::Confirmed {
                 height: 0,
                 block: BlockHash::all_zeros(),
@@ -599,5 +2177,701 @@ mod test {
                 block: BlockHash::all_zeros()
             }
         );
+        assert!(
+            TxStatus::Stale {
+                replaced_by: Txid::all_zeros(),
+                block: BlockHash::all_zeros()
+            } < TxStatus::DoubleSpent {
+                conflicting: None,
+                block: None,
+            }
+        );
+        assert!(
+            TxStatus::DoubleSpent {
+                conflicting: Some(Txid::all_zeros()),
+                block: None,
+            } < TxStatus::DoubleSpent {
+                conflicting: Some(Txid::all_zeros()),
+                block: Some(BlockHash::all_zeros()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_tx_status_transitions() {
+        let peer: PeerId = ([0, 0, 0, 0], 0).into();
+        let unconfirmed = TxStatus::Unconfirmed;
+        let acknowledged = TxStatus::Acknowledged { peer };
+        let in_mempool = TxStatus::InMempool {
+            peer,
+            since: LocalTime::default(),
+        };
+        let confirmed = TxStatus::Confirmed {
+            height: 0,
+            block: BlockHash::all_zeros(),
+        };
+        let reverted = TxStatus::Reverted {
+            transaction: gen::transaction(&mut fastrand::Rng::new()),
+        };
+        let stale = TxStatus::Stale {
+            replaced_by: Txid::all_zeros(),
+            block: BlockHash::all_zeros(),
+        };
+        let conflicted = TxStatus::DoubleSpent {
+            conflicting: None,
+            block: None,
+        };
+        let double_spent = TxStatus::DoubleSpent {
+            conflicting: Some(Txid::all_zeros()),
+            block: Some(BlockHash::all_zeros()),
+        };
+
+        // Format: from, to, legal.
+        let table = [
+            (&unconfirmed, &acknowledged, true),
+            (&unconfirmed, &confirmed, true),
+            (&unconfirmed, &reverted, false),
+            (&acknowledged, &in_mempool, true),
+            (&acknowledged, &unconfirmed, true),
+            (&in_mempool, &confirmed, true),
+            (&in_mempool, &stale, true),
+            (&in_mempool, &conflicted, true),
+            (&confirmed, &reverted, true),
+            (&confirmed, &unconfirmed, false),
+            (&confirmed, &in_mempool, false),
+            (&confirmed, &confirmed, false),
+            (&confirmed, &double_spent, false),
+            (&reverted, &confirmed, true),
+            (&reverted, &acknowledged, true),
+            (&reverted, &stale, true),
+            (&reverted, &reverted, false),
+            (&stale, &stale, true),
+            (&stale, &confirmed, false),
+            (&stale, &unconfirmed, false),
+            (&conflicted, &confirmed, true),
+            (&conflicted, &double_spent, true),
+            (&double_spent, &confirmed, false),
+            (&double_spent, &unconfirmed, false),
+        ];
+        for (from, to, legal) in table {
+            assert_eq!(
+                from.can_transition_to(to),
+                legal,
+                "{} -> {}",
+                from.code(),
+                to.code()
+            );
+        }
+    }
+
+    #[test]
+    fn test_event_codes() {
+        use nakamoto_common::bitcoin::network::message_bloom::BloomFlags;
+        use nakamoto_common::collections::HashSet;
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let genesis = nakamoto_common::network::Network::Regtest.genesis();
+        let block = gen::block(&genesis, &mut rng);
+        let header = block.header;
+        let hash = header.block_hash();
+        let merkle_block = MerkleBlock::from_block_with_predicate(&block, |_| false);
+        let tx = gen::transaction(&mut rng);
+        let filter = FilterLoad {
+            filter: vec![],
+            hash_funcs: 0,
+            tweak: 0,
+            flags: BloomFlags::None,
+        };
+        let events = vec![
+            Event::Initializing,
+            Event::Ready {
+                tip: 0,
+                filter_tip: 0,
+                time: LocalTime::default(),
+            },
+            Event::PeerLoadedBloomFilter {
+                filter: filter.clone(),
+                peer: addr,
+                fp_rate: 0.,
+            },
+            Event::LoadBloomFilter {
+                addr,
+                filter: filter.clone(),
+            },
+            Event::PeerConnected {
+                addr,
+                local_addr: addr,
+                link: Link::Outbound,
+                attempt_id: 0,
+                connect_latency: LocalDuration::from_secs(0),
+            },
+            Event::PeerConnecting {
+                addr,
+                source: Source::Dns,
+                services: ServiceFlags::NETWORK,
+                attempt_id: 0,
+            },
+            Event::PeerDisconnected {
+                addr,
+                reason: fsm::DisconnectReason::Command.into(),
+            },
+            Event::PeerTimedOut { addr },
+            Event::PeerLatencyUpdated {
+                addr,
+                rtt: LocalDuration::from_secs(1),
+                average: LocalDuration::from_secs(1),
+            },
+            Event::PeerCongested { addr, trend: 2. },
+            Event::PeerConnectionFailed {
+                addr,
+                error: Arc::new(io::ErrorKind::ConnectionRefused.into()),
+                attempt_id: 0,
+            },
+            Event::PeerNegotiated {
+                addr,
+                link: Link::Outbound,
+                services: ServiceFlags::NETWORK,
+                persistent: false,
+                height: 0,
+                receiver: Address::new(&addr, ServiceFlags::NONE),
+                user_agent: String::new(),
+                version: 0,
+                relay: false,
+                attempt_id: 0,
+            },
+            Event::PeerHeightUpdated { height: 0 },
+            Event::PeerMisbehaved {
+                addr,
+                reason: "",
+                severity: Severity::Minor,
+            },
+            Event::BlockConnected { header, height: 0 },
+            Event::BlockDisconnected {
+                header,
+                height: 1,
+                fork_height: 0,
+            },
+            Event::BlockProcessed {
+                block: block.clone(),
+                height: 0,
+                fees: None,
+            },
+            Event::BlockMatched {
+                height: 0,
+                block: block.clone(),
+            },
+            Event::MerkleBlockProcessed {
+                merkle_block: merkle_block.clone(),
+                height: 0,
+                matches: vec![],
+                matched: false,
+                cached: false,
+            },
+            Event::ReceivedMerkleBlock {
+                height: 0,
+                merkle_block,
+                matched_txids: vec![],
+            },
+            Event::BlockHeadersSynced { height: 0, hash },
+            Event::BlockHeadersImported(ChainImport {
+                hash,
+                height: 0,
+                connected: NonEmpty::new((0, header)),
+                reverted: vec![],
+                reorg: false,
+                reorg_depth: 0,
+            }),
+            Event::BlockFilterImported(ChainImport {
+                hash,
+                height: 0,
+                connected: NonEmpty::new((0, header)),
+                reverted: vec![],
+                reorg: false,
+                reorg_depth: 0,
+            }),
+            Event::FeeEstimated {
+                block: hash,
+                height: 0,
+                fees: FeeEstimate {
+                    low: 0,
+                    median: 0,
+                    high: 0,
+                    sample_size: 0,
+                    low_confidence: true,
+                },
+            },
+            Event::FilterProcessed {
+                block: hash,
+                height: 0,
+                matched: false,
+                cached: false,
+            },
+            Event::FilterReceived {
+                from: addr,
+                filter: BlockFilter::new(&[]),
+                height: 0,
+                block: hash,
+            },
+            Event::FaultyFilterDetected {
+                height: 0,
+                peer: addr,
+            },
+            Event::FilterRescanStarted {
+                start: 0,
+                stop: None,
+            },
+            Event::FilterRescanStopped { height: 0 },
+            Event::MerkleBlockRescanStopped {
+                height: 0,
+                checkpoint: 0,
+                matched: 0,
+                false_positives: 0,
+            },
+            Event::MerkleBlockRescanStarted {
+                start: 0,
+                stop: None,
+            },
+            Event::FilterHeadersSynced {
+                height: 0,
+                hash: FilterHash::all_zeros(),
+                block: BlockHash::all_zeros(),
+            },
+            Event::TxStatusChanged {
+                txid: tx.txid(),
+                status: TxStatus::Unconfirmed,
+            },
+            Event::ReceivedMatchedTx {
+                transaction: tx,
+                from: addr,
+                height: None,
+            },
+            Event::Scanned { height: 0 },
+            Event::MessageReceived {
+                from: addr,
+                message: Arc::new(NetworkMessage::Verack),
+            },
+            Event::AddressBookExhausted,
+            Event::Error {
+                kind: ErrorKind::Other,
+                error: Arc::new(io::Error::from(io::ErrorKind::Other)),
+            },
+            Event::FourOrMorePeersConnected,
+            Event::PeerBanned {
+                addr,
+                score: 100,
+                until: LocalTime::default(),
+            },
+            Event::BandwidthReport {
+                sent: 1024,
+                received: 2048,
+                period: LocalDuration::from_mins(1),
+            },
+            Event::FilterRescanProgress {
+                current: 1000,
+                start: 0,
+                stop: Some(2000),
+                matched: 3,
+            },
+            Event::PeerBackpressure {
+                addr,
+                queued_bytes: 1024,
+            },
+            Event::PeerBackpressureRelieved {
+                addr,
+                queued_bytes: 0,
+            },
+            Event::PeerServicesChanged {
+                addr,
+                old: ServiceFlags::NETWORK,
+                new: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+            },
+            Event::CompactFilterMatched {
+                block: BlockHash::all_zeros(),
+                height: 1,
+                scripts: vec![],
+            },
+            Event::WatchlistUpdated { size: 1 },
+            Event::PeerIdle {
+                addr,
+                idle_for: LocalDuration::from_mins(10),
+            },
+            Event::SyncStalled {
+                stalled_for: LocalDuration::from_mins(5),
+                tip: 0,
+            },
+            Event::DoubleSpendProofReceived {
+                txid: Txid::all_zeros(),
+                from: addr,
+            },
+            Event::AddressesReceived {
+                from: addr,
+                count: 1000,
+            },
+            Event::ShuttingDown,
+            Event::NoRelayPeers {
+                txid: Txid::all_zeros(),
+            },
+            Event::ScanRewound { to: 0 },
+            Event::OrphanHeaderReceived {
+                hash: BlockHash::all_zeros(),
+                prev: BlockHash::all_zeros(),
+            },
+            Event::PeerFeeFilter { addr, feerate: 0 },
+            Event::NoFilterPeers {
+                kind: FilterKind::Compact,
+            },
+            Event::BlockEvicted {
+                hash: BlockHash::all_zeros(),
+                height: 0,
+            },
+            Event::AddressesSent { to: addr, count: 0 },
+            Event::PeerReconnectScheduled {
+                addr,
+                at: LocalTime::default(),
+            },
+            Event::DataNotFound {
+                from: addr,
+                kind: InvType::Block,
+            },
+            Event::PeerSkipped { addr, reason: "" },
+            Event::TxStatusBatch {
+                status: TxStatus::Unconfirmed,
+                txids: vec![],
+            },
+            Event::HeadersServed { to: addr, count: 0 },
+            Event::FilterSyncStalled {
+                stalled_for: LocalDuration::from_mins(5),
+                height: 0,
+            },
+            Event::MerkleBlockRescanProgress {
+                checkpoint: 0,
+                start: 0,
+                stop: None,
+                matched: 0,
+                peer: Some(addr),
+            },
+        ];
+
+        // Nb. This match is exhaustive, so that adding a variant requires adding it here.
+        let index = |event: &Event| match event {
+            Event::Initializing => 0,
+            Event::Ready { .. } => 1,
+            Event::PeerLoadedBloomFilter { .. } => 2,
+            Event::LoadBloomFilter { .. } => 3,
+            Event::PeerConnected { .. } => 4,
+            Event::PeerConnecting { .. } => 5,
+            Event::PeerDisconnected { .. } => 6,
+            Event::PeerTimedOut { .. } => 7,
+            Event::PeerLatencyUpdated { .. } => 8,
+            Event::PeerCongested { .. } => 9,
+            Event::PeerConnectionFailed { .. } => 10,
+            Event::PeerNegotiated { .. } => 11,
+            Event::PeerHeightUpdated { .. } => 12,
+            Event::PeerMisbehaved { .. } => 13,
+            Event::BlockConnected { .. } => 14,
+            Event::BlockDisconnected { .. } => 15,
+            Event::BlockProcessed { .. } => 16,
+            Event::BlockMatched { .. } => 17,
+            Event::MerkleBlockProcessed { .. } => 18,
+            Event::ReceivedMerkleBlock { .. } => 19,
+            Event::BlockHeadersSynced { .. } => 20,
+            Event::BlockHeadersImported(_) => 21,
+            Event::BlockFilterImported(_) => 22,
+            Event::FeeEstimated { .. } => 23,
+            Event::FilterProcessed { .. } => 24,
+            Event::FilterReceived { .. } => 25,
+            Event::FaultyFilterDetected { .. } => 26,
+            Event::FilterRescanStarted { .. } => 27,
+            Event::FilterRescanStopped { .. } => 28,
+            Event::MerkleBlockRescanStopped { .. } => 29,
+            Event::MerkleBlockRescanStarted { .. } => 30,
+            Event::FilterHeadersSynced { .. } => 31,
+            Event::TxStatusChanged { .. } => 32,
+            Event::ReceivedMatchedTx { .. } => 33,
+            Event::Scanned { .. } => 34,
+            Event::MessageReceived { .. } => 35,
+            Event::AddressBookExhausted => 36,
+            Event::Error { .. } => 37,
+            Event::FourOrMorePeersConnected => 38,
+            Event::PeerBanned { .. } => 39,
+            Event::BandwidthReport { .. } => 40,
+            Event::FilterRescanProgress { .. } => 41,
+            Event::PeerBackpressure { .. } => 42,
+            Event::PeerBackpressureRelieved { .. } => 43,
+            Event::PeerServicesChanged { .. } => 44,
+            Event::CompactFilterMatched { .. } => 45,
+            Event::WatchlistUpdated { .. } => 46,
+            Event::PeerIdle { .. } => 47,
+            Event::SyncStalled { .. } => 48,
+            Event::DoubleSpendProofReceived { .. } => 49,
+            Event::AddressesReceived { .. } => 50,
+            Event::ShuttingDown => 51,
+            Event::NoRelayPeers { .. } => 52,
+            Event::ScanRewound { .. } => 53,
+            Event::OrphanHeaderReceived { .. } => 54,
+            Event::PeerFeeFilter { .. } => 55,
+            Event::NoFilterPeers { .. } => 56,
+            Event::BlockEvicted { .. } => 57,
+            Event::AddressesSent { .. } => 58,
+            Event::PeerReconnectScheduled { .. } => 59,
+            Event::DataNotFound { .. } => 60,
+            Event::PeerSkipped { .. } => 61,
+            Event::TxStatusBatch { .. } => 62,
+            Event::HeadersServed { .. } => 63,
+            Event::FilterSyncStalled { .. } => 64,
+            Event::MerkleBlockRescanProgress { .. } => 65,
+        };
+        let mut codes = HashSet::with_hasher(rng.into());
+
+        for (i, event) in events.iter().enumerate() {
+            let code = event.code();
+
+            assert_eq!(index(event), i, "event `{}` is out of place", code);
+            assert!(!code.is_empty());
+            assert!(
+                code.chars().all(|c| c.is_ascii_lowercase() || c == '-'),
+                "code `{}` is not kebab-case",
+                code
+            );
+            assert!(codes.insert(code), "code `{}` is not unique", code);
+
+            let fields = format!("{event:#}");
+            assert!(fields.starts_with(&format!("event={code}")));
+            assert!(!fields.contains('\n'), "fields of `{}` span lines", code);
+        }
+        assert_eq!(codes.len(), events.len());
+        assert_eq!(Event::Initializing.code(), "initializing");
+        assert_eq!(events[4].code(), "peer-connected");
+        assert_eq!(events[21].code(), "block-headers-imported");
+    }
+
+    #[test]
+    fn test_display_alternate() {
+        let addr: PeerId = ([1, 2, 3, 4], 8333).into();
+        let event = Event::PeerConnected {
+            addr,
+            local_addr: ([0, 0, 0, 0], 8333).into(),
+            link: Link::Outbound,
+            attempt_id: 7,
+            connect_latency: LocalDuration::from_millis(120),
+        };
+        assert_eq!(
+            format!("{event:#}"),
+            "event=peer-connected addr=1.2.3.4:8333 local_addr=0.0.0.0:8333 link=Outbound \
+             attempt_id=7 connect_latency=120ms"
+        );
+        // The default output is unchanged.
+        assert_eq!(event.to_string(), "Peer 1.2.3.4:8333 connected (Outbound)");
+
+        // Values that may contain spaces are quoted.
+        let event = Event::PeerMisbehaved {
+            addr,
+            reason: "invalid `cfilter` message",
+            severity: Severity::Severe,
+        };
+        assert_eq!(
+            format!("{event:#}"),
+            "event=peer-misbehaved addr=1.2.3.4:8333 reason=\"invalid `cfilter` message\" \
+             severity=severe"
+        );
+        let event = Event::Error {
+            kind: ErrorKind::Network,
+            error: Arc::new(io::Error::new(io::ErrorKind::Other, "connection reset")),
+        };
+        assert_eq!(
+            format!("{event:#}"),
+            "event=error kind=network error=\"connection reset\""
+        );
+
+        // Optional values are omitted when unset.
+        let event = Event::FilterRescanStarted {
+            start: 10,
+            stop: None,
+        };
+        assert_eq!(format!("{event:#}"), "event=filter-rescan-started start=10");
+
+        let timed = TimedEvent {
+            at: LocalTime::from_secs(1_600_000_000),
+            event: Event::TxStatusChanged {
+                txid: Txid::all_zeros(),
+                status: TxStatus::Unconfirmed,
+            },
+        };
+        assert_eq!(
+            format!("{timed:#}"),
+            format!(
+                "at=1600000000 event=tx-status-changed txid={} status=unconfirmed",
+                Txid::all_zeros()
+            )
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    fn roundtrip(event: Event) -> String {
+        let json = serde_json::to_string(&event).unwrap();
+        let decoded: Event = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        assert_eq!(decoded.to_string(), event.to_string());
+
+        json
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_roundtrip() {
+        use nakamoto_common::bitcoin::network::message_bloom::BloomFlags;
+        use nakamoto_common::block::filter::BlockFilter;
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let genesis = nakamoto_common::network::Network::Regtest.genesis();
+        let headers = gen::headers(genesis, 2, &mut rng);
+        let block = gen::block(&headers[2], &mut rng);
+        let tx = gen::transaction(&mut rng);
+
+        let json = roundtrip(Event::Initializing);
+        assert_eq!(json, r#""Initializing""#);
+
+        let json = roundtrip(Event::Ready {
+            tip: 2,
+            filter_tip: 1,
+            time: LocalTime::from_secs(1_600_000_000),
+        });
+        assert!(json.starts_with(r#"{"Ready":{"#), "{}", json);
+
+        roundtrip(Event::PeerConnected {
+            addr,
+            local_addr: ([0, 0, 0, 0], 8333).into(),
+            link: Link::Outbound,
+            attempt_id: 7,
+            connect_latency: LocalDuration::from_millis(120),
+        });
+        roundtrip(Event::PeerConnecting {
+            addr,
+            source: Source::Dns,
+            services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+            attempt_id: 7,
+        });
+        roundtrip(Event::PeerNegotiated {
+            addr,
+            link: Link::Inbound,
+            services: ServiceFlags::NETWORK,
+            persistent: false,
+            height: 2,
+            receiver: Address::new(&addr, ServiceFlags::NONE),
+            user_agent: String::from("/nakamoto:0.4.0/"),
+            version: 70016,
+            relay: true,
+            attempt_id: 7,
+        });
+        roundtrip(Event::PeerLatencyUpdated {
+            addr,
+            rtt: LocalDuration::from_millis(120),
+            average: LocalDuration::from_millis(100),
+        });
+        roundtrip(Event::LoadBloomFilter {
+            addr,
+            filter: FilterLoad {
+                filter: vec![0xde, 0xad],
+                hash_funcs: 5,
+                tweak: 1,
+                flags: BloomFlags::All,
+            },
+        });
+        roundtrip(Event::BlockHeadersImported(ChainImport {
+            hash: headers[2].block_hash(),
+            height: 2,
+            connected: NonEmpty::from_vec(vec![(1, headers[1]), (2, headers[2])]).unwrap(),
+            reverted: vec![],
+            reorg: false,
+            reorg_depth: 0,
+        }));
+        roundtrip(Event::BlockMatched {
+            height: 3,
+            block: block.clone(),
+        });
+        roundtrip(Event::ReceivedMerkleBlock {
+            height: 3,
+            merkle_block: MerkleBlock::from_block_with_predicate(&block, |_| true),
+            matched_txids: block.txdata.iter().map(|tx| tx.txid()).collect(),
+        });
+        roundtrip(Event::FilterReceived {
+            from: addr,
+            filter: BlockFilter::new(&[0x1, 0x2, 0x3]),
+            height: 3,
+            block: block.block_hash(),
+        });
+        roundtrip(Event::FeeEstimated {
+            block: block.block_hash(),
+            height: 3,
+            fees: FeeEstimate {
+                low: 1,
+                median: 2,
+                high: 3,
+                sample_size: 3,
+                low_confidence: true,
+            },
+        });
+
+        for status in [
+            TxStatus::Unconfirmed,
+            TxStatus::Acknowledged { peer: addr },
+            TxStatus::InMempool {
+                peer: addr,
+                since: LocalTime::from_secs(1_600_000_000),
+            },
+            TxStatus::Confirmed {
+                height: 3,
+                block: block.block_hash(),
+            },
+            TxStatus::Reverted {
+                transaction: tx.clone(),
+            },
+            TxStatus::Stale {
+                replaced_by: tx.txid(),
+                block: block.block_hash(),
+            },
+            TxStatus::DoubleSpent {
+                conflicting: Some(tx.txid()),
+                block: Some(block.block_hash()),
+            },
+        ] {
+            let json = roundtrip(Event::TxStatusChanged {
+                txid: tx.txid(),
+                status: status.clone(),
+            });
+            let decoded: Event = serde_json::from_str(&json).unwrap();
+
+            assert_matches!(decoded, Event::TxStatusChanged { status: s, .. } if s == status);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_display_only() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let event = Event::MessageReceived {
+            from: addr,
+            message: Arc::new(NetworkMessage::Ping(42)),
+        };
+        let json = serde_json::to_string(&event).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"MessageReceived":{"from":"88.88.88.88:8333","message":"ping"}}"#
+        );
+        assert!(serde_json::from_str::<Event>(&json).is_err());
+
+        let event = Event::PeerMisbehaved {
+            addr,
+            reason: "invalid `headers`",
+            severity: Severity::Severe,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+
+        assert!(json.contains("invalid `headers`"));
+        assert!(serde_json::from_str::<Event>(&json).is_err());
     }
 }
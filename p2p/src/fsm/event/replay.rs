@@ -0,0 +1,189 @@
+//! Recording and replaying of event streams.
+//!
+//! Events are recorded as newline-delimited JSON, one event per line, so that an event
+//! stream captured on a live node can be fed to a consumer without a network.
+//!
+//! Nb. Some events can't be deserialized, eg. [`Event::MessageReceived`], since their
+//! fields don't survive serialization. These fail to replay with an [`Error::Decode`],
+//! and can be skipped. Errors carried by events are replayed as [`std::io::Error`]s
+//! with the original message.
+use std::io;
+
+use thiserror::Error;
+
+use super::Event;
+
+/// An error recording or replaying events.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// An event couldn't be encoded or decoded.
+    #[error("invalid event: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+/// Records events to a writer, as newline-delimited JSON.
+#[derive(Debug)]
+pub struct EventLog<W> {
+    writer: W,
+}
+
+impl<W: io::Write> EventLog<W> {
+    /// Create a new event log, writing to the given writer.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Record an event.
+    pub fn write(&mut self, event: &Event) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(Error::from)
+    }
+
+    /// Get the underlying writer back.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Replays events recorded by an [`EventLog`].
+#[derive(Debug)]
+pub struct EventReplay<R> {
+    lines: io::Lines<R>,
+}
+
+impl<R: io::BufRead> EventReplay<R> {
+    /// Create a new replay, reading from the given reader.
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: io::BufRead> Iterator for EventReplay<R> {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            // Skip blank lines, eg. at the end of a file.
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(serde_json::from_str(&line).map_err(Error::from));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+    use nakamoto_common::block::time::{LocalDuration, LocalTime};
+    use nakamoto_common::network::Network;
+    use nakamoto_common::p2p::peer::Source;
+    use nakamoto_test::assert_matches;
+
+    use crate::fsm::event::{ErrorKind, Severity};
+    use crate::fsm::{Link, PeerId};
+
+    #[test]
+    fn test_record_replay() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let genesis = Network::Regtest.genesis_hash();
+        let events = vec![
+            Event::Initializing,
+            Event::Ready {
+                tip: 0,
+                filter_tip: 0,
+                time: LocalTime::from_secs(1_600_000_000),
+            },
+            Event::PeerConnecting {
+                addr,
+                source: Source::Dns,
+                services: ServiceFlags::NETWORK,
+                attempt_id: 1,
+            },
+            Event::PeerConnected {
+                addr,
+                local_addr: ([0, 0, 0, 0], 8333).into(),
+                link: Link::Outbound,
+                attempt_id: 1,
+                connect_latency: LocalDuration::from_millis(120),
+            },
+            Event::PeerConnectionFailed {
+                addr,
+                error: Arc::new(io::ErrorKind::ConnectionRefused.into()),
+                attempt_id: 2,
+            },
+            Event::BlockHeadersSynced {
+                height: 0,
+                hash: genesis,
+            },
+            Event::Error {
+                kind: ErrorKind::Storage,
+                error: Arc::new(io::Error::new(io::ErrorKind::Other, "disk full")),
+            },
+        ];
+
+        let mut log = EventLog::new(Vec::new());
+        for event in &events {
+            log.write(event).unwrap();
+        }
+        let recorded = log.into_inner();
+        assert_eq!(
+            recorded.iter().filter(|b| **b == b'\n').count(),
+            events.len()
+        );
+
+        let replayed = EventReplay::new(recorded.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(replayed.len(), events.len());
+
+        for (expected, actual) in events.iter().zip(&replayed) {
+            assert_eq!(actual.code(), expected.code());
+            assert_eq!(actual.to_string(), expected.to_string());
+            assert_eq!(
+                serde_json::to_string(actual).unwrap(),
+                serde_json::to_string(expected).unwrap()
+            );
+        }
+        assert_matches!(
+            &replayed[6],
+            Event::Error { kind: ErrorKind::Storage, error } if error.to_string() == "disk full"
+        );
+
+        // Events that can't be deserialized are reported, and the replay continues.
+        let mut log = EventLog::new(Vec::new());
+        log.write(&Event::PeerMisbehaved {
+            addr,
+            reason: "unrequested `tx` message",
+            severity: Severity::Minor,
+        })
+        .unwrap();
+        log.write(&Event::Initializing).unwrap();
+
+        let recorded = log.into_inner();
+        let mut replay = EventReplay::new(recorded.as_slice());
+        assert_matches!(replay.next(), Some(Err(Error::Decode(_))));
+        assert_matches!(replay.next(), Some(Ok(Event::Initializing)));
+        assert_matches!(replay.next(), None);
+    }
+}
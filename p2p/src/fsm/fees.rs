@@ -14,12 +14,24 @@ use super::Height;
 /// Maximum depth of a re-org that we are able to handle.
 pub const MAX_UTXO_SNAPSHOTS: usize = 12;
 
+/// Number of recent blocks kept by default in the fee history.
+pub const DEFAULT_FEE_HISTORY_WINDOW: usize = 144;
+
+/// Probability with which a fee rate estimated for a confirmation target should
+/// get a transaction confirmed within that target.
+pub const FEE_ESTIMATE_CONFIDENCE: f64 = 0.95;
+
+/// Minimum number of fee-paying transactions for a block fee estimate to be meaningful.
+/// Estimates derived from fewer transactions are flagged as low-confidence.
+pub const MIN_FEE_SAMPLE_SIZE: usize = 10;
+
 /// Transaction fee rate in satoshis/vByte.
 pub type FeeRate = u64;
 
 /// Fee rate estimate for a single block.
 /// Measured in satoshis/vByte.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FeeEstimate {
     /// The lowest fee rate included in the block.
     pub low: FeeRate,
@@ -27,6 +39,11 @@ pub struct FeeEstimate {
     pub median: FeeRate,
     /// The highest fee rate included in the block.
     pub high: FeeRate,
+    /// Number of fee-paying transactions the estimate is derived from.
+    pub sample_size: usize,
+    /// Whether the sample is smaller than [`MIN_FEE_SAMPLE_SIZE`], making the estimate
+    /// unreliable, eg. in the case of a near-empty block.
+    pub low_confidence: bool,
 }
 
 impl FeeEstimate {
@@ -36,27 +53,18 @@ impl FeeEstimate {
     /// ```
     /// use nakamoto_p2p::fsm::fees::FeeEstimate;
     ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![3, 9, 2]),
-    ///     Some(FeeEstimate { low: 2, median: 3, high: 9 }),
-    /// );
+    /// let median = |fees| FeeEstimate::from(fees).map(|e| (e.low, e.median, e.high));
     ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![4, 6]),
-    ///     Some(FeeEstimate { low: 4, median: 5, high: 6 }),
-    /// );
+    /// assert_eq!(median(vec![3, 9, 2]), Some((2, 3, 9)));
+    /// assert_eq!(median(vec![4, 6]), Some((4, 5, 6)));
+    /// assert_eq!(median(vec![9, 2, 1, 7]), Some((1, 5, 9)));
+    /// assert_eq!(median(vec![3]), Some((3, 3, 3)));
+    /// assert_eq!(median(vec![]), None);
     ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![9, 2, 1, 7]),
-    ///     Some(FeeEstimate { low: 1, median: 5, high: 9 }),
-    /// );
-    ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![3]),
-    ///     Some(FeeEstimate { low: 3, median: 3, high: 3 }),
-    /// );
-    ///
-    /// assert_eq!(FeeEstimate::from(vec![]), None);
+    /// // Estimates from few transactions are flagged.
+    /// let estimate = FeeEstimate::from(vec![3, 9, 2]).unwrap();
+    /// assert_eq!(estimate.sample_size, 3);
+    /// assert!(estimate.low_confidence);
     /// ```
     pub fn from(mut fees: Vec<FeeRate>) -> Option<Self> {
         fees.sort_unstable();
@@ -76,14 +84,171 @@ impl FeeEstimate {
                 low: *fees.first(),
                 median,
                 high: *fees.last(),
+                sample_size: count,
+                low_confidence: count < MIN_FEE_SAMPLE_SIZE,
             }
         })
     }
 }
 
+/// Rolling history of per-block fee estimates, used to estimate the fee rate needed for
+/// a transaction to confirm within a number of blocks.
+///
+/// Fee estimates can be fed from [`Event::FeeEstimated`](super::Event::FeeEstimated) events.
+///
+/// ```
+/// use nakamoto_p2p::fsm::fees::{FeeEstimate, FeeHistory};
+///
+/// let mut history = FeeHistory::new(3);
+/// assert_eq!(history.estimate_for_target(1), None);
+///
+/// for (height, median) in [(1, 10), (2, 20), (3, 30), (4, 40)] {
+///     let estimate = FeeEstimate { low: 1, median, high: 100, sample_size: 10, low_confidence: false };
+///     history.insert(height, estimate);
+/// }
+/// assert_eq!(history.len(), 3);
+/// assert_eq!(history.estimate_for_target(1), Some(40));
+/// assert_eq!(history.estimate_for_target(6), Some(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FeeHistory {
+    /// Fee estimates of recent blocks, oldest first.
+    estimates: VecDeque<(Height, FeeEstimate)>,
+    /// Maximum number of blocks kept.
+    window: usize,
+}
+
+impl Default for FeeHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_FEE_HISTORY_WINDOW)
+    }
+}
+
+impl FeeHistory {
+    /// Create a new fee history keeping the given number of recent blocks.
+    pub fn new(window: usize) -> Self {
+        Self {
+            estimates: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    /// Get the maximum number of blocks kept.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Get the number of blocks currently kept.
+    pub fn len(&self) -> usize {
+        self.estimates.len()
+    }
+
+    /// Check whether the history is empty.
+    pub fn is_empty(&self) -> bool {
+        self.estimates.is_empty()
+    }
+
+    /// Add the fee estimate of a block. Estimates that aren't for a greater height than the
+    /// last one are ignored. The oldest estimate is dropped once the window is full.
+    pub fn insert(&mut self, height: Height, estimate: FeeEstimate) {
+        if matches!(self.estimates.back(), Some((h, _)) if *h >= height) {
+            return;
+        }
+        self.estimates.push_back((height, estimate));
+
+        while self.estimates.len() > self.window {
+            self.estimates.pop_front();
+        }
+    }
+
+    /// Rollback to a certain height, dropping the estimates of blocks above it.
+    pub fn rollback(&mut self, height: Height) {
+        self.estimates.retain(|(h, _)| *h <= height);
+    }
+
+    /// Estimate the fee rate needed for a transaction to be confirmed within the given number
+    /// of blocks, with a probability of [`FEE_ESTIMATE_CONFIDENCE`].
+    ///
+    /// A fee rate is considered sufficient for a block if it is at least the block's median
+    /// fee rate. The estimate is the percentile of recent block medians for which the
+    /// chance of none of the next `blocks` blocks being sufficient is below the confidence
+    /// threshold. Returns [`None`] if there is no history.
+    pub fn estimate_for_target(&self, blocks: u16) -> Option<FeeRate> {
+        let mut medians = self
+            .estimates
+            .iter()
+            .map(|(_, e)| e.median)
+            .collect::<Vec<_>>();
+
+        if medians.is_empty() {
+            return None;
+        }
+        medians.sort_unstable();
+
+        let blocks = blocks.max(1) as f64;
+        let percentile = 1. - (1. - FEE_ESTIMATE_CONFIDENCE).powf(1. / blocks);
+        let index = (percentile * medians.len() as f64).ceil() as usize;
+
+        medians.get(index.saturating_sub(1)).copied()
+    }
+}
+
 /// Set of unspent transaction outputs (UTXO).
 type UtxoSet = HashMap<OutPoint, TxOut>;
 
+/// Compute the fee estimate of a block, given the outputs spent by its transactions.
+///
+/// This is useful to backfill estimates for blocks that were processed without the
+/// necessary UTXOs, ie. whose [`Event::BlockProcessed`](super::Event::BlockProcessed) event
+/// carried no fees. Outputs created within the block itself don't need to be included in
+/// `prevouts`. Transactions with missing or invalid previous outputs are skipped.
+/// Returns [`None`] if none of the transactions could be processed.
+pub fn estimate_block_fees(
+    block: &Block,
+    prevouts: &HashMap<OutPoint, TxOut>,
+) -> Option<FeeEstimate> {
+    let mut outputs = HashMap::with_hasher(Default::default());
+    let mut fees = Vec::new();
+
+    for tx in &block.txdata {
+        let txid = tx.txid();
+
+        for (vout, output) in tx.output.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                vout: vout as u32,
+            };
+            outputs.insert(outpoint, output);
+        }
+        if tx.is_coin_base() {
+            continue;
+        }
+
+        let received = tx.input.iter().try_fold(0, |acc, input| {
+            let prevout = &input.previous_output;
+
+            prevouts
+                .get(prevout)
+                .or_else(|| outputs.get(prevout).copied())
+                .map(|out| acc + out.value)
+        });
+        let sent = tx.output.iter().map(|o| o.value).sum::<u64>();
+
+        if let Some(fee) = received.and_then(|r| r.checked_sub(sent)) {
+            fees.push(fee_rate(tx, fee));
+        }
+    }
+    FeeEstimate::from(fees)
+}
+
+/// Calculate the fee rate of a transaction paying the given fee.
+fn fee_rate(tx: &Transaction, fee: u64) -> FeeRate {
+    let weight = tx.weight();
+    let rate = fee as f64 / (weight as f64 / WITNESS_SCALE_FACTOR as f64);
+
+    rate.round() as FeeRate
+}
+
 /// Transaction fee rate estimator.
 #[derive(Debug, Default)]
 pub struct FeeEstimator {
@@ -135,6 +300,27 @@ impl FeeEstimator {
         }
     }
 
+    /// Get an output of the UTXO set.
+    pub fn utxo(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.utxos.get(outpoint)
+    }
+
+    /// Calculate the fee rate of an unconfirmed transaction, without applying it. Returns
+    /// [`None`] if any of the outputs it spends are missing from the UTXO set.
+    pub fn fee_rate(&self, tx: &Transaction) -> Option<FeeRate> {
+        if tx.is_coin_base() {
+            return None;
+        }
+        let received = tx.input.iter().try_fold(0, |acc, input| {
+            self.utxos
+                .get(&input.previous_output)
+                .map(|out| acc + out.value)
+        })?;
+        let sent = tx.output.iter().map(|o| o.value).sum::<u64>();
+
+        received.checked_sub(sent).map(|fee| fee_rate(tx, fee))
+    }
+
     /// Apply the transaction to the UTXO set and calculate the fee rate.
     fn apply(&mut self, tx: &Transaction) -> Option<FeeRate> {
         let txid = tx.txid();
@@ -168,11 +354,7 @@ impl FeeEstimator {
         }
         assert!(received >= sent, "you can't spend what you don't have",);
 
-        let fee = received - sent;
-        let weight = tx.weight();
-        let rate = fee as f64 / (weight as f64 / WITNESS_SCALE_FACTOR as f64);
-
-        Some(rate.round() as FeeRate)
+        Some(fee_rate(tx, received - sent))
     }
 }
 
@@ -245,4 +427,145 @@ mod tests {
         assert_eq!(fe.snapshots.len(), 0);
         assert_eq!(fe.height, 0);
     }
+
+    #[test]
+    fn test_estimate_block_fees() {
+        use nakamoto_common::bitcoin::hashes::Hash as _;
+        use nakamoto_common::bitcoin::Txid;
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = gen::genesis(&mut rng);
+        let mut prevouts = HashMap::with_hasher(rng.clone().into());
+
+        // Create a transaction spending the given output, paying the given fee rate.
+        let spend = |prevout: OutPoint, value: u64, rate: FeeRate, rng: &mut fastrand::Rng| {
+            let mut tx = gen::transaction_with(prevout, value, rng);
+            tx.output.truncate(1);
+            tx.output[0].value = value - rate * tx.weight() as u64 / WITNESS_SCALE_FACTOR as u64;
+            tx
+        };
+        let external = OutPoint::new(Txid::all_zeros(), 0);
+        prevouts.insert(external, gen::tx_out(&mut rng));
+
+        let value = prevouts[&external].value;
+        let tx1 = spend(external, value, 12, &mut rng);
+        // Spends an output created within the same block.
+        let tx2 = spend(
+            OutPoint::new(tx1.txid(), 0),
+            tx1.output[0].value,
+            3,
+            &mut rng,
+        );
+        let tx3 = spend(
+            OutPoint::new(tx2.txid(), 0),
+            tx2.output[0].value,
+            40,
+            &mut rng,
+        );
+        // Spends an unknown output.
+        let tx4 = gen::transaction(&mut rng);
+
+        let block = gen::block_with(
+            &genesis.header,
+            vec![gen::coinbase(&mut rng), tx1, tx2, tx3, tx4],
+            &mut rng,
+        );
+        assert_eq!(
+            estimate_block_fees(&block, &prevouts),
+            Some(FeeEstimate {
+                low: 3,
+                median: 12,
+                high: 40,
+                sample_size: 3,
+                low_confidence: true,
+            })
+        );
+        // The same estimate is computed when processing the block with the UTXOs available.
+        let mut fe = FeeEstimator::default();
+        fe.utxos = prevouts.clone();
+        assert_eq!(
+            fe.process(block.clone(), 1),
+            estimate_block_fees(&block, &prevouts)
+        );
+
+        // Without the external prevouts, only in-block spends are estimated.
+        prevouts.clear();
+        assert_eq!(
+            estimate_block_fees(&block, &prevouts),
+            Some(FeeEstimate {
+                low: 3,
+                median: 22,
+                high: 40,
+                sample_size: 2,
+                low_confidence: true,
+            })
+        );
+        assert_eq!(estimate_block_fees(&genesis, &prevouts), None);
+    }
+
+    #[test]
+    fn test_fee_estimate_confidence() {
+        let sparse = FeeEstimate::from(vec![7]).unwrap();
+        assert_eq!(sparse.sample_size, 1);
+        assert!(sparse.low_confidence);
+
+        let dense = FeeEstimate::from(vec![7; MIN_FEE_SAMPLE_SIZE]).unwrap();
+        assert_eq!(dense.sample_size, MIN_FEE_SAMPLE_SIZE);
+        assert!(!dense.low_confidence);
+
+        let event = |fees| crate::fsm::Event::FeeEstimated {
+            block: gen::genesis(&mut fastrand::Rng::new()).block_hash(),
+            height: 1,
+            fees,
+        };
+        assert!(event(sparse).to_string().contains("low confidence"));
+        assert!(!event(dense).to_string().contains("low confidence"));
+    }
+
+    #[test]
+    fn test_fee_history() {
+        let estimate = |median| FeeEstimate {
+            low: 1,
+            median,
+            high: median * 2,
+            sample_size: MIN_FEE_SAMPLE_SIZE,
+            low_confidence: false,
+        };
+        let mut history = FeeHistory::new(10);
+
+        assert!(history.is_empty());
+        assert_eq!(history.window(), 10);
+        assert_eq!(history.estimate_for_target(1), None);
+
+        for height in 1..=20 {
+            history.insert(height, estimate(height as FeeRate));
+        }
+        // Only the last 10 blocks are kept.
+        assert_eq!(history.len(), 10);
+        // Stale estimates are ignored.
+        history.insert(20, estimate(1000));
+        assert_eq!(history.estimate_for_target(1), Some(20));
+
+        // Longer targets require lower fee rates.
+        let mut last = FeeRate::MAX;
+        for target in 1..=12 {
+            let rate = history.estimate_for_target(target).unwrap();
+
+            assert!(rate <= last);
+            assert!(rate >= 11);
+            last = rate;
+        }
+        // A target of zero is treated as the next block.
+        assert_eq!(
+            history.estimate_for_target(0),
+            history.estimate_for_target(1)
+        );
+
+        history.rollback(15);
+        assert_eq!(history.len(), 5);
+        assert_eq!(history.estimate_for_target(1), Some(15));
+
+        history.rollback(0);
+        assert_eq!(history.estimate_for_target(1), None);
+    }
 }
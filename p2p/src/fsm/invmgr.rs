@@ -19,22 +19,34 @@
 //! the [`InventoryManager::timer_expired`] function is called. Confirmed transactions are removed
 //! after they are burried at a certain depth.
 //!
-use std::collections::BTreeMap;
+//! ## Block cache
+//!
+//! Downloaded blocks are kept in a cache bounded by their total size, since blocks are often
+//! requested again after a re-org, eg. when matching filters are re-scanned. Requested blocks
+//! found in the cache are processed without issuing a `getdata`. When a block is evicted from
+//! the cache, [`Event::BlockEvicted`] is emitted.
+//!
+use std::collections::{BTreeMap, VecDeque};
 
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::blockdata::script::Instruction;
+use nakamoto_common::bitcoin::consensus::encode;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::{constants::ServiceFlags, message_blockdata::Inventory};
-use nakamoto_common::bitcoin::{Block, BlockHash, Transaction, Txid};
+use nakamoto_common::bitcoin::{Block, BlockHash, MerkleBlock, PublicKey, Transaction, Txid};
 
 // TODO: Timeout should be configurable
 // TODO: Add exponential back-off
 
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::collections::{AddressBook, HashMap};
+use nakamoto_common::collections::{AddressBook, HashMap, HashSet, SizedCache};
+use nakamoto_common::p2p::dsproof::{self, DoubleSpendProof};
 
-use super::fees::FeeEstimator;
+use super::fees::{FeeEstimator, FeeRate};
 use super::output::{Io, Outbox};
-use super::{event::TxStatus, Event, Height, PeerId};
+use super::{event::TxStatus, ChainImport, ErrorKind, Event, Height, InvType, PeerId, Severity};
 
 /// Time between re-broadcasts of inventories.
 pub const REBROADCAST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -51,6 +63,92 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 /// Block depth at which confirmed transactions are pruned and no longer reverted after a re-org.
 pub const TRANSACTION_PRUNE_DEPTH: Height = 12;
 
+/// Time during which new transaction announcements are coalesced into a single `inv`.
+pub const ANNOUNCE_WINDOW: LocalDuration = LocalDuration::from_millis(500);
+
+/// Maximum total size of the blocks kept in the block cache, in bytes.
+pub const BLOCK_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Number of `notfound` responses for transactions a peer announced, within
+/// [`NOT_FOUND_WINDOW`], after which the peer is considered misbehaving.
+pub const MAX_NOT_FOUND: usize = 3;
+
+/// Time window during which `notfound` responses from a peer are counted.
+pub const NOT_FOUND_WINDOW: LocalDuration = LocalDuration::from_mins(10);
+
+/// Suggested number of transactions changing to the same status at once, above which a
+/// single [`Event::TxStatusBatch`] is emitted instead of one event per transaction.
+/// Batching is disabled by default.
+pub const TX_STATUS_BATCH_THRESHOLD: usize = 8;
+
+/// Order in which queued blocks are requested from peers.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum BlockOrder {
+    /// Request lower blocks first. This is the default, since blocks are processed in
+    /// order of height.
+    #[default]
+    OldestFirst,
+    /// Request higher blocks first, eg. for wallets prioritizing recent activity.
+    NewestFirst,
+}
+
+/// An error in the inventory manager.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The status of a transaction changed in a way that isn't allowed, which indicates a bug.
+    #[error("illegal status transition of transaction {txid}: {from} -> {to}")]
+    IllegalTransition {
+        /// Transaction id.
+        txid: Txid,
+        /// Current status.
+        from: &'static str,
+        /// Rejected status.
+        to: &'static str,
+    },
+}
+
+/// An error resulting from announcing a transaction.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AnnounceError {
+    /// The transaction spends outputs already spent by one of our unconfirmed transactions.
+    /// Unconfirmed transactions can't be replaced on Bitcoin Cash, so it would never be
+    /// relayed.
+    #[error("transaction {txid} conflicts with unconfirmed transaction {conflict}")]
+    Conflict {
+        /// Transaction id.
+        txid: Txid,
+        /// Conflicting transaction id.
+        conflict: Txid,
+    },
+}
+
+/// Inventory manager configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Time during which new transaction announcements to a peer are coalesced into a
+    /// single `inv` message.
+    pub announce_window: LocalDuration,
+    /// Maximum total size of the downloaded blocks kept in memory, in bytes.
+    pub block_cache_size: usize,
+    /// Order in which queued blocks are requested.
+    pub block_order: BlockOrder,
+    /// Number of transactions confirmed by the same block, above which their status
+    /// changes are reported as a single [`Event::TxStatusBatch`]. If `None`, status
+    /// changes are always reported individually.
+    pub tx_status_batch: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            announce_window: ANNOUNCE_WINDOW,
+            block_cache_size: BLOCK_CACHE_SIZE,
+            block_order: BlockOrder::default(),
+            tx_status_batch: None,
+        }
+    }
+}
+
 /// Inventory manager peer.
 #[derive(Debug)]
 pub struct Peer {
@@ -64,21 +162,44 @@ pub struct Peer {
     attempts: usize,
     /// Last time we attempted to send inventories to this peer.
     last_attempt: Option<LocalTime>,
-
-    /// Number of times a certain block was requested.
-    #[allow(dead_code)]
-    requests: HashMap<BlockHash, usize>,
+    /// Since when inventories that were never announced to this peer are queued.
+    pending_since: Option<LocalTime>,
+    /// Minimum fee rate this peer relays, in satoshis per kilobyte, as set with `feefilter`.
+    fee_filter: u64,
+
+    /// Blocks requested from this peer that haven't arrived yet.
+    requests: HashSet<BlockHash>,
+    /// Times of the recent `notfound` responses for transactions this peer announced.
+    not_found: VecDeque<LocalTime>,
 }
 
 impl Peer {
     fn attempted(&mut self, time: LocalTime) {
         self.last_attempt = Some(time);
+        self.pending_since = None;
         self.attempts += 1;
     }
 
-    #[allow(dead_code)]
     fn requested(&mut self, hash: BlockHash) {
-        *self.requests.entry(hash).or_default() += 1;
+        self.requests.insert(hash);
+    }
+
+    /// Record a `notfound` response for a transaction this peer announced. Returns `true`
+    /// if the peer sent [`MAX_NOT_FOUND`] of them within [`NOT_FOUND_WINDOW`].
+    fn not_found(&mut self, time: LocalTime) -> bool {
+        while let Some(t) = self.not_found.front() {
+            if time - *t < NOT_FOUND_WINDOW {
+                break;
+            }
+            self.not_found.pop_front();
+        }
+        self.not_found.push_back(time);
+
+        if self.not_found.len() >= MAX_NOT_FOUND {
+            self.not_found.clear();
+            return true;
+        }
+        false
     }
 
     fn reset(&mut self) {
@@ -87,9 +208,41 @@ impl Peer {
     }
 }
 
+/// A transaction requested from a peer, after it was announced via `inv`.
+#[derive(Debug)]
+struct TxRequest {
+    /// Peer the transaction was requested from.
+    peer: PeerId,
+    /// Time by which the peer should have sent the transaction.
+    deadline: LocalTime,
+    /// Other peers that announced the transaction, in order of announcement.
+    announcers: Vec<PeerId>,
+    /// Peers the transaction was requested from before, which may still respond late.
+    tried: Vec<PeerId>,
+}
+
+impl TxRequest {
+    /// Move the request on to the next announcer that is still connected. Returns the
+    /// peer to request the transaction from, or `None` if no announcer is left.
+    fn retry(&mut self, peers: &AddressBook<PeerId, Peer>, deadline: LocalTime) -> Option<PeerId> {
+        self.announcers.retain(|a| peers.contains_key(a));
+
+        if self.announcers.is_empty() {
+            return None;
+        }
+        self.tried.push(self.peer);
+        self.peer = self.announcers.remove(0);
+        self.deadline = deadline;
+
+        Some(self.peer)
+    }
+}
+
 /// Inventory manager state.
 #[derive(Debug)]
 pub struct InventoryManager<C> {
+    /// Manager configuration.
+    config: Config,
     /// Peer map.
     peers: AddressBook<PeerId, Peer>,
     /// Timeout used for retrying broadcasts.
@@ -107,6 +260,18 @@ pub struct InventoryManager<C> {
     pub remaining: HashMap<BlockHash, Option<LocalTime>>,
     /// Blocks received, waiting to be processed.
     pub received: HashMap<Height, Block>,
+    /// Downloaded blocks, along with their height, kept in case they are requested again.
+    blocks: SizedCache<BlockHash, (Height, Block)>,
+    /// Transactions requested from peers, after being announced.
+    tx_requests: HashMap<Txid, TxRequest>,
+    /// Transactions that may be received without a pending request, and until when they
+    /// are expected, eg. transactions matched by a merkle block, which the sender follows
+    /// up with, or transactions whose request was given up on.
+    tx_expected: HashMap<Txid, LocalTime>,
+    /// Whether transactions announced by peers are fetched.
+    mempool_watch: bool,
+    /// Last status reported for each of our transactions, until it's final or pruned.
+    statuses: HashMap<Txid, TxStatus>,
 
     last_tick: Option<LocalTime>,
     rng: fastrand::Rng,
@@ -124,14 +289,20 @@ impl<C> Iterator for InventoryManager<C> {
 
 impl<C: Clock> InventoryManager<C> {
     /// Create a new inventory manager.
-    pub fn new(rng: fastrand::Rng, clock: C) -> Self {
+    pub fn new(config: Config, rng: fastrand::Rng, clock: C) -> Self {
         Self {
+            blocks: SizedCache::new(config.block_cache_size),
+            config,
             peers: AddressBook::new(rng.clone()),
             mempool: BTreeMap::new(),
             estimator: FeeEstimator::default(),
             confirmed: HashMap::with_hasher(rng.clone().into()),
             remaining: HashMap::with_hasher(rng.clone().into()),
             received: HashMap::with_hasher(rng.clone().into()),
+            tx_requests: HashMap::with_hasher(rng.clone().into()),
+            tx_expected: HashMap::with_hasher(rng.clone().into()),
+            mempool_watch: false,
+            statuses: HashMap::with_hasher(rng.clone().into()),
             timeout: REBROADCAST_TIMEOUT,
             last_tick: None,
             rng,
@@ -152,6 +323,23 @@ impl<C: Clock> InventoryManager<C> {
         self.mempool.contains_key(txid)
     }
 
+    /// Fetch the transactions announced by peers, so that they can be matched against the
+    /// watchlist.
+    pub fn watch_mempool(&mut self) {
+        self.mempool_watch = true;
+    }
+
+    /// Stop fetching the transactions announced by peers.
+    pub fn unwatch_mempool(&mut self) {
+        self.mempool_watch = false;
+    }
+
+    #[cfg(test)]
+    /// Get the pending transaction requests, and the peer each one was requested from.
+    pub fn tx_requests(&self) -> impl Iterator<Item = (&Txid, &PeerId)> {
+        self.tx_requests.iter().map(|(txid, req)| (txid, &req.peer))
+    }
+
     /// Event received.
     pub fn received_event<T: BlockReader>(&mut self, event: Event, tree: &T) {
         match event {
@@ -166,7 +354,7 @@ impl<C: Clock> InventoryManager<C> {
             Event::PeerDisconnected { addr, .. } => {
                 self.peers.remove(&addr);
             }
-            Event::BlockHeadersImported { reverted, .. } => {
+            Event::BlockHeadersImported(ChainImport { reverted, .. }) => {
                 for (height, _) in reverted {
                     self.block_reverted(height);
                 }
@@ -181,15 +369,45 @@ impl<C: Clock> InventoryManager<C> {
                 }
                 NetworkMessage::Tx(tx) => {
                     let txid = tx.txid();
-                    log::debug!(target: "p2p", "transaction {} from {}", txid, from)
+                    log::debug!(target: "p2p", "transaction {} from {}", txid, from);
+
+                    self.received_tx(from, txid);
+
+                    // A relayed transaction conflicting with one of ours is a double-spend
+                    // attempt. Nodes that saw it first will reject our transaction.
+                    if !self.mempool.contains_key(&txid) {
+                        for conflict in self.conflicts(tx) {
+                            self.status_changed(
+                                conflict,
+                                TxStatus::DoubleSpent {
+                                    conflicting: Some(txid),
+                                    block: None,
+                                },
+                            );
+                        }
+                    }
+                }
+                NetworkMessage::MerkleBlock(merkle_block) => {
+                    self.received_merkle_block(merkle_block);
                 }
                 NetworkMessage::GetData(invs) => {
                     self.received_getdata(from, invs);
                     // TODO: (*self.hooks.on_getdata)(addr, invs, &self.outbox);
                 }
-                NetworkMessage::Inv(msg)=> {
-                    log::debug!("Received INV message {:?}", msg);
-                    log::info!("Received INV message {:?}", msg);
+                NetworkMessage::Inv(invs) => {
+                    log::debug!(target: "p2p", "Received INV message {:?}", invs);
+                    self.received_inv(from, invs);
+                }
+                NetworkMessage::NotFound(invs) => {
+                    self.received_notfound(from, invs);
+                }
+                NetworkMessage::FeeFilter(feerate) => {
+                    self.received_feefilter(from, *feerate);
+                }
+                NetworkMessage::Unknown { command, payload }
+                    if command.as_ref() == dsproof::COMMAND =>
+                {
+                    self.received_dsproof(from, payload);
                 }
                 _ => {}
             },
@@ -199,10 +417,13 @@ impl<C: Clock> InventoryManager<C> {
 
     /// Called when a peer is negotiated.
     fn peer_negotiated(&mut self, addr: PeerId, services: ServiceFlags, relay: bool) {
-        // Add existing inventories to this peer's outbox so that they are announced.
+        // Add existing inventories to this peer's outbox so that they are announced,
+        // if the peer wants transactions relayed to it.
         let mut outbox = HashMap::with_hasher(self.rng.clone().into());
-        for (txid, tx) in self.mempool.iter() {
-            outbox.insert(*txid, tx.clone());
+        if relay {
+            for (txid, tx) in self.mempool.iter() {
+                outbox.insert(*txid, tx.clone());
+            }
         }
 
         self.schedule_tick();
@@ -215,22 +436,41 @@ impl<C: Clock> InventoryManager<C> {
 
                 outbox,
                 last_attempt: None,
-                requests: HashMap::with_hasher(self.rng.clone().into()),
+                pending_since: None,
+                fee_filter: 0,
+                requests: HashSet::with_hasher(self.rng.clone().into()),
+                not_found: VecDeque::new(),
             },
         );
     }
 
+    /// Called when a peer sets its minimum relay fee rate, with BIP 133 `feefilter`.
+    /// Queued transactions the peer won't relay are no longer announced to it.
+    fn received_feefilter(&mut self, from: PeerId, feerate: i64) {
+        let Some(peer) = self.peers.get_mut(&from) else {
+            return;
+        };
+        let estimator = &self.estimator;
+        let feerate = feerate.max(0) as u64;
+
+        peer.fee_filter = feerate;
+        peer.outbox
+            .retain(|_, tx| relays(feerate, estimator.fee_rate(tx)));
+
+        self.outbox.event(Event::PeerFeeFilter {
+            addr: from,
+            feerate,
+        });
+    }
+
     /// Called when a block is reverted.
     pub fn block_reverted(&mut self, height: Height) {
         self.estimator.rollback(height - 1);
 
         if let Some(transactions) = self.confirmed.remove(&height) {
             for transaction in transactions {
-                self.announce(transaction.clone());
-                self.outbox.event(Event::TxStatusChanged {
-                    txid: transaction.txid(),
-                    status: TxStatus::Reverted { transaction },
-                });
+                self.queue(transaction.clone());
+                self.status_changed(transaction.txid(), TxStatus::Reverted { transaction });
             }
         }
     }
@@ -251,8 +491,17 @@ impl<C: Clock> InventoryManager<C> {
         {
             // Prune confirmed transactions burried passed a certain depth.
             let height = tree.height();
-            self.confirmed
-                .retain(|h, _| height - h <= TRANSACTION_PRUNE_DEPTH);
+            let statuses = &mut self.statuses;
+
+            self.confirmed.retain(|h, txs| {
+                let keep = height - h <= TRANSACTION_PRUNE_DEPTH;
+                if !keep {
+                    for tx in txs {
+                        statuses.remove(&tx.txid());
+                    }
+                }
+                keep
+            });
         }
 
         // Handle retries annd disconnects.
@@ -264,8 +513,14 @@ impl<C: Clock> InventoryManager<C> {
 
             // Peer inventory announce timeout.
             if !peer.outbox.is_empty() {
-                let elapsed = now - peer.last_attempt.unwrap_or_default();
-                if elapsed < self.timeout {
+                // New inventories are announced once the announcement window has lapsed,
+                // along with any other inventories queued in the meantime. Otherwise,
+                // inventories are re-announced after the timeout.
+                if let Some(since) = peer.pending_since {
+                    if now - since < self.config.announce_window {
+                        continue;
+                    }
+                } else if now - peer.last_attempt.unwrap_or_default() < self.timeout {
                     continue;
                 }
 
@@ -280,7 +535,11 @@ impl<C: Clock> InventoryManager<C> {
 
                 peer.attempted(now);
 
-                let invs = Vec::with_capacity(peer.outbox.len());
+                let invs = peer
+                    .outbox
+                    .keys()
+                    .map(|txid| Inventory::Transaction(*txid))
+                    .collect();
 
                 self.outbox.inv(*addr, invs);
                 self.outbox.set_timer(self.timeout);
@@ -292,24 +551,38 @@ impl<C: Clock> InventoryManager<C> {
             self.outbox.event(Event::PeerTimedOut { addr });
         }
 
-        // Handle block request queue.
-        let queue = self
-            .remaining
-            .iter_mut()
-            .filter(|(_, t)| now - t.unwrap_or_default() >= REQUEST_TIMEOUT);
+        // Re-request timed out transactions from the next peer that announced them.
+        let mut abandoned = Vec::new();
 
-        for (block_hash, last_request) in queue {
-            if let Some((addr, _)) = self
-                .peers
-                .sample_with(|_, p| p.services.has(ServiceFlags::NETWORK))
-            {
-                log::debug!(target: "p2p", "Requesting block {} from {}", block_hash, addr);
+        for (txid, req) in self.tx_requests.iter_mut() {
+            if now < req.deadline {
+                continue;
+            }
+            if let Some(peer) = req.retry(&self.peers, now + REQUEST_TIMEOUT) {
+                log::debug!(target: "p2p", "Re-requesting transaction {} from {}", txid, peer);
 
                 self.outbox
-                    .get_data(*addr, vec![Inventory::Block(*block_hash)]);
+                    .get_data(peer, vec![Inventory::Transaction(*txid)]);
                 self.outbox.set_timer(REQUEST_TIMEOUT);
+            } else {
+                abandoned.push(*txid);
+            }
+        }
+        self.tx_expected.retain(|_, until| now < *until);
+
+        for txid in abandoned {
+            self.abandon_tx_request(txid);
+        }
+
+        // Handle block request queue.
+        for block_hash in self.download_queue(tree) {
+            let last_request = self.remaining.get(&block_hash).copied().flatten();
 
-                *last_request = Some(now);
+            if now - last_request.unwrap_or_default() < REQUEST_TIMEOUT {
+                continue;
+            }
+            if let Some(addr) = self.request_block(block_hash, |_| true) {
+                log::debug!(target: "p2p", "Requested block {} from {}", block_hash, addr);
             } else {
                 log::debug!(
                     target: "p2p",
@@ -320,6 +593,96 @@ impl<C: Clock> InventoryManager<C> {
         }
     }
 
+    /// Called when a `notfound` is received from a peer, in response to one of our `getdata`
+    /// requests. The missing data is requested from another peer right away.
+    ///
+    /// Peers repeatedly not finding transactions they announced count as misbehaving.
+    fn received_notfound(&mut self, from: PeerId, invs: &[Inventory]) {
+        let now = self.clock.local_time();
+
+        for inv in invs {
+            match inv {
+                Inventory::Block(hash) => {
+                    if !self.remaining.contains_key(hash) {
+                        continue;
+                    }
+                    let Some(peer) = self.peers.get_mut(&from) else {
+                        continue;
+                    };
+                    if !peer.requests.remove(hash) {
+                        continue;
+                    }
+                    self.outbox.event(Event::DataNotFound {
+                        from,
+                        kind: InvType::Block,
+                    });
+
+                    if let Some(addr) = self.request_block(*hash, |addr| *addr != from) {
+                        log::debug!(target: "p2p", "Re-requested block {} from {}", hash, addr);
+                    }
+                }
+                Inventory::Transaction(txid) => {
+                    let Some(req) = self.tx_requests.get_mut(txid) else {
+                        continue;
+                    };
+                    if req.peer != from {
+                        continue;
+                    }
+                    self.outbox.event(Event::DataNotFound {
+                        from,
+                        kind: InvType::Transaction,
+                    });
+                    if let Some(peer) = req.retry(&self.peers, now + REQUEST_TIMEOUT) {
+                        log::debug!(
+                            target: "p2p",
+                            "Re-requesting transaction {} from {}", txid, peer
+                        );
+
+                        self.outbox
+                            .get_data(peer, vec![Inventory::Transaction(*txid)]);
+                        self.outbox.set_timer(REQUEST_TIMEOUT);
+                    } else {
+                        self.abandon_tx_request(*txid);
+                    }
+
+                    // Nb. Transaction requests are only made to peers that announced them.
+                    if let Some(peer) = self.peers.get_mut(&from) {
+                        if peer.not_found(now) {
+                            self.outbox.event(Event::PeerMisbehaved {
+                                addr: from,
+                                reason: "repeated `notfound` for announced transactions",
+                                severity: Severity::Minor,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Request a queued block from a random peer serving blocks and matching the predicate.
+    /// Returns the peer the block was requested from, if any.
+    fn request_block(
+        &mut self,
+        hash: BlockHash,
+        predicate: impl Fn(&PeerId) -> bool,
+    ) -> Option<PeerId> {
+        let (addr, _) = self
+            .peers
+            .sample_with(|addr, p| p.services.has(ServiceFlags::NETWORK) && predicate(addr))?;
+        let addr = *addr;
+
+        self.outbox.get_data(addr, vec![Inventory::Block(hash)]);
+        self.outbox.set_timer(REQUEST_TIMEOUT);
+        self.remaining.insert(hash, Some(self.clock.local_time()));
+
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.requested(hash);
+        }
+        Some(addr)
+    }
+
     /// Called when a `getdata` is received from a peer.
     pub fn received_getdata(&mut self, addr: PeerId, invs: &[Inventory]) {
         for inv in invs {
@@ -340,10 +703,7 @@ impl<C: Clock> InventoryManager<C> {
                                 if peer.outbox.is_empty() {
                                     log::debug!(target: "p2p", "Peer {} transaction outbox is empty", &addr);
                                 }
-                                self.outbox.event(Event::TxStatusChanged {
-                                    txid,
-                                    status: TxStatus::Acknowledged { peer: addr },
-                                });
+                                self.status_changed(txid, TxStatus::Acknowledged { peer: addr });
                             }
                         }
                     }
@@ -354,6 +714,191 @@ impl<C: Clock> InventoryManager<C> {
         }
     }
 
+    /// Called when an `inv` is received from a peer.
+    ///
+    /// Announced transactions that aren't ours are requested from the first peer that
+    /// announces them. Announcements from other peers are only used if that peer
+    /// doesn't respond in time.
+    pub fn received_inv(&mut self, addr: PeerId, invs: &[Inventory]) {
+        let now = self.clock.local_time();
+        let mut request = Vec::new();
+
+        for inv in invs {
+            if let Inventory::Transaction(txid) = inv {
+                // A peer announcing one of our transactions means it's in its mempool.
+                // Only the first announcement is reported.
+                if self.mempool.contains_key(txid) {
+                    if !matches!(self.statuses.get(txid), Some(TxStatus::InMempool { .. })) {
+                        self.status_changed(
+                            *txid,
+                            TxStatus::InMempool {
+                                peer: addr,
+                                since: now,
+                            },
+                        );
+                    }
+                } else if !self.mempool_watch {
+                    // Other transactions are only of interest while watching mempools.
+                } else if let Some(req) = self.tx_requests.get_mut(txid) {
+                    if req.peer != addr && !req.announcers.contains(&addr) {
+                        req.announcers.push(addr);
+                    }
+                } else {
+                    self.tx_requests.insert(
+                        *txid,
+                        TxRequest {
+                            peer: addr,
+                            deadline: now + REQUEST_TIMEOUT,
+                            announcers: Vec::new(),
+                            tried: Vec::new(),
+                        },
+                    );
+                    request.push(Inventory::Transaction(*txid));
+                }
+            } else if let Inventory::Unknown {
+                inv_type: dsproof::MSG_DOUBLESPENDPROOF,
+                ..
+            } = inv
+            {
+                // Double-spend proofs are only of interest if we have transactions that
+                // could be double-spent.
+                if !self.mempool.is_empty() {
+                    request.push(inv.clone());
+                }
+            }
+        }
+
+        if !request.is_empty() {
+            self.outbox.get_data(addr, request);
+            self.outbox.set_timer(REQUEST_TIMEOUT);
+        }
+    }
+
+    /// Give up on a transaction request, once no announcer is left to request it from.
+    /// The transaction is still accepted from the peers that were asked, for a while.
+    fn abandon_tx_request(&mut self, txid: Txid) {
+        if self.tx_requests.remove(&txid).is_some() {
+            self.tx_expected
+                .insert(txid, self.clock.local_time() + REQUEST_TIMEOUT);
+        }
+    }
+
+    /// Called when a `tx` is received from a peer.
+    ///
+    /// Transactions that were neither requested from this peer nor matched by one of
+    /// its merkle blocks count as misbehavior.
+    fn received_tx(&mut self, addr: PeerId, txid: Txid) {
+        if self.tx_expected.remove(&txid).is_some() {
+            return;
+        }
+        match self.tx_requests.get(&txid) {
+            // Nb. A peer we've given up on may still respond after its deadline.
+            Some(req) if req.peer == addr || req.tried.contains(&addr) => {
+                // The other peers that were asked may still respond.
+                if !req.tried.is_empty() {
+                    self.tx_expected
+                        .insert(txid, self.clock.local_time() + REQUEST_TIMEOUT);
+                }
+                self.tx_requests.remove(&txid);
+            }
+            _ => {
+                self.outbox.event(Event::PeerMisbehaved {
+                    addr,
+                    reason: "unrequested `tx` message",
+                    severity: Severity::Minor,
+                });
+            }
+        }
+    }
+
+    /// Called when a `dsproof-beta` message is received from a peer.
+    ///
+    /// Proofs about transactions we aren't tracking are ignored. Valid proofs mean that
+    /// one of our transactions was double-spent, and may never confirm.
+    fn received_dsproof(&mut self, from: PeerId, payload: &[u8]) {
+        let Ok(proof) = encode::deserialize::<DoubleSpendProof>(payload) else {
+            self.outbox.event(Event::PeerMisbehaved {
+                addr: from,
+                reason: "invalid `dsproof-beta` message",
+                severity: Severity::Severe,
+            });
+            return;
+        };
+        let Some((tx, input)) = self.mempool.values().find_map(|tx| {
+            tx.input
+                .iter()
+                .find(|input| input.previous_output == proof.outpoint)
+                .map(|input| (tx, input))
+        }) else {
+            return;
+        };
+        let txid = tx.txid();
+
+        // The spender signatures are checked against the key our own input reveals, and the
+        // amount of the output it spends. Without either, eg. if the output isn't a P2PKH
+        // output we know of, the proof can't be verified and is ignored.
+        let pubkey = input
+            .script_sig
+            .instructions()
+            .filter_map(Result::ok)
+            .last()
+            .and_then(|i| match i {
+                Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes).ok(),
+                Instruction::Op(_) => None,
+            });
+        let Some((pubkey, amount)) = pubkey.zip(
+            self.estimator
+                .utxo(&proof.outpoint)
+                .map(|output| output.value),
+        ) else {
+            log::debug!(
+                target: "p2p",
+                "Ignoring unverifiable double-spend proof for {} from {}", txid, from
+            );
+            return;
+        };
+
+        if let Err(err) = proof
+            .validate(tx)
+            .and_then(|()| proof.verify(&pubkey, amount))
+        {
+            log::debug!(target: "p2p", "Invalid double-spend proof from {}: {}", from, err);
+
+            self.outbox.event(Event::PeerMisbehaved {
+                addr: from,
+                reason: "invalid `dsproof-beta` message",
+                severity: Severity::Severe,
+            });
+            return;
+        }
+        self.outbox
+            .event(Event::DoubleSpendProofReceived { txid, from });
+        self.status_changed(
+            txid,
+            TxStatus::DoubleSpent {
+                conflicting: None,
+                block: None,
+            },
+        );
+    }
+
+    /// Called when a `merkleblock` is received. Peers follow up merkle blocks with the
+    /// matched transactions, without these being requested.
+    fn received_merkle_block(&mut self, merkle_block: &MerkleBlock) {
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+        let until = self.clock.local_time() + REQUEST_TIMEOUT;
+
+        if merkle_block
+            .extract_matches(&mut matches, &mut indexes)
+            .is_ok()
+        {
+            for txid in matches {
+                self.tx_expected.insert(txid, until);
+            }
+        }
+    }
+
     /// Called when a block is received from a peer.
     /// Returns the list of confirmed [`Txid`].
     ///
@@ -366,6 +911,11 @@ impl<C: Clock> InventoryManager<C> {
     ) -> Vec<Txid> {
         let hash = block.block_hash();
 
+        // We're done requesting this block.
+        for peer in self.peers.values_mut() {
+            peer.requests.remove(&hash);
+        }
+
         if self.remaining.remove(&hash).is_none() {
             // Nb. The remote isn't necessarily sending an unsolicited block here.
             // We often have to ask multiple peers to get a response, so we may
@@ -373,11 +923,6 @@ impl<C: Clock> InventoryManager<C> {
             return vec![];
         }
 
-        // We're done requesting this block.
-        for peer in self.peers.values_mut() {
-            peer.requests.remove(&hash);
-        }
-
         // Find the block height, otherwise we've somehow requested a block which
         // isn't part of the active chain. This could happen in the case of a re-org
         // and a delayed block arrival.
@@ -387,6 +932,15 @@ impl<C: Clock> InventoryManager<C> {
             return vec![];
         };
 
+        for (hash, (height, _)) in self.blocks.put(hash, (height, block.clone()), block.size()) {
+            self.outbox.event(Event::BlockEvicted { hash, height });
+        }
+        self.process_block(height, block)
+    }
+
+    /// Queue a downloaded block for processing, and process the queue if there are no
+    /// more blocks to download. Returns the list of confirmed [`Txid`].
+    fn process_block(&mut self, height: Height, block: Block) -> Vec<Txid> {
         // Add to processing queue. Blocks are processed in-order only.
         self.received.insert(height, block);
 
@@ -408,6 +962,21 @@ impl<C: Clock> InventoryManager<C> {
             .and_then(|h| self.received.remove(&h).map(|b| (h, b)))
         {
             let hash = block.block_hash();
+            let status = TxStatus::Confirmed {
+                block: hash,
+                height,
+            };
+            // If enough of our transactions are confirmed by this block, report them in
+            // a single batch once the block is processed.
+            let mut batch = self.config.tx_status_batch.and_then(|threshold| {
+                let count = block
+                    .txdata
+                    .iter()
+                    .filter(|tx| self.mempool.contains_key(&tx.txid()))
+                    .count();
+
+                (count > threshold).then(|| Vec::with_capacity(count))
+            });
 
             for tx in &block.txdata {
                 let txid = tx.txid();
@@ -426,15 +995,31 @@ impl<C: Clock> InventoryManager<C> {
                         .or_default()
                         .push(transaction.clone());
 
-                    self.outbox.event(Event::TxStatusChanged {
-                        txid: transaction.txid(),
-                        status: TxStatus::Confirmed {
-                            block: hash,
-                            height,
-                        },
-                    });
+                    if let Some(batch) = &mut batch {
+                        if self.set_status(txid, &status) {
+                            batch.push(txid);
+                        }
+                    } else {
+                        self.status_changed(txid, status.clone());
+                    }
+                } else {
+                    // Transactions of ours that conflict with this one were double-spent,
+                    // and can no longer be confirmed.
+                    for conflict in self.conflicts(tx) {
+                        self.remove(&conflict);
+                        self.status_changed(
+                            conflict,
+                            TxStatus::DoubleSpent {
+                                conflicting: Some(txid),
+                                block: Some(hash),
+                            },
+                        );
+                    }
                 }
             }
+            if let Some(txids) = batch {
+                self.outbox.event(Event::TxStatusBatch { status, txids });
+            }
             // Process block through fee estimator.
             let fees = self.estimator.process(block.clone(), height);
 
@@ -448,51 +1033,202 @@ impl<C: Clock> InventoryManager<C> {
     }
 
     /// Announce inventories to all matching peers. Retries if necessary.
-    pub fn announce(&mut self, tx: Transaction) -> Vec<PeerId> {
+    ///
+    /// Announcements queued within the configured window are sent to each peer in a
+    /// single `inv` message. Only peers that accept transaction relay are announced to;
+    /// if there are none, [`Event::NoRelayPeers`] is emitted.
+    ///
+    /// Transactions conflicting with one of our unconfirmed transactions are rejected.
+    pub fn announce(&mut self, tx: Transaction) -> Result<Vec<PeerId>, AnnounceError> {
+        let txid = tx.txid();
+
+        if let Some(conflict) = self.conflicts(&tx).into_iter().next() {
+            return Err(AnnounceError::Conflict { txid, conflict });
+        }
+        let addrs = self.queue(tx);
+
+        if addrs.is_empty() {
+            self.outbox.event(Event::NoRelayPeers { txid });
+        }
+        self.status_changed(txid, TxStatus::Unconfirmed);
+
+        Ok(addrs)
+    }
+
+    /// Queue a transaction to be announced to all matching peers.
+    fn queue(&mut self, tx: Transaction) -> Vec<PeerId> {
         // All peers we are sending inventories to.
         let mut addrs = Vec::new();
 
+        let now = self.clock.local_time();
         let txid = tx.txid();
 
         // Insert transaction into the peer outboxes and keep a local copy for re-broadcasting later.
         self.mempool.insert(txid, tx.clone());
 
-        for (addr, peer) in self.peers.iter_mut().filter(|(_, p)| p.relay) {
-            peer.outbox.insert(txid, tx.clone());
+        // Peers whose fee filter is above the transaction's fee rate would drop it.
+        let rate = self.estimator.fee_rate(&tx);
+
+        for (addr, peer) in self
+            .peers
+            .iter_mut()
+            .filter(|(_, p)| p.relay && relays(p.fee_filter, rate))
+        {
+            if peer.outbox.insert(txid, tx.clone()).is_none() {
+                peer.pending_since.get_or_insert(now);
+            }
             addrs.push(*addr);
         }
-        self.schedule_tick();
+        self.outbox.set_timer(self.config.announce_window);
 
         addrs
     }
 
     /// Attempt to get a block from the network. Retries if necessary.
+    ///
+    /// If the block was already downloaded and is still cached, it's processed right away.
     pub fn get_block(&mut self, hash: BlockHash) {
+        if let Some((height, block)) = self.blocks.get(&hash).cloned() {
+            log::debug!(target: "p2p", "Block {hash} found in cache");
+
+            self.process_block(height, block);
+            return;
+        }
         log::debug!(target: "p2p", "Queueing block {hash} to be requested");
 
         self.remaining.entry(hash).or_insert(None);
         self.schedule_tick();
     }
 
+    /// Get the downloaded blocks still in the block cache, from the least to the most
+    /// recently used.
+    pub fn cached_blocks(&self) -> impl Iterator<Item = &Block> + '_ {
+        self.blocks.entries().map(|(_, (_, block))| block)
+    }
+
+    /// Get the queue of blocks left to download, in the order they are requested.
+    ///
+    /// Blocks are ordered by height, as configured by [`Config::block_order`]. Blocks
+    /// that aren't part of the active chain come last.
+    pub fn download_queue<T: BlockReader>(&self, tree: &T) -> Vec<BlockHash> {
+        let mut queue = self
+            .remaining
+            .keys()
+            .map(|hash| (tree.get_block(hash).map(|(height, _)| height), *hash))
+            .collect::<Vec<_>>();
+
+        queue.sort_unstable_by(|(a, a_hash), (b, b_hash)| {
+            let order = match (a, b) {
+                (Some(a), Some(b)) => match self.config.block_order {
+                    BlockOrder::OldestFirst => a.cmp(b),
+                    BlockOrder::NewestFirst => b.cmp(a),
+                },
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            };
+            order.then_with(|| a_hash.cmp(b_hash))
+        });
+        queue.into_iter().map(|(_, hash)| hash).collect()
+    }
+
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Get the transactions in our mempool conflicting with the given transaction.
+    fn conflicts(&self, tx: &Transaction) -> Vec<Txid> {
+        let txid = tx.txid();
+
+        self.mempool
+            .values()
+            .filter(|t| t.txid() != txid && conflicting(t, tx))
+            .map(|t| t.txid())
+            .collect()
+    }
+
+    /// Remove a transaction from the mempool and peer outboxes.
+    fn remove(&mut self, txid: &Txid) {
+        self.mempool.remove(txid);
+
+        for peer in self.peers.values_mut() {
+            peer.outbox.remove(txid);
+        }
+    }
+
+    /// Report a status change of one of our transactions.
+    ///
+    /// Illegal transitions indicate a bug: they panic in debug builds, and are otherwise
+    /// reported as an error and dropped.
+    fn status_changed(&mut self, txid: Txid, status: TxStatus) {
+        if self.set_status(txid, &status) {
+            self.outbox.event(Event::TxStatusChanged { txid, status });
+        }
+    }
+
+    /// Record a status change of one of our transactions, without reporting it.
+    /// Returns whether the transition is legal. See [`InventoryManager::status_changed`].
+    fn set_status(&mut self, txid: Txid, status: &TxStatus) -> bool {
+        if let Some(prev) = self.statuses.get(&txid) {
+            debug_assert!(
+                prev.can_transition_to(status),
+                "InventoryManager::status_changed: illegal transition of {txid}: {prev:?} -> {status:?}"
+            );
+            if !prev.can_transition_to(status) {
+                self.outbox.error(
+                    ErrorKind::Other,
+                    Error::IllegalTransition {
+                        txid,
+                        from: prev.code(),
+                        to: status.code(),
+                    },
+                );
+                return false;
+            }
+        }
+        match status {
+            // Final statuses don't need to be tracked anymore.
+            TxStatus::Stale { .. } | TxStatus::DoubleSpent { block: Some(_), .. } => {
+                self.statuses.remove(&txid);
+            }
+            _ => {
+                self.statuses.insert(txid, status.clone());
+            }
+        }
+        true
+    }
+
     fn schedule_tick(&mut self) {
         self.last_tick = None; // Disable rate-limiting for the next tick.
         self.outbox.set_timer(LocalDuration::from_secs(1));
     }
 }
 
+/// Check whether two transactions conflict, ie. spend at least one common output.
+fn conflicting(a: &Transaction, b: &Transaction) -> bool {
+    a.input.iter().any(|i| {
+        b.input
+            .iter()
+            .any(|j| i.previous_output == j.previous_output)
+    })
+}
+
+/// Check whether a peer with the given fee filter, in satoshis per kilobyte, relays
+/// transactions paying the given fee rate. Transactions of unknown fee rate are assumed
+/// to be relayed.
+fn relays(fee_filter: u64, rate: Option<FeeRate>) -> bool {
+    rate.map_or(true, |r| r.saturating_mul(1000) >= fee_filter)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::borrow::BorrowMut;
     use std::net;
+    use std::sync::Arc;
 
     use crate::fsm::network::Network;
     use crate::fsm::{output, Locators};
 
     use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use nakamoto_common::bitcoin::OutPoint;
     use nakamoto_common::block::time::RefClock;
     use nakamoto_common::block::tree::BlockTree as _;
     use nakamoto_common::collections::HashSet;
@@ -527,7 +1263,7 @@ mod tests {
 
         let block = chain.iter().find(|b| b.block_hash() == hash).unwrap();
 
-        let mut invmgr = InventoryManager::new(rng.clone(), clock.clone());
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), clock.clone());
 
         invmgr.peer_negotiated(
             ([66, 66, 66, 66], 8333).into(),
@@ -583,6 +1319,69 @@ mod tests {
         // invmgr.timer_expired(&tree);
     }
 
+    #[test]
+    fn test_block_order() {
+        let network = Network::Regtest;
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let heights = [9, 3, 14, 6];
+
+        for (order, expected) in [
+            (BlockOrder::OldestFirst, [3, 6, 9, 14]),
+            (BlockOrder::NewestFirst, [14, 9, 6, 3]),
+        ] {
+            let mut invmgr = InventoryManager::new(
+                Config {
+                    block_order: order,
+                    ..Config::default()
+                },
+                rng.clone(),
+                clock.clone(),
+            );
+            invmgr.peer_negotiated(([66, 66, 66, 66], 8333).into(), ServiceFlags::NETWORK, true);
+
+            for height in heights {
+                invmgr.get_block(chain[height].block_hash());
+            }
+            let expected = expected
+                .iter()
+                .map(|h| chain[*h].block_hash())
+                .collect::<Vec<_>>();
+
+            assert_eq!(invmgr.download_queue(&tree), expected);
+
+            invmgr.timer_expired(&tree);
+
+            let requested = output::test::messages(&mut invmgr)
+                .filter_map(|(_, m)| match m {
+                    NetworkMessage::GetData(invs) => Some(invs),
+                    _ => None,
+                })
+                .flatten()
+                .map(|inv| match inv {
+                    Inventory::Block(hash) => hash,
+                    other => panic!("unexpected inventory {other:?}"),
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                requested, expected,
+                "Blocks are requested in {order:?} order"
+            );
+
+            // Received blocks are removed from the queue.
+            invmgr.received_block(&([66, 66, 66, 66], 8333).into(), chain[9].clone(), &tree);
+            assert!(!invmgr
+                .download_queue(&tree)
+                .contains(&chain[9].block_hash()));
+        }
+    }
+
     #[test]
     fn test_get_block() {
         logger::init(log::Level::Debug);
@@ -601,7 +1400,7 @@ mod tests {
         let inv = vec![Inventory::Block(hash)];
         let block = chain.iter().find(|b| b.block_hash() == hash).unwrap();
 
-        let mut invmgr = InventoryManager::new(rng.clone(), clock.clone());
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), clock.clone());
 
         invmgr.peer_negotiated(
             ([66, 66, 66, 66], 8333).into(),
@@ -683,19 +1482,300 @@ mod tests {
     }
 
     #[test]
-    fn test_rebroadcast_timeout() {
-        let network = Network::Mainnet;
-        let tree = model::Cache::from(NonEmpty::new(network.genesis()));
-        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
-        let mut rng = fastrand::Rng::with_seed(1);
+    fn test_block_cache() {
+        let network = Network::Regtest;
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+        let remote: PeerId = ([66, 66, 66, 66], 8333).into();
+
+        let chain = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let block = chain[4].clone();
+        let hash = block.block_hash();
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+
+        // On a cache miss, the block is requested from the network.
+        invmgr.get_block(hash);
+        invmgr.timer_expired(&tree);
+        assert!(output::test::messages(&mut invmgr).any(
+            |(_, m)| matches!(m, NetworkMessage::GetData(i) if i == &[Inventory::Block(hash)])
+        ));
+        invmgr.received_block(&remote, block.clone(), &tree);
+        assert_matches!(
+            events(invmgr.outbox.drain()).next(),
+            Some(Event::BlockProcessed { height: 4, .. })
+        );
+
+        // On a cache hit, eg. after a re-org, the block is processed without a request.
+        invmgr.get_block(hash);
+        assert!(invmgr.remaining.is_empty());
+        assert_matches!(
+            events(invmgr.outbox.drain()).next(),
+            Some(Event::BlockProcessed { block: b, height: 4, .. }) if b == block
+        );
+        clock.elapse(REQUEST_TIMEOUT);
+        invmgr.timer_expired(&tree);
+        assert_eq!(output::test::messages(&mut invmgr).count(), 0);
+    }
+
+    #[test]
+    fn test_block_cache_eviction() {
+        let network = Network::Regtest;
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+        let remote: PeerId = ([66, 66, 66, 66], 8333).into();
+
+        let chain = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let blocks = chain.tail[..4].to_vec();
+
+        // Budget for the three largest blocks, so that at most three blocks fit.
+        let mut sizes = blocks.iter().map(|b| b.size()).collect::<Vec<_>>();
+        sizes.sort_unstable();
+        let cfg = Config {
+            block_cache_size: sizes[1..].iter().sum(),
+            ..Config::default()
+        };
+        let mut invmgr = InventoryManager::new(cfg, rng, clock);
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+
+        for block in &blocks[..3] {
+            invmgr.get_block(block.block_hash());
+            invmgr.received_block(&remote, block.clone(), &tree);
+        }
+        assert!(!events(invmgr.outbox.drain()).any(|e| matches!(e, Event::BlockEvicted { .. })));
+
+        // Using a block protects it from eviction, so the least recently used block goes first.
+        invmgr.get_block(blocks[0].block_hash());
+        invmgr.get_block(blocks[3].block_hash());
+        invmgr.received_block(&remote, blocks[3].clone(), &tree);
+
+        let evicted = events(invmgr.outbox.drain())
+            .filter_map(|e| match e {
+                Event::BlockEvicted { hash, height } => Some((hash, height)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(!evicted.is_empty());
+        assert_eq!(evicted[0], (blocks[1].block_hash(), 2));
+        assert!(evicted
+            .iter()
+            .all(|(hash, _)| *hash != blocks[0].block_hash() && *hash != blocks[3].block_hash()));
+    }
+
+    #[test]
+    fn test_announce_coalescing() {
+        let network = Network::Mainnet;
+        let tree = model::Cache::from(NonEmpty::new(network.genesis()));
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let txs = (0..4)
+            .map(|_| gen::transaction(&mut rng))
+            .collect::<Vec<_>>();
+        let invs = |invmgr: &mut InventoryManager<_>| {
+            output::test::messages_from(&mut invmgr.outbox, &remote)
+                .filter_map(|m| match m {
+                    NetworkMessage::Inv(invs) => Some(invs),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+        invmgr.timer_expired(&tree);
+        invmgr.outbox.drain().for_each(drop);
+
+        // Three transactions are broadcast within the window, the last one right at its expiry.
+        for (i, tx) in txs.iter().take(3).enumerate() {
+            if i > 0 {
+                clock.elapse(ANNOUNCE_WINDOW / 2);
+            }
+            invmgr.announce(tx.clone()).unwrap();
+            if i < 2 {
+                invmgr.timer_expired(&tree);
+            }
+        }
+        let statuses = events(&mut invmgr.outbox)
+            .filter(|e| {
+                matches!(
+                    e,
+                    Event::TxStatusChanged {
+                        status: TxStatus::Unconfirmed,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(statuses, 3, "Status changes are reported immediately");
+
+        invmgr.timer_expired(&tree);
+        let sent = invs(&mut invmgr);
+        assert_eq!(sent.len(), 1, "A single `inv` is sent");
+        assert_eq!(sent[0].len(), 3);
+        for tx in txs.iter().take(3) {
+            assert!(sent[0].contains(&Inventory::Transaction(tx.txid())));
+        }
+
+        // A transaction broadcast right after the flush is announced at the end of the
+        // next window.
+        invmgr.announce(txs[3].clone()).unwrap();
+        invmgr.timer_expired(&tree);
+        assert!(invs(&mut invmgr).is_empty());
+
+        clock.elapse(ANNOUNCE_WINDOW);
+        invmgr.timer_expired(&tree);
+        let sent = invs(&mut invmgr);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains(&Inventory::Transaction(txs[3].txid())));
+    }
+
+    #[test]
+    fn test_announce_relay() {
+        let network = Network::Mainnet;
+        let tree = model::Cache::from(NonEmpty::new(network.genesis()));
+        let alice: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let bob: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+        let carol: net::SocketAddr = ([77, 77, 77, 77], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let tx = gen::transaction(&mut rng);
+        let announced = |invmgr: &mut InventoryManager<_>| {
+            output::test::messages(&mut invmgr.outbox)
+                .filter_map(|(addr, m)| match m {
+                    NetworkMessage::Inv(invs) if invs == [Inventory::Transaction(tx.txid())] => {
+                        Some(addr)
+                    }
+                    _ => None,
+                })
+                .collect::<HashSet<_>>()
+        };
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), clock.clone());
+        invmgr.peer_negotiated(alice, ServiceFlags::NETWORK, true);
+        invmgr.peer_negotiated(bob, ServiceFlags::NETWORK, false);
+        invmgr.peer_negotiated(carol, ServiceFlags::NETWORK, true);
+
+        let peers = invmgr.announce(tx.clone()).unwrap();
+        assert_eq!(
+            peers.into_iter().collect::<HashSet<_>>(),
+            [alice, carol].into_iter().collect()
+        );
+
+        clock.elapse(ANNOUNCE_WINDOW);
+        invmgr.timer_expired(&tree);
+        assert_eq!(announced(&mut invmgr), [alice, carol].into_iter().collect());
+
+        // Without any relay peer, the transaction isn't silently dropped.
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
+        invmgr.peer_negotiated(bob, ServiceFlags::NETWORK, false);
+
+        assert!(invmgr.announce(tx.clone()).unwrap().is_empty());
+        assert_matches!(
+            events(invmgr.outbox.drain()).next(),
+            Some(Event::NoRelayPeers { txid }) if txid == tx.txid()
+        );
+
+        // It's announced once a relay peer connects.
+        invmgr.peer_negotiated(alice, ServiceFlags::NETWORK, true);
+        invmgr.timer_expired(&tree);
+        assert_eq!(announced(&mut invmgr), [alice].into_iter().collect());
+    }
+
+    #[test]
+    fn test_feefilter() {
+        let network = Network::Regtest;
+        let genesis = network.genesis();
+        let tree = model::Cache::from(NonEmpty::new(genesis));
+        let alice: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let bob: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+
+        let mut coinbase = gen::coinbase(&mut rng);
+        coinbase.output[0].value = 100_000;
+        coinbase.output.truncate(1);
+        coinbase.output.push(coinbase.output[0].clone());
+
+        // Spend a coinbase output, paying the given fee.
+        let spend = |vout: u32, fee: u64, rng: &mut fastrand::Rng| {
+            let prevout = OutPoint {
+                txid: coinbase.txid(),
+                vout,
+            };
+            let mut tx = gen::transaction_with(prevout, 100_000, rng);
+            tx.output.truncate(1);
+            tx.output[0].value = 100_000 - fee;
+            tx
+        };
+        let low = spend(0, 0, &mut rng);
+        let high = spend(1, 50_000, &mut rng);
+        let announced = |invmgr: &mut InventoryManager<_>, tx: &Transaction| {
+            output::test::messages(&mut invmgr.outbox)
+                .filter_map(|(addr, m)| match m {
+                    NetworkMessage::Inv(invs)
+                        if invs.contains(&Inventory::Transaction(tx.txid())) =>
+                    {
+                        Some(addr)
+                    }
+                    _ => None,
+                })
+                .collect::<HashSet<_>>()
+        };
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), clock.clone());
+        invmgr
+            .estimator
+            .process(gen::block_with(&genesis, vec![coinbase], &mut rng), 1);
+        invmgr.peer_negotiated(alice, ServiceFlags::NETWORK, true);
+        invmgr.peer_negotiated(bob, ServiceFlags::NETWORK, true);
+        invmgr.received_event(
+            Event::MessageReceived {
+                from: bob,
+                message: Arc::new(NetworkMessage::FeeFilter(1000)),
+            },
+            &tree,
+        );
+        assert_matches!(
+            events(invmgr.outbox.drain()).next(),
+            Some(Event::PeerFeeFilter { addr, feerate: 1000 }) if addr == bob
+        );
+
+        // The low-fee transaction isn't announced to the peer that would drop it.
+        assert_eq!(invmgr.announce(low.clone()), Ok(vec![alice]));
+        clock.elapse(ANNOUNCE_WINDOW);
+        invmgr.timer_expired(&tree);
+        assert_eq!(announced(&mut invmgr, &low), [alice].into_iter().collect());
+
+        invmgr.announce(high.clone()).unwrap();
+        clock.elapse(ANNOUNCE_WINDOW);
+        invmgr.timer_expired(&tree);
+        assert_eq!(
+            announced(&mut invmgr, &high),
+            [alice, bob].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_rebroadcast_timeout() {
+        let network = Network::Mainnet;
+        let tree = model::Cache::from(NonEmpty::new(network.genesis()));
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
 
         let clock = RefClock::from(LocalTime::now());
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, clock.clone());
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
 
         invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
-        invmgr.announce(tx);
+        invmgr.announce(tx).unwrap();
+        clock.elapse(ANNOUNCE_WINDOW);
         invmgr.timer_expired(&tree);
 
         assert_eq!(
@@ -732,10 +1812,11 @@ mod tests {
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, clock.clone());
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
 
         invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
-        invmgr.announce(tx.clone());
+        invmgr.announce(tx.clone()).unwrap();
+        clock.elapse(ANNOUNCE_WINDOW);
 
         // We attempt to broadcast up to `MAX_ATTEMPTS` times.
         for _ in 0..MAX_ATTEMPTS {
@@ -779,10 +1860,10 @@ mod tests {
         let time = LocalTime::now();
 
         let mut tree = model::Cache::from(headers);
-        let mut invmgr = InventoryManager::new(rng, time);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, time);
 
         invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
-        invmgr.announce(tx.clone());
+        invmgr.announce(tx.clone()).unwrap();
         invmgr.get_block(main_block1.block_hash());
         invmgr.received_block(&remote, main_block1, &tree);
 
@@ -828,23 +1909,115 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_tx_status_batch() {
+        let network = Network::Regtest;
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
+
+        let mut chain = gen::blockchain(network.genesis_block(), 16, &mut rng);
+        let tip = chain.last().header;
+        let txs = (0..TX_STATUS_BATCH_THRESHOLD + 1)
+            .map(|_| gen::transaction(&mut rng))
+            .collect::<Vec<_>>();
+        let block = gen::block_with(&tip, txs.clone(), &mut rng);
+        let single = gen::transaction(&mut rng);
+        let next = gen::block_with(&block.header, vec![single.clone()], &mut rng);
+
+        chain.push(block.clone());
+        chain.push(next.clone());
+
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+
+        // Status changes are reported individually by default.
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), LocalTime::now());
+
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+        for tx in &txs {
+            invmgr.announce(tx.clone()).unwrap();
+        }
+        invmgr.outbox.drain().for_each(drop);
+        invmgr.get_block(block.block_hash());
+        invmgr.received_block(&remote, block.clone(), &tree);
+
+        let emitted = events(invmgr.outbox.drain()).collect::<Vec<_>>();
+        assert!(!emitted
+            .iter()
+            .any(|e| matches!(e, Event::TxStatusBatch { .. })));
+        assert_eq!(
+            emitted
+                .iter()
+                .filter(|e| matches!(e, Event::TxStatusChanged { .. }))
+                .count(),
+            txs.len()
+        );
+
+        let config = Config {
+            tx_status_batch: Some(TX_STATUS_BATCH_THRESHOLD),
+            ..Config::default()
+        };
+        let mut invmgr = InventoryManager::new(config, rng, LocalTime::now());
+
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+        for tx in txs.iter().chain(Some(&single)) {
+            invmgr.announce(tx.clone()).unwrap();
+        }
+        invmgr.outbox.drain().for_each(drop);
+
+        // A block confirming more transactions than the threshold yields a single batch.
+        invmgr.get_block(block.block_hash());
+        invmgr.received_block(&remote, block.clone(), &tree);
+
+        let emitted = events(invmgr.outbox.drain()).collect::<Vec<_>>();
+        assert!(!emitted
+            .iter()
+            .any(|e| matches!(e, Event::TxStatusChanged { .. })));
+        assert_matches!(
+            emitted
+                .iter()
+                .filter(|e| matches!(e, Event::TxStatusBatch { .. }))
+                .collect::<Vec<_>>()
+                .as_slice(),
+            [Event::TxStatusBatch { status: TxStatus::Confirmed { block: hash, .. }, txids }]
+            if *hash == block.block_hash()
+            && *txids == txs.iter().map(|tx| tx.txid()).collect::<Vec<_>>()
+        );
+        assert!(txs.iter().all(|tx| !invmgr.contains(&tx.txid())));
+
+        // Below the threshold, status changes are reported individually.
+        invmgr.get_block(next.block_hash());
+        invmgr.received_block(&remote, next, &tree);
+
+        let emitted = events(invmgr.outbox.drain()).collect::<Vec<_>>();
+        assert!(!emitted
+            .iter()
+            .any(|e| matches!(e, Event::TxStatusBatch { .. })));
+        assert!(emitted.iter().any(|e| matches!(
+            e,
+            Event::TxStatusChanged { txid, status: TxStatus::Confirmed { .. } }
+            if *txid == single.txid()
+        )));
+    }
+
     #[test]
     fn test_wtx_inv() {
         let network = Network::Mainnet;
         let tree = model::Cache::from(NonEmpty::new(network.genesis()));
 
         let mut rng = fastrand::Rng::with_seed(1);
-        let time = LocalTime::now();
+        let clock = RefClock::from(LocalTime::now());
 
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let remote2: net::SocketAddr = ([88, 88, 88, 89], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, time);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
 
         invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
-        invmgr.announce(tx);
+        invmgr.announce(tx).unwrap();
 
+        clock.elapse(ANNOUNCE_WINDOW);
         invmgr.timer_expired(&tree);
         let invs = output::test::messages_from(&mut invmgr.outbox, &remote)
             .filter_map(|m| {
@@ -876,10 +2049,10 @@ mod tests {
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, LocalTime::now());
+        let mut invmgr = InventoryManager::new(Config::default(), rng, LocalTime::now());
 
         invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
-        invmgr.announce(tx.clone());
+        invmgr.announce(tx.clone()).unwrap();
 
         invmgr.received_getdata(remote, &[Inventory::Transaction(tx.txid())]);
         let tr = output::test::messages_from(&mut invmgr.outbox, &remote)
@@ -907,4 +2080,454 @@ mod tests {
             .unwrap();
         assert_eq!(tr.txid(), tx.txid());
     }
+
+    #[test]
+    fn test_conflicting_announcement() {
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+
+        // The conflicting transaction spends the same inputs, with a higher fee.
+        let original = gen::transaction(&mut rng);
+        let mut conflicting = original.clone();
+        conflicting.output[0].value -= 1;
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng, LocalTime::now());
+
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+        invmgr.announce(original.clone()).unwrap();
+        invmgr.outbox.drain().for_each(drop);
+
+        // Unconfirmed transactions can't be replaced.
+        assert_eq!(
+            invmgr.announce(conflicting.clone()),
+            Err(AnnounceError::Conflict {
+                txid: conflicting.txid(),
+                conflict: original.txid(),
+            })
+        );
+        assert!(invmgr.contains(&original.txid()));
+        assert!(!invmgr.contains(&conflicting.txid()));
+        assert!(events(invmgr.outbox.drain()).next().is_none());
+
+        // The same transaction can be announced again.
+        assert_eq!(invmgr.announce(original.clone()), Ok(vec![remote]));
+    }
+
+    #[test]
+    fn test_double_spent() {
+        let network = Network::Regtest;
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+
+        let main = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let tip = main.last().header;
+        let headers = NonEmpty::from_vec(main.iter().map(|b| b.header).collect()).unwrap();
+        let mut tree = model::Cache::from(headers);
+
+        let tx = gen::transaction(&mut rng);
+        let mut double_spend = tx.clone();
+        double_spend.output[0].value -= 1;
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), LocalTime::now());
+        let double_spent = |invmgr: &mut InventoryManager<_>| {
+            events(invmgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::TxStatusChanged {
+                        txid,
+                        status: TxStatus::DoubleSpent { conflicting, block },
+                    } => Some((txid, conflicting, block)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+        invmgr.announce(tx.clone()).unwrap();
+
+        // A conflicting transaction is relayed to us.
+        invmgr.received_event(
+            Event::MessageReceived {
+                from: remote,
+                message: Arc::new(NetworkMessage::Tx(double_spend.clone())),
+            },
+            &tree,
+        );
+        assert_eq!(
+            double_spent(&mut invmgr),
+            vec![(tx.txid(), Some(double_spend.txid()), None)]
+        );
+        assert!(invmgr.contains(&tx.txid()));
+
+        // The conflicting transaction is confirmed.
+        let block = gen::block_with(&tip, vec![double_spend.clone()], &mut rng);
+        tree.import_blocks(std::iter::once(block.header), &LocalTime::now())
+            .unwrap();
+        invmgr.get_block(block.block_hash());
+        invmgr.received_block(&remote, block.clone(), &tree);
+
+        assert_eq!(
+            double_spent(&mut invmgr),
+            vec![(
+                tx.txid(),
+                Some(double_spend.txid()),
+                Some(block.block_hash())
+            )]
+        );
+        assert!(!invmgr.contains(&tx.txid()));
+    }
+
+    #[test]
+    fn test_double_spend_proof() {
+        use nakamoto_common::bitcoin::blockdata::script::{Builder, Script};
+        use nakamoto_common::bitcoin::consensus::encode::serialize;
+        use nakamoto_common::bitcoin::hashes::Hash;
+        use nakamoto_common::bitcoin::network::message::CommandString;
+        use nakamoto_common::bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+        use nakamoto_common::p2p::dsproof::Spender;
+
+        let network = Network::Regtest;
+        let genesis = network.genesis();
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
+        let tree = model::Cache::from(NonEmpty::new(genesis));
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let pubkey = PublicKey::new(key.public_key(&secp));
+        let script_code = Script::new_p2pkh(&pubkey.pubkey_hash());
+
+        // A pay-to-pubkey-hash output of ours.
+        let mut coinbase = gen::coinbase(&mut rng);
+        coinbase.output.truncate(1);
+        coinbase.output[0].value = 100_000;
+        coinbase.output[0].script_pubkey = script_code.clone();
+        let outpoint = OutPoint {
+            txid: coinbase.txid(),
+            vout: 0,
+        };
+
+        // Spend our output, with a signature made with the given key.
+        let spend = |key: &SecretKey, rng: &mut fastrand::Rng| {
+            let mut tx = gen::transaction_with(outpoint, 100_000, rng);
+            tx.input[0].script_sig = Builder::new().push_slice(&[0x41]).into_script();
+
+            let sighash = Spender::new(&tx, 0)
+                .unwrap()
+                .sighash(&outpoint, &script_code, 100_000);
+            let mut signature = secp
+                .sign_ecdsa(&Message::from_slice(&sighash[..]).unwrap(), key)
+                .serialize_der()
+                .to_vec();
+            signature.push(0x41); // SIGHASH_ALL | SIGHASH_FORKID
+
+            tx.input[0].script_sig = Builder::new()
+                .push_slice(&signature)
+                .push_key(&pubkey)
+                .into_script();
+            tx
+        };
+        let tx = spend(&key, &mut rng);
+        let conflict = spend(&key, &mut rng);
+        let proof = DoubleSpendProof::new(
+            outpoint,
+            Spender::new(&tx, 0).unwrap(),
+            Spender::new(&conflict, 0).unwrap(),
+        );
+        let message = |proof: &DoubleSpendProof| Event::MessageReceived {
+            from: remote,
+            message: Arc::new(NetworkMessage::Unknown {
+                command: CommandString::try_from_static(dsproof::COMMAND).unwrap(),
+                payload: serialize(proof),
+            }),
+        };
+        let inv = Inventory::Unknown {
+            inv_type: dsproof::MSG_DOUBLESPENDPROOF,
+            hash: proof.id().into_inner(),
+        };
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), LocalTime::now());
+        invmgr
+            .estimator
+            .process(gen::block_with(&genesis, vec![coinbase], &mut rng), 1);
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+
+        // Proofs aren't requested while we have no transactions that could be double-spent.
+        invmgr.received_inv(remote, &[inv.clone()]);
+        assert!(output::test::messages_from(&mut invmgr.outbox, &remote)
+            .all(|m| !matches!(m, NetworkMessage::GetData(_))));
+
+        invmgr.announce(tx.clone()).unwrap();
+        invmgr.outbox.drain().for_each(drop);
+
+        invmgr.received_inv(remote, &[inv.clone()]);
+        assert!(output::test::messages_from(&mut invmgr.outbox, &remote)
+            .any(|m| m == NetworkMessage::GetData(vec![inv.clone()])));
+
+        // Proofs with a spend that isn't signed by the key of our output are invalid.
+        let forged = DoubleSpendProof::new(
+            outpoint,
+            Spender::new(&tx, 0).unwrap(),
+            Spender::new(
+                &spend(&SecretKey::from_slice(&[0x22; 32]).unwrap(), &mut rng),
+                0,
+            )
+            .unwrap(),
+        );
+        invmgr.received_event(message(&forged), &tree);
+        assert_matches!(
+            events(invmgr.outbox.drain()).collect::<Vec<_>>().as_slice(),
+            [Event::PeerMisbehaved { addr, .. }] if *addr == remote
+        );
+
+        invmgr.received_event(message(&proof), &tree);
+
+        let mut received = events(invmgr.outbox.drain())
+            .collect::<Vec<_>>()
+            .into_iter();
+        assert_matches!(
+            received.next(),
+            Some(Event::DoubleSpendProofReceived { txid, from })
+            if txid == tx.txid() && from == remote
+        );
+        assert_matches!(
+            received.next(),
+            Some(Event::TxStatusChanged {
+                txid,
+                status: TxStatus::DoubleSpent { conflicting: None, block: None },
+            })
+            if txid == tx.txid()
+        );
+
+        // Proofs with identical spends are invalid.
+        let invalid = DoubleSpendProof {
+            spender2: proof.spender1.clone(),
+            ..proof.clone()
+        };
+        invmgr.received_event(message(&invalid), &tree);
+        assert_matches!(
+            events(invmgr.outbox.drain()).next(),
+            Some(Event::PeerMisbehaved { addr, .. }) if addr == remote
+        );
+
+        // Proofs about transactions that aren't ours are ignored.
+        let unrelated = DoubleSpendProof {
+            outpoint: gen::transaction(&mut rng).input[0].previous_output,
+            ..proof
+        };
+        invmgr.received_event(message(&unrelated), &tree);
+        assert_eq!(events(invmgr.outbox.drain()).count(), 0);
+    }
+
+    #[test]
+    fn test_tx_in_mempool() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let other_peer: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+        let tx = gen::transaction(&mut rng);
+        let other = gen::transaction(&mut rng);
+        let time = LocalTime::now();
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng, time);
+
+        invmgr.peer_negotiated(remote, ServiceFlags::NETWORK, true);
+        invmgr.announce(tx.clone()).unwrap();
+        invmgr.outbox.drain().for_each(drop);
+
+        invmgr.received_inv(
+            remote,
+            &[
+                Inventory::Transaction(other.txid()),
+                Inventory::Transaction(tx.txid()),
+            ],
+        );
+        let mut events = events(invmgr.outbox.drain());
+
+        assert_matches!(
+            events.next(),
+            Some(Event::TxStatusChanged {
+                txid,
+                status: TxStatus::InMempool { peer, since },
+            }) if txid == tx.txid() && peer == remote && since == time
+        );
+        assert_matches!(events.next(), None);
+        drop(events);
+
+        // Further announcements of the transaction aren't reported again.
+        invmgr.received_inv(remote, &[Inventory::Transaction(tx.txid())]);
+        invmgr.received_inv(other_peer, &[Inventory::Transaction(tx.txid())]);
+        assert_matches!(output::test::events(invmgr.outbox.drain()).next(), None);
+    }
+
+    #[test]
+    fn test_tx_request_dedup() {
+        let network = Network::Mainnet;
+        let tree = model::Cache::from(NonEmpty::new(network.genesis()));
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let alice: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let bob: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+        let tx = gen::transaction(&mut rng);
+        let unrequested = gen::transaction(&mut rng);
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng, clock.clone());
+
+        invmgr.peer_negotiated(alice, ServiceFlags::NETWORK, true);
+        invmgr.peer_negotiated(bob, ServiceFlags::NETWORK, true);
+        invmgr.outbox.drain().for_each(drop);
+
+        // Announced transactions are only requested while watching mempools.
+        invmgr.received_inv(alice, &[Inventory::Transaction(tx.txid())]);
+        assert_eq!(invmgr.tx_requests().count(), 0);
+
+        invmgr.watch_mempool();
+
+        // The transaction is only requested from the first peer that announces it.
+        invmgr.received_inv(alice, &[Inventory::Transaction(tx.txid())]);
+        invmgr.received_inv(bob, &[Inventory::Transaction(tx.txid())]);
+
+        assert_eq!(
+            invmgr.tx_requests().collect::<Vec<_>>(),
+            vec![(&tx.txid(), &alice)]
+        );
+        assert_eq!(
+            output::test::messages_from(&mut invmgr.outbox, &alice)
+                .filter(|m| matches!(m, NetworkMessage::GetData(_)))
+                .count(),
+            1
+        );
+        invmgr.outbox.drain().for_each(drop);
+
+        // Alice times out, so the transaction is requested from Bob.
+        clock.elapse(REQUEST_TIMEOUT);
+        invmgr.timer_expired(&tree);
+
+        assert_eq!(
+            invmgr.tx_requests().collect::<Vec<_>>(),
+            vec![(&tx.txid(), &bob)]
+        );
+        assert_matches!(
+            output::test::messages_from(&mut invmgr.outbox, &bob).next(),
+            Some(NetworkMessage::GetData(invs)) if invs == vec![Inventory::Transaction(tx.txid())]
+        );
+        invmgr.outbox.drain().for_each(drop);
+
+        // Alice responds late, followed by Bob. Neither is misbehaving.
+        for from in [alice, bob] {
+            invmgr.received_event(
+                Event::MessageReceived {
+                    from,
+                    message: Arc::new(NetworkMessage::Tx(tx.clone())),
+                },
+                &tree,
+            );
+            assert_eq!(invmgr.tx_requests().count(), 0);
+            assert_matches!(output::test::events(invmgr.outbox.drain()).next(), None);
+        }
+
+        // A transaction we never asked for is misbehavior.
+        invmgr.received_event(
+            Event::MessageReceived {
+                from: alice,
+                message: Arc::new(NetworkMessage::Tx(unrequested)),
+            },
+            &tree,
+        );
+        assert_matches!(
+            events(invmgr.outbox.drain()).next(),
+            Some(Event::PeerMisbehaved { addr, .. }) if addr == alice
+        );
+    }
+
+    #[test]
+    fn test_notfound() {
+        let network = Network::Regtest;
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+        let chain = gen::blockchain(network.genesis_block(), 4, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let hash = chain[2].block_hash();
+        let notfound = |from: PeerId, inv: Inventory| Event::MessageReceived {
+            from,
+            message: Arc::new(NetworkMessage::NotFound(vec![inv])),
+        };
+
+        let mut invmgr = InventoryManager::new(Config::default(), rng.clone(), clock.clone());
+        invmgr.watch_mempool();
+        invmgr.peer_negotiated(alice, ServiceFlags::NETWORK, true);
+        invmgr.peer_negotiated(bob, ServiceFlags::NETWORK, true);
+
+        invmgr.get_block(hash);
+        invmgr.timer_expired(&tree);
+
+        let (requested, _) = output::test::messages(&mut invmgr)
+            .find(|(_, m)| matches!(m, NetworkMessage::GetData(_)))
+            .unwrap();
+        let other = if requested == alice { bob } else { alice };
+
+        // The block is re-requested from the other peer right away.
+        invmgr.received_event(notfound(requested, Inventory::Block(hash)), &tree);
+
+        let outputs = invmgr.outbox.drain().collect::<Vec<_>>();
+        assert!(outputs.iter().any(|o| matches!(
+            o,
+            Io::Event(Event::DataNotFound { from, kind: InvType::Block }) if *from == requested
+        )));
+        assert_eq!(
+            output::test::messages_from(outputs.into_iter(), &other)
+                .filter(|m| matches!(m, NetworkMessage::GetData(invs) if invs == &[Inventory::Block(hash)]))
+                .count(),
+            1
+        );
+
+        // A `notfound` for data we didn't request from the peer is ignored.
+        invmgr.received_event(notfound(requested, Inventory::Block(hash)), &tree);
+        assert_eq!(invmgr.outbox.drain().count(), 0);
+
+        // Repeatedly not finding announced transactions is misbehavior.
+        for i in 1..=MAX_NOT_FOUND {
+            let txid = gen::transaction(&mut rng).txid();
+
+            invmgr.received_inv(alice, &[Inventory::Transaction(txid)]);
+            invmgr.received_inv(bob, &[Inventory::Transaction(txid)]);
+            invmgr.outbox.drain().for_each(drop);
+
+            invmgr.received_event(notfound(alice, Inventory::Transaction(txid)), &tree);
+            assert_eq!(
+                invmgr.tx_requests().collect::<Vec<_>>(),
+                vec![(&txid, &bob)]
+            );
+            invmgr.tx_requests.clear();
+
+            let events = events(invmgr.outbox.drain()).collect::<Vec<_>>();
+            assert_eq!(
+                events
+                    .iter()
+                    .any(|e| matches!(e, Event::PeerMisbehaved { addr, .. } if *addr == alice)),
+                i == MAX_NOT_FOUND
+            );
+        }
+
+        // Occasional `notfound` responses are not.
+        for _ in 0..MAX_NOT_FOUND * 2 {
+            let txid = gen::transaction(&mut rng).txid();
+
+            invmgr.received_inv(alice, &[Inventory::Transaction(txid)]);
+            invmgr.received_inv(bob, &[Inventory::Transaction(txid)]);
+            invmgr.outbox.drain().for_each(drop);
+
+            clock.elapse(NOT_FOUND_WINDOW / 2);
+            invmgr.received_event(notfound(alice, Inventory::Transaction(txid)), &tree);
+            invmgr.tx_requests.clear();
+
+            assert!(!events(invmgr.outbox.drain())
+                .any(|e| matches!(e, Event::PeerMisbehaved { addr, .. } if addr == alice)));
+        }
+
+        // Once the block arrives, it's no longer pending with any peer.
+        invmgr.received_block(&other, chain[2].clone(), &tree);
+        assert!(invmgr.peers.values().all(|p| p.requests.is_empty()));
+    }
 }
@@ -3,15 +3,19 @@
 //! See [`Outbox`] type.
 use log::*;
 use std::collections::VecDeque;
+use std::io;
 use std::net;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 pub use crossbeam_channel as chan;
 
-use nakamoto_common::bitcoin::network::address::Address;
-use nakamoto_common::bitcoin::network::message::NetworkMessage;
+use nakamoto_common::bitcoin::consensus::encode::VarInt;
+use nakamoto_common::bitcoin::consensus::Encodable;
+use nakamoto_common::bitcoin::network::address::{AddrV2Message, Address};
+use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
-use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
+use nakamoto_common::bitcoin::network::message_bloom::{FilterAdd, FilterLoad};
 use nakamoto_common::bitcoin::network::message_filter::{
     CFHeaders, CFilter, GetCFHeaders, GetCFilters,
 };
@@ -19,11 +23,18 @@ use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 use nakamoto_common::bitcoin::Transaction;
 use nakamoto_common::block::time::LocalDuration;
 use nakamoto_common::block::{BlockHash, BlockHeader, BlockTime, Height};
+use nakamoto_common::collections::{HashMap, HashSet};
 
-use crate::fsm::{Event, PeerId};
+use crate::fsm::{ErrorKind, Event, PeerId};
 
 use super::Locators;
 
+/// Number of bytes queued for a peer above which [`Event::PeerBackpressure`] is emitted.
+pub const BACKPRESSURE_HIGH_WATER: usize = 4 * 1024 * 1024;
+/// Number of bytes queued for a peer below which [`Event::PeerBackpressureRelieved`]
+/// is emitted, once backpressure was signaled.
+pub const BACKPRESSURE_LOW_WATER: usize = 1024 * 1024;
+
 /// Output of a state transition of the `Protocol` state machine.
 pub type Io = nakamoto_net::Io<NetworkMessage, Event, super::DisconnectReason>;
 
@@ -33,13 +44,32 @@ impl From<Event> for Io {
     }
 }
 
+/// Priority of an output. High-priority outputs are handed off before the normal-priority
+/// outputs of other peers, but never before earlier outputs for the same peer, so that each
+/// peer receives its messages in the order they were sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Priority {
+    /// Time-sensitive control messages, ie. `version`, `verack`, `ping` and `pong`.
+    High,
+    /// Everything else, eg. data requests and events.
+    Normal,
+}
+
 /// Holds protocol outputs and pending I/O.
 #[derive(Debug)]
 pub struct Outbox {
     /// Protocol version.
     version: u32,
+    /// High-priority output queue. Writes are queued along with their size on the wire.
+    control: VecDeque<(Io, usize)>,
     /// Output queue.
-    outbound: VecDeque<Io>,
+    outbound: VecDeque<(Io, usize)>,
+    /// Number of writes and disconnects in the normal-priority queue, for each peer.
+    pending: HashMap<PeerId, usize>,
+    /// Bytes queued for each peer.
+    queued: HashMap<PeerId, usize>,
+    /// Peers for which backpressure was signaled.
+    congested: HashSet<PeerId>,
 }
 
 impl Default for Outbox {
@@ -51,9 +81,39 @@ impl Default for Outbox {
 impl Iterator for Outbox {
     type Item = Io;
 
-    /// Get the next item in the outbound queue.
+    /// Get the next item in the outbound queue, high-priority items first.
     fn next(&mut self) -> Option<Io> {
-        self.outbound.pop_front()
+        let (next, size) = self.control.pop_front().or_else(|| {
+            let next = self.outbound.pop_front();
+
+            if let Some((Io::Write(addr, _) | Io::Disconnect(addr, _), _)) = &next {
+                if let Some(pending) = self.pending.get_mut(addr) {
+                    *pending -= 1;
+
+                    if *pending == 0 {
+                        self.pending.remove(addr);
+                    }
+                }
+            }
+            next
+        })?;
+
+        if let Io::Write(addr, _) = &next {
+            let queued = self.queued.entry(*addr).or_default();
+            *queued = queued.saturating_sub(size);
+
+            let queued_bytes = *queued;
+            if queued_bytes == 0 {
+                self.queued.remove(addr);
+            }
+            if queued_bytes < BACKPRESSURE_LOW_WATER && self.congested.remove(addr) {
+                self.event(Event::PeerBackpressureRelieved {
+                    addr: *addr,
+                    queued_bytes,
+                });
+            }
+        }
+        Some(next)
     }
 }
 
@@ -62,23 +122,78 @@ impl Outbox {
     pub fn new(version: u32) -> Self {
         Self {
             version,
+            control: VecDeque::new(),
             outbound: VecDeque::new(),
+            pending: HashMap::default(),
+            queued: HashMap::default(),
+            congested: HashSet::default(),
         }
     }
 
-    /// Push an output to the channel.
+    /// Push an output to the channel, with normal priority.
     pub fn push(&mut self, output: Io) {
-        self.outbound.push_back(output);
+        self.push_with(output, Priority::Normal)
+    }
+
+    /// Push an output to the channel, with the given priority.
+    ///
+    /// If normal-priority outputs are already queued for the same peer, the output is queued
+    /// behind them, whatever its priority.
+    pub fn push_with(&mut self, output: Io, priority: Priority) {
+        let peer = match &output {
+            Io::Write(addr, _) | Io::Disconnect(addr, _) => Some(*addr),
+            _ => None,
+        };
+        let priority = match peer {
+            Some(addr) if self.pending.contains_key(&addr) => Priority::Normal,
+            _ => priority,
+        };
+        let queue = match priority {
+            Priority::High => &mut self.control,
+            Priority::Normal => {
+                if let Some(addr) = peer {
+                    *self.pending.entry(addr).or_default() += 1;
+                }
+                &mut self.outbound
+            }
+        };
+
+        if let Io::Write(addr, payload) = output {
+            // Nb. The size is computed once, when the message is queued.
+            let msg = RawNetworkMessage { magic: 0, payload };
+            let size = wire_size(&msg);
+            let queued = self.queued.entry(addr).or_default();
+            *queued += size;
+
+            let queued_bytes = *queued;
+            queue.push_back((Io::Write(addr, msg.payload), size));
+
+            if queued_bytes > BACKPRESSURE_HIGH_WATER && self.congested.insert(addr) {
+                self.event(Event::PeerBackpressure { addr, queued_bytes });
+            }
+        } else {
+            queue.push_back((output, 0));
+        }
     }
 
-    /// Drain the outbound queue.
+    /// Drain the outbound queue, high-priority items first.
+    ///
+    /// Outputs are handed off like with [`Iterator::next`], so the per-peer queued byte
+    /// counts are kept up to date, and relieved backpressure is signaled.
     pub fn drain(&mut self) -> impl Iterator<Item = Io> + '_ {
-        self.outbound.drain(..)
+        std::iter::from_fn(|| self.next())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Get the number of bytes queued for a peer.
+    pub fn queued_bytes(&self, addr: &PeerId) -> usize {
+        self.queued.get(addr).copied().unwrap_or_default()
     }
 
-    /// Get the outbound i/o queue.
-    pub fn outbound(&mut self) -> &VecDeque<Io> {
-        &self.outbound
+    /// Get the normal-priority outbound i/o queue.
+    pub fn outbound(&self) -> impl Iterator<Item = &Io> + '_ {
+        self.outbound.iter().map(|(io, _)| io)
     }
 
     /// Push a message to the channel.
@@ -89,6 +204,13 @@ impl Outbox {
         self
     }
 
+    /// Push a control message to the channel, ahead of normal-priority outputs.
+    fn control(&mut self, addr: PeerId, payload: NetworkMessage) -> &Self {
+        debug!(target: "p2p", "Sending {} to {}", payload.cmd(), addr);
+        self.push_with(Io::Write(addr, payload), Priority::High);
+        self
+    }
+
     /// Push an event to the channel.
     pub fn event<E: std::fmt::Display + Into<Event>>(&mut self, event: E) {
         info!(target: "p2p", "{event}");
@@ -117,13 +239,13 @@ impl Outbox {
 
     /// Send a `version` message.
     pub fn version(&mut self, addr: PeerId, msg: VersionMessage) -> &mut Self {
-        self.message(addr, NetworkMessage::Version(msg));
+        self.control(addr, NetworkMessage::Version(msg));
         self
     }
 
     /// Send a `verack` message.
     pub fn verack(&mut self, addr: PeerId) -> &mut Self {
-        self.message(addr, NetworkMessage::Verack);
+        self.control(addr, NetworkMessage::Verack);
         self
     }
 
@@ -135,13 +257,13 @@ impl Outbox {
 
     /// Send a `ping` message.
     pub fn ping(&mut self, addr: net::SocketAddr, nonce: u64) -> &mut Self {
-        self.message(addr, NetworkMessage::Ping(nonce));
+        self.control(addr, NetworkMessage::Ping(nonce));
         self
     }
 
     /// Send a `pong` message.
     pub fn pong(&mut self, addr: net::SocketAddr, nonce: u64) -> &mut Self {
-        self.message(addr, NetworkMessage::Pong(nonce));
+        self.control(addr, NetworkMessage::Pong(nonce));
         self
     }
 
@@ -155,6 +277,11 @@ impl Outbox {
         self.message(addr, NetworkMessage::Addr(addrs));
     }
 
+    /// Send a BIP 155 `addrv2` message to a peer.
+    pub fn addr_v2(&mut self, addr: PeerId, addrs: Vec<AddrV2Message>) {
+        self.message(addr, NetworkMessage::AddrV2(addrs));
+    }
+
     /// Get headers from a peer.
     pub fn get_headers(&mut self, addr: PeerId, (locator_hashes, stop_hash): Locators) {
         let msg = NetworkMessage::GetHeaders(GetHeadersMessage {
@@ -242,19 +369,95 @@ impl Outbox {
         self.message(*addr, NetworkMessage::FilterLoad(filter));
     }
 
+    /// Sends a `filteradd` message to a peer.
+    pub fn send_bloom_filter_add(&mut self, addr: &PeerId, data: Vec<u8>) {
+        self.message(*addr, NetworkMessage::FilterAdd(FilterAdd { data }));
+    }
+
     /// Sends a `MemPool` message to a peer.
     pub fn get_mempool(&mut self, addr: &PeerId) {
         self.message(*addr, NetworkMessage::MemPool);
     }
 
-    /// Output an error.
-    pub fn error(&mut self, error: impl std::error::Error + Send + Sync + 'static) {
+    /// Output an error of the given kind.
+    pub fn error(
+        &mut self,
+        kind: ErrorKind,
+        error: impl std::error::Error + Send + Sync + 'static,
+    ) {
         self.event(Event::Error {
+            kind,
             error: Arc::new(error),
         })
     }
 }
 
+/// Bandwidth accounting. Tallies the bytes sent to and received from peers,
+/// as they are serialized on the wire.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    /// Bytes sent since the last reset.
+    sent: AtomicU64,
+    /// Bytes received since the last reset.
+    received: AtomicU64,
+}
+
+impl Bandwidth {
+    /// Record an outgoing message.
+    pub fn sent(&self, msg: &RawNetworkMessage) {
+        self.sent.fetch_add(Self::size(msg), Ordering::Relaxed);
+    }
+
+    /// Record an incoming message.
+    pub fn received(&self, msg: &RawNetworkMessage) {
+        self.received.fetch_add(Self::size(msg), Ordering::Relaxed);
+    }
+
+    /// Get the bytes sent and received since the last reset, and reset the counters.
+    pub fn reset(&self) -> (u64, u64) {
+        (
+            self.sent.swap(0, Ordering::Relaxed),
+            self.received.swap(0, Ordering::Relaxed),
+        )
+    }
+
+    /// Size of a message on the wire, including its header.
+    fn size(msg: &RawNetworkMessage) -> u64 {
+        wire_size(msg) as u64
+    }
+}
+
+/// Size of a message header on the wire: magic, command, payload length and checksum.
+const MESSAGE_HEADER_SIZE: usize = 24;
+
+/// Size of a message on the wire, including its header.
+///
+/// The size of the messages that make up the bulk of the traffic, eg. blocks and
+/// transactions, is computed from their contents, without encoding them. Other messages
+/// are small, and are encoded to find out their size.
+fn wire_size(msg: &RawNetworkMessage) -> usize {
+    let vec_size = |len: usize, item: usize| VarInt(len as u64).len() + len * item;
+    let payload_size = match &msg.payload {
+        NetworkMessage::Block(block) => block.size(),
+        NetworkMessage::Tx(tx) => tx.size(),
+        // Nb. Each header is followed by an empty transaction count.
+        NetworkMessage::Headers(headers) => vec_size(headers.len(), 81),
+        NetworkMessage::Inv(invs)
+        | NetworkMessage::GetData(invs)
+        | NetworkMessage::NotFound(invs) => vec_size(invs.len(), 36),
+        NetworkMessage::MerkleBlock(block) => {
+            let bits = (block.txn.bits().len() + 7) / 8;
+
+            80 + 4 + vec_size(block.txn.hashes().len(), 32) + vec_size(bits, 1)
+        }
+        NetworkMessage::CFilter(cfilter) => 1 + 32 + vec_size(cfilter.filter.len(), 1),
+        // Nb. Unknown payloads are encoded with a length prefix.
+        NetworkMessage::Unknown { payload, .. } => vec_size(payload.len(), 1),
+        _ => return msg.consensus_encode(&mut io::sink()).unwrap_or_default(),
+    };
+    MESSAGE_HEADER_SIZE + payload_size
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -314,3 +517,157 @@ pub mod test {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nakamoto_common::bitcoin::network::message::CommandString;
+    use nakamoto_common::bitcoin_hashes::Hash as _;
+    use nakamoto_test::assert_matches;
+
+    #[test]
+    fn test_backpressure() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut outbox = Outbox::default();
+        let payload = |size: usize| NetworkMessage::Unknown {
+            command: CommandString::try_from("oversized").unwrap(),
+            payload: vec![0; size],
+        };
+        let backpressure = |outbox: &mut Outbox| {
+            outbox
+                .outbound()
+                .filter(|o| matches!(o, Io::Event(Event::PeerBackpressure { .. })))
+                .count()
+        };
+
+        outbox.message(addr, payload(BACKPRESSURE_HIGH_WATER / 2));
+        assert_eq!(backpressure(&mut outbox), 0);
+
+        // Crossing the threshold signals backpressure once.
+        outbox.message(addr, payload(BACKPRESSURE_HIGH_WATER / 2));
+        outbox.message(addr, payload(BACKPRESSURE_HIGH_WATER / 2));
+        assert_eq!(backpressure(&mut outbox), 1);
+        assert!(outbox.queued_bytes(&addr) > BACKPRESSURE_HIGH_WATER);
+
+        // Popping messages relieves the backpressure once below the low-water mark.
+        let mut relieved = Vec::new();
+        while let Some(io) = outbox.next() {
+            if let Io::Event(Event::PeerBackpressureRelieved {
+                addr: a,
+                queued_bytes,
+            }) = io
+            {
+                relieved.push((a, queued_bytes));
+            }
+        }
+        assert_eq!(relieved, vec![(addr, 0)]);
+        assert_eq!(outbox.queued_bytes(&addr), 0);
+
+        // Crossing the threshold again signals backpressure again.
+        outbox.message(addr, payload(BACKPRESSURE_HIGH_WATER + 1));
+        assert_eq!(backpressure(&mut outbox), 1);
+
+        // Draining the outbox signals relieved backpressure too.
+        assert!(outbox.drain().any(|io| matches!(
+            io,
+            Io::Event(Event::PeerBackpressureRelieved { addr: a, queued_bytes: 0 }) if a == addr
+        )));
+        assert_eq!(outbox.queued_bytes(&addr), 0);
+        assert!(outbox.next().is_none());
+    }
+
+    #[test]
+    fn test_wire_size() {
+        use nakamoto_common::bitcoin::network::message_filter::CFilter;
+        use nakamoto_common::bitcoin::MerkleBlock;
+        use nakamoto_common::network::Network;
+        use nakamoto_test::block::gen;
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let genesis = Network::Regtest.genesis();
+        let headers = gen::headers(genesis, 8, &mut rng);
+        let block = gen::block(&headers[8], &mut rng);
+        let merkle_block = MerkleBlock::from_block_with_predicate(&block, |_| rng.bool());
+        let msgs = [
+            NetworkMessage::Block(block.clone()),
+            NetworkMessage::Tx(gen::transaction(&mut rng)),
+            NetworkMessage::Headers(headers.into()),
+            NetworkMessage::Inv(vec![Inventory::Block(block.block_hash()); 300]),
+            NetworkMessage::MerkleBlock(merkle_block),
+            NetworkMessage::CFilter(CFilter {
+                filter_type: 0,
+                block_hash: block.block_hash(),
+                filter: vec![7; 300],
+            }),
+            NetworkMessage::Unknown {
+                command: CommandString::try_from("unknown").unwrap(),
+                payload: vec![0; 42],
+            },
+            NetworkMessage::Ping(42),
+        ];
+
+        // The computed size matches the encoded size.
+        for payload in msgs {
+            let msg = RawNetworkMessage {
+                magic: Network::Mainnet.magic(),
+                payload,
+            };
+            let encoded = msg.consensus_encode(&mut io::sink()).unwrap();
+
+            assert_eq!(wire_size(&msg), encoded, "{}", msg.cmd());
+        }
+    }
+
+    #[test]
+    fn test_priority() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let inventory = |n: u8| vec![Inventory::Block(BlockHash::from_inner([n; 32]))];
+        let mut outbox = Outbox::default();
+
+        outbox.get_data(alice, inventory(1));
+        outbox.pong(bob, 42);
+        outbox.ping(alice, 43);
+        outbox.get_data(bob, inventory(2));
+        outbox.ping(bob, 44);
+
+        // Control messages skip ahead of other peers' messages, but each peer receives its
+        // messages in order.
+        let outputs = outbox
+            .by_ref()
+            .filter_map(|o| match o {
+                Io::Write(addr, msg) => Some((addr, msg)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outputs,
+            vec![
+                (bob, NetworkMessage::Pong(42)),
+                (alice, NetworkMessage::GetData(inventory(1))),
+                (alice, NetworkMessage::Ping(43)),
+                (bob, NetworkMessage::GetData(inventory(2))),
+                (bob, NetworkMessage::Ping(44)),
+            ]
+        );
+        assert_eq!(outbox.queued_bytes(&alice), 0);
+        assert_eq!(outbox.queued_bytes(&bob), 0);
+
+        // Once a peer's messages are handed off, its control messages take priority again.
+        outbox.get_data(bob, inventory(3));
+        outbox.ping(alice, 45);
+        assert_matches!(outbox.next(), Some(Io::Write(a, NetworkMessage::Ping(45))) if a == alice);
+        outbox.drain().for_each(drop);
+
+        // Draining also hands off high-priority outputs first.
+        outbox.event(Event::Initializing);
+        outbox.verack(alice);
+        assert_matches!(
+            outbox.drain().collect::<Vec<_>>().as_slice(),
+            [
+                Io::Write(_, NetworkMessage::Verack),
+                Io::Event(Event::Initializing)
+            ]
+        );
+    }
+}
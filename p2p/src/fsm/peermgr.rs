@@ -35,7 +35,7 @@ use crate::fsm::DisconnectReason;
 use crate::Event;
 
 use super::output::{Io, Outbox};
-use super::{Hooks, Link, PeerId, Whitelist};
+use super::{jitter, ErrorKind, Feature, Hooks, Link, PeerId, Severity, Whitelist};
 
 /// Time to wait for response during peer handshake before disconnecting the peer.
 pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(12);
@@ -48,9 +48,24 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Maximum number of outbound peer connections within the same network group.
+pub const MAX_OUTBOUND_PER_GROUP: usize = 2;
+/// Average latency above which an inbound peer may be evicted to make room for a new one.
+pub const EVICTION_LATENCY: LocalDuration = LocalDuration::from_secs(2);
+/// Maximum fraction of the reconnection delay by which reconnections are randomly offset.
+pub const RETRY_JITTER: f64 = 0.2;
+
+/// Ban score at which a misbehaving peer is banned.
+pub const BAN_THRESHOLD: u32 = 100;
+/// Time for which misbehaving peers are banned.
+pub const BAN_DURATION: LocalDuration = LocalDuration::from_mins(60 * 24);
+/// Time it takes for a peer's ban score to decrease by one point.
+pub const BAN_SCORE_DECAY: LocalDuration = LocalDuration::from_mins(1);
 
 /// Maximum height difference for a stale peer, to maintain the connection (2 weeks).
 const MAX_STALE_HEIGHT_DIFFERENCE: Height = 2016;
+/// Ban score added for minor misbehavior.
+const MINOR_MISBEHAVIOR_SCORE: u32 = 20;
 
 /// A time offset, in seconds.
 type TimeOffset = i64;
@@ -84,12 +99,29 @@ pub struct Config {
     pub max_inbound_peers: usize,
     /// Maximum time to wait between reconnection attempts.
     pub retry_max_wait: LocalDuration,
-    /// Minimum time to wait between reconnection attempts.
+    /// Minimum time to wait between reconnection attempts. This is the delay before the
+    /// first attempt, which is doubled after every failed attempt.
     pub retry_min_wait: LocalDuration,
+    /// Maximum fraction of the reconnection delay by which reconnections are randomly
+    /// offset, so that peers disconnected at the same time aren't all retried at once.
+    pub retry_jitter: f64,
     /// Our user agent.
     pub user_agent: &'static str,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
+    /// Ban score at which misbehaving peers are banned.
+    pub ban_threshold: u32,
+    /// Time for which misbehaving peers are banned.
+    pub ban_duration: LocalDuration,
+    /// Time it takes for a peer's ban score to decrease by one point.
+    pub ban_score_decay: LocalDuration,
+    /// Time a connected peer has to complete the handshake, before it is disconnected.
+    pub handshake_timeout: LocalDuration,
+    /// Maximum number of outbound connections to peers in the same network group.
+    pub max_outbound_per_group: usize,
+    /// Average latency above which inbound peers may be evicted when the inbound
+    /// connection limit is reached.
+    pub eviction_latency: LocalDuration,
 }
 
 /// Peer negotiation (handshake) state.
@@ -113,6 +145,8 @@ pub struct Connection {
     pub link: Link,
     /// Connected since this time.
     pub since: LocalTime,
+    /// Id of the connection attempt that established this connection.
+    pub attempt_id: u64,
 }
 
 /// Peer state.
@@ -122,6 +156,8 @@ pub enum Peer {
     Connecting {
         /// Time the connection was attempted.
         time: LocalTime,
+        /// Connection attempt id.
+        attempt_id: u64,
     },
     /// A connection is established.
     Connected {
@@ -143,6 +179,8 @@ pub struct PeerInfo {
     pub services: ServiceFlags,
     /// Peer user agent string.
     pub user_agent: String,
+    /// Node implementation, as advertised in the user agent.
+    pub implementation: PeerImplementation,
     /// An offset in seconds, between this peer's clock and ours.
     /// A positive offset means the peer's clock is ahead of ours.
     pub time_offset: TimeOffset,
@@ -169,6 +207,100 @@ impl PeerInfo {
     }
 }
 
+/// Bitcoin Cash node software run by a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerImplementation {
+    /// Bitcoin Cash Node (BCHN).
+    BitcoinCashNode,
+    /// Bitcoin Unlimited (BU).
+    BitcoinUnlimited,
+    /// bchd.
+    Bchd,
+    /// Flowee the Hub.
+    Flowee,
+    /// Knuth.
+    Knuth,
+    /// Bitcoin Verde.
+    BitcoinVerde,
+    /// Nakamoto.
+    Nakamoto,
+    /// Unknown or malformed user agent. Holds the user agent as advertised.
+    Unknown(String),
+}
+
+impl std::fmt::Display for PeerImplementation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BitcoinCashNode => write!(f, "Bitcoin Cash Node"),
+            Self::BitcoinUnlimited => write!(f, "Bitcoin Unlimited"),
+            Self::Bchd => write!(f, "bchd"),
+            Self::Flowee => write!(f, "Flowee"),
+            Self::Knuth => write!(f, "Knuth"),
+            Self::BitcoinVerde => write!(f, "Bitcoin Verde"),
+            Self::Nakamoto => write!(f, "Nakamoto"),
+            Self::Unknown(user_agent) => write!(f, "unknown ({user_agent:?})"),
+        }
+    }
+}
+
+/// Classify a peer's node software from its user agent.
+///
+/// User agents are expected to follow BIP 14, eg. `/Bitcoin Cash Node:27.0.0(EB32.0)/`.
+/// Only the first, ie. outermost component is considered, and names are matched
+/// case-insensitively.
+pub fn classify_user_agent(user_agent: &str) -> PeerImplementation {
+    let unknown = || PeerImplementation::Unknown(user_agent.to_owned());
+
+    let Some(component) = user_agent
+        .strip_prefix('/')
+        .and_then(|ua| ua.split('/').next())
+    else {
+        return unknown();
+    };
+    let Some((name, _version)) = component.split_once(':') else {
+        return unknown();
+    };
+
+    match name.trim().to_ascii_lowercase().as_str() {
+        "bitcoin cash node" => PeerImplementation::BitcoinCashNode,
+        "bitcoinunlimited" | "bch unlimited" => PeerImplementation::BitcoinUnlimited,
+        "bchd" => PeerImplementation::Bchd,
+        "flowee" => PeerImplementation::Flowee,
+        "knuth" => PeerImplementation::Knuth,
+        "bitcoin verde" => PeerImplementation::BitcoinVerde,
+        "nakamoto" => PeerImplementation::Nakamoto,
+        _ => unknown(),
+    }
+}
+
+/// A network group. Addresses in the same group are likely to be operated by the same
+/// entity, eg. a hosting provider, so outbound connections are spread across groups.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NetworkGroup {
+    /// The /16 range of an IPv4 address.
+    V4([u8; 2]),
+    /// The /32 range of an IPv6 address.
+    V6([u16; 2]),
+}
+
+/// Get the network group of an IP address. IPv4-mapped IPv6 addresses are grouped with
+/// the IPv4 address they map to.
+pub fn network_group(ip: &net::IpAddr) -> NetworkGroup {
+    match ip {
+        net::IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            NetworkGroup::V4([a, b])
+        }
+        net::IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return network_group(&net::IpAddr::V4(ip));
+            }
+            let [a, b, ..] = ip.segments();
+            NetworkGroup::V6([a, b])
+        }
+    }
+}
+
 /// Manages peer connections and handshake.
 #[derive(Debug)]
 pub struct PeerManager<C> {
@@ -181,6 +313,11 @@ pub struct PeerManager<C> {
     peers: HashMap<net::SocketAddr, Peer>,
     /// Peers that have been disconnected and a retry attempt is scheduled.
     disconnected: HashMap<net::SocketAddr, (Option<LocalTime>, usize)>,
+    /// Accumulated ban scores of misbehaving peers, and the time as of which they are
+    /// decayed.
+    scores: HashMap<PeerId, (u32, LocalTime)>,
+    /// Id of the next connection attempt.
+    next_attempt_id: u64,
     outbox: Outbox,
     rng: fastrand::Rng,
     hooks: Hooks,
@@ -200,12 +337,15 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
     pub fn new(config: Config, rng: fastrand::Rng, hooks: Hooks, clock: C) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
         let disconnected = HashMap::with_hasher(rng.clone().into());
+        let scores = HashMap::with_hasher(rng.clone().into());
 
         Self {
             config,
             last_idle: None,
             peers,
             disconnected,
+            scores,
+            next_attempt_id: 0,
             outbox: Outbox::default(),
             rng,
             hooks,
@@ -219,7 +359,8 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
 
         for addr in peers {
             if !self.connect(&addr) {
-                self.outbox.error(Error::ConnectionFailed { addr });
+                self.outbox
+                    .error(ErrorKind::Network, Error::ConnectionFailed { addr });
             }
         }
         self.outbox.set_timer(IDLE_TIMEOUT);
@@ -232,8 +373,12 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
             Event::PeerTimedOut { addr } => {
                 self.disconnect(addr, DisconnectReason::PeerTimeout("other"));
             }
-            Event::PeerMisbehaved { addr, reason } => {
-                self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason));
+            Event::PeerMisbehaved {
+                addr,
+                reason,
+                severity,
+            } => {
+                self.misbehaved(addr, reason, severity);
             }
             Event::MessageReceived { from, message } => match message.as_ref() {
                 NetworkMessage::Version(msg) => {
@@ -243,6 +388,9 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                     self.received_verack(&from);
                 }
 
+                // Handled by the inventory manager.
+                NetworkMessage::Unknown { command, .. }
+                    if command.as_ref() == nakamoto_common::p2p::dsproof::COMMAND => {}
                 NetworkMessage::Unknown {
                     command: ref cmd, ..
                 } => {
@@ -254,15 +402,74 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
         }
     }
 
+    /// Called when a peer misbehaved. Adds to the peer's ban score, and bans the peer
+    /// if the score reaches the configured threshold. Severe misbehavior alone reaches
+    /// the threshold.
+    fn misbehaved(&mut self, addr: PeerId, reason: &'static str, severity: Severity) {
+        log::debug!(target: "p2p", "Peer {} misbehaved ({}): {}", addr, severity, reason);
+
+        let penalty = match severity {
+            Severity::Minor => MINOR_MISBEHAVIOR_SCORE,
+            Severity::Severe => self.config.ban_threshold,
+        };
+        if self.penalize(addr, penalty) {
+            self.disconnect(addr, DisconnectReason::PeerBanned);
+        } else if severity == Severity::Severe {
+            self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason));
+        }
+    }
+
+    /// Add to the peer's ban score. Returns `true` if the peer was banned as a result.
+    fn penalize(&mut self, addr: PeerId, penalty: u32) -> bool {
+        if penalty == 0 {
+            return false;
+        }
+        let now = self.clock.local_time();
+        let since = self.scores.get(&addr).map_or(now, |(score, since)| {
+            decay(*score, *since, now, self.config.ban_score_decay).1
+        });
+        let score = self.ban_score(&addr).saturating_add(penalty);
+        self.scores.insert(addr, (score, since));
+
+        log::debug!(target: "p2p", "Peer {} ban score increased to {}", addr, score);
+
+        if score >= self.config.ban_threshold {
+            let until = self.clock.local_time() + self.config.ban_duration;
+
+            self.scores.remove(&addr);
+            self.outbox.event(Event::PeerBanned { addr, score, until });
+
+            return true;
+        }
+        false
+    }
+
+    /// Get the current ban score of a peer.
+    pub fn ban_score(&self, addr: &PeerId) -> u32 {
+        let now = self.clock.local_time();
+
+        self.scores.get(addr).map_or(0, |(score, since)| {
+            decay(*score, *since, now, self.config.ban_score_decay).0
+        })
+    }
+
     /// A persistent peer has been disconnected.
+    ///
+    /// Reconnections are backed off exponentially, starting from the minimum wait, and
+    /// doubling up to the maximum wait, until the peer is negotiated with again.
     fn persistent_disconnected(&mut self, addr: &net::SocketAddr, local_time: LocalTime) {
         let (retry_at, attempts) = self.disconnected.entry(*addr).or_default();
-        let delay = LocalDuration::from_secs(2u64.saturating_pow(*attempts as u32))
-            .clamp(self.config.retry_min_wait, self.config.retry_max_wait);
+        // Nb. The exponent is capped so that the delay can't overflow.
+        let delay = (self.config.retry_min_wait * 2u64.pow(usize::min(*attempts, 32) as u32))
+            .min(self.config.retry_max_wait);
+        let delay = jitter(delay, self.config.retry_jitter, &mut self.rng);
+        let at = local_time + delay;
 
-        *retry_at = Some(local_time + delay);
+        *retry_at = Some(at);
         *attempts += 1;
 
+        self.outbox
+            .event(Event::PeerReconnectScheduled { addr: *addr, at });
         self.outbox.set_timer(delay);
     }
 
@@ -303,10 +510,13 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
         }
         debug_assert!(!self.is_connected(&addr), "{} is already connected", addr);
 
-        // TODO: There is a chance that we simultaneously connect to a peer that is connecting
-        // to us. This would create two connections to the same peer, one outbound and one
-        // inbound. To prevent this, we could look at IPs when receiving inbound connections,
-        // to check whether we are already connected to the peer.
+        // Inbound connections aren't attempted by us, so they get a new attempt id.
+        let (attempt_id, connect_latency) = match self.peers.get(&addr) {
+            Some(Peer::Connecting { attempt_id, time }) => (*attempt_id, local_time - *time),
+            _ => (self.attempt_id(), LocalDuration::from_secs(0)),
+        };
+        let inbound_full = self.is_inbound_full();
+        let duplicate = self.duplicate(&addr);
 
         self.peers.insert(
             addr,
@@ -316,18 +526,19 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                     local_addr,
                     link,
                     since: local_time,
+                    attempt_id,
                 },
                 peer: None,
             },
         );
-        self.disconnected.remove(&addr);
 
         match link {
             Link::Inbound => {
-                if self.connected().filter(|c| c.link.is_inbound()).count()
-                    >= self.config.max_inbound_peers
-                {
-                    // TODO: Test this branch.
+                if let Some((_, Link::Outbound)) = duplicate {
+                    // We may connect to a peer that is simultaneously connecting to us. Since
+                    // we chose to connect to this peer, our own connection is preferred.
+                    self._disconnect(addr, DisconnectReason::DuplicateConnection);
+                } else if inbound_full {
                     // Don't allow inbound connections beyond the configured limit.
                     self._disconnect(addr, DisconnectReason::ConnectionLimit);
                 } else {
@@ -335,6 +546,9 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                 }
             }
             Link::Outbound => {
+                if let Some((other, Link::Inbound)) = duplicate {
+                    self._disconnect(other, DisconnectReason::DuplicateConnection);
+                }
                 let nonce = self.rng.u64(..);
                 self.outbox.version(
                     addr,
@@ -342,12 +556,14 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                 );
             }
         }
-        // Set a timeout for receiving the `version` message.
-        self.outbox.set_timer(HANDSHAKE_TIMEOUT);
+        // Set a timeout for completing the handshake.
+        self.outbox.set_timer(self.config.handshake_timeout);
         self.outbox.event(Event::PeerConnected {
             addr,
             local_addr,
             link,
+            attempt_id,
+            connect_latency,
         });
     }
 
@@ -360,20 +576,31 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
     ) {
         let local_time = self.clock.local_time();
 
-        debug_assert!(self.peers.contains_key(addr));
+        // Nb. Force-disconnected peers are cleaned up before the reactor reports the
+        // disconnection, in which case there is nothing left to do.
+        if !self.peers.contains_key(addr) {
+            return;
+        }
         debug_assert!(!self.is_disconnected(addr));
 
+        if let network::Disconnect::StateMachine(r) = &reason {
+            self.penalize(*addr, r.ban_score());
+        }
+
         if self.is_disconnecting(addr) || self.is_connected(addr) {
             self.outbox.event(Event::PeerDisconnected {
                 addr: *addr,
                 reason,
             });
-        } else if self.is_connecting(addr) {
+        } else if let Some(Peer::Connecting { attempt_id, .. }) = self.peers.get(addr) {
             // If we haven't yet established a connection, the disconnect reason
             // should always be a `ConnectionError`.
             if let network::Disconnect::ConnectionError(error) = reason {
-                self.outbox
-                    .event(Event::PeerConnectionFailed { addr: *addr, error });
+                self.outbox.event(Event::PeerConnectionFailed {
+                    addr: *addr,
+                    error,
+                    attempt_id: *attempt_id,
+                });
             }
         }
         self.peers.remove(addr);
@@ -387,6 +614,22 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
         }
     }
 
+    /// Called when shutting down. Marks all peers for disconnection, and returns the
+    /// remaining outputs, ending with the disconnections.
+    pub fn shutdown(&mut self) -> impl Iterator<Item = Io> + '_ {
+        let peers = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| !matches!(peer, Peer::Disconnecting))
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for addr in peers {
+            self._disconnect(addr, DisconnectReason::Command);
+        }
+        self.outbox.drain()
+    }
+
     /// Called when a `version` message was received.
     fn received_version(&mut self, addr: &PeerId, msg: &VersionMessage, height: Height) {
         if let Err(reason) = self.handle_version(addr, msg, height) {
@@ -477,18 +720,18 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                             self.version(conn.addr, conn.local_addr, nonce, height, now),
                         )
                         // .wtxid_relay(conn.addr)
-                        .verack(conn.addr)
-                        .send_headers(conn.addr)
-                        .set_timer(HANDSHAKE_TIMEOUT);
+                        .verack(conn.addr);
                 }
                 Link::Outbound => {
                     self.outbox
                         // .wtxid_relay(conn.addr)
-                        .verack(conn.addr)
-                        .send_headers(conn.addr)
-                        .set_timer(HANDSHAKE_TIMEOUT);
+                        .verack(conn.addr);
                 }
             }
+            // Ask for new blocks to be announced with `headers` instead of `inv` (BIP 130).
+            if Feature::SendHeaders.is_supported(version, services) {
+                self.outbox.send_headers(conn.addr);
+            }
             let conn = conn.clone();
             let persistent = self.config.persistent.contains(&conn.addr);
 
@@ -502,6 +745,7 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                         time_offset: timestamp - now.block_time() as i64,
                         services,
                         persistent,
+                        implementation: classify_user_agent(&user_agent),
                         user_agent,
                         receiver,
                         state: HandshakeState::ReceivedVersion { since: now },
@@ -524,6 +768,8 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
         }) = self.peers.get_mut(addr)
         {
             if let HandshakeState::ReceivedVersion { .. } = peer.state {
+                // Reconnections are no longer backed off, once the handshake succeeds.
+                self.disconnected.remove(addr);
                 self.outbox.event(Event::PeerNegotiated {
                     addr: *addr,
                     link: conn.link,
@@ -534,6 +780,7 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
                     height: peer.height,
                     version: peer.version,
                     relay: peer.relay,
+                    attempt_id: conn.attempt_id,
                 });
                 self.clock.record_offset(*addr, peer.time_offset);
 
@@ -558,32 +805,39 @@ impl<C: AdjustedClock<PeerId>> PeerManager<C> {
         for addr in self.idle_peers(local_time).collect::<Vec<_>>() {
             timed_out.push((addr, "connection"));
         }
-        // Time out peers that haven't sent a `verack` quickly enough.
-        for (peer, conn) in self.peers() {
-            match peer.state {
-                HandshakeState::ReceivedVersion { since } => {
-                    if local_time - since >= HANDSHAKE_TIMEOUT {
-                        timed_out.push((conn.addr, "handshake"));
-                    }
-                }
-                HandshakeState::ReceivedVerack { .. } => {}
-            }
-        }
-        // Time out peers that haven't sent a `version` quickly enough.
-        for connected in self.peers.values().filter_map(|c| match c {
-            Peer::Connected { conn, peer: None } => Some(conn),
+        // Time out peers that haven't completed the handshake quickly enough, ie. that
+        // haven't sent a `version` or `verack` since they connected.
+        for (conn, negotiated) in self.peers.values().filter_map(|p| match p {
+            Peer::Connected { conn, peer } => Some((
+                conn,
+                matches!(
+                    peer,
+                    Some(PeerInfo {
+                        state: HandshakeState::ReceivedVerack { .. },
+                        ..
+                    })
+                ),
+            )),
             _ => None,
         }) {
-            if local_time - connected.since >= HANDSHAKE_TIMEOUT {
-                timed_out.push((connected.addr, "handshake"));
+            if !negotiated && local_time - conn.since >= self.config.handshake_timeout {
+                timed_out.push((conn.addr, "handshake"));
             }
         }
         // Disconnect all timed out peers.
         for (addr, reason) in timed_out {
+            if reason == "handshake" {
+                self.outbox.event(Event::PeerTimedOut { addr });
+            }
             self._disconnect(addr, DisconnectReason::PeerTimeout(reason));
         }
 
         if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+            // Forget the ban scores that have fully decayed.
+            let interval = self.config.ban_score_decay;
+            self.scores
+                .retain(|_, (score, since)| decay(*score, *since, local_time, interval).0 > 0);
+
             self.maintain_connections(addrs);
             self.outbox.set_timer(IDLE_TIMEOUT);
             self.last_idle = Some(local_time);
@@ -652,6 +906,12 @@ impl<C: Clock> PeerManager<C> {
         )
     }
 
+    /// Check whether a new inbound connection would reach the inbound connection limit.
+    pub fn is_inbound_full(&self) -> bool {
+        self.connected().filter(|c| c.link.is_inbound()).count() + 1
+            >= self.config.max_inbound_peers
+    }
+
     /// Check whether a peer is connecting.
     pub fn is_connecting(&self, addr: &PeerId) -> bool {
         self.peers
@@ -659,6 +919,24 @@ impl<C: Clock> PeerManager<C> {
             .map_or(false, |c| matches!(c, Peer::Connecting { .. }))
     }
 
+    /// Find another connection, or connection attempt, to the IP of the given peer. Returns
+    /// the address and link of that connection.
+    ///
+    /// Nb. Since the network layer identifies connections by socket address, two connections
+    /// to the same peer only differ by port.
+    fn duplicate(&self, addr: &PeerId) -> Option<(PeerId, Link)> {
+        self.peers.iter().find_map(|(other, peer)| {
+            if other == addr || other.ip() != addr.ip() {
+                return None;
+            }
+            match peer {
+                Peer::Connecting { .. } => Some((*other, Link::Outbound)),
+                Peer::Connected { conn, .. } => Some((*other, conn.link)),
+                Peer::Disconnecting => None,
+            }
+        })
+    }
+
     /// Check whether a peer is connected.
     pub fn is_connected(&self, addr: &PeerId) -> bool {
         self.peers
@@ -666,6 +944,16 @@ impl<C: Clock> PeerManager<C> {
             .map_or(false, |c| matches!(c, Peer::Connected { .. }))
     }
 
+    /// Check whether a negotiated peer supports the given feature.
+    pub fn peer_supports(&self, addr: &PeerId, feature: Feature) -> bool {
+        match self.peers.get(addr) {
+            Some(Peer::Connected {
+                peer: Some(peer), ..
+            }) if peer.is_negotiated() => feature.is_supported(peer.version, peer.services),
+            _ => false,
+        }
+    }
+
     /// Check whether a peer is disconnected.
     pub fn is_disconnected(&self, addr: &PeerId) -> bool {
         !self.peers.contains_key(addr)
@@ -723,12 +1011,22 @@ impl<C: Clock> PeerManager<C> {
         if !self.config.domains.contains(&Domain::for_address(addr)) {
             return false;
         }
-        self.peers.insert(*addr, Peer::Connecting { time });
+        let attempt_id = self.attempt_id();
+
+        self.peers
+            .insert(*addr, Peer::Connecting { time, attempt_id });
         self.outbox.connect(*addr, CONNECTION_TIMEOUT);
 
         true
     }
 
+    /// Allocate a new connection attempt id.
+    fn attempt_id(&mut self) -> u64 {
+        let id = self.next_attempt_id;
+        self.next_attempt_id += 1;
+        id
+    }
+
     /// Disconnect from a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         if self.is_connected(&addr) {
@@ -736,6 +1034,14 @@ impl<C: Clock> PeerManager<C> {
         }
     }
 
+    /// Mark a peer as disconnecting, when its disconnection was queued elsewhere, eg. when
+    /// it was evicted by the ping manager.
+    pub fn peer_disconnecting(&mut self, addr: PeerId) {
+        if self.is_connected(&addr) {
+            self.peers.insert(addr, Peer::Disconnecting);
+        }
+    }
+
     /// Disconnect a peer (internal).
     fn _disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         self.outbox.disconnect(addr, reason);
@@ -797,6 +1103,8 @@ impl<C: Clock> PeerManager<C> {
         // Keep track of new addresses we're connecting to, and loop until
         // we've connected to enough addresses.
         let mut connecting = HashSet::with_hasher(self.rng.clone().into());
+        // Addresses skipped to keep our outbound connections diverse.
+        let mut skipped = HashSet::with_hasher(self.rng.clone().into());
 
         while connecting.len() < delta {
             if let Some((addr, source)) =
@@ -821,13 +1129,32 @@ impl<C: Clock> PeerManager<C> {
                     // connections.
                     debug_assert!(!self.is_connected(&sockaddr));
 
-                    if self.connect(&sockaddr) {
-                        connecting.insert(sockaddr);
-                        self.outbox.event(Event::PeerConnecting {
+                    if self.outbound_in_group(network_group(&sockaddr.ip()))
+                        >= self.config.max_outbound_per_group
+                    {
+                        // If we're sampling addresses we've already skipped, there are no
+                        // more candidates outside of the saturated groups.
+                        if !skipped.insert(sockaddr) {
+                            break;
+                        }
+                        self.outbox.event(Event::PeerSkipped {
                             addr: sockaddr,
-                            source,
-                            services: addr.services,
+                            reason: "group diversity",
                         });
+                        continue;
+                    }
+                    if self.connect(&sockaddr) {
+                        connecting.insert(sockaddr);
+
+                        if let Some(Peer::Connecting { attempt_id, .. }) = self.peers.get(&sockaddr)
+                        {
+                            self.outbox.event(Event::PeerConnecting {
+                                addr: sockaddr,
+                                source,
+                                services: addr.services,
+                                attempt_id: *attempt_id,
+                            });
+                        }
                     }
                 }
             } else {
@@ -840,10 +1167,26 @@ impl<C: Clock> PeerManager<C> {
         }
     }
 
+    /// Number of outbound connections and connection attempts to peers in the given
+    /// network group.
+    fn outbound_in_group(&self, group: NetworkGroup) -> usize {
+        self.peers
+            .iter()
+            .filter(|(addr, peer)| {
+                let outbound = match peer {
+                    Peer::Connecting { .. } => true,
+                    Peer::Connected { conn, .. } => conn.link.is_outbound(),
+                    Peer::Disconnecting => false,
+                };
+                outbound && network_group(&addr.ip()) == group
+            })
+            .count()
+    }
+
     /// Peers that have been idle longer than [`CONNECTION_TIMEOUT`].
     fn idle_peers(&self, now: LocalTime) -> impl Iterator<Item = PeerId> + '_ {
         self.peers.iter().filter_map(move |(addr, c)| {
-            if let Peer::Connecting { time } = c {
+            if let Peer::Connecting { time, .. } = c {
                 if now - *time >= CONNECTION_TIMEOUT {
                     return Some(*addr);
                 }
@@ -853,6 +1196,22 @@ impl<C: Clock> PeerManager<C> {
     }
 }
 
+/// Decay a ban score as of the given time, by one point for every interval elapsed since.
+/// Returns the decayed score, and the time as of which it is decayed.
+fn decay(
+    score: u32,
+    since: LocalTime,
+    now: LocalTime,
+    interval: LocalDuration,
+) -> (u32, LocalTime) {
+    let steps = (now - since).as_millis() / interval.as_millis().max(1);
+
+    if steps >= score as u128 {
+        return (0, now);
+    }
+    (score - steps as u32, since + interval * steps as u64)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -863,6 +1222,8 @@ mod tests {
     use nakamoto_common::p2p::peer::Source;
     use nakamoto_test::assert_matches;
 
+    use crate::fsm::output;
+
     mod util {
         use super::*;
 
@@ -876,10 +1237,17 @@ mod tests {
                 persistent: vec![],
                 retry_max_wait: LocalDuration::from_mins(60),
                 retry_min_wait: LocalDuration::from_secs(1),
+                retry_jitter: 0.,
                 services: ServiceFlags::NONE,
                 preferred_services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
                 required_services: ServiceFlags::NETWORK,
                 whitelist: Whitelist::default(),
+                ban_threshold: BAN_THRESHOLD,
+                ban_duration: BAN_DURATION,
+                ban_score_decay: BAN_SCORE_DECAY,
+                handshake_timeout: HANDSHAKE_TIMEOUT,
+                max_outbound_per_group: MAX_OUTBOUND_PER_GROUP,
+                eviction_latency: EVICTION_LATENCY,
             }
         }
     }
@@ -937,6 +1305,126 @@ mod tests {
         assert_eq!(peermgr.connecting().next(), Some(&remote));
     }
 
+    #[test]
+    fn test_persistent_connection_error() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(AdjustedTime::new(LocalTime::now()));
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+
+        // The persistent peer can't be connected to, since its domain is unsupported.
+        let cfg = Config {
+            persistent: vec![remote],
+            domains: vec![Domain::IPV6],
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), time);
+
+        peermgr.initialize(&mut VecDeque::new());
+        assert_matches!(
+            output::test::events(peermgr.outbox.drain()).next(),
+            Some(Event::Error { kind: ErrorKind::Network, error })
+            if matches!(
+                error.downcast_ref::<Error>(),
+                Some(Error::ConnectionFailed { addr }) if *addr == remote
+            )
+        );
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let rng = fastrand::Rng::new();
+        let time = RefClock::from(AdjustedTime::new(LocalTime::now()));
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+        let min = LocalDuration::from_secs(1);
+        let max = LocalDuration::from_secs(16);
+
+        let mut addrs = VecDeque::new();
+        let cfg = Config {
+            persistent: vec![remote],
+            retry_min_wait: min,
+            retry_max_wait: max,
+            retry_jitter: 0.2,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng.clone(), Hooks::default(), time.clone());
+        // Fail to connect, and return the scheduled reconnection delay.
+        let fail = |peermgr: &mut PeerManager<_>, addrs: &mut VecDeque<_>| {
+            let now = time.local_time();
+
+            assert!(peermgr.is_connecting(&remote));
+            peermgr.peer_disconnected(
+                &remote,
+                addrs,
+                network::Disconnect::ConnectionError(std::sync::Arc::new(
+                    std::io::ErrorKind::ConnectionRefused.into(),
+                )),
+            );
+            let at = output::test::events(peermgr.outbox.drain())
+                .find_map(|e| match e {
+                    Event::PeerReconnectScheduled { addr, at } if addr == remote => Some(at),
+                    _ => None,
+                })
+                .expect("a reconnection is scheduled");
+
+            // The peer is reconnected to once the delay has passed.
+            time.elapse(at - now);
+            peermgr.timer_expired(addrs);
+
+            at - now
+        };
+        let within = |delay: LocalDuration, expected: LocalDuration| {
+            let millis = expected.as_millis() as f64;
+            let delay = delay.as_millis() as f64;
+
+            delay >= millis * 0.8 - 1. && delay <= millis * 1.2 + 1.
+        };
+
+        peermgr.initialize(&mut addrs);
+
+        // Successive failures are backed off exponentially, up to the maximum wait.
+        let mut delays = Vec::new();
+        for _ in 0..7 {
+            delays.push(fail(&mut peermgr, &mut addrs));
+        }
+        for (i, delay) in delays.iter().enumerate() {
+            let expected = LocalDuration::min(min * 2u64.pow(i as u32), max);
+            assert!(
+                within(*delay, expected),
+                "{} is not within {}",
+                delay,
+                expected
+            );
+        }
+        for pair in delays[..5].windows(2) {
+            assert!(pair[1] > pair[0]);
+        }
+
+        // Negotiating with the peer resets the backoff.
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+        };
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.received_version(&remote, &version, height);
+        peermgr.received_verack(&remote);
+        assert_matches!(
+            output::test::events(peermgr.outbox.drain()).last(),
+            Some(Event::PeerNegotiated { addr, .. }) if addr == remote
+        );
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            DisconnectReason::PeerTimeout("ping").into(),
+        );
+        let now = time.local_time();
+        assert_matches!(
+            output::test::events(peermgr.outbox.drain()).last(),
+            Some(Event::PeerReconnectScheduled { addr, at }) if addr == remote && within(at - now, min)
+        );
+    }
+
     #[test]
     fn test_wtxidrelay_misbehavior() {
         let rng = fastrand::Rng::with_seed(1);
@@ -964,6 +1452,293 @@ mod tests {
         assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
     }
 
+    #[test]
+    fn test_attempt_ids() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(util::config(), rng.clone(), Hooks::default(), time.clone());
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let inbound = ([124, 43, 110, 2], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+        };
+        let attempt_ids = |peermgr: &mut PeerManager<_>| {
+            output::test::events(peermgr.outbox.drain())
+                .filter_map(|e| match e {
+                    Event::PeerConnecting { attempt_id, .. } => Some(("connecting", attempt_id)),
+                    Event::PeerConnected { attempt_id, .. } => Some(("connected", attempt_id)),
+                    Event::PeerConnectionFailed { attempt_id, .. } => Some(("failed", attempt_id)),
+                    Event::PeerNegotiated { attempt_id, .. } => Some(("negotiated", attempt_id)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.outbox.drain().for_each(drop);
+
+        addrs.push_back((Address::new(&remote, ServiceFlags::NETWORK), Source::Dns));
+        peermgr.maintain_connections(&mut addrs);
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            network::Disconnect::ConnectionError(std::sync::Arc::new(
+                std::io::ErrorKind::TimedOut.into(),
+            )),
+        );
+        assert_eq!(
+            attempt_ids(&mut peermgr),
+            vec![("connecting", 0), ("failed", 0)]
+        );
+
+        // A retry to the same address is a new attempt.
+        addrs.push_back((Address::new(&remote, ServiceFlags::NETWORK), Source::Dns));
+        peermgr.maintain_connections(&mut addrs);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.received_version(&remote, &version, height);
+        peermgr.received_verack(&remote);
+        assert_eq!(
+            attempt_ids(&mut peermgr),
+            vec![("connecting", 1), ("connected", 1), ("negotiated", 1)]
+        );
+
+        // Inbound connections get their own id.
+        peermgr.peer_connected(inbound, local, Link::Inbound, height);
+        assert_eq!(attempt_ids(&mut peermgr), vec![("connected", 2)]);
+    }
+
+    #[test]
+    fn test_connect_latency() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(AdjustedTime::new(LocalTime::now()));
+        let height = 144;
+        let latency = LocalDuration::from_millis(350);
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
+        let inbound: PeerId = ([124, 43, 110, 2], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), time.clone());
+        let connect_latency = |peermgr: &mut PeerManager<_>| {
+            output::test::events(peermgr.outbox.drain())
+                .find_map(|e| match e {
+                    Event::PeerConnected {
+                        connect_latency, ..
+                    } => Some(connect_latency),
+                    _ => None,
+                })
+                .expect("the peer connects")
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.outbox.drain().for_each(drop);
+
+        addrs.push_back((Address::new(&remote, ServiceFlags::NETWORK), Source::Dns));
+        peermgr.maintain_connections(&mut addrs);
+        assert_matches!(
+            output::test::events(peermgr.outbox.drain()).next(),
+            Some(Event::PeerConnecting { addr, .. }) if addr == remote
+        );
+
+        time.elapse(latency);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        assert_eq!(connect_latency(&mut peermgr), latency);
+
+        // Inbound connections aren't attempted by us.
+        time.elapse(latency);
+        peermgr.peer_connected(inbound, local, Link::Inbound, height);
+        assert_eq!(connect_latency(&mut peermgr), LocalDuration::from_secs(0));
+    }
+
+    #[test]
+    fn test_group_diversity() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), time);
+
+        peermgr.initialize(&mut addrs);
+        peermgr.outbox.drain().for_each(drop);
+
+        // Several addresses in the same /16, and one in another group.
+        for i in 1..=4 {
+            let remote: PeerId = ([124, 43, i, 1], 8333).into();
+            addrs.push_back((Address::new(&remote, ServiceFlags::NETWORK), Source::Dns));
+        }
+        let other: PeerId = ([88, 88, 88, 88], 8333).into();
+        addrs.push_back((Address::new(&other, ServiceFlags::NETWORK), Source::Dns));
+
+        peermgr.maintain_connections(&mut addrs);
+
+        let events = output::test::events(peermgr.outbox.drain()).collect::<Vec<_>>();
+        let connecting = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::PeerConnecting { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let skipped = events
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    Event::PeerSkipped {
+                        reason: "group diversity",
+                        ..
+                    }
+                )
+            })
+            .count();
+
+        assert_eq!(
+            connecting
+                .iter()
+                .filter(|a| network_group(&a.ip()) == NetworkGroup::V4([124, 43]))
+                .count(),
+            MAX_OUTBOUND_PER_GROUP
+        );
+        assert!(connecting.contains(&other));
+        assert_eq!(skipped, 4 - MAX_OUTBOUND_PER_GROUP);
+        assert!(addrs.is_empty());
+
+        // IPv4-mapped addresses are grouped with their IPv4 counterpart.
+        assert_eq!(
+            network_group(&"::ffff:124.43.9.9".parse().unwrap()),
+            NetworkGroup::V4([124, 43])
+        );
+        assert_eq!(
+            network_group(&"2001:db8:1::1".parse().unwrap()),
+            NetworkGroup::V6([0x2001, 0xdb8])
+        );
+    }
+
+    #[test]
+    fn test_classify_user_agent() {
+        let cases = [
+            (
+                "/Bitcoin Cash Node:27.1.0(EB32.0)/",
+                PeerImplementation::BitcoinCashNode,
+            ),
+            (
+                "/Bitcoin Cash Node:24.0.0(EB32.0; bchn.org)/",
+                PeerImplementation::BitcoinCashNode,
+            ),
+            (
+                "/BCH Unlimited:2.0.0.1(EB32; AD12)/",
+                PeerImplementation::BitcoinUnlimited,
+            ),
+            (
+                "/BitcoinUnlimited:1.9.2(EB32; AD12)/",
+                PeerImplementation::BitcoinUnlimited,
+            ),
+            ("/bchd:0.20.0/", PeerImplementation::Bchd),
+            ("/Flowee:2023.08.1/", PeerImplementation::Flowee),
+            ("/knuth:0.28.0/", PeerImplementation::Knuth),
+            ("/Bitcoin Verde:2.2.1/", PeerImplementation::BitcoinVerde),
+            ("/nakamoto:0.4.0/", PeerImplementation::Nakamoto),
+            // Only the outermost component is considered.
+            (
+                "/Bitcoin Cash Node:26.0.0(EB32.0)/Fork:0.1/",
+                PeerImplementation::BitcoinCashNode,
+            ),
+        ];
+        for (user_agent, expected) in cases {
+            assert_eq!(classify_user_agent(user_agent), expected, "{user_agent}");
+        }
+
+        // Unknown or malformed user agents.
+        for user_agent in [
+            "/Satoshi:0.16.3/",
+            "/Bitcoin ABC:0.22.0(EB32.0)/",
+            "Bitcoin Cash Node:27.1.0",
+            "/Bitcoin Cash Node/",
+            "//",
+            "/:1.0/",
+            "",
+        ] {
+            assert_eq!(
+                classify_user_agent(user_agent),
+                PeerImplementation::Unknown(user_agent.to_owned()),
+                "{user_agent:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_peer_implementation() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(util::config(), rng.clone(), Hooks::default(), time.clone());
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            user_agent: "/Bitcoin Cash Node:27.1.0(EB32.0)/".to_owned(),
+            ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.received_version(&remote, &version, height);
+        peermgr.received_verack(&remote);
+
+        let (peer, _) = peermgr.peers().next().unwrap();
+        assert_eq!(peer.implementation, PeerImplementation::BitcoinCashNode);
+    }
+
+    #[test]
+    fn test_peer_supports() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(util::config(), rng.clone(), Hooks::default(), time.clone());
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+            version: 70012,
+            ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.received_version(&remote, &version, height);
+
+        // The handshake isn't complete yet.
+        assert!(!peermgr.peer_supports(&remote, Feature::CompactFilters));
+
+        peermgr.received_verack(&remote);
+        assert!(peermgr.peer_supports(&remote, Feature::CompactFilters));
+        assert!(peermgr.peer_supports(&remote, Feature::SendHeaders));
+        // Not signaled.
+        assert!(!peermgr.peer_supports(&remote, Feature::BloomFilters));
+        // Protocol version too low.
+        assert!(!peermgr.peer_supports(&remote, Feature::FeeFilter));
+        // Unknown peer.
+        assert!(!peermgr.peer_supports(&local, Feature::SendHeaders));
+    }
+
     #[test]
     fn test_connect_timeout() {
         let rng = fastrand::Rng::with_seed(1);
@@ -996,6 +1771,52 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_handshake_timeout() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(AdjustedTime::new(LocalTime::now()));
+        let height = 144;
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let cfg = Config {
+            handshake_timeout: LocalDuration::from_secs(30),
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng.clone(), Hooks::default(), time.clone());
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.received_version(&remote, &version, height);
+        peermgr.outbox.drain().for_each(drop);
+
+        // The window is measured from the connection, and isn't reset by the `version`.
+        time.elapse(LocalDuration::from_secs(29));
+        peermgr.timer_expired(&mut addrs);
+        assert!(peermgr.is_connected(&remote));
+
+        time.elapse(LocalDuration::from_secs(1));
+        peermgr.timer_expired(&mut addrs);
+        assert!(!peermgr.is_connected(&remote));
+
+        let outputs = peermgr.outbox.drain().collect::<Vec<_>>();
+        assert!(outputs.iter().any(|o| matches!(
+            o,
+            Io::Disconnect(a, DisconnectReason::PeerTimeout("handshake")) if *a == remote
+        )));
+        assert_matches!(
+            output::test::events(outputs.into_iter()).next(),
+            Some(Event::PeerTimedOut { addr }) if addr == remote
+        );
+    }
+
     #[test]
     fn test_disconnects() {
         let rng = fastrand::Rng::with_seed(1);
@@ -1175,4 +1996,214 @@ mod tests {
             assert_eq!(peermgr.delta(), delta, "{:?}", case);
         }
     }
+
+    #[test]
+    fn test_ban_score() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.outbox.drain().for_each(drop);
+
+        // Minor misbehavior accumulates, without disconnecting the peer.
+        for i in 1..BAN_THRESHOLD / MINOR_MISBEHAVIOR_SCORE {
+            peermgr.misbehaved(remote, "unsolicited message", Severity::Minor);
+            assert_eq!(peermgr.ban_score(&remote), MINOR_MISBEHAVIOR_SCORE * i);
+        }
+        assert!(peermgr.is_connected(&remote));
+        assert!(output::test::events(peermgr.outbox.drain())
+            .next()
+            .is_none());
+
+        // Crossing the threshold bans and disconnects the peer.
+        peermgr.misbehaved(remote, "unsolicited message", Severity::Minor);
+
+        assert_matches!(
+            output::test::events(&mut peermgr.outbox).next(),
+            Some(Event::PeerBanned { addr, score, until })
+            if addr == remote && score == BAN_THRESHOLD && until == time.local_time() + BAN_DURATION
+        );
+        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+        assert_eq!(peermgr.ban_score(&remote), 0);
+    }
+
+    #[test]
+    fn test_ban_invalid_data() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), time);
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        peermgr.outbox.drain().for_each(drop);
+        peermgr.misbehaved(remote, "invalid `cfilter` message", Severity::Severe);
+
+        assert_matches!(
+            output::test::events(peermgr.outbox.drain()).next(),
+            Some(Event::PeerBanned { addr, .. }) if addr == remote
+        );
+        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+    }
+
+    #[test]
+    fn test_ban_severe_misbehavior() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let cfg = Config {
+            ban_threshold: BAN_THRESHOLD * 2,
+            ..util::config()
+        };
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), time);
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        peermgr.outbox.drain().for_each(drop);
+
+        // Severe misbehavior bans the peer, whatever the configured threshold.
+        peermgr.misbehaved(remote, "invalid `headers` message", Severity::Severe);
+
+        let outputs = peermgr.outbox.drain().collect::<Vec<_>>();
+        assert!(outputs.iter().any(|o| matches!(
+            o,
+            Io::Event(Event::PeerBanned { addr, score, .. })
+            if *addr == remote && *score == BAN_THRESHOLD * 2
+        )));
+        assert!(outputs.iter().any(|o| matches!(
+            o,
+            Io::Disconnect(addr, DisconnectReason::PeerBanned) if *addr == remote
+        )));
+        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+    }
+
+    #[test]
+    fn test_disconnect_reason_ban_score() {
+        let table = [
+            (
+                DisconnectReason::PeerMisbehaving("unexpected `verack` message received"),
+                0,
+            ),
+            (DisconnectReason::PeerMagic(0xdeadbeef), BAN_THRESHOLD),
+            (
+                DisconnectReason::DecodeError(std::sync::Arc::new(
+                    nakamoto_common::bitcoin::consensus::encode::Error::ParseFailed("test"),
+                )),
+                BAN_THRESHOLD / 2,
+            ),
+            (DisconnectReason::PeerTimeout("ping"), 5),
+            (DisconnectReason::PeerProtocolVersion(1), 0),
+            (DisconnectReason::PeerServices(ServiceFlags::NONE), 0),
+            (DisconnectReason::PeerHeight(0), 0),
+            (DisconnectReason::SelfConnection, 0),
+            (DisconnectReason::ConnectionLimit, 0),
+            (DisconnectReason::Command, 0),
+            (DisconnectReason::PeerBanned, 0),
+            (DisconnectReason::Evicted("slow"), 0),
+            (DisconnectReason::DuplicateConnection, 0),
+            (DisconnectReason::Other("other"), 0),
+        ];
+        for (reason, score) in table {
+            assert_eq!(reason.ban_score(), score, "{}", reason);
+        }
+    }
+
+    #[test]
+    fn test_disconnect_penalty() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = AdjustedTime::new(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), time);
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            DisconnectReason::PeerTimeout("ping").into(),
+        );
+        assert_eq!(peermgr.ban_score(&remote), 5);
+
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        peermgr.outbox.drain().for_each(drop);
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            DisconnectReason::PeerMagic(0xdeadbeef).into(),
+        );
+
+        assert_matches!(
+            output::test::events(peermgr.outbox.drain()).find(|e| matches!(e, Event::PeerBanned { .. })),
+            Some(Event::PeerBanned { addr, .. }) if addr == remote
+        );
+        assert_eq!(peermgr.ban_score(&remote), 0);
+    }
+
+    #[test]
+    fn test_ban_score_decay() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(AdjustedTime::new(LocalTime::now()));
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        peermgr.misbehaved(remote, "unsolicited message", Severity::Minor);
+        assert_eq!(peermgr.ban_score(&remote), MINOR_MISBEHAVIOR_SCORE);
+
+        // The score decays by one point every interval, including partial intervals
+        // carried over to the next penalty.
+        time.elapse(BAN_SCORE_DECAY * 5 + BAN_SCORE_DECAY / 2);
+        assert_eq!(peermgr.ban_score(&remote), MINOR_MISBEHAVIOR_SCORE - 5);
+
+        peermgr.misbehaved(remote, "unsolicited message", Severity::Minor);
+        assert_eq!(peermgr.ban_score(&remote), MINOR_MISBEHAVIOR_SCORE * 2 - 5);
+
+        time.elapse(BAN_SCORE_DECAY / 2);
+        assert_eq!(peermgr.ban_score(&remote), MINOR_MISBEHAVIOR_SCORE * 2 - 6);
+
+        // Peers aren't banned for repeated timeouts spread out over time.
+        for _ in 0..BAN_THRESHOLD {
+            time.elapse(BAN_SCORE_DECAY * 5);
+            peermgr.peer_disconnected(
+                &remote,
+                &mut addrs,
+                DisconnectReason::PeerTimeout("ping").into(),
+            );
+            peermgr.connect(&remote);
+            peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        }
+        assert!(!output::test::events(peermgr.outbox.drain())
+            .any(|e| matches!(e, Event::PeerBanned { .. })));
+
+        // Fully decayed scores are forgotten.
+        time.elapse(BAN_SCORE_DECAY * BAN_THRESHOLD as u64);
+        peermgr.timer_expired(&mut addrs);
+        assert!(peermgr.scores.is_empty());
+    }
 }
@@ -1,6 +1,8 @@
 //! Ping manager.
 //!
 //! Detects dead peer connections and responds to peer `ping` messages.
+//! Also detects peers that answer pings, but don't serve any data, and peers whose latency
+//! keeps growing, which is a sign of a congested connection.
 //!
 //! *Implementation of BIP 0031.*
 //!
@@ -9,57 +11,233 @@ use std::net;
 
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
-use nakamoto_common::collections::HashMap;
+use nakamoto_common::collections::OrderedMap;
 
-use crate::fsm::PeerId;
+use crate::fsm::{jitter, Link, PeerId};
 
 use super::{
     output::{Io, Outbox},
-    DisconnectReason, Event,
+    DisconnectReason, Event, Severity,
 };
 
 /// Time interval to wait between sent pings.
 pub const PING_INTERVAL: LocalDuration = LocalDuration::from_mins(2);
+/// Time interval to wait between pings sent to inbound peers.
+pub const INBOUND_PING_INTERVAL: LocalDuration = PING_INTERVAL;
 /// Time to wait to receive a pong when sending a ping.
 pub const PING_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+/// Maximum fraction of the ping interval by which pings are randomly offset.
+pub const PING_JITTER: f64 = 0.25;
+/// Number of pings in a row a peer may leave unanswered before it is disconnected.
+pub const MAX_UNANSWERED_PINGS: usize = 1;
+/// Number of unsolicited `pong` messages a peer may send before it is considered misbehaving.
+pub const MAX_UNSOLICITED_PONGS: usize = 8;
+/// Time after which a responsive peer that hasn't sent us any useful data is considered idle.
+pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(10);
 
 /// Maximum number of latencies recorded per peer.
-const MAX_RECORDED_LATENCIES: usize = 64;
+pub const MAX_RECORDED_LATENCIES: usize = 64;
+/// Weight given to the latest latency sample in a peer's moving average latency.
+pub const LATENCY_ALPHA: f64 = 0.125;
+/// Number of most recent latencies compared against the older ones to detect congestion.
+pub const CONGESTION_WINDOW: usize = 4;
+/// Factor by which recent latencies must exceed the older ones for a peer to be congested.
+pub const CONGESTION_FACTOR: f64 = 2.;
+
+/// Ping manager configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Time interval to wait between sent pings.
+    pub interval: LocalDuration,
+    /// Time interval to wait between pings sent to inbound peers. Since these connections
+    /// are less under our control, they may be pinged less often than outbound ones.
+    pub inbound_interval: LocalDuration,
+    /// Time to wait to receive a pong when sending a ping.
+    pub timeout: LocalDuration,
+    /// Number of pings in a row a peer may leave unanswered before it is disconnected.
+    pub max_unanswered: usize,
+    /// Number of unsolicited `pong` messages a peer may send before it is considered
+    /// misbehaving.
+    pub max_unsolicited_pongs: usize,
+    /// Time after which a responsive peer that hasn't sent us any useful data is
+    /// considered idle.
+    pub idle_timeout: LocalDuration,
+    /// Maximum number of latencies recorded per peer. Older latencies are discarded.
+    pub max_recorded_latencies: usize,
+    /// Smoothing factor of the exponential moving average latency, between `0.0` and `1.0`.
+    /// Higher values give more weight to recent samples.
+    pub latency_alpha: f64,
+    /// Number of most recent latencies compared against the older ones to detect
+    /// congestion. At least as many older latencies are needed for a baseline.
+    /// A window of `0` disables congestion detection.
+    pub congestion_window: usize,
+    /// Factor by which every recent latency must exceed the baseline average for a
+    /// peer to be considered congested.
+    pub congestion_factor: f64,
+    /// Maximum fraction of the ping interval by which each peer's pings are randomly
+    /// offset, so that pings to peers connected at the same time don't all go out at
+    /// once. Clamped between `0.0` and `1.0`. A jitter of `0.0` disables it.
+    pub jitter: f64,
+}
+
+impl Config {
+    /// Get the interval between pings sent to a peer with the given link.
+    fn interval(&self, link: Link) -> LocalDuration {
+        match link {
+            Link::Inbound => self.inbound_interval,
+            Link::Outbound => self.interval,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: PING_INTERVAL,
+            inbound_interval: INBOUND_PING_INTERVAL,
+            timeout: PING_TIMEOUT,
+            max_unanswered: MAX_UNANSWERED_PINGS,
+            max_unsolicited_pongs: MAX_UNSOLICITED_PONGS,
+            idle_timeout: IDLE_TIMEOUT,
+            max_recorded_latencies: MAX_RECORDED_LATENCIES,
+            latency_alpha: LATENCY_ALPHA,
+            congestion_window: CONGESTION_WINDOW,
+            congestion_factor: CONGESTION_FACTOR,
+            // Nb. Jitter is opt-in, so that pings are sent at predictable times by default.
+            jitter: 0.,
+        }
+    }
+}
+
+/// Latency metrics of a peer, derived from its recorded latencies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerMetric {
+    /// Peer address.
+    pub addr: PeerId,
+    /// Average round-trip latency, in milliseconds.
+    pub avg_ms: u64,
+    /// Exponential moving average of the round-trip latency, in milliseconds.
+    pub ewma_ms: u64,
+    /// Lowest recorded round-trip latency, in milliseconds.
+    pub min_ms: u64,
+    /// Highest recorded round-trip latency, in milliseconds.
+    pub max_ms: u64,
+    /// Number of recorded latencies.
+    pub samples: usize,
+}
 
 #[derive(Debug)]
 enum State {
     AwaitingPong { nonce: u64, since: LocalTime },
-    Idle { since: LocalTime },
+    Idle,
 }
 
 #[derive(Debug)]
 struct Peer {
     address: net::SocketAddr,
+    /// Connection link, determining the ping interval.
+    link: Link,
     state: State,
+    /// Time at which the next `ping` should be sent.
+    next_ping: LocalTime,
+    /// Number of pings in a row that went unanswered.
+    unanswered: usize,
+    /// Number of unsolicited `pong` messages received since the last valid one.
+    unsolicited_pongs: usize,
+    /// Time at which this peer last sent us useful data, or was negotiated.
+    last_useful: LocalTime,
+    /// Whether this peer was reported idle since it last sent useful data.
+    idle: bool,
     /// Observed round-trip latencies for this peer.
     latencies: VecDeque<LocalDuration>,
+    /// Maximum number of latencies to record.
+    max_latencies: usize,
+    /// Lowest round-trip latency ever observed for this peer.
+    min_latency: Option<LocalDuration>,
+    /// Highest round-trip latency ever observed for this peer.
+    max_latency: Option<LocalDuration>,
+    /// Exponential moving average of the round-trip latency.
+    ewma_latency: LocalDuration,
+    /// Smoothing factor of the moving average.
+    alpha: f64,
+    /// Whether this peer was reported congested since its latency last went back down.
+    congested: bool,
 }
 
 impl Peer {
     /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
     fn latency(&self) -> LocalDuration {
         let sum: LocalDuration = self.latencies.iter().sum();
 
         sum / self.latencies.len() as u32
     }
 
+    /// Get the exponential moving average latency of this peer. Unlike the average,
+    /// recent samples are given more weight.
+    fn ewma_latency(&self) -> LocalDuration {
+        self.ewma_latency
+    }
+
+    /// Get the lowest latency observed for this peer.
+    fn min_latency(&self) -> Option<LocalDuration> {
+        self.min_latency
+    }
+
+    /// Get the highest latency observed for this peer.
+    fn max_latency(&self) -> Option<LocalDuration> {
+        self.max_latency
+    }
+
+    /// Get the latency trend of this peer, ie. the ratio of its recent average latency to
+    /// the average of the older latencies, if its latency grew steadily over the last
+    /// `window` samples by at least the given factor.
+    ///
+    /// Nb. Every recent sample must exceed the baseline, so that a single spike isn't
+    /// mistaken for congestion.
+    fn congestion(&self, window: usize, factor: f64) -> Option<f64> {
+        if window == 0 || self.latencies.len() < window * 2 {
+            return None;
+        }
+        let millis = |d: &LocalDuration| d.as_millis() as f64;
+        // Nb. Latencies are recorded most recent first.
+        let recent = self.latencies.iter().take(window).map(millis);
+        let older = self.latencies.iter().skip(window).map(millis);
+        let baseline = older.sum::<f64>() / (self.latencies.len() - window) as f64;
+        let lowest = recent.clone().fold(f64::INFINITY, f64::min);
+
+        if baseline > 0. && lowest >= baseline * factor {
+            Some(recent.sum::<f64>() / window as f64 / baseline)
+        } else {
+            None
+        }
+    }
+
     fn record_latency(&mut self, sample: LocalDuration) {
+        self.ewma_latency = if self.latencies.is_empty() {
+            sample
+        } else {
+            let ewma = self.alpha * sample.as_millis() as f64
+                + (1. - self.alpha) * self.ewma_latency.as_millis() as f64;
+
+            LocalDuration::from_millis(ewma.round() as u128)
+        };
         self.latencies.push_front(sample);
-        self.latencies.truncate(MAX_RECORDED_LATENCIES);
+        self.latencies.truncate(self.max_latencies);
+
+        // Nb. Unlike the recorded latencies, the extremes are kept for the lifetime of the peer.
+        self.min_latency = Some(self.min_latency.map_or(sample, |min| min.min(sample)));
+        self.max_latency = Some(self.max_latency.map_or(sample, |max| max.max(sample)));
     }
 }
 
 /// Detects dead peer connections.
 #[derive(Debug)]
 pub struct PingManager<C> {
-    peers: HashMap<PeerId, Peer>,
-    ping_timeout: LocalDuration,
+    /// Connected peers, in the order they were negotiated with.
+    peers: OrderedMap<PeerId, Peer>,
+    /// Ping manager configuration.
+    config: Config,
     /// Random number generator.
     rng: fastrand::Rng,
     outbox: Outbox,
@@ -76,13 +254,18 @@ impl<C> Iterator for PingManager<C> {
 
 impl<C: Clock> PingManager<C> {
     /// Create a new ping manager.
-    pub fn new(ping_timeout: LocalDuration, rng: fastrand::Rng, clock: C) -> Self {
-        let peers = HashMap::with_hasher(rng.clone().into());
+    ///
+    /// All randomness, ie. ping nonces and jitter, is drawn from the given generator. Passing
+    /// a seeded generator, eg. [`fastrand::Rng::with_seed`], makes pings deterministic.
+    pub fn new(mut config: Config, rng: fastrand::Rng, clock: C) -> Self {
+        let peers = OrderedMap::new();
         let outbox = Outbox::default();
 
+        config.jitter = config.jitter.clamp(0., 1.);
+
         Self {
             peers,
-            ping_timeout,
+            config,
             rng,
             outbox,
             clock,
@@ -92,8 +275,8 @@ impl<C: Clock> PingManager<C> {
     /// Event received.
     pub fn received_event<T>(&mut self, event: Event, _tree: &T) {
         match event {
-            Event::PeerNegotiated { addr, .. } => {
-                self.peer_negotiated(addr);
+            Event::PeerNegotiated { addr, link, .. } => {
+                self.peer_negotiated(addr, link);
             }
             Event::PeerDisconnected { addr, .. } => {
                 self.peers.remove(&addr);
@@ -105,73 +288,217 @@ impl<C: Clock> PingManager<C> {
                 NetworkMessage::Pong(nonce) => {
                     self.received_pong(from, *nonce);
                 }
+                NetworkMessage::Headers(_)
+                | NetworkMessage::Block(_)
+                | NetworkMessage::MerkleBlock(_)
+                | NetworkMessage::Tx(_)
+                | NetworkMessage::CFilter(_)
+                | NetworkMessage::CFHeaders(_)
+                | NetworkMessage::CFCheckpt(_) => {
+                    self.useful_message_received(&from);
+                }
                 _ => {}
             },
             _ => {}
         }
     }
 
+    /// Get the peers whose average latency exceeds the given threshold, slowest first.
+    ///
+    /// Peers without any recorded latency are not included.
+    pub fn stale_peers(&self, threshold: LocalDuration) -> Vec<PeerId> {
+        let mut stale = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| !peer.latencies.is_empty())
+            .map(|(addr, peer)| (*addr, peer.latency()))
+            .filter(|(_, latency)| *latency > threshold)
+            .collect::<Vec<_>>();
+
+        stale.sort_by(|(_, a), (_, b)| b.cmp(a));
+        stale.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Get the average round-trip latency of a peer, if any was recorded.
+    pub fn latency(&self, addr: &PeerId) -> Option<LocalDuration> {
+        self.peers
+            .get(addr)
+            .filter(|peer| !peer.latencies.is_empty())
+            .map(|peer| peer.latency())
+    }
+
+    /// Disconnect the peer with the highest average latency, eg. to make room for a better
+    /// peer. Peers without any recorded latency are never chosen.
+    ///
+    /// Returns the evicted peer, if any.
+    pub fn disconnect_slowest(&mut self) -> Option<PeerId> {
+        let (addr, latency) = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| !peer.latencies.is_empty())
+            .map(|(addr, peer)| (*addr, peer.latency()))
+            .max_by_key(|(_, latency)| *latency)?;
+
+        log::debug!(target: "p2p", "Evicting slowest peer {} (latency = {})", addr, latency);
+
+        // Nb. The peer is forgotten right away, so that it isn't chosen again before
+        // the disconnection is processed.
+        self.peers.remove(&addr);
+        self.outbox
+            .disconnect(addr, DisconnectReason::Evicted("slow"));
+
+        Some(addr)
+    }
+
+    /// Record that a peer sent us useful data, eg. in response to a data request.
+    pub fn useful_message_received(&mut self, addr: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.last_useful = self.clock.local_time();
+            peer.idle = false;
+        }
+    }
+
+    /// Get a snapshot of the latency metrics of all peers with recorded latencies.
+    pub fn metrics(&self) -> Vec<PeerMetric> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| !peer.latencies.is_empty())
+            .map(|(addr, peer)| {
+                let millis = |d: &LocalDuration| d.as_millis() as u64;
+
+                PeerMetric {
+                    addr: *addr,
+                    avg_ms: millis(&peer.latency()),
+                    ewma_ms: millis(&peer.ewma_latency()),
+                    min_ms: peer.latencies.iter().map(millis).min().unwrap_or_default(),
+                    max_ms: peer.latencies.iter().map(millis).max().unwrap_or_default(),
+                    samples: peer.latencies.len(),
+                }
+            })
+            .collect()
+    }
+
     /// Called when a peer is negotiated.
-    fn peer_negotiated(&mut self, address: PeerId) {
+    fn peer_negotiated(&mut self, address: PeerId, link: Link) {
         let nonce = self.rng.u64(..);
         let now = self.clock.local_time();
+        let interval = self.ping_interval(link);
 
-        self.outbox.ping(address, nonce);
+        self.outbox
+            .ping(address, nonce)
+            .set_timer(self.config.timeout)
+            .set_timer(interval);
         self.peers.insert(
             address,
             Peer {
                 address,
+                link,
                 state: State::AwaitingPong { nonce, since: now },
+                next_ping: now + interval,
+                unanswered: 0,
+                unsolicited_pongs: 0,
+                last_useful: now,
+                idle: false,
                 latencies: VecDeque::new(),
+                // Nb. At least one latency is recorded, for the average to be defined.
+                max_latencies: self.config.max_recorded_latencies.max(1),
+                min_latency: None,
+                max_latency: None,
+                ewma_latency: LocalDuration::from_millis(0),
+                alpha: self.config.latency_alpha.clamp(0., 1.),
+                congested: false,
             },
         );
     }
 
+    /// Called when shutting down. Cancels outstanding pings, so that no peer is
+    /// disconnected for not answering them, and returns the remaining outputs.
+    pub fn shutdown(&mut self) -> impl Iterator<Item = Io> + '_ {
+        self.peers.clear();
+        self.outbox.drain()
+    }
+
     /// Called when a tick is received.
     pub fn timer_expired(&mut self) {
         let now = self.clock.local_time();
 
         for peer in self.peers.values_mut() {
+            // Peers that answer our pings but haven't served any data in a while are idle.
+            // Peers that don't answer pings are handled below.
+            let idle_for = now - peer.last_useful;
+
+            if !peer.idle && peer.unanswered == 0 && idle_for >= self.config.idle_timeout {
+                peer.idle = true;
+
+                self.outbox.event(Event::PeerIdle {
+                    addr: peer.address,
+                    idle_for,
+                });
+            }
+
             match peer.state {
                 State::AwaitingPong { since, .. } => {
-                    // TODO: By using nonces we should be able to overlap ping messages.
-                    // This would allow us to only disconnect a peer after N ping messages
-                    // are sent in a row with no reply.
-                    //
                     // A ping was sent and we're waiting for a `pong`. If too much
-                    // time has passed, we consider this peer dead, and disconnect
-                    // from them.
-                    if now - since >= self.ping_timeout {
-                        self.outbox
-                            .disconnect(peer.address, DisconnectReason::PeerTimeout("ping"));
+                    // time has passed, we count the ping as unanswered. After too
+                    // many unanswered pings in a row, we consider this peer dead,
+                    // and disconnect from them. Otherwise, we ping them again.
+                    if now - since >= self.config.timeout {
+                        peer.unanswered += 1;
+
+                        if peer.unanswered >= self.config.max_unanswered {
+                            self.outbox
+                                .disconnect(peer.address, DisconnectReason::PeerTimeout("ping"));
+                        } else {
+                            let nonce = self.rng.u64(..);
+
+                            self.outbox
+                                .ping(peer.address, nonce)
+                                .set_timer(self.config.timeout);
+
+                            peer.state = State::AwaitingPong { nonce, since: now };
+                        }
                     }
                 }
-                State::Idle { since } => {
-                    // We aren't waiting for any `pong`. Check whether enough time has passed since we
-                    // received the last `pong`, and if so, send a new `ping`.
-                    if now - since >= PING_INTERVAL {
+                State::Idle => {
+                    // We aren't waiting for any `pong`. Check whether it's time to send
+                    // a new `ping`.
+                    if now >= peer.next_ping {
                         let nonce = self.rng.u64(..);
+                        let interval = jitter(
+                            self.config.interval(peer.link),
+                            self.config.jitter,
+                            &mut self.rng,
+                        );
 
                         self.outbox
                             .ping(peer.address, nonce)
-                            .set_timer(self.ping_timeout)
-                            .set_timer(PING_INTERVAL);
+                            .set_timer(self.config.timeout)
+                            .set_timer(interval);
 
                         peer.state = State::AwaitingPong { nonce, since: now };
+                        peer.next_ping = now + interval;
                     }
                 }
             }
         }
     }
 
-    /// Called when a `ping` is received.
-    fn received_ping(&mut self, addr: PeerId, nonce: u64) -> bool {
-        if self.peers.contains_key(&addr) {
-            self.outbox.pong(addr, nonce);
+    /// Get the interval until the next `ping` to a peer with the given link, with jitter
+    /// applied.
+    fn ping_interval(&mut self, link: Link) -> LocalDuration {
+        jitter(
+            self.config.interval(link),
+            self.config.jitter,
+            &mut self.rng,
+        )
+    }
 
-            return true;
-        }
-        false
+    /// Called when a `ping` is received.
+    ///
+    /// Pings are always answered, even if the peer isn't tracked yet, since a peer may
+    /// ping us before we process its negotiation (BIP 31).
+    fn received_ping(&mut self, addr: PeerId, nonce: u64) {
+        self.outbox.pong(addr, nonce);
     }
 
     /// Called when a `pong` is received.
@@ -185,16 +512,682 @@ impl<C: Clock> PingManager<C> {
                     since,
                 } => {
                     if nonce == last_nonce {
-                        peer.record_latency(now - since);
-                        peer.state = State::Idle { since: now };
+                        let rtt = now - since;
+
+                        peer.record_latency(rtt);
+                        peer.state = State::Idle;
+                        peer.unanswered = 0;
+                        peer.unsolicited_pongs = 0;
+
+                        if let (Some(min), Some(max)) = (peer.min_latency(), peer.max_latency()) {
+                            // Latencies varying by an order of magnitude indicate a flaky peer.
+                            if max.as_millis() >= min.as_millis() * 10 {
+                                log::debug!(
+                                    target: "p2p",
+                                    "Peer {} latency is unstable (min = {}, max = {})",
+                                    addr, min, max
+                                );
+                            }
+                        }
+
+                        self.outbox.event(Event::PeerLatencyUpdated {
+                            addr,
+                            rtt,
+                            average: peer.latency(),
+                        });
+
+                        // Congestion is only reported once, until the latency goes back down.
+                        match peer.congestion(
+                            self.config.congestion_window,
+                            self.config.congestion_factor,
+                        ) {
+                            Some(trend) if !peer.congested => {
+                                peer.congested = true;
+                                self.outbox.event(Event::PeerCongested { addr, trend });
+                            }
+                            Some(_) => {}
+                            None => peer.congested = false,
+                        }
 
                         return true;
                     }
                 }
-                // Unsolicited or redundant `pong`. Ignore.
-                State::Idle { .. } => {}
+                // Unsolicited or redundant `pong`. A single one can be a late duplicate, so
+                // only repeated offenses are reported.
+                State::Idle => {
+                    peer.unsolicited_pongs += 1;
+
+                    if peer.unsolicited_pongs == self.config.max_unsolicited_pongs {
+                        self.outbox.event(Event::PeerMisbehaved {
+                            addr,
+                            reason: "excessive unsolicited pong",
+                            severity: Severity::Minor,
+                        });
+                    }
+                }
             }
         }
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_common::block::time::{MockClock, RefClock};
+    use nakamoto_test::assert_matches;
+
+    use crate::fsm::output;
+
+    #[test]
+    fn test_latency_updated() {
+        let clock = RefClock::from(LocalTime::now());
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut pingmgr = PingManager::new(Config::default(), fastrand::Rng::new(), clock.clone());
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+
+        let nonce = output::test::messages_from(pingmgr.outbox.drain(), &addr)
+            .find_map(|m| match m {
+                NetworkMessage::Ping(nonce) => Some(nonce),
+                _ => None,
+            })
+            .expect("a `ping` is sent");
+
+        let rtt = LocalDuration::from_millis(320);
+        clock.elapse(rtt);
+
+        // A `pong` with the wrong nonce is ignored.
+        assert!(!pingmgr.received_pong(addr, nonce.wrapping_add(1)));
+        assert!(pingmgr.received_pong(addr, nonce));
+
+        let (rtt_, average) = output::test::events(pingmgr.outbox.drain())
+            .find_map(|e| match e {
+                Event::PeerLatencyUpdated {
+                    addr: a,
+                    rtt,
+                    average,
+                } if a == addr => Some((rtt, average)),
+                _ => None,
+            })
+            .expect("a latency update is emitted");
+
+        assert_eq!(rtt_, rtt);
+        assert_eq!(average, rtt);
+        assert_matches!(pingmgr.peers.get(&addr).unwrap().state, State::Idle);
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(LocalTime::from_secs(1_600_000_000));
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut pingmgr = PingManager::new(Config::default(), fastrand::Rng::new(), clock.clone());
+        let pings = |pingmgr: &mut PingManager<_>| {
+            output::test::messages_from(pingmgr.outbox.drain(), &addr)
+                .filter_map(|m| match m {
+                    NetworkMessage::Ping(nonce) => Some(nonce),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+        let nonce = pings(&mut pingmgr)[0];
+
+        clock.advance(LocalDuration::from_millis(150));
+        assert!(pingmgr.received_pong(addr, nonce));
+        assert_eq!(
+            pingmgr.latency(&addr),
+            Some(LocalDuration::from_millis(150))
+        );
+        pingmgr.outbox.drain().for_each(drop);
+
+        // No `ping` is sent until the interval has passed.
+        clock.advance(LocalDuration::from_millis(PING_INTERVAL.as_millis() - 151));
+        pingmgr.timer_expired();
+        assert!(pings(&mut pingmgr).is_empty());
+
+        clock.advance(LocalDuration::from_millis(1));
+        pingmgr.timer_expired();
+        assert_eq!(pings(&mut pingmgr).len(), 1);
+
+        // The peer doesn't answer in time, and is disconnected.
+        clock.advance(PING_TIMEOUT);
+        pingmgr.timer_expired();
+        assert_matches!(
+            pingmgr.outbox.drain().find(|o| matches!(o, Io::Disconnect(..))),
+            Some(Io::Disconnect(a, DisconnectReason::PeerTimeout("ping"))) if a == addr
+        );
+    }
+
+    #[test]
+    fn test_deterministic_nonces() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let nonces = |seed: u64| {
+            let clock = RefClock::from(LocalTime::now());
+            let rng = fastrand::Rng::with_seed(seed);
+            let config = Config {
+                jitter: PING_JITTER,
+                ..Config::default()
+            };
+            let mut pingmgr = PingManager::new(config, rng, clock.clone());
+            let mut nonces = Vec::new();
+            let mut ping = |pingmgr: &mut PingManager<_>| {
+                let nonce = output::test::messages_from(pingmgr.outbox.drain(), &addr)
+                    .find_map(|m| match m {
+                        NetworkMessage::Ping(nonce) => Some(nonce),
+                        _ => None,
+                    })
+                    .expect("a `ping` is sent");
+                nonces.push(nonce);
+                nonce
+            };
+
+            pingmgr.peer_negotiated(addr, Link::Outbound);
+            let nonce = ping(&mut pingmgr);
+            assert!(pingmgr.received_pong(addr, nonce));
+
+            clock.elapse(PING_INTERVAL + PING_INTERVAL);
+            pingmgr.timer_expired();
+            let nonce = ping(&mut pingmgr);
+            assert!(pingmgr.received_pong(addr, nonce));
+
+            nonces
+        };
+        let expected = nonces(42);
+
+        assert_eq!(expected, vec![12558987674375533620, 14652274819296609082]);
+        assert_eq!(nonces(42), expected, "the same seed yields the same nonces");
+        assert_ne!(nonces(43), expected);
+    }
+
+    #[test]
+    fn test_config() {
+        let clock = RefClock::from(LocalTime::now());
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let config = Config {
+            interval: LocalDuration::from_secs(10),
+            timeout: LocalDuration::from_secs(3),
+            max_unanswered: 3,
+            ..Config::default()
+        };
+        let mut pingmgr = PingManager::new(config, fastrand::Rng::new(), clock.clone());
+        let pings = |pingmgr: &mut PingManager<_>| {
+            output::test::messages_from(pingmgr.outbox.drain(), &addr)
+                .filter_map(|m| match m {
+                    NetworkMessage::Ping(nonce) => Some(nonce),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+        let nonce = pings(&mut pingmgr)[0];
+
+        clock.elapse(LocalDuration::from_secs(1));
+        assert!(pingmgr.received_pong(addr, nonce));
+
+        // The next `ping` is sent after the configured interval.
+        clock.elapse(LocalDuration::from_secs(9));
+        pingmgr.timer_expired();
+        assert_eq!(pings(&mut pingmgr).len(), 1);
+
+        // Unanswered pings are retried until the limit is reached.
+        for _ in 0..config.max_unanswered - 1 {
+            clock.elapse(config.timeout);
+            pingmgr.timer_expired();
+
+            assert_eq!(pings(&mut pingmgr).len(), 1);
+        }
+        clock.elapse(config.timeout);
+        pingmgr.timer_expired();
+
+        assert_matches!(
+            pingmgr.outbox.drain().next(),
+            Some(Io::Disconnect(a, DisconnectReason::PeerTimeout("ping"))) if a == addr
+        );
+    }
+
+    #[test]
+    fn test_unsolicited_pongs() {
+        let clock = RefClock::from(LocalTime::now());
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut pingmgr = PingManager::new(Config::default(), fastrand::Rng::new(), clock.clone());
+        let ping = |pingmgr: &mut PingManager<_>| {
+            output::test::messages_from(pingmgr.outbox.drain(), &addr)
+                .find_map(|m| match m {
+                    NetworkMessage::Ping(nonce) => Some(nonce),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+        let nonce = ping(&mut pingmgr);
+        assert!(pingmgr.received_pong(addr, nonce));
+        pingmgr.outbox.drain().for_each(drop);
+
+        // A late duplicate isn't reported.
+        assert!(!pingmgr.received_pong(addr, nonce));
+        assert_eq!(output::test::events(pingmgr.outbox.drain()).count(), 0);
+
+        for _ in 0..MAX_UNSOLICITED_PONGS * 2 {
+            assert!(!pingmgr.received_pong(addr, nonce));
+        }
+        let misbehaved = output::test::events(pingmgr.outbox.drain())
+            .filter(|e| {
+                matches!(
+                    e,
+                    Event::PeerMisbehaved { addr: a, reason: "excessive unsolicited pong", .. }
+                    if *a == addr
+                )
+            })
+            .count();
+
+        assert_eq!(misbehaved, 1);
+
+        // A valid `pong` resets the count, so sporadic duplicates aren't reported, while
+        // repeated offenses are reported again.
+        let mut reported = Vec::new();
+        for n in [
+            1,
+            MAX_UNSOLICITED_PONGS - 1,
+            MAX_UNSOLICITED_PONGS - 1,
+            MAX_UNSOLICITED_PONGS,
+        ] {
+            clock.elapse(PING_INTERVAL);
+            pingmgr.timer_expired();
+
+            let nonce = ping(&mut pingmgr);
+            assert!(pingmgr.received_pong(addr, nonce));
+
+            for _ in 0..n {
+                assert!(!pingmgr.received_pong(addr, nonce));
+            }
+            reported.push(
+                output::test::events(pingmgr.outbox.drain())
+                    .any(|e| matches!(e, Event::PeerMisbehaved { .. })),
+            );
+        }
+        assert_eq!(reported, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn test_peer_idle() {
+        let clock = RefClock::from(LocalTime::now());
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let config = Config {
+            interval: LocalDuration::from_mins(1),
+            idle_timeout: LocalDuration::from_mins(5),
+            ..Config::default()
+        };
+        let mut pingmgr = PingManager::new(config, fastrand::Rng::new(), clock.clone());
+        let mut idle = Vec::new();
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+
+        // The peer answers all our pings, but never sends any data.
+        for _ in 0..10 {
+            for io in pingmgr.outbox.drain().collect::<Vec<_>>() {
+                match io {
+                    Io::Write(_, NetworkMessage::Ping(nonce)) => {
+                        pingmgr.received_pong(addr, nonce);
+                    }
+                    Io::Event(Event::PeerIdle { addr, idle_for }) => {
+                        idle.push((addr, idle_for));
+                    }
+                    _ => {}
+                }
+            }
+            clock.elapse(config.interval);
+            pingmgr.timer_expired();
+        }
+        assert_eq!(idle, vec![(addr, config.idle_timeout)]);
+
+        // Once the peer sends useful data, it's no longer idle.
+        pingmgr.useful_message_received(&addr);
+        pingmgr.outbox.drain().for_each(drop);
+
+        clock.elapse(config.interval);
+        pingmgr.timer_expired();
+
+        assert!(!output::test::events(pingmgr.outbox.drain())
+            .any(|e| matches!(e, Event::PeerIdle { .. })));
+    }
+
+    #[test]
+    fn test_inbound_interval() {
+        let clock = RefClock::from(LocalTime::now());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let config = Config {
+            interval: LocalDuration::from_mins(2),
+            inbound_interval: LocalDuration::from_mins(10),
+            ..Config::default()
+        };
+        let mut pingmgr = PingManager::new(config, fastrand::Rng::new(), clock.clone());
+        let now = clock.local_time();
+
+        pingmgr.peer_negotiated(alice, Link::Outbound);
+        pingmgr.peer_negotiated(bob, Link::Inbound);
+
+        assert_eq!(pingmgr.peers[&alice].next_ping, now + config.interval);
+        assert_eq!(pingmgr.peers[&bob].next_ping, now + config.inbound_interval);
+
+        // Answer the initial pings, and only the outbound peer is pinged again after
+        // the regular interval.
+        let answer = |pingmgr: &mut PingManager<_>| {
+            let mut pinged = Vec::new();
+            for io in pingmgr.outbox.drain().collect::<Vec<_>>() {
+                if let Io::Write(addr, NetworkMessage::Ping(nonce)) = io {
+                    pingmgr.received_pong(addr, nonce);
+                    pinged.push(addr);
+                }
+            }
+            pinged
+        };
+        answer(&mut pingmgr);
+
+        clock.elapse(config.interval);
+        pingmgr.timer_expired();
+        assert_eq!(answer(&mut pingmgr), vec![alice]);
+
+        clock.elapse(LocalDuration::from_mins(8));
+        pingmgr.timer_expired();
+        assert!(answer(&mut pingmgr).contains(&bob));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let mut pingmgr =
+            PingManager::new(Config::default(), fastrand::Rng::new(), LocalTime::now());
+
+        pingmgr.peer_negotiated(alice, Link::Outbound);
+        pingmgr.peer_negotiated(bob, Link::Outbound);
+
+        let peer = pingmgr.peers.get_mut(&alice).unwrap();
+        for ms in [100, 200, 600] {
+            peer.record_latency(LocalDuration::from_millis(ms));
+        }
+
+        // Peers without recorded latencies are not included.
+        assert_eq!(
+            pingmgr.metrics(),
+            vec![PeerMetric {
+                addr: alice,
+                avg_ms: 300,
+                ewma_ms: 174,
+                min_ms: 100,
+                max_ms: 600,
+                samples: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_disconnect_slowest() {
+        let mut pingmgr =
+            PingManager::new(Config::default(), fastrand::Rng::new(), LocalTime::now());
+        let peers: [(PeerId, &[u128]); 4] = [
+            (([88, 88, 88, 88], 8333).into(), &[100, 120]),
+            (([99, 99, 99, 99], 8333).into(), &[400, 500]),
+            (([77, 77, 77, 77], 8333).into(), &[300]),
+            (([66, 66, 66, 66], 8333).into(), &[]),
+        ];
+        assert_eq!(pingmgr.disconnect_slowest(), None);
+
+        for (addr, latencies) in peers {
+            pingmgr.peer_negotiated(addr, Link::Outbound);
+
+            let peer = pingmgr.peers.get_mut(&addr).unwrap();
+            for ms in latencies {
+                peer.record_latency(LocalDuration::from_millis(*ms));
+            }
+        }
+        pingmgr.outbox.drain().for_each(drop);
+
+        // Peers are evicted slowest first, and peers without latencies are never evicted.
+        for i in [1, 2, 0] {
+            let (expected, _) = peers[i];
+
+            assert_eq!(pingmgr.disconnect_slowest(), Some(expected));
+            assert_matches!(
+                pingmgr.outbox.drain().next(),
+                Some(Io::Disconnect(addr, DisconnectReason::Evicted(_))) if addr == expected
+            );
+        }
+        assert_eq!(pingmgr.disconnect_slowest(), None);
+    }
+
+    #[test]
+    fn test_ping_jitter() {
+        let time = LocalTime::now();
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let config = Config {
+            jitter: PING_JITTER,
+            ..Config::default()
+        };
+        let mut pingmgr = PingManager::new(config, fastrand::Rng::new(), time);
+
+        pingmgr.peer_negotiated(alice, Link::Outbound);
+        pingmgr.peer_negotiated(bob, Link::Outbound);
+
+        let a = pingmgr.peers.get(&alice).unwrap().next_ping;
+        let b = pingmgr.peers.get(&bob).unwrap().next_ping;
+        let max = LocalDuration::from_millis(
+            (PING_INTERVAL.as_millis() as f64 * PING_JITTER) as u128 + 1,
+        );
+
+        assert_ne!(a, b);
+        assert!(a.diff(time + PING_INTERVAL) <= max);
+        assert!(b.diff(time + PING_INTERVAL) <= max);
+
+        // The jitter is clamped, so that pings are never scheduled in the past.
+        let config = Config {
+            jitter: 2.,
+            ..Config::default()
+        };
+        let pingmgr = PingManager::new(config, fastrand::Rng::new(), time);
+        assert_eq!(pingmgr.config.jitter, 1.);
+    }
+
+    #[test]
+    fn test_latency_extremes() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let rng = fastrand::Rng::with_seed(1);
+        let mut pingmgr = PingManager::new(Config::default(), rng.clone(), LocalTime::now());
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+
+        let peer = pingmgr.peers.get_mut(&addr).unwrap();
+        assert_eq!(peer.min_latency(), None);
+        assert_eq!(peer.max_latency(), None);
+
+        let min = LocalDuration::from_millis(5);
+        let max = LocalDuration::from_millis(5000);
+
+        peer.record_latency(LocalDuration::from_millis(100));
+        peer.record_latency(min);
+        peer.record_latency(max);
+
+        // Fill the buffer with other samples, so that the extremes roll over.
+        for _ in 0..MAX_RECORDED_LATENCIES * 2 {
+            peer.record_latency(LocalDuration::from_millis(rng.u128(50..500)));
+        }
+        assert_eq!(peer.latencies.len(), MAX_RECORDED_LATENCIES);
+        assert!(!peer.latencies.contains(&min));
+        assert!(!peer.latencies.contains(&max));
+
+        assert_eq!(peer.min_latency(), Some(min));
+        assert_eq!(peer.max_latency(), Some(max));
+    }
+
+    #[test]
+    fn test_ewma_latency() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut pingmgr = PingManager::new(
+            Config::default(),
+            fastrand::Rng::with_seed(1),
+            LocalTime::now(),
+        );
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+        assert!(pingmgr.metrics().is_empty());
+
+        let peer = pingmgr.peers.get_mut(&addr).unwrap();
+        for _ in 0..MAX_RECORDED_LATENCIES {
+            peer.record_latency(LocalDuration::from_millis(100));
+        }
+        assert_eq!(peer.ewma_latency(), LocalDuration::from_millis(100));
+
+        // The connection degrades: the moving average reacts faster than the mean.
+        let degraded = LocalDuration::from_millis(500);
+        for _ in 0..16 {
+            peer.record_latency(degraded);
+        }
+        let (ewma, mean) = (peer.ewma_latency(), peer.latency());
+        let off = |latency: LocalDuration| degraded.as_millis() - latency.as_millis();
+
+        assert!(ewma > mean, "{ewma} > {mean}");
+        assert!(off(ewma) < off(mean) / 4);
+        assert_eq!(pingmgr.metrics()[0].ewma_ms, ewma.as_millis() as u64);
+        assert_eq!(pingmgr.latency(&addr), Some(mean));
+    }
+
+    #[test]
+    fn test_peer_congested() {
+        let clock = RefClock::from(LocalTime::now());
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let config = Config::default();
+        let mut pingmgr = PingManager::new(config, fastrand::Rng::new(), clock.clone());
+        let mut congested = Vec::new();
+
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+
+        // The peer is stable at first, then its latency keeps growing.
+        let rtts = [100, 100, 100, 100, 150, 250, 350, 450, 550, 650, 750];
+        for (i, rtt) in rtts.into_iter().enumerate() {
+            let nonce = output::test::messages_from(pingmgr.outbox.drain(), &addr)
+                .find_map(|m| match m {
+                    NetworkMessage::Ping(nonce) => Some(nonce),
+                    _ => None,
+                })
+                .expect("a `ping` is sent");
+
+            clock.elapse(LocalDuration::from_millis(rtt));
+            assert!(pingmgr.received_pong(addr, nonce));
+
+            for event in output::test::events(pingmgr.outbox.drain()) {
+                if let Event::PeerCongested { addr: a, trend } = event {
+                    congested.push((i, a, trend));
+                }
+            }
+            clock.elapse(config.interval);
+            pingmgr.timer_expired();
+        }
+
+        // Congestion is detected once every recent latency is at least twice the older
+        // average, and only reported once.
+        assert_eq!(congested.len(), 1, "{congested:?}");
+
+        let (i, a, trend) = congested[0];
+        assert_eq!(i, 8);
+        assert_eq!(a, addr);
+        assert!(trend >= CONGESTION_FACTOR, "{trend}");
+
+        // Once the latency goes back down, the peer is no longer considered congested.
+        let peer = pingmgr.peers.get_mut(&addr).unwrap();
+        for _ in 0..CONGESTION_WINDOW {
+            peer.record_latency(LocalDuration::from_millis(100));
+        }
+        assert_eq!(peer.congestion(CONGESTION_WINDOW, CONGESTION_FACTOR), None);
+    }
+
+    #[test]
+    fn test_max_recorded_latencies() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+
+        for max in [1, 8, MAX_RECORDED_LATENCIES, 256] {
+            let config = Config {
+                max_recorded_latencies: max,
+                ..Config::default()
+            };
+            let mut pingmgr = PingManager::new(config, fastrand::Rng::new(), LocalTime::now());
+
+            pingmgr.peer_negotiated(addr, Link::Outbound);
+
+            let peer = pingmgr.peers.get_mut(&addr).unwrap();
+            for ms in 1..=max as u128 * 2 {
+                peer.record_latency(LocalDuration::from_millis(ms));
+            }
+            assert_eq!(peer.latencies.len(), max);
+            // Only the latest samples are kept.
+            assert_eq!(
+                peer.latencies.back(),
+                Some(&LocalDuration::from_millis(max as u128 + 1))
+            );
+        }
+
+        // The average is taken over the samples actually recorded.
+        let mut pingmgr =
+            PingManager::new(Config::default(), fastrand::Rng::new(), LocalTime::now());
+        pingmgr.peer_negotiated(addr, Link::Outbound);
+
+        let peer = pingmgr.peers.get_mut(&addr).unwrap();
+        peer.record_latency(LocalDuration::from_millis(100));
+        peer.record_latency(LocalDuration::from_millis(300));
+
+        assert_eq!(peer.latency(), LocalDuration::from_millis(200));
+    }
+
+    #[test]
+    fn test_stale_peers() {
+        let mut pingmgr =
+            PingManager::new(Config::default(), fastrand::Rng::new(), LocalTime::now());
+        let peers: Vec<(PeerId, Option<u128>)> = vec![
+            (([88, 88, 88, 1], 8333).into(), Some(300)),
+            (([88, 88, 88, 2], 8333).into(), Some(50)),
+            (([88, 88, 88, 3], 8333).into(), Some(900)),
+            (([88, 88, 88, 4], 8333).into(), None),
+            (([88, 88, 88, 5], 8333).into(), Some(100)),
+        ];
+
+        for (addr, latency) in &peers {
+            pingmgr.peer_negotiated(*addr, Link::Outbound);
+
+            if let Some(ms) = latency {
+                let peer = pingmgr.peers.get_mut(addr).unwrap();
+                peer.record_latency(LocalDuration::from_millis(*ms));
+            }
+        }
+
+        assert_eq!(
+            pingmgr.stale_peers(LocalDuration::from_millis(100)),
+            vec![peers[2].0, peers[0].0]
+        );
+        assert_eq!(
+            pingmgr.stale_peers(LocalDuration::from_millis(0)),
+            vec![peers[2].0, peers[0].0, peers[4].0, peers[1].0]
+        );
+        assert!(pingmgr.stale_peers(LocalDuration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_ping_untracked_peer() {
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut pingmgr =
+            PingManager::new(Config::default(), fastrand::Rng::new(), LocalTime::now());
+
+        assert!(!pingmgr.peers.contains_key(&addr));
+        pingmgr.received_ping(addr, 42);
+
+        assert_matches!(
+            output::test::messages_from(pingmgr.outbox.drain(), &addr).next(),
+            Some(NetworkMessage::Pong(42))
+        );
+        assert!(!pingmgr.peers.contains_key(&addr));
+    }
+}
@@ -20,7 +20,7 @@ use nakamoto_common::collections::{AddressBook, HashMap};
 use nakamoto_common::nonempty::NonEmpty;
 
 use super::output::{Io, Outbox};
-use super::Event;
+use super::{ChainImport, ErrorKind, Event, Severity};
 use super::{DisconnectReason, Link, Locators, PeerId};
 
 /// How long to wait for a request, eg. `getheaders` to be fulfilled.
@@ -36,8 +36,17 @@ pub const MAX_MESSAGE_INVS: usize = 50000;
 pub const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
 /// Idle timeout.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
+/// How long the header chain tip can go without advancing while we're behind our peers,
+/// before sync is considered stalled.
+pub const STALL_TIMEOUT: LocalDuration = LocalDuration::from_mins(5);
 /// Services required from peers for header sync.
 pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
+/// Maximum number of `getheaders` requests in flight to a single peer. Since each response
+/// carries at most [`MAX_MESSAGE_HEADERS`], this also bounds the headers in flight.
+pub const MAX_INFLIGHT_REQUESTS_PER_PEER: usize = 4;
+/// Round-trip time budget for in-flight `getheaders` requests to a single peer. The lower
+/// a peer's latency, the more requests fit in the budget.
+pub const INFLIGHT_LATENCY_BUDGET: LocalDuration = LocalDuration::from_secs(1);
 
 /// Maximum headers announced in a `headers` message, when unsolicited.
 const MAX_UNSOLICITED_HEADERS: usize = 24;
@@ -64,6 +73,10 @@ struct Peer {
     link: Link,
     last_active: Option<LocalTime>,
     last_asked: Option<Locators>,
+    /// Whether the peer announced its tip to us with an unsolicited `headers` message.
+    announced: bool,
+    /// Average round-trip latency, as measured by the ping manager.
+    latency: Option<LocalDuration>,
 }
 
 /// Sync manager configuration.
@@ -73,6 +86,9 @@ pub struct Config {
     pub max_message_headers: usize,
     /// How long to wait for a response from a peer.
     pub request_timeout: LocalDuration,
+    /// How long the tip can go without advancing while we're behind our peers, before
+    /// sync is considered stalled.
+    pub stall_timeout: LocalDuration,
     /// Consensus parameters.
     pub params: Params,
 }
@@ -91,8 +107,12 @@ pub struct SyncManager<C> {
     last_peer_sample: Option<LocalTime>,
     /// Last time we idled.
     last_idle: Option<LocalTime>,
-    /// In-flight header requests to peers.
-    inflight: HashMap<PeerId, GetHeaders>,
+    /// In-flight header requests to peers, oldest first.
+    inflight: HashMap<PeerId, Vec<GetHeaders>>,
+    /// Last tip height observed, and since when.
+    last_progress: Option<(Height, LocalTime)>,
+    /// Whether sync was reported stalled since the tip last advanced.
+    stalled: bool,
     /// State-machine output.
     outbox: Outbox,
     /// Clock.
@@ -135,6 +155,8 @@ impl<C: Clock> SyncManager<C> {
             last_peer_sample,
             last_idle,
             inflight,
+            last_progress: None,
+            stalled: false,
             outbox,
             clock,
         }
@@ -175,6 +197,11 @@ impl<C: Clock> SyncManager<C> {
             Event::PeerDisconnected { addr, .. } => {
                 self.unregister(&addr);
             }
+            Event::PeerLatencyUpdated { addr, average, .. } => {
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    peer.latency = Some(average);
+                }
+            }
             // Event::BlockHeadersSynced { height, hash } => {}
             Event::MessageReceived { from, message } => match message.as_ref() {
                 NetworkMessage::Headers(headers) => {
@@ -233,6 +260,10 @@ impl<C: Clock> SyncManager<C> {
     }
 
     /// Called when we received a `getheaders` message from a peer.
+    ///
+    /// Headers are served from the best locator on our active chain, up to the configured
+    /// maximum. If none of the locators are on our active chain, an empty `headers`
+    /// message is sent.
     pub fn received_getheaders<T: BlockReader>(
         &mut self,
         addr: &PeerId,
@@ -244,12 +275,18 @@ impl<C: Clock> SyncManager<C> {
         if self.is_syncing() || max == 0 {
             return;
         }
-        let headers = tree.locate_headers(&locator_hashes, stop_hash, max);
+        // Nb. Without any locators, the peer is asking for the header of the stop hash.
+        let unknown =
+            !locator_hashes.is_empty() && !locator_hashes.iter().any(|h| tree.contains(h));
+        let headers = if unknown {
+            vec![]
+        } else {
+            tree.locate_headers(&locator_hashes, stop_hash, max)
+        };
+        let count = headers.len();
 
-        if headers.is_empty() {
-            return;
-        }
         self.outbox.headers(*addr, headers);
+        self.outbox.event(Event::HeadersServed { to: *addr, count });
     }
 
     /// Import blocks into our block tree.
@@ -269,21 +306,32 @@ impl<C: Clock> SyncManager<C> {
         }) = &result
         {
             let reorg = !reverted.is_empty();
+            let reorg_depth = reverted.len();
+            // The common ancestor is right below the lowest reverted block.
+            let fork_height = reverted
+                .iter()
+                .map(|(h, _)| *h)
+                .min()
+                .map_or(*height, |h| h - 1);
 
             for (height, header) in reverted.iter().cloned() {
-                self.outbox
-                    .event(Event::BlockDisconnected { height, header });
+                self.outbox.event(Event::BlockDisconnected {
+                    height,
+                    header,
+                    fork_height,
+                });
             }
             for (height, header) in connected.iter().cloned() {
                 self.outbox.event(Event::BlockConnected { height, header });
             }
-            self.outbox.event(Event::BlockHeadersImported {
+            self.outbox.event(Event::BlockHeadersImported(ChainImport {
                 reorg,
+                reorg_depth,
                 hash: *hash,
                 height: *height,
                 connected: connected.clone(),
                 reverted: reverted.clone(),
-            });
+            }));
             self.broadcast_tip(hash, tree);
         }
         result
@@ -296,7 +344,7 @@ impl<C: Clock> SyncManager<C> {
         headers: &[BlockHeader],
         tree: &mut T,
     ) {
-        let request = self.inflight.remove(from);
+        let request = self.take_request(from, headers.first().map(|h| h.prev_blockhash));
         let Some(headers) = NonEmpty::from_vec(headers.to_vec()) else {
             return;
         };
@@ -308,6 +356,20 @@ impl<C: Clock> SyncManager<C> {
 
             return;
         }
+        // When solicited, the headers must connect to one of the locators we sent. Few
+        // headers may be an announcement (BIP 130) sent while our request was in flight,
+        // which is processed like any other announcement.
+        if length > MAX_UNSOLICITED_HEADERS && request.is_none() && self.inflight.contains_key(from)
+        {
+            let prev = headers.first().prev_blockhash;
+            let reason = "headers don't connect to locator";
+            log::debug!("Received headers from {from} with unknown parent {prev}");
+
+            self.inflight.remove(from);
+            self.record_misbehavior(from, reason);
+
+            return;
+        }
         // When unsolicited, we don't want to process too many headers in case of a DoS.
         if length > MAX_UNSOLICITED_HEADERS && request.is_none() {
             log::debug!("Received {} unsolicited headers from {}", length, from);
@@ -324,13 +386,20 @@ impl<C: Clock> SyncManager<C> {
 
         let root = headers.first().block_hash();
         let best = headers.last().block_hash();
+        let prev = headers.first().prev_blockhash;
 
         if tree.contains(&best) {
             return;
         }
+        let orphan = !tree.is_known(&prev);
 
         match self.import_block_headers(headers.into_iter(), tree) {
             Ok(ImportResult::TipUnchanged) => {
+                // Orphans are kept by the block tree, and connected once their parent arrives.
+                if orphan && tree.is_known(&root) {
+                    self.outbox
+                        .event(Event::OrphanHeaderReceived { hash: root, prev });
+                }
                 // Try to find a common ancestor that leads up to the first header in
                 // the list we received.
                 let locators = (tree.locator_hashes(tree.height()), root);
@@ -341,10 +410,15 @@ impl<C: Clock> SyncManager<C> {
             Ok(ImportResult::TipChanged { hash, height, .. }) => {
                 // Update peer height.
                 if let Some(peer) = self.peers.get_mut(from) {
-                    if height > peer.height {
+                    if height >= peer.height {
                         peer.tip = hash;
                         peer.height = height;
                     }
+                    // Peers announcing blocks via `headers` (BIP 130) keep us up to date
+                    // on their own.
+                    if request.is_none() {
+                        peer.announced = true;
+                    }
                 }
                 // Keep track of when we last updated our tip. This is useful to check
                 // whether our tip is stale.
@@ -367,7 +441,7 @@ impl<C: Clock> SyncManager<C> {
             }
             // If this is an error with the underlying store, we have to propagate
             // this up, because we can't handle it here.
-            Err(Error::Store(e)) => self.outbox.error(e),
+            Err(Error::Store(e)) => self.outbox.error(ErrorKind::Storage, e),
             // If we got a bad block from the peer, we can handle it here.
             Err(
                 e @ Error::InvalidBlockPoW
@@ -404,8 +478,8 @@ impl<C: Clock> SyncManager<C> {
         timeout: LocalDuration,
         on_timeout: OnTimeout,
     ) {
-        // Don't request more than once from the same peer.
-        if self.inflight.contains_key(&addr) {
+        // Don't request more than the peer's in-flight window allows.
+        if self.inflight_requests(&addr) >= self.window(&addr) {
             return;
         }
         if let Some(peer) = self.peers.get_mut(&addr) {
@@ -420,7 +494,7 @@ impl<C: Clock> SyncManager<C> {
                 on_timeout,
             };
 
-            self.inflight.insert(addr, req.clone());
+            self.inflight.entry(addr).or_default().push(req.clone());
             self.outbox.get_headers(addr, req.locators);
             self.outbox.set_timer(timeout);
         }
@@ -429,8 +503,13 @@ impl<C: Clock> SyncManager<C> {
     /// Called when we received an `inv` message. This will happen if we are out of sync with a
     /// peer, and blocks are being announced. Otherwise, we expect to receive a `headers` message.
     pub fn received_inv<T: BlockReader>(&mut self, addr: PeerId, inv: &[Inventory], tree: &T) {
-        // Don't try to fetch headers from `inv` message while syncing. It's not helpful.
-        if self.is_syncing() {
+        // Don't try to fetch headers from `inv` message while syncing. It's not helpful,
+        // unless the peer is already serving us and has room in its in-flight window.
+        if self
+            .inflight
+            .get(&addr)
+            .map_or(self.is_syncing(), |reqs| reqs.len() >= self.window(&addr))
+        {
             return;
         }
         // Ignore and disconnect peers misbehaving.
@@ -476,19 +555,23 @@ impl<C: Clock> SyncManager<C> {
         let timed_out = self
             .inflight
             .iter()
+            .flat_map(|(peer, reqs)| reqs.iter().map(move |req| (*peer, req)))
             .filter_map(|(peer, req)| {
                 if local_time - req.sent_at >= timeout {
-                    Some((*peer, req.on_timeout, req.clone()))
+                    Some((peer, req.on_timeout, req.clone()))
                 } else {
                     None
                 }
             })
             .collect::<Vec<_>>();
 
+        self.inflight.retain(|_, reqs| {
+            reqs.retain(|req| local_time - req.sent_at < timeout);
+            !reqs.is_empty()
+        });
+
         let mut sync = false;
         for (peer, on_timeout, req) in timed_out {
-            self.inflight.remove(&peer);
-
             match on_timeout {
                 OnTimeout::Ignore => {
                     // It's likely that the peer just didn't have the requested header.
@@ -517,6 +600,43 @@ impl<C: Clock> SyncManager<C> {
         } else {
             self.idle(tree);
         }
+        self.check_stalled(tree);
+    }
+
+    /// Check whether header sync has stalled, ie. our tip hasn't advanced in a while,
+    /// even though our peers have longer chains.
+    fn check_stalled<T: BlockReader>(&mut self, tree: &T) {
+        let now = self.clock.local_time();
+        let tip = tree.height();
+
+        match self.last_progress {
+            Some((height, since)) if height == tip => {
+                if self.best_height().map_or(true, |h| h <= tip) {
+                    // We're caught up with our peers, so there's nothing to stall on.
+                    self.last_progress = Some((tip, now));
+                    self.stalled = false;
+                    return;
+                }
+                if self.stalled {
+                    return;
+                }
+                let stalled_for = now - since;
+
+                if stalled_for >= self.config.stall_timeout {
+                    self.stalled = true;
+                    self.outbox.event(Event::SyncStalled { stalled_for, tip });
+                } else {
+                    self.outbox
+                        .set_timer(since + self.config.stall_timeout - now);
+                }
+            }
+            _ => {
+                // The tip advanced, or we're checking for the first time.
+                self.last_progress = Some((tip, now));
+                self.stalled = false;
+                self.outbox.set_timer(self.config.stall_timeout);
+            }
+        }
     }
 
     /// Get the best known height out of all our peers.
@@ -524,17 +644,40 @@ impl<C: Clock> SyncManager<C> {
         self.peers.iter().map(|(_, p)| p.height).max()
     }
 
+    /// Get the peers whose advertised height is at least the given height.
+    pub fn peers_with_height(&self, height: Height) -> impl Iterator<Item = &PeerId> + '_ {
+        self.peers
+            .iter()
+            .filter(move |(_, p)| p.height >= height)
+            .map(|(addr, _)| addr)
+    }
+
     /// Are we currently syncing?
     pub fn is_syncing(&self) -> bool {
         !self.inflight.is_empty()
     }
 
+    /// Get the in-flight `getheaders` window of a peer, ie. how many requests it can have
+    /// in flight at once. Peers with a lower latency have a larger window, up to
+    /// [`MAX_INFLIGHT_REQUESTS_PER_PEER`]. Peers with unknown latency have a window of one.
+    pub fn window(&self, addr: &PeerId) -> usize {
+        let Some(latency) = self.peers.get(addr).and_then(|p| p.latency) else {
+            return 1;
+        };
+        let window = INFLIGHT_LATENCY_BUDGET.as_millis() / latency.as_millis().max(1);
+
+        (window as usize).clamp(1, MAX_INFLIGHT_REQUESTS_PER_PEER)
+    }
+
     ///////////////////////////////////////////////////////////////////////////
 
+    /// Report a peer that sent invalid headers. Since the peer can't be trusted to follow
+    /// the protocol, its misbehavior is severe.
     fn record_misbehavior(&mut self, addr: &PeerId, reason: &'static str) {
         self.outbox.event(Event::PeerMisbehaved {
             addr: *addr,
             reason,
+            severity: Severity::Severe,
         });
     }
 
@@ -568,6 +711,8 @@ impl<C: Clock> SyncManager<C> {
     fn register(&mut self, addr: PeerId, height: Height, preferred: bool, link: Link) {
         let last_active = None;
         let last_asked = None;
+        let announced = false;
+        let latency = None;
         let tip = BlockHash::all_zeros();
 
         self.peers.insert(
@@ -579,6 +724,8 @@ impl<C: Clock> SyncManager<C> {
                 preferred,
                 last_active,
                 last_asked,
+                announced,
+                latency,
             },
         );
     }
@@ -603,10 +750,32 @@ impl<C: Clock> SyncManager<C> {
             .map(|(a, _)| **a)
     }
 
+    /// Get the number of `getheaders` requests in flight to a peer.
+    fn inflight_requests(&self, addr: &PeerId) -> usize {
+        self.inflight.get(addr).map_or(0, Vec::len)
+    }
+
+    /// Take the in-flight request from a peer that the given headers respond to, ie. whose
+    /// locators the first header connects to. If no header was received, the oldest
+    /// request is taken.
+    fn take_request(&mut self, addr: &PeerId, prev: Option<BlockHash>) -> Option<GetHeaders> {
+        let reqs = self.inflight.get_mut(addr)?;
+        let ix = match prev {
+            Some(prev) => reqs.iter().position(|r| r.locators.0.contains(&prev))?,
+            None => 0,
+        };
+        let req = reqs.remove(ix);
+
+        if reqs.is_empty() {
+            self.inflight.remove(addr);
+        }
+        Some(req)
+    }
+
     /// Check whether a peer is a good request candidate for the given locators.
     /// This function ensures that we don't ask the same peer twice for the same locators.
     fn is_request_candidate(&self, addr: &PeerId, peer: &Peer, locators: &[BlockHash]) -> bool {
-        !self.inflight.contains_key(addr)
+        self.inflight_requests(addr) < self.window(addr)
             && peer.link.is_outbound()
             && peer.last_asked.as_ref().map_or(true, |l| l.0 != locators)
     }
@@ -629,7 +798,10 @@ impl<C: Clock> SyncManager<C> {
 
     /// Check if we're currently syncing with these locators.
     fn syncing(&self, locators: &Locators) -> bool {
-        self.inflight.values().any(|r| &r.locators == locators)
+        self.inflight
+            .values()
+            .flatten()
+            .any(|r| &r.locators == locators)
     }
 
     /// Start syncing if we're out of sync.
@@ -700,10 +872,14 @@ impl<C: Clock> SyncManager<C> {
 
         // If we think we're in sync and we haven't asked other peers in a while, then
         // sample their headers just to make sure we're on the right chain.
+        // Peers that announced our tip to us via `headers` are skipped, since they'll
+        // announce any new block the same way.
+        let (tip, _) = tree.tip();
         let locators = tree.locator_hashes(tree.height());
         let addrs = self
             .peers
             .iter()
+            .filter(|(_, p)| !(p.announced && p.tip == tip))
             .filter(|(a, p)| self.is_request_candidate(a, p, &locators))
             .map(|(a, _)| *a)
             .collect::<Vec<_>>();
@@ -16,12 +16,12 @@ use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
 use nakamoto_common::bitcoin::util::bloom::BloomFilter;
 use nakamoto_common::bitcoin_hashes::hex::ToHex;
 
-use super::event::TxStatus;
+use super::event::{ChainImport, TxStatus};
 use super::{addrmgr, cbfmgr, peermgr, pingmgr, syncmgr};
 use super::{
     chan, network::Network, BlockHash, BlockHeader, Command, Config, DisconnectReason, Event,
-    HashSet, Height, Io, Limits, NetworkMessage, PeerId, RawNetworkMessage, ServiceFlags,
-    VersionMessage,
+    HashSet, Height, Io, Limits, MessageMask, NetworkMessage, PeerId, RawNetworkMessage,
+    ServiceFlags, Severity, TimedEvent, VersionMessage,
 };
 use super::{PROTOCOL_VERSION, USER_AGENT};
 
@@ -49,6 +49,7 @@ use nakamoto_common::block::tree::BlockReader as _;
 use nakamoto_common::collections::HashMap;
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::KnownAddress;
+use nakamoto_common::p2p::peer::Reputation as _;
 use nakamoto_common::p2p::peer::Source;
 
 use nakamoto_test::arbitrary;
@@ -167,6 +168,482 @@ fn test_idle_disconnect() {
         .expect("peer disconnects remote");
 }
 
+#[test]
+fn test_reputation() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let latency = LocalDuration::from_millis(300);
+
+    peer.connect_addr(&remote, Link::Outbound);
+
+    let nonce = peer
+        .messages(&remote)
+        .find_map(|m| match m {
+            NetworkMessage::Ping(nonce) => Some(nonce),
+            _ => None,
+        })
+        .expect("`ping` is sent");
+
+    peer.elapse(latency);
+    peer.received(&remote, NetworkMessage::Pong(nonce));
+    peer.elapse(LocalDuration::from_mins(1));
+    peer.protocol
+        .disconnected(&remote, DisconnectReason::PeerTimeout("test").into());
+
+    let rep = peer
+        .protocol
+        .reputation()
+        .get(&remote.ip())
+        .unwrap()
+        .clone();
+    assert_eq!(rep.sessions, 1);
+    assert_eq!(rep.latency, Some(latency));
+    assert_eq!(rep.misbehaviors, 0);
+    assert_eq!(rep.uptime, latency + LocalDuration::from_mins(1));
+    assert_eq!(
+        peer.protocol.reputation().best_peers(1),
+        vec![Address::new(&remote, rep.services)]
+    );
+
+    // Misbehavior is recorded.
+    peer.protocol.event(Event::PeerMisbehaved {
+        addr: remote,
+        reason: "test",
+        severity: Severity::Minor,
+    });
+    let rep = peer.protocol.reputation().get(&remote.ip()).unwrap();
+    assert_eq!(rep.misbehaviors, 1);
+
+    // Inbound peers aren't tracked.
+    let inbound: PeerId = ([241, 19, 44, 19], 40123).into();
+
+    peer.connect_addr(&inbound, Link::Inbound);
+    peer.elapse(LocalDuration::from_mins(1));
+    peer.protocol.event(Event::PeerMisbehaved {
+        addr: inbound,
+        reason: "test",
+        severity: Severity::Minor,
+    });
+    peer.protocol
+        .disconnected(&inbound, DisconnectReason::PeerTimeout("test").into());
+
+    assert!(peer.protocol.reputation().get(&inbound.ip()).is_none());
+    assert_eq!(peer.protocol.reputation().best_peers(8).len(), 1);
+}
+
+#[test]
+fn test_get_peer_metrics() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, Link::Outbound);
+
+    let nonce = peer
+        .messages(&remote)
+        .find_map(|m| match m {
+            NetworkMessage::Ping(nonce) => Some(nonce),
+            _ => None,
+        })
+        .expect("`ping` is sent");
+
+    let (transmit, receive) = chan::bounded(1);
+    peer.command(Command::GetPeerMetrics(transmit.clone()));
+    assert_eq!(receive.try_recv().unwrap(), vec![]);
+
+    peer.elapse(LocalDuration::from_millis(300));
+    peer.received(&remote, NetworkMessage::Pong(nonce));
+    peer.command(Command::GetPeerMetrics(transmit));
+
+    let metrics = receive.try_recv().unwrap();
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0].addr, remote);
+    assert_eq!(metrics[0].avg_ms, 300);
+    assert_eq!(metrics[0].samples, 1);
+}
+
+#[test]
+fn test_resume_merkle_block_rescan() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+
+    peer.command(Command::ResumeMerkleBlockRescan {
+        checkpoint: 10,
+        to: Some(20),
+        peer: None,
+    });
+
+    assert!(peer.events().any(|e| matches!(
+        e,
+        Event::MerkleBlockRescanStarted {
+            start: 11,
+            stop: Some(20)
+        }
+    )));
+}
+
+#[test]
+fn test_get_bloom_filter() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let (transmit, receive) = chan::bounded(1);
+
+    peer.connect(
+        &PeerDummy {
+            addr: remote,
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: ServiceFlags::NETWORK,
+            relay: true,
+            time: peer.local_time(),
+        },
+        Link::Outbound,
+    );
+    peer.command(Command::GetBloomFilter(remote, transmit.clone()));
+    assert_eq!(receive.try_recv().unwrap(), None);
+
+    let mut filter = nakamoto_common::bloom::BloomFilter::with_fp_rate(10, 0.0001).unwrap();
+    filter.insert(b"wallet");
+
+    peer.command(Command::LoadBloomFilter(filter.clone().into(), remote));
+    peer.command(Command::AddToBloomFilter(b"change".to_vec(), remote));
+    peer.command(Command::GetBloomFilter(remote, transmit));
+
+    // Our copy of the filter includes the elements added after loading it.
+    let loaded = receive.try_recv().unwrap().expect("the filter is loaded");
+    filter.insert(b"change");
+
+    assert_eq!(loaded, filter);
+}
+
+#[test]
+fn test_inbound_eviction() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let fast: PeerId = ([241, 19, 44, 18], 8333).into();
+    let slow: PeerId = ([241, 19, 44, 19], 8333).into();
+    let new: PeerId = ([241, 19, 44, 20], 8333).into();
+
+    peer.protocol.peermgr.config.max_inbound_peers = 3;
+
+    for (remote, latency) in [(fast, 300), (slow, 3000)] {
+        peer.connect_addr(&remote, Link::Inbound);
+
+        let nonce = peer
+            .messages(&remote)
+            .find_map(|m| match m {
+                NetworkMessage::Ping(nonce) => Some(nonce),
+                _ => None,
+            })
+            .expect("`ping` is sent");
+
+        peer.elapse(LocalDuration::from_millis(latency));
+        peer.received(&remote, NetworkMessage::Pong(nonce));
+    }
+    peer.drain();
+
+    // The inbound limit is reached, so the slowest inbound peer makes room for the new one.
+    peer.protocol.connected(new, &peer.addr, Link::Inbound);
+
+    let outputs = peer.outputs().collect::<Vec<_>>();
+    assert!(outputs.iter().any(|o| matches!(
+        o, Io::Disconnect(addr, DisconnectReason::Evicted("slow")) if addr == &slow
+    )));
+    assert!(!outputs
+        .iter()
+        .any(|o| matches!(o, Io::Disconnect(addr, _) if addr == &new || addr == &fast)));
+}
+
+#[test]
+fn test_disconnect_peer() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, Link::Outbound);
+
+    let nonce = peer
+        .messages(&remote)
+        .find_map(|m| match m {
+            NetworkMessage::Ping(nonce) => Some(nonce),
+            _ => None,
+        })
+        .expect("`ping` is sent");
+
+    peer.elapse(LocalDuration::from_millis(300));
+    peer.received(&remote, NetworkMessage::Pong(nonce));
+    peer.drain();
+
+    assert!(peer.protocol.peermgr.is_connected(&remote));
+    assert!(peer.protocol.pingmgr.latency(&remote).is_some());
+    assert_eq!(peer.protocol.peers_with_height(0), vec![remote]);
+
+    peer.command(Command::DisconnectPeer(
+        remote,
+        DisconnectReason::PeerMisbehaving("test"),
+    ));
+
+    let outputs = peer.outputs().collect::<Vec<_>>();
+    let disconnect = outputs
+        .iter()
+        .position(|o| {
+            matches!(
+                o, Io::Disconnect(addr, DisconnectReason::PeerMisbehaving("test")) if addr == &remote
+            )
+        })
+        .expect("peer disconnects remote");
+    let disconnected = outputs
+        .iter()
+        .position(|o| {
+            matches!(
+                o, Io::Event(Event::PeerDisconnected { addr, .. }) if addr == &remote
+            )
+        })
+        .expect("`PeerDisconnected` is emitted");
+    assert!(disconnect < disconnected);
+
+    // The peer is cleaned up across managers right away.
+    assert!(!peer.protocol.peermgr.is_connected(&remote));
+    assert!(peer.protocol.pingmgr.latency(&remote).is_none());
+    assert!(peer.protocol.peers_with_height(0).is_empty());
+    assert_eq!(
+        peer.protocol
+            .reputation()
+            .get(&remote.ip())
+            .unwrap()
+            .sessions,
+        1
+    );
+
+    // The remote isn't pinged anymore.
+    peer.elapse(pingmgr::PING_INTERVAL);
+    assert_eq!(peer.messages(&remote).count(), 0);
+
+    // When the reactor reports the disconnection, there is nothing left to do.
+    peer.protocol
+        .disconnected(&remote, DisconnectReason::PeerMisbehaving("test").into());
+    assert!(!peer
+        .events()
+        .any(|e| matches!(e, Event::PeerDisconnected { .. })));
+    assert_eq!(
+        peer.protocol
+            .reputation()
+            .get(&remote.ip())
+            .unwrap()
+            .sessions,
+        1
+    );
+
+    // Disconnecting a peer that isn't connected does nothing.
+    peer.command(Command::DisconnectPeer(remote, DisconnectReason::Command));
+    assert_eq!(peer.outputs().count(), 0);
+}
+
+#[test]
+fn test_shutdown() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, Link::Outbound);
+    // A `ping` is outstanding.
+    peer.elapse(pingmgr::PING_INTERVAL);
+    peer.drain();
+
+    // A `pong` is queued, but not handed off yet.
+    peer.received(&remote, NetworkMessage::Ping(42));
+    peer.protocol.shutdown();
+
+    let outputs = peer.outputs().collect::<Vec<_>>();
+    assert_matches!(outputs.first(), Some(Io::Event(Event::ShuttingDown)));
+
+    let pong = outputs
+        .iter()
+        .position(|o| matches!(o, Io::Write(a, msg) if a == &remote && msg.payload == NetworkMessage::Pong(42)))
+        .expect("the `pong` is flushed");
+    let disconnect = outputs
+        .iter()
+        .position(|o| matches!(o, Io::Disconnect(a, DisconnectReason::Command) if a == &remote))
+        .expect("the remote is disconnected");
+    assert!(pong < disconnect);
+
+    // The outstanding `ping` doesn't cause a disconnect.
+    peer.elapse(pingmgr::PING_TIMEOUT);
+    assert!(!peer
+        .outputs()
+        .any(|o| matches!(o, Io::Disconnect(_, DisconnectReason::PeerTimeout(_)))));
+}
+
+#[test]
+fn test_bandwidth_report() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, Link::Outbound);
+    peer.elapse(super::BANDWIDTH_REPORT_INTERVAL);
+    peer.events()
+        .find(|e| matches!(e, Event::BandwidthReport { sent, received, .. } if *sent > 0 && *received > 0))
+        .expect("the handshake is accounted for");
+    // Discard whatever was output by the timers, so that only the messages below are counted.
+    peer.drain();
+    peer.protocol.bandwidth.reset();
+
+    // A `ping` and its `pong` are both 32 bytes on the wire: a 24-byte header
+    // and an 8-byte nonce.
+    peer.received(&remote, NetworkMessage::Ping(42));
+    peer.messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::Pong(42)))
+        .expect("a `pong` is sent");
+    peer.drain();
+
+    peer.elapse(super::BANDWIDTH_REPORT_INTERVAL);
+    let (sent, received, period) = peer
+        .events()
+        .find_map(|e| match e {
+            Event::BandwidthReport {
+                sent,
+                received,
+                period,
+            } => Some((sent, received, period)),
+            _ => None,
+        })
+        .expect("bandwidth is reported");
+
+    assert_eq!(sent, 32);
+    assert_eq!(received, 32);
+    assert_eq!(period, super::BANDWIDTH_REPORT_INTERVAL);
+
+    // The counters are reset after each report.
+    peer.elapse(super::BANDWIDTH_REPORT_INTERVAL);
+    peer.events()
+        .find(|e| {
+            matches!(
+                e,
+                Event::BandwidthReport {
+                    sent: 0,
+                    received: 0,
+                    ..
+                }
+            )
+        })
+        .expect("counters are reset");
+}
+
+#[test]
+fn test_sync_stalled() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+    let stalled = |alice: &mut Peer<Protocol>| {
+        alice
+            .events()
+            .filter(|e| matches!(e, Event::SyncStalled { .. }))
+            .count()
+    };
+
+    // The remote reports a longer chain, but never sends us any headers.
+    alice.connect_addr(&remote, Link::Outbound);
+    alice.elapse(LocalDuration::from_secs(1));
+    alice.drain();
+
+    alice.elapse(syncmgr::STALL_TIMEOUT);
+    assert_matches!(
+        alice.events().find(|e| matches!(e, Event::SyncStalled { .. })),
+        Some(Event::SyncStalled { stalled_for, tip: 0 }) if stalled_for == syncmgr::STALL_TIMEOUT
+    );
+    alice.drain();
+
+    // The stall is only reported once.
+    alice.elapse(syncmgr::STALL_TIMEOUT);
+    assert_eq!(stalled(&mut alice), 0);
+
+    // A peer that is caught up with its peers never reports a stall.
+    let mut bob = Peer::genesis(
+        "bob",
+        [49, 49, 49, 49],
+        network,
+        vec![],
+        fastrand::Rng::new(),
+    );
+
+    bob.connect(
+        &PeerDummy {
+            addr: remote,
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: bob.local_time(),
+        },
+        Link::Outbound,
+    );
+    bob.elapse(LocalDuration::from_secs(1));
+    bob.elapse(syncmgr::STALL_TIMEOUT);
+    bob.elapse(syncmgr::STALL_TIMEOUT);
+    assert_eq!(stalled(&mut bob), 0);
+}
+
+#[test]
+fn test_peers_with_height() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    // Peers with their advertised heights and ping latencies, in milliseconds.
+    let peers: [(PeerId, Height, Option<u64>); 4] = [
+        (([241, 19, 44, 18], 8333).into(), 200, Some(300)),
+        (([241, 19, 44, 19], 8333).into(), 150, Some(100)),
+        (([241, 19, 44, 20], 8333).into(), 100, Some(50)),
+        (([241, 19, 44, 21], 8333).into(), 180, None),
+    ];
+
+    for (addr, height, latency) in peers {
+        alice.connect(
+            &PeerDummy {
+                addr,
+                height,
+                protocol_version: PROTOCOL_VERSION,
+                services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+                relay: true,
+                time: alice.local_time(),
+            },
+            Link::Outbound,
+        );
+        let nonce = alice
+            .messages(&addr)
+            .find_map(|m| match m {
+                NetworkMessage::Ping(nonce) => Some(nonce),
+                _ => None,
+            })
+            .expect("`ping` is sent");
+
+        if let Some(latency) = latency {
+            alice.elapse(LocalDuration::from_millis(latency as u128));
+            alice.received(&addr, NetworkMessage::Pong(nonce));
+        }
+        alice.drain();
+    }
+
+    // The fastest peers go first, and peers with unknown latency last.
+    assert_eq!(
+        alice.protocol.peers_with_height(150),
+        vec![peers[1].0, peers[0].0, peers[3].0]
+    );
+    assert_eq!(alice.protocol.peers_with_height(190), vec![peers[0].0]);
+    assert!(alice.protocol.peers_with_height(201).is_empty());
+}
+
 #[test]
 fn test_inv_getheaders() {
     let rng = fastrand::Rng::new();
@@ -190,6 +667,324 @@ fn test_inv_getheaders() {
         .expect("a timer should be returned");
 }
 
+#[test]
+fn test_headers_announcement() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let block = gen::block(&network.genesis(), &mut rng);
+
+    alice.tick(LocalTime::from_block_time(block.header.time));
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+    alice.drain();
+
+    // The remote announces a new block with an unsolicited `headers` message.
+    alice.received(&remote, NetworkMessage::Headers(vec![block.header]));
+    assert_matches!(
+        alice.events().find(|e| matches!(e, Event::BlockHeadersImported(_))),
+        Some(Event::BlockHeadersImported(ChainImport { height: 1, hash, .. }))
+        if hash == block.block_hash()
+    );
+    assert_eq!(alice.protocol.tree.height(), 1);
+    assert!(!alice
+        .messages(&remote)
+        .any(|m| matches!(m, NetworkMessage::GetHeaders(_))));
+
+    // Once our tip goes stale, the peer isn't asked again for the tip it announced.
+    alice.elapse(LocalDuration::from_mins(60));
+    assert!(!alice
+        .messages(&remote)
+        .any(|m| matches!(m, NetworkMessage::GetHeaders(_))));
+}
+
+#[test]
+fn test_orphan_headers() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let headers = gen::headers(network.genesis(), 2, &mut rng);
+    let (parent, child) = (headers[1], headers[2]);
+
+    alice.tick(LocalTime::from_block_time(child.time));
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+    alice.drain();
+
+    // The child arrives before its parent, and is kept as an orphan.
+    alice.received(&remote, NetworkMessage::Headers(vec![child]));
+    assert_matches!(
+        alice.events().find(|e| matches!(e, Event::OrphanHeaderReceived { .. })),
+        Some(Event::OrphanHeaderReceived { hash, prev })
+        if hash == child.block_hash() && prev == parent.block_hash()
+    );
+    assert_eq!(alice.protocol.tree.height(), 0);
+
+    // Once the parent arrives, both headers are connected.
+    alice.received(&remote, NetworkMessage::Headers(vec![parent]));
+    assert_matches!(
+        alice.events().find(|e| matches!(e, Event::BlockHeadersImported(_))),
+        Some(Event::BlockHeadersImported(ChainImport { height: 2, hash, .. }))
+        if hash == child.block_hash()
+    );
+    assert_eq!(alice.protocol.tree.height(), 2);
+}
+
+#[test]
+fn test_getheaders_locator_validation() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let headers = gen::headers(network.genesis(), 40, &mut rng);
+    let bob: PeerId = ([241, 19, 44, 18], 8333).into();
+    let eve: PeerId = ([241, 19, 44, 19], 8333).into();
+
+    for (remote, connects) in [(eve, false), (bob, true)] {
+        let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+
+        alice.tick(LocalTime::from_block_time(headers.last().time));
+        alice.connect(
+            &PeerDummy {
+                addr: remote,
+                height: 40,
+                protocol_version: PROTOCOL_VERSION,
+                services: syncmgr::REQUIRED_SERVICES,
+                relay: true,
+                time: alice.local_time(),
+            },
+            Link::Outbound,
+        );
+
+        let locators = alice
+            .messages(&remote)
+            .find_map(|m| match m {
+                NetworkMessage::GetHeaders(GetHeadersMessage { locator_hashes, .. }) => {
+                    Some(locator_hashes)
+                }
+                _ => None,
+            })
+            .expect("Alice asks for headers");
+        assert_eq!(locators, vec![network.genesis_hash()]);
+
+        // Bob responds with headers building on the locator, Eve with headers that don't.
+        let response = if connects {
+            headers.tail.clone()
+        } else {
+            headers.tail[4..].to_vec()
+        };
+        alice.received(&remote, NetworkMessage::Headers(response));
+
+        let misbehaved = alice.events().any(|e| {
+            matches!(
+                e,
+                Event::PeerMisbehaved { addr, reason: "headers don't connect to locator", .. }
+                if addr == remote
+            )
+        });
+        let disconnected = alice
+            .outputs()
+            .any(|o| matches!(o, Io::Disconnect(a, _) if a == remote));
+
+        assert_eq!(misbehaved, !connects);
+        assert_eq!(disconnected, !connects);
+        assert_eq!(alice.protocol.tree.height(), if connects { 40 } else { 0 });
+    }
+}
+
+/// Test that a block announced while our `getheaders` is in flight isn't mistaken for a
+/// response that doesn't connect to the locator.
+#[test]
+fn test_getheaders_announcement_inflight() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let headers = gen::headers(network.genesis(), 40, &mut rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+
+    alice.tick(LocalTime::from_block_time(headers.last().time));
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height: 40,
+            protocol_version: PROTOCOL_VERSION,
+            services: syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+    alice
+        .messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::GetHeaders(_)))
+        .expect("Alice asks for headers");
+
+    // The announced header doesn't connect to our chain, so it's kept as an orphan.
+    let announced = headers.tail[20];
+    alice.received(&remote, NetworkMessage::Headers(vec![announced]));
+
+    let events = alice.events().collect::<Vec<_>>();
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, Event::PeerMisbehaved { .. })));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        Event::OrphanHeaderReceived { hash, .. } if *hash == announced.block_hash()
+    )));
+    assert!(!alice
+        .outputs()
+        .any(|o| matches!(o, Io::Disconnect(a, _) if a == remote)));
+
+    // The response to our request is still processed.
+    alice.received(&remote, NetworkMessage::Headers(headers.tail.clone()));
+    assert_eq!(alice.protocol.tree.height(), 40);
+}
+
+#[test]
+fn test_getheaders_responder() {
+    use nakamoto_common::bitcoin_hashes::Hash as _;
+
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let height = 144;
+    let headers = BITCOIN_HEADERS.tail[0..height].to_vec();
+    let remote = PeerDummy::new([241, 19, 44, 18], network, 0, ServiceFlags::NETWORK);
+
+    let mut bob = Peer::new(
+        "bob",
+        [97, 97, 97, 97],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    bob.tick(LocalTime::from_block_time(headers.last().unwrap().time));
+    bob.connect(&remote, Link::Inbound);
+    bob.drain();
+
+    let serve = |bob: &mut Peer<Protocol>, locator_hashes: Vec<BlockHash>| {
+        bob.received(
+            &remote.addr,
+            NetworkMessage::GetHeaders(GetHeadersMessage {
+                version: PROTOCOL_VERSION,
+                locator_hashes,
+                stop_hash: BlockHash::all_zeros(),
+            }),
+        );
+        let served = bob
+            .messages(&remote.addr)
+            .find_map(|m| match m {
+                NetworkMessage::Headers(headers) => Some(headers),
+                _ => None,
+            })
+            .expect("Bob sends a `headers` message");
+        let count = bob
+            .events()
+            .find_map(|e| match e {
+                Event::HeadersServed { to, count } if to == remote.addr => Some(count),
+                _ => None,
+            })
+            .expect("Bob serves headers");
+
+        assert_eq!(served.len(), count);
+        served
+    };
+
+    // Headers are served from the best known locator, up to the tip.
+    let locator = 100;
+    let served = serve(
+        &mut bob,
+        vec![
+            BlockHash::from_hex("0000000000b7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
+                .unwrap(),
+            headers[locator - 1].block_hash(),
+            network.genesis_hash(),
+        ],
+    );
+    assert_eq!(served, headers[locator..].to_vec());
+
+    // Unknown locators yield an empty response.
+    let served = serve(
+        &mut bob,
+        vec![BlockHash::from_hex(
+            "0000000000b7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206",
+        )
+        .unwrap()],
+    );
+    assert!(served.is_empty());
+}
+
+#[test]
+fn test_getheaders_window() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let announced = (0..syncmgr::MAX_INFLIGHT_REQUESTS_PER_PEER)
+        .map(|_| gen::block(&network.genesis(), &mut rng).block_hash())
+        .collect::<Vec<_>>();
+    let mut inflight = Vec::new();
+
+    for latency in [
+        LocalDuration::from_millis(50),
+        LocalDuration::from_millis(800),
+    ] {
+        let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+
+        alice.connect(
+            &PeerDummy {
+                addr: remote,
+                height: 144,
+                protocol_version: PROTOCOL_VERSION,
+                services: syncmgr::REQUIRED_SERVICES,
+                relay: true,
+                time: alice.local_time(),
+            },
+            Link::Outbound,
+        );
+        assert_eq!(alice.protocol.syncmgr.window(&remote), 1);
+
+        alice.protocol.event(Event::PeerLatencyUpdated {
+            addr: remote,
+            rtt: latency,
+            average: latency,
+        });
+        // While we're syncing, the peer announces blocks we don't know about.
+        for hash in &announced {
+            alice.received(&remote, NetworkMessage::Inv(vec![Inventory::Block(*hash)]));
+        }
+        let requests = alice
+            .messages(&remote)
+            .filter(|m| matches!(m, NetworkMessage::GetHeaders(_)))
+            .count();
+
+        assert!(requests <= alice.protocol.syncmgr.window(&remote));
+        inflight.push((alice.protocol.syncmgr.window(&remote), requests));
+    }
+
+    // The low-latency peer gets more requests in flight.
+    let max = syncmgr::MAX_INFLIGHT_REQUESTS_PER_PEER;
+    assert_eq!(inflight, vec![(max, max), (1, 1)]);
+}
+
 #[test]
 fn test_bad_magic() {
     let rng = fastrand::Rng::new();
@@ -449,6 +1244,170 @@ fn test_handshake_version_hook() {
         .expect("peer should send a 'verack' message back");
 }
 
+#[test]
+fn test_message_size_limits() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let received = Arc::new(AtomicUsize::new(0));
+    let mut cfg = Config::default();
+    cfg.hooks.on_message = {
+        let received = received.clone();
+        Arc::new(move |_, _, _| {
+            received.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+
+    let mut alice = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let eve = PeerDummy::new([131, 31, 11, 66], network, 144, ServiceFlags::NETWORK);
+    let hash = network.genesis_hash();
+    let time = alice.local_time().block_time();
+
+    for (remote, msg, reason) in [
+        (
+            &bob,
+            NetworkMessage::Inv(vec![Inventory::Block(hash); syncmgr::MAX_MESSAGE_INVS + 1]),
+            "oversized `inv` message",
+        ),
+        (
+            &eve,
+            NetworkMessage::Addr(vec![
+                (time, Address::new(&bob.addr, ServiceFlags::NETWORK));
+                addrmgr::MAX_ADDR_ADDRESSES + 1
+            ]),
+            "oversized `addr` message",
+        ),
+    ] {
+        alice.connect(remote, Link::Outbound);
+        alice.drain();
+
+        let count = received.load(Ordering::SeqCst);
+        alice.received(&remote.addr, msg);
+
+        // The message is rejected before it reaches any consumer.
+        assert_eq!(received.load(Ordering::SeqCst), count);
+        assert!(alice.events().any(|e| matches!(
+            e, Event::PeerMisbehaved { addr, reason: r, .. } if addr == remote.addr && r == reason
+        )));
+        alice
+            .outputs()
+            .find(|o| matches!(o, Io::Disconnect(a, _) if a == &remote.addr))
+            .expect("the peer is disconnected");
+    }
+
+    // Messages within the limits are processed.
+    let alex = PeerDummy::new([131, 31, 11, 99], network, 144, ServiceFlags::NETWORK);
+    alice.connect(&alex, Link::Outbound);
+
+    let count = received.load(Ordering::SeqCst);
+    alice.received(
+        &alex.addr,
+        NetworkMessage::Inv(vec![Inventory::Block(hash); syncmgr::MAX_MESSAGE_INVS]),
+    );
+    assert_eq!(received.load(Ordering::SeqCst), count + 1);
+}
+
+#[test]
+fn test_message_mask() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let mut cfg = Config::default();
+    cfg.message_mask = MessageMask::from_commands(["inv"]).unwrap();
+
+    let mut alice = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+
+    alice.connect(&bob, Link::Outbound);
+    alice.drain();
+
+    alice.received(&bob.addr, NetworkMessage::Ping(42));
+    alice.received(&bob.addr, NetworkMessage::Inv(vec![]));
+
+    let outputs = alice.outputs().collect::<Vec<_>>();
+    let published = outputs
+        .iter()
+        .filter_map(|o| match o {
+            Io::Event(Event::MessageReceived { from, message }) if from == &bob.addr => {
+                Some(message.cmd())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(published, vec!["inv"]);
+
+    // Messages that aren't selected are still handled.
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Io::Write(addr, msg) if addr == &bob.addr && msg.payload == NetworkMessage::Pong(42)
+    )));
+
+    // By default, no messages are published.
+    let mut alice = Peer::genesis(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        vec![],
+        fastrand::Rng::new(),
+    );
+
+    alice.connect(&bob, Link::Outbound);
+    alice.drain();
+    alice.received(&bob.addr, NetworkMessage::Inv(vec![]));
+
+    assert!(!alice
+        .events()
+        .any(|e| matches!(e, Event::MessageReceived { .. })));
+}
+
+#[test]
+fn test_timed_events() {
+    use std::sync::Mutex;
+
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let timed = Arc::new(Mutex::new(Vec::new()));
+    let mut cfg = Config::default();
+    cfg.hooks.on_event = {
+        let timed = timed.clone();
+        Arc::new(move |e: TimedEvent| timed.lock().unwrap().push(e))
+    };
+
+    let mut alice = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+
+    alice.init();
+    let events = alice.events().collect::<Vec<_>>();
+    let start = alice.local_time();
+
+    alice.elapse(LocalDuration::from_secs(60));
+    alice.connect(&bob, Link::Outbound);
+    alice.drain();
+
+    let timed = timed.lock().unwrap();
+    let (before, after): (Vec<_>, Vec<_>) = timed.iter().partition(|e| e.at == start);
+
+    // The inner events are the ones emitted.
+    assert_eq!(
+        before.iter().map(|e| e.event.code()).collect::<Vec<_>>(),
+        events.iter().map(|e| e.code()).collect::<Vec<_>>()
+    );
+    assert!(after
+        .iter()
+        .all(|e| e.at == start + LocalDuration::from_secs(60)));
+
+    let connected = after
+        .iter()
+        .find(|e| matches!(e.event, Event::PeerConnected { .. }))
+        .expect("a timed event is emitted for the connection");
+    assert_eq!(
+        connected.to_string(),
+        format!("[{}] {}", connected.at, connected.event)
+    );
+}
+
 #[test]
 fn test_handshake_initial_messages() {
     let rng = fastrand::Rng::new();
@@ -507,6 +1466,68 @@ fn test_connection_error() {
     );
 }
 
+#[test]
+fn test_duplicate_connection() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let inbound: PeerId = ([131, 31, 11, 33], 50123).into();
+
+    peer.connect(&remote, Link::Outbound);
+    peer.drain();
+
+    // The remote connects to us while we're connected to it. Our outbound connection is kept.
+    peer.protocol.connected(inbound, &peer.addr, Link::Inbound);
+
+    let outputs = peer.outputs().collect::<Vec<_>>();
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Io::Disconnect(addr, DisconnectReason::DuplicateConnection) if addr == &inbound
+    )));
+    assert!(!outputs
+        .iter()
+        .any(|o| matches!(o, Io::Disconnect(addr, _) if addr == &remote.addr)));
+
+    peer.disconnected(&inbound, DisconnectReason::DuplicateConnection.into());
+    assert!(peer.events().any(|e| matches!(
+        e,
+        Event::PeerDisconnected {
+            addr,
+            reason: nakamoto_net::Disconnect::StateMachine(DisconnectReason::DuplicateConnection),
+        } if addr == inbound
+    )));
+    assert_matches!(
+        peer.protocol.peermgr.peers().map(|(_, c)| c).collect::<Vec<_>>().as_slice(),
+        [conn] if conn.addr == remote.addr && conn.link == Link::Outbound
+    );
+}
+
+#[test]
+fn test_duplicate_connection_inbound_first() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([131, 31, 11, 33], 8333).into();
+    let inbound: PeerId = ([131, 31, 11, 33], 50123).into();
+
+    peer.connect_addr(&inbound, Link::Inbound);
+    peer.drain();
+
+    // We connect to a peer that is already connected to us. The inbound connection is
+    // dropped in favor of ours.
+    peer.protocol.peermgr.connect(&remote);
+    peer.protocol.connected(remote, &peer.addr, Link::Outbound);
+
+    assert!(peer.protocol.peermgr.is_disconnecting(&inbound));
+    assert!(peer.protocol.peermgr.is_connected(&remote));
+    assert!(
+        peer.messages(&remote)
+            .any(|m| matches!(m, NetworkMessage::Version(_))),
+        "the outbound handshake is started"
+    );
+}
+
 #[test]
 fn test_getaddr() {
     let rng = fastrand::Rng::new();
@@ -865,6 +1886,74 @@ fn test_submit_transactions() {
         .expect("Alice responds to `getdata` with a `tx` message");
 }
 
+#[test]
+fn test_mempool_watch() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let relevant = gen::transaction(&mut rng);
+    let irrelevant = gen::transaction(&mut rng);
+    let script = relevant.output[0].script_pubkey.clone();
+
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: ServiceFlags::NETWORK,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+    alice.drain();
+
+    // Once the filter is loaded, the peer is asked for its mempool.
+    let mut filter = nakamoto_common::bloom::BloomFilter::with_fp_rate(1, 0.0001).unwrap();
+    filter.insert(script.as_bytes());
+
+    alice.command(Command::WatchMempool(vec![script]));
+    alice.command(Command::LoadBloomFilter(filter.into(), remote));
+
+    let msgs = alice.messages(&remote).collect::<Vec<_>>();
+    assert!(msgs
+        .iter()
+        .any(|m| matches!(m, NetworkMessage::FilterLoad(_))));
+    assert!(msgs.iter().any(|m| matches!(m, NetworkMessage::MemPool)));
+
+    // Announced transactions are requested, and relevant ones are reported as in the mempool.
+    for tx in [&irrelevant, &relevant] {
+        let inv = vec![Inventory::Transaction(tx.txid())];
+
+        alice.received(&remote, NetworkMessage::Inv(inv.clone()));
+        alice
+            .messages(&remote)
+            .find(|m| m == &NetworkMessage::GetData(inv.clone()))
+            .expect("Alice requests the announced transaction");
+        alice.received(&remote, NetworkMessage::Tx(tx.clone()));
+    }
+
+    let events = alice.events().collect::<Vec<_>>();
+    let matched = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::ReceivedMatchedTx {
+                transaction,
+                height: None,
+                ..
+            } => Some(transaction.txid()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(matched, vec![relevant.txid()]);
+    assert!(events.iter().any(|e| matches!(
+        e,
+        Event::TxStatusChanged { txid, status: TxStatus::InMempool { peer, .. } }
+        if *txid == relevant.txid() && *peer == remote
+    )));
+}
+
 /// Should rebroadcast `inv` when no `getdata` is received.
 /// Should rebroadcast when a new peer connects.
 #[test]
@@ -1238,7 +2327,7 @@ fn test_submitted_transaction_filtering() {
             .protocol
             .cbfmgr
             .rescan
-            .transactions
+            .transactions()
             .contains_key(&tx.txid()),
         "The transaction is no longer watched"
     );
@@ -1476,7 +2565,7 @@ fn test_block_events() {
             event @ Event::Ready { .. } => Some(event),
             event @ Event::BlockConnected { .. } => Some(event),
             event @ Event::BlockDisconnected { .. } => Some(event),
-            event @ Event::BlockHeadersImported { .. } => Some(event),
+            event @ Event::BlockHeadersImported(_) => Some(event),
             _ => None,
         })
     }
@@ -1508,7 +2597,7 @@ fn test_block_events() {
     }
     assert_matches!(
         events.next().unwrap(),
-        Event::BlockHeadersImported {  height, ..  }
+        Event::BlockHeadersImported(ChainImport { height, .. })
         if height == best
     );
     assert_eq!(events.count(), 0);
@@ -1529,8 +2618,8 @@ fn test_block_events() {
     );
     assert_matches!(
         events.next().unwrap(),
-        Event::BlockHeadersImported { height, .. }
-        if height == best + 1
+        Event::BlockHeadersImported(ChainImport { height, reorg, reorg_depth, .. })
+        if height == best + 1 && !reorg && reorg_depth == 0
     );
     assert_eq!(0, events.count());
 
@@ -1544,16 +2633,16 @@ fn test_block_events() {
     // Disconnected events.
     assert_matches!(
         events.next().unwrap(),
-        Event::BlockDisconnected { height, header }
-        if height == best + 1 && header.block_hash() == extra.block_hash()
+        Event::BlockDisconnected { height, header, fork_height: fork }
+        if height == best + 1 && header.block_hash() == extra.block_hash() && fork == fork_height
     );
     for height_ in (fork_height + 1..=best).rev() {
         let hash_ = headers[height_ as usize].block_hash();
 
         assert_matches!(
             events.next().unwrap(),
-            Event::BlockDisconnected { height, header }
-            if height == height_ as Height && header.block_hash() == hash_
+            Event::BlockDisconnected { height, header, fork_height: fork }
+            if height == height_ as Height && header.block_hash() == hash_ && fork == fork_height
         );
     }
 
@@ -1570,8 +2659,8 @@ fn test_block_events() {
 
     assert_matches!(
         events.next().unwrap(),
-        Event::BlockHeadersImported { height, .. }
-        if height == fork_best
+        Event::BlockHeadersImported(ChainImport { height, reorg, reorg_depth, .. })
+        if height == fork_best && reorg && reorg_depth == (best + 1 - fork_height) as usize
     );
     assert!(events.next().is_none());
 }
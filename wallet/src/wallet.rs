@@ -249,7 +249,7 @@ impl<H: Handle> Wallet<H> {
                 self.ui.handle_filter_processed(height);
                 self.tips.cfilter = height;
             }
-            client::Event::BlockHeadersImported { height, .. } => {
+            client::Event::BlockHeadersImported(client::ChainImport { height, .. }) => {
                 self.tips.header = height;
             }
             client::Event::BlockMatched { block, height } => {
@@ -266,7 +266,7 @@ impl<H: Handle> Wallet<H> {
                     balance,
                 );
             }
-            client::Event::ReceivedMatchedTx { transaction } => {
+            client::Event::ReceivedMatchedTx { transaction, .. } => {
                 // for t in &matches {
                 self.apply(&transaction, watch);
                 // }